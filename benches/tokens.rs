@@ -0,0 +1,168 @@
+//! Benchmarks of lexing and planning a large token tree, measuring both
+//! the time they take and the heap memory taken by the lexed tokens.
+//!
+//! To compare layouts of [`Token`], save a baseline before changing them
+//! with `cargo bench --bench tokens -- --save-baseline before`,
+//! then compare to it with `cargo bench --bench tokens -- --baseline before`.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::convert::Infallible;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use criterion::measurement::{Measurement, ValueFormatter};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+
+use bfup::config::Config;
+use bfup::lex::{Lexer, Token};
+use bfup::plan::plan;
+
+/// Number of groups in the benchmarked input.
+const GROUPS: usize = 100_000;
+
+/// Bytes allocated on the heap and not deallocated yet.
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+/// The system allocator, counting the [`ALLOCATED`] bytes.
+struct Counting;
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+            ALLOCATED.fetch_add(new_size, Ordering::Relaxed);
+        }
+        new_ptr
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: Counting = Counting;
+
+/// [`Measurement`] of the heap bytes a routine leaves allocated,
+/// the memory taken by what it returns.
+struct Retained;
+
+impl Measurement for Retained {
+    type Intermediate = usize;
+    type Value = usize;
+
+    fn start(&self) -> Self::Intermediate {
+        ALLOCATED.load(Ordering::Relaxed)
+    }
+
+    fn end(&self, start: Self::Intermediate) -> Self::Value {
+        ALLOCATED.load(Ordering::Relaxed).saturating_sub(start)
+    }
+
+    fn add(&self, v1: &Self::Value, v2: &Self::Value) -> Self::Value {
+        v1 + v2
+    }
+
+    fn zero(&self) -> Self::Value {
+        0
+    }
+
+    fn to_f64(&self, value: &Self::Value) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        &BytesFormatter
+    }
+}
+
+/// Formatter of the [`Retained`] bytes.
+struct BytesFormatter;
+
+impl ValueFormatter for BytesFormatter {
+    fn scale_values(&self, typical_value: f64, values: &mut [f64]) -> &'static str {
+        let (denominator, unit) = match typical_value {
+            value if value < 1024.0 => (1.0, "B"),
+            value if value < 1024.0 * 1024.0 => (1024.0, "KiB"),
+            _ => (1024.0 * 1024.0, "MiB"),
+        };
+        for value in values {
+            *value /= denominator;
+        }
+
+        unit
+    }
+
+    fn scale_throughputs(
+        &self,
+        _typical_value: f64,
+        _throughput: &Throughput,
+        _values: &mut [f64],
+    ) -> &'static str {
+        "B"
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        "B"
+    }
+}
+
+/// Get an input with `groups` groups of a handful of tokens, a third of them
+/// multiplied and most of them expanding a macro.
+fn input(groups: usize) -> String {
+    let mut input = String::from("$a(+>-<)");
+    for index in 0..groups {
+        input.push_str(match index % 3 {
+            0 => "(+>a)",
+            1 => "#3(<-)",
+            _ => "(a.(,a))",
+        });
+    }
+
+    input
+}
+
+fn lex(input: &str, config: &Config) -> Vec<Token> {
+    Lexer::new(input.chars().map(Ok::<char, Infallible>), config)
+        .read_all_tokens()
+        .expect("The input should be lexed.")
+}
+
+fn bench_time(c: &mut Criterion) {
+    let config = Config::default();
+    let input = input(GROUPS);
+    let tokens = lex(&input, &config);
+
+    let mut group = c.benchmark_group("tokens");
+    group.sample_size(20);
+    group.throughput(Throughput::Bytes(input.len() as u64));
+    group.bench_function("lex", |b| b.iter(|| lex(&input, &config)));
+    group.bench_function("plan", |b| b.iter(|| plan(&tokens)));
+    group.finish();
+}
+
+fn bench_memory(c: &mut Criterion<Retained>) {
+    let config = Config::default();
+    let input = input(GROUPS);
+
+    c.bench_function("tokens/retained", |b| {
+        b.iter_batched(|| (), |()| lex(&input, &config), BatchSize::PerIteration)
+    });
+}
+
+criterion_group!(time, bench_time);
+criterion_group! {
+    name = memory;
+    config = Criterion::default().with_measurement(Retained).sample_size(10);
+    targets = bench_memory
+}
+criterion_main!(time, memory);
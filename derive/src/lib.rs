@@ -213,17 +213,11 @@ impl ToTokens for FieldList {
 /// Parse the arguments passed into [`enum_fields`] into a [`SkipList`] and [`FieldList`].
 fn parse_enum_fields_args(input: ParseStream) -> Result<(SkipList, FieldList)> {
     let skip_list = if input.peek(Token![!]) {
-        match SkipList::parse(input) {
-            Ok(list) => list,
-            Err(error) => return Err(error),
-        }
+        SkipList::parse(input)?
     } else {
         SkipList::new()
     };
-    let field_list = match FieldList::parse(input) {
-        Ok(list) => list,
-        Err(error) => return Err(error),
-    };
+    let field_list = FieldList::parse(input)?;
 
     Ok((skip_list, field_list))
 }
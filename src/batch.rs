@@ -0,0 +1,148 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use anyhow::{Context, Result};
+use serde::Deserialize;
+
+use crate::config::{Config, ConfigCache, Dialect};
+use crate::pre::Emit;
+
+/// Job of a batch, read from a json list of jobs.
+#[derive(Deserialize, fmt::Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Job {
+    pub input: PathBuf,
+    pub output: PathBuf,
+    /// Ron config file, takes precedence over the `dialect`.
+    #[serde(default)]
+    pub config: Option<PathBuf>,
+    #[serde(default)]
+    pub dialect: Option<Dialect>,
+    #[serde(default)]
+    pub emit: Emit,
+    /// Width the output is aligned to *(unaligned if `None`)*.
+    #[serde(default)]
+    pub width: Option<usize>,
+}
+
+/// Read the json list of jobs at `path`,
+/// their relative paths are made relative to its directory.
+pub fn read_jobs(path: &Path) -> Result<Vec<Job>> {
+    let content = fs::read(path).with_context(|| format!("failed to read '{}'", path.display()))?;
+    let mut jobs: Vec<Job> = serde_json::from_slice(&content)
+        .with_context(|| format!("failed to parse the jobs in '{}'", path.display()))?;
+
+    let dir = path.parent().unwrap_or(Path::new(""));
+    for job in &mut jobs {
+        job.input = dir.join(&job.input);
+        job.output = dir.join(&job.output);
+        job.config = job.config.as_ref().map(|config| dir.join(config));
+    }
+
+    Ok(jobs)
+}
+
+/// Run every one of `jobs` with `run_job`, each on its own thread if `parallel`,
+/// returning their results in order.
+///
+/// `run_job` is passed the config of the job, `default_config` for the jobs
+/// without a config or dialect, the config files are shared through `cache`.
+pub fn run_batch<T: Send>(
+    jobs: &[Job],
+    default_config: &Config,
+    cache: &Mutex<ConfigCache>,
+    parallel: bool,
+    run_job: &(dyn Fn(&Job, &Config) -> Result<T> + Sync),
+) -> Vec<Result<T>> {
+    let run = |job: &Job| {
+        let job_config = job_config(job, cache)?;
+        run_job(job, job_config.as_deref().unwrap_or(default_config))
+    };
+    if !parallel {
+        return jobs.iter().map(run).collect();
+    }
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = jobs
+            .iter()
+            .map(|job| scope.spawn(move || run(job)))
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("A job should not panic."))
+            .collect()
+    })
+}
+
+/// Read the config of `job` through `cache`, or the config of its dialect,
+/// if it has either.
+fn job_config(job: &Job, cache: &Mutex<ConfigCache>) -> Result<Option<Arc<Config>>> {
+    match (&job.config, job.dialect) {
+        (Some(path), _) => {
+            let content = fs::read(path)
+                .with_context(|| format!("failed to open config '{}'", path.display()))?;
+            let config = cache
+                .lock()
+                .expect("The config cache should not be poisoned.")
+                .get_or_read(&content, Some(path))
+                .with_context(|| format!("failed to parse config '{}'", path.display()))?;
+            Ok(Some(config))
+        }
+        (None, Some(dialect)) => Ok(Some(Arc::new(dialect.config()))),
+        (None, None) => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+
+    #[test]
+    fn batch_jobs() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("bfup-batch-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        fs::write(dir.join("in.bfp"), "#3(+>)")?;
+        fs::write(dir.join("in.ook"), "Ook. Ook?")?;
+        fs::write(
+            dir.join("jobs.json"),
+            r#"[
+                {"input": "in.bfp", "output": "out.bf", "width": 4},
+                {"input": "in.bfp", "output": "counts.json", "emit": "tokens-count"},
+                {"input": "in.ook", "output": "out.ook", "dialect": "ook"},
+                {"input": "missing.bfp", "output": "missing.bf"}
+            ]"#,
+        )?;
+
+        let jobs = read_jobs(&dir.join("jobs.json"))?;
+        let results = run_batch(
+            &jobs,
+            &Config::default(),
+            &Mutex::new(ConfigCache::default()),
+            true,
+            &|job, config| {
+                let input = fs::read_to_string(&job.input)?;
+                Ok((input, *config == Dialect::Ook.config()))
+            },
+        );
+        assert!(
+            jobs[0].input == dir.join("in.bfp") && jobs[1].output == dir.join("counts.json"),
+            "The paths of the jobs should be relative to the list."
+        );
+        assert!(
+            matches!(
+                &results[..],
+                [Ok((input, false)), Ok(_), Ok((_, true)), Err(_)] if input == "#3(+>)"
+            ),
+            "Only the job with a missing input should fail, \
+            the Ook! one with its dialect, got {results:?}."
+        );
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
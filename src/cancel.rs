@@ -0,0 +1,138 @@
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Error returned when an operation was cancelled
+/// through a [`CancellationToken`].
+#[derive(thiserror::Error, fmt::Debug)]
+#[error("operation cancelled.")]
+pub struct Cancelled;
+
+/// Flag shared between threads, used to abort
+/// long running operations *(e.g. [`preprocess`][crate::pre::preprocess])*.
+///
+/// Every clone of a `CancellationToken` refers to the same flag,
+/// so cancelling one of them cancels all of them.
+///
+/// It also keeps the temporary files being written, to be removed
+/// when the process can't wait for the operations to abort.
+///
+/// A token created [`with_deadline()`][CancellationToken::with_deadline]
+/// is also cancelled once its deadline passes.
+#[derive(Clone, Default, fmt::Debug)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    partial_paths: Arc<Mutex<Vec<PathBuf>>>,
+    deadline: Option<Instant>,
+}
+
+impl CancellationToken {
+    /// Create a new, not cancelled `CancellationToken`.
+    pub fn new() -> Self {
+        CancellationToken::default()
+    }
+
+    /// Get a token cancelled with this one, or once `deadline` passes.
+    pub fn with_deadline(&self, deadline: Instant) -> Self {
+        CancellationToken {
+            deadline: Some(deadline),
+            ..self.clone()
+        }
+    }
+
+    /// Check whether the deadline of the token has passed.
+    pub fn is_past_deadline(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Request the cancellation of every operation using this token.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Check whether the cancellation has been requested.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed) || self.is_past_deadline()
+    }
+
+    /// Return [`Cancelled`] if the cancellation has been requested.
+    pub fn check(&self) -> Result<(), Cancelled> {
+        if self.is_cancelled() {
+            Err(Cancelled)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Register `path` as a temporary file, removed by [`remove_partial_paths`]
+    /// if it still exists.
+    ///
+    /// [`remove_partial_paths`]: CancellationToken::remove_partial_paths
+    pub fn register_partial_path(&self, path: PathBuf) {
+        self.partial_paths
+            .lock()
+            .expect("The lock shouldn't be poisoned.")
+            .push(path);
+    }
+
+    /// Remove every registered temporary file, ignoring errors.
+    ///
+    /// Called before exiting without waiting for the operations to abort.
+    pub fn remove_partial_paths(&self) {
+        // the main thread may have panicked while holding the lock
+        let paths = match self.partial_paths.lock() {
+            Ok(paths) => paths,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        for path in paths.iter() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use anyhow::Result;
+
+    use super::*;
+
+    #[test]
+    fn cancellation_deadline() {
+        let cancellation = CancellationToken::new();
+        let expired = cancellation.with_deadline(Instant::now());
+        let pending = cancellation.with_deadline(Instant::now() + Duration::from_secs(60));
+
+        assert!(
+            expired.is_cancelled() && !cancellation.is_cancelled() && !pending.is_cancelled(),
+            "Only the token past its deadline should be cancelled."
+        );
+        cancellation.cancel();
+        assert!(
+            pending.is_cancelled() && !pending.is_past_deadline(),
+            "The token should be cancelled with the one it was created from."
+        );
+    }
+
+    #[test]
+    fn cancellation_removes_partial_paths() -> Result<()> {
+        let path = std::env::temp_dir().join(format!("bfup-cancel-{}.part", std::process::id()));
+        fs::write(&path, "+")?;
+
+        let cancellation = CancellationToken::new();
+        cancellation.clone().register_partial_path(path.clone());
+        cancellation.remove_partial_paths();
+
+        assert!(
+            !path.exists(),
+            "The registered partial path should be removed through any clone."
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,193 @@
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::hash::Hasher;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+/// State of the emitter after writing a number of operators,
+/// from which an interrupted run can be resumed.
+#[derive(Clone, Copy, Default, PartialEq, Eq, fmt::Debug, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// Number of emitted operators.
+    pub operators: usize,
+    /// Number of bytes written to the output.
+    pub bytes: u64,
+    /// Width of the last, unfinished line.
+    pub line_len: usize,
+}
+
+/// First line of a [`CheckpointLog`], identifying the run that saved it.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    /// Hash of the input and the options the output was written with.
+    run: u64,
+}
+
+/// [`Hasher`] computing the 64-bit FNV-1a hash of the run,
+/// which unlike the std hashers doesn't change between Rust releases,
+/// so the checkpoints stay resumable after upgrading.
+pub struct RunHasher(u64);
+
+impl Default for RunHasher {
+    fn default() -> Self {
+        RunHasher(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Hasher for RunHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+/// Path of the copy of stdin read by the run writing to `partial_output_path`,
+/// which is hashed while it's copied and preprocessed from the copy.
+pub fn stdin_path(partial_output_path: &Path) -> PathBuf {
+    let mut file_name = partial_output_path
+        .file_name()
+        .unwrap_or_default()
+        .to_os_string();
+    file_name.push(".stdin");
+
+    partial_output_path.with_file_name(file_name)
+}
+
+/// Path of the checkpoint log of the output written to `partial_output_path`.
+pub fn log_path(partial_output_path: &Path) -> PathBuf {
+    let mut file_name = partial_output_path
+        .file_name()
+        .unwrap_or_default()
+        .to_os_string();
+    file_name.push(".checkpoints");
+
+    partial_output_path.with_file_name(file_name)
+}
+
+/// Append-only log of [`Checkpoints`][Checkpoint], one ron struct per line,
+/// after a header with the hash of the run that saved them.
+pub struct CheckpointLog {
+    file: File,
+}
+
+impl CheckpointLog {
+    /// Open the log at `path` for appending, creating it if it doesn't exist.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(CheckpointLog {
+            file: OpenOptions::new().create(true).append(true).open(path)?,
+        })
+    }
+
+    /// Append `checkpoint` to the log.
+    pub fn append(&mut self, checkpoint: &Checkpoint) -> io::Result<()> {
+        let line = ron::to_string(checkpoint).map_err(io::Error::other)?;
+        writeln!(self.file, "{line}")?;
+        self.file.sync_data()
+    }
+
+    /// Read every checkpoint from the log at `path`,
+    /// ignoring a last line cut off by an interruption.
+    ///
+    /// Returns error if the checkpoints weren't saved by the run hashed to `run`,
+    /// as the output can only be resumed with the same input and options.
+    pub fn read(path: &Path, run: u64) -> io::Result<Vec<Checkpoint>> {
+        let mut lines = BufReader::new(File::open(path)?).lines();
+        let header: Option<Header> = lines
+            .next()
+            .transpose()?
+            .and_then(|line| ron::from_str(&line).ok());
+        if header.is_none_or(|header| header.run != run) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the checkpoints were saved by a run with another input, config or alignment",
+            ));
+        }
+
+        let mut checkpoints: Vec<Checkpoint> = Vec::new();
+        for line in lines {
+            match ron::from_str(&line?) {
+                Ok(checkpoint) => checkpoints.push(checkpoint),
+                Err(_) => break,
+            }
+        }
+
+        Ok(checkpoints)
+    }
+
+    /// Rewrite the log at `path` to only contain `checkpoints`,
+    /// saved by the run hashed to `run`.
+    pub fn truncate(path: &Path, run: u64, checkpoints: &[Checkpoint]) -> io::Result<()> {
+        let mut lines = ron::to_string(&Header { run }).map_err(io::Error::other)?;
+        lines.push('\n');
+        for checkpoint in checkpoints {
+            lines.push_str(&ron::to_string(checkpoint).map_err(io::Error::other)?);
+            lines.push('\n');
+        }
+
+        fs::write(path, lines)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+
+    #[test]
+    fn checkpoint_run_hasher() {
+        let mut hasher = RunHasher::default();
+        hasher.write(b"a");
+        assert!(
+            hasher.finish() == 0xaf63_dc4c_8601_ec8c,
+            "{:x} should be the FNV-1a hash of \"a\".",
+            hasher.finish()
+        );
+    }
+
+    #[test]
+    fn checkpoint_log_round_trip() -> Result<()> {
+        let path =
+            std::env::temp_dir().join(format!("bfup-test-{}.checkpoints", std::process::id()));
+        let checkpoints = [
+            Checkpoint {
+                operators: 4,
+                bytes: 5,
+                line_len: 0,
+            },
+            Checkpoint {
+                operators: 8,
+                bytes: 10,
+                line_len: 2,
+            },
+        ];
+
+        CheckpointLog::truncate(&path, 7, &checkpoints[..1])?;
+        CheckpointLog::open(&path)?.append(&checkpoints[1])?;
+        fs::OpenOptions::new()
+            .append(true)
+            .open(&path)?
+            .write_all(b"(operators:")?;
+        let read = CheckpointLog::read(&path, 7);
+        let other_run = CheckpointLog::read(&path, 8);
+        fs::remove_file(&path)?;
+
+        assert!(
+            read? == checkpoints,
+            "The checkpoints should be read back without the cut off line."
+        );
+        assert!(
+            other_run.is_err_and(|error| error.kind() == io::ErrorKind::InvalidData),
+            "The checkpoints of another run shouldn't be read."
+        );
+
+        Ok(())
+    }
+}
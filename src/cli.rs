@@ -1,18 +1,80 @@
-use std::fs::File;
-use std::io::{stdin, stdout, BufRead, BufReader, BufWriter, Write};
-use std::path::PathBuf;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::hash::{Hash, Hasher};
+use std::io::{
+    self, stdin, stdout, BufRead, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write,
+};
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
-use anyhow::{Context, Result};
-use clap::Parser;
-use utf8_chars::BufReadCharsExt;
+use anyhow::{bail, Context, Result};
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgGroup, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use colored::Colorize;
+use utf8_chars::{BufReadCharsExt, ReadCharError};
 
-use crate::config::{self, Config};
-use crate::pre::{preprocess, preprocess_and_align};
+use crate::batch::{read_jobs, run_batch, Job};
+use crate::cancel::CancellationToken;
+use crate::checkpoint::{self, Checkpoint, CheckpointLog, RunHasher};
+#[cfg(feature = "compress")]
+use crate::compress::{decompressed, Compression};
+use crate::config::{
+    self, BudgetPolicy, Config, ConfigCache, ConfigField, Dialect, EofPolicy, EscapeInGroups,
+    MultiplierPolicy, UnrecognizedPolicy,
+};
+use crate::diagnostic::{Diagnostic, DiagnosticSink, MessageFormat, Reported, Severity};
+use crate::diff::diff;
+use crate::difftest::{difftest, read_inputs, DEFAULT_TIMEOUT};
+use crate::equiv::{equivalence, Equivalence, Side};
+use crate::generate::{self, Generating};
+use crate::grapheme::Segmenting;
+use crate::inject::{FailingChars, FailingWriter};
+use crate::lex::{
+    self, token_at, Coverage, Lexer, MacroDefinition, Provenance, Token, TokenCounts,
+    UnrecognizedChars,
+};
+use crate::metrics::{Metrics, StatsFormat, Usage};
+#[cfg(feature = "unstable-pass-plugins")]
+use crate::pass::{DylibPass, PassManager};
+use crate::pass::{Pipeline, Stage};
+#[cfg(feature = "unstable-pass-plugins")]
+use crate::plan::Step;
+use crate::plan::{plan_counted, plan_with, write_plan};
+use crate::playground::{open_in_browser, playground_url};
+use crate::pre::{
+    annotate, check_rectangle, emit_plan, emit_tokens, emit_unsplit, lex_tokens, record_coverage,
+    stream_tokens, verify_size, AlignBy, Annotation, Emit, Gutter, OutputPosition, Report,
+    Resumable,
+};
+use crate::record::{read_recording, write_recording};
+use crate::region::{select, Region};
+use crate::repl::{History, Repl};
+use crate::run::{Profile, Program};
+#[cfg(feature = "serve")]
+use crate::serve::{self, Server};
+use crate::span::SourceId;
+use crate::suggest::{free_symbols, suggest};
+use crate::template::{self, Interpolating, Variables};
+use crate::wizard::Wizard;
 
-const DEFAULT_LINE_WIDTH: usize = 32;
+const DEFAULT_LINE_WIDTH: NonZeroUsize = NonZeroUsize::new(32).unwrap();
+const DEFAULT_UNRECOGNIZED_THRESHOLD: &str = "50";
+/// Name of the REPL history file in the home directory.
+const HISTORY_NAME: &str = ".bfup_history";
+/// Output path standing for stdout.
+const STDOUT_PATH: &str = "-";
+/// Version printed with `--test-mode`, so the tests don't change with the version.
+const TEST_MODE_VERSION: &str = "0.0.0-test";
 
-#[derive(Parser)]
+#[derive(Parser, Clone)]
 #[command(author, version, about, long_about = None)]
+#[command(group(ArgGroup::new("config_args").multiple(true)))]
 #[command(help_template(
     "\
 {name} {version}
@@ -23,13 +85,62 @@ const DEFAULT_LINE_WIDTH: usize = 32;
 "
 ))]
 struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
     /// File to preprocess [default: stdin]
     #[arg(value_name = "FILE")]
     input: Option<PathBuf>,
 
-    /// Specify output filename
+    /// Arguments passed to the program run with --run on its tape, from the
+    /// first cell, each followed by a zero cell (e.g. from a '#!/usr/bin/env -S bfup --run'
+    /// script)
+    #[arg(
+        value_name = "ARGS",
+        requires = "run",
+        trailing_var_arg = true,
+        allow_hyphen_values = true
+    )]
+    args: Vec<String>,
+
+    /// Specify output filename, '-' for stdout, can be repeated
+    /// to write the output to every FILE [default: stdout]
     #[arg(short = 'o', long, value_name = "FILE")]
-    output: Option<PathBuf>,
+    output: Vec<PathBuf>,
+
+    /// Compress the output, appending the format's extension to the output files
+    #[cfg(feature = "compress")]
+    #[arg(long,
+        conflicts_with_all = ["open", "checkpoint_every"],
+        value_enum,
+        value_name = "FORMAT",
+    )]
+    #[cfg_attr(feature = "clipboard", arg(conflicts_with = "copy"))]
+    compress: Option<Compression>,
+
+    /// Also copy the output to the system clipboard
+    #[cfg(feature = "clipboard")]
+    #[arg(long)]
+    copy: bool,
+
+    /// Open the output in the default browser, with '{}' in TEMPLATE
+    /// replaced with the url-encoded output [default: the output is appended]
+    #[arg(long, value_name = "TEMPLATE")]
+    open: Option<String>,
+
+    /// Run the jobs in the json list in FILE instead of preprocessing the input,
+    /// each job has an 'input', 'output' and optionally a 'config' file,
+    /// 'dialect', 'emit' kind and 'width', the other options apply to every job
+    #[arg(long, value_name = "FILE", conflicts_with_all = ["input", "output"])]
+    batch: Option<PathBuf>,
+
+    /// Run the batch jobs in parallel
+    #[arg(long, requires = "batch")]
+    parallel: bool,
+
+    /// Save a copy of the raw input read from stdin to FILE
+    #[arg(short = 't', long, conflicts_with = "input", value_name = "FILE")]
+    tee: Option<PathBuf>,
 
     /// Read preprocessor config from a ron file.
     #[arg(short = 'C', long, value_name = "FILE")]
@@ -37,14 +148,48 @@ struct Cli {
 
     /// Specify recognized operators
     #[arg(short = '+', long,
-        conflicts_with = "config_file",
+        conflicts_with = "config_file", group = "config_args",
         default_value_t = String::from(config::DEFAULT_OPERATORS),
     )]
     operators: String,
 
+    /// Treat the operators made of multiple chars (e.g. emoji with modifiers)
+    /// as single operators
+    #[arg(long, conflicts_with = "config_file", group = "config_args")]
+    graphemes: bool,
+
+    /// Add an operator made of multiple chars (e.g. 'Ook.'),
+    /// matched before the rest of the input, the longest first
+    #[arg(
+        long = "word-operator",
+        value_name = "WORD",
+        conflicts_with = "config_file",
+        group = "config_args"
+    )]
+    word_operators: Vec<String>,
+
+    /// Specify the stages run while preprocessing, in order
+    /// [default: every stage]
+    #[arg(
+        long,
+        conflicts_with = "config_file",
+        group = "config_args",
+        value_enum,
+        value_delimiter = ',',
+        value_name = "STAGES"
+    )]
+    passes: Option<Vec<Stage>>,
+
+    /// Cancel the operators undoing each other ('+-', '<>'), remove the empty
+    /// loops ('[]') and the loops right after another one of the output
+    /// (requires brainfuck's operators and the optimize stage,
+    /// --stats reports how many operators were removed)
+    #[arg(long)]
+    optimize: bool,
+
     /// Specify number prefix
     #[arg(short = '#', long,
-        conflicts_with = "config_file",
+        conflicts_with = "config_file", group = "config_args",
         default_value_t = config::DEFAULT_NUMBER_PREFIX,
         value_name = "CHAR",
     )]
@@ -52,7 +197,7 @@ struct Cli {
 
     /// Specify macro prefix
     #[arg(short = 'm', long,
-        conflicts_with = "config_file",
+        conflicts_with = "config_file", group = "config_args",
         default_value_t = config::DEFAULT_MACRO_PREFIX,
         value_name = "CHAR",
     )]
@@ -60,7 +205,7 @@ struct Cli {
 
     /// Specify escape prefix
     #[arg(short = 'e', long,
-        conflicts_with = "config_file",
+        conflicts_with = "config_file", group = "config_args",
         default_value_t = config::DEFAULT_ESCAPE_PREFIX,
         value_name = "CHAR",
     )]
@@ -68,7 +213,7 @@ struct Cli {
 
     /// Specify group start delimiter
     #[arg(long,
-        conflicts_with = "config_file",
+        conflicts_with = "config_file", group = "config_args",
         default_value_t = config::DEFAULT_GROUP_START_DELIMITER,
         value_name = "CHAR",
     )]
@@ -76,12 +221,231 @@ struct Cli {
 
     /// Specify group end delimiter
     #[arg(long,
-        conflicts_with = "config_file",
+        conflicts_with = "config_file", group = "config_args",
         default_value_t = config::DEFAULT_GROUP_END_DELIMITER,
         value_name = "CHAR",
     )]
     group_end_delimiter: char,
 
+    /// Add a group delimiter pair interchangeable with the main one,
+    /// groups must be closed by their own pair, can be repeated
+    #[arg(long,
+        conflicts_with = "config_file", group = "config_args",
+        value_parser = parse_delimiter_pair,
+        value_name = "STARTEND",
+    )]
+    delimiter_pair: Vec<(char, char)>,
+
+    /// Match operators, prefixes and delimiters regardless of case
+    #[arg(
+        short = 'i',
+        long,
+        conflicts_with = "config_file",
+        group = "config_args"
+    )]
+    case_insensitive: bool,
+
+    /// Treat spaces, tabs and newlines as operators
+    #[arg(
+        short = 'w',
+        long,
+        conflicts_with = "config_file",
+        group = "config_args"
+    )]
+    whitespace_significant: bool,
+
+    /// Allow macro definitions spanning the rest of the line ('$x = tokens...'),
+    /// spliced where they're used without a group
+    #[arg(long, conflicts_with = "config_file", group = "config_args")]
+    line_macros: bool,
+
+    /// Allow including the macros and tokens of other files ('$%"lib.bfup"')
+    #[arg(long, conflicts_with = "config_file", group = "config_args")]
+    includes: bool,
+
+    /// Read the token after '$?x' only if the macro 'x' is defined ('$?x(...)')
+    #[arg(long, conflicts_with = "config_file", group = "config_args")]
+    conditionals: bool,
+
+    /// Repeat the group after '#N@i' N times, with '#i' as the repetition's number ('#5@i(#i+>)')
+    #[arg(long, conflicts_with = "config_file", group = "config_args")]
+    counted_loops: bool,
+
+    /// Define the named numbers after '$=', used after the number prefix ('$=W#32 #W+')
+    #[arg(long, conflicts_with = "config_file", group = "config_args")]
+    constants: bool,
+
+    /// Name the cells after '$&' and move the pointer to them
+    /// after '&' ('$&counter#3 &counter+')
+    #[arg(long, conflicts_with = "config_file", group = "config_args")]
+    named_cells: bool,
+
+    /// Allow the empty groups ('()'), which emit nothing
+    #[arg(long, conflicts_with = "config_file", group = "config_args")]
+    allow_empty_groups: bool,
+
+    /// Expand the quoted strings into code printing them ('"Hello"')
+    #[arg(long, conflicts_with = "config_file", group = "config_args")]
+    strings: bool,
+
+    /// Read numbers written with the decimal digits of any script ('#٣')
+    #[arg(long, conflicts_with = "config_file", group = "config_args")]
+    unicode_digits: bool,
+
+    /// Separate the digits of numbers with CHAR ('#1_000')
+    #[arg(
+        long,
+        value_name = "CHAR",
+        default_value_t = config::DEFAULT_NUMBER_SEPARATOR,
+        conflicts_with = "config_file",
+        group = "config_args"
+    )]
+    number_separator: char,
+
+    /// Don't allow separating the digits of numbers
+    #[arg(
+        long,
+        conflicts_with_all = ["config_file", "number_separator"],
+        group = "config_args"
+    )]
+    no_number_separator: bool,
+
+    /// Skip everything from CHAR until the end of the line
+    #[arg(
+        long,
+        value_name = "CHAR",
+        conflicts_with = "config_file",
+        group = "config_args"
+    )]
+    line_comment: Option<char>,
+
+    /// Skip everything between the START and END delimiters of one or two chars
+    #[arg(long,
+        conflicts_with = "config_file", group = "config_args",
+        value_parser = parse_block_delimiters,
+        value_name = "'START END'",
+    )]
+    block_comment: Option<(String, String)>,
+
+    /// Copy everything between the START and END delimiters of one or two chars
+    /// to the output as it is
+    #[arg(long,
+        conflicts_with = "config_file", group = "config_args",
+        value_parser = parse_block_delimiters,
+        value_name = "'START END'",
+    )]
+    raw_block: Option<(String, String)>,
+
+    /// Replace every 'CHAR{KEY}' in the input with the value of the variable KEY
+    /// before lexing (with '@' the default, only replaced when --set is passed)
+    #[arg(
+        long,
+        value_name = "CHAR",
+        conflicts_with = "config_file",
+        group = "config_args"
+    )]
+    variable_prefix: Option<char>,
+
+    /// Warn about unrecognized characters
+    #[arg(long, conflicts_with = "config_file", group = "config_args")]
+    strict_chars: bool,
+
+    /// Make unrecognized characters errors
+    #[arg(long, requires = "strict_chars")]
+    deny: bool,
+
+    /// Specify characters never reported as unrecognized
+    #[arg(
+        long,
+        conflicts_with = "config_file",
+        group = "config_args",
+        value_name = "CHARS"
+    )]
+    allowed_chars: Option<String>,
+
+    /// Specify behavior on unterminated groups and macro definitions
+    #[arg(long,
+        conflicts_with = "config_file", group = "config_args",
+        value_enum,
+        default_value_t = EofPolicy::Strict,
+        value_name = "POLICY",
+    )]
+    eof_policy: EofPolicy,
+
+    /// Specify how consecutive numbers combine into a multiplier
+    #[arg(long,
+        conflicts_with = "config_file", group = "config_args",
+        value_enum,
+        default_value_t = MultiplierPolicy::Replace,
+        value_name = "POLICY",
+    )]
+    multiplier_policy: MultiplierPolicy,
+
+    /// Specify whether escaped group delimiters are skipped or emitted
+    #[arg(long,
+        conflicts_with = "config_file", group = "config_args",
+        value_enum,
+        default_value_t = EscapeInGroups::Skip,
+        value_name = "MODE",
+    )]
+    escape_in_groups: EscapeInGroups,
+
+    /// Limit the number of operators a single expansion
+    /// of the macro CHAR can emit, can be repeated
+    #[arg(long,
+        conflicts_with = "config_file", group = "config_args",
+        value_parser = parse_char_number,
+        value_name = "CHAR=SIZE",
+    )]
+    macro_budget: Vec<(char, usize)>,
+
+    /// Specify behavior on macros exceeding their budget
+    #[arg(long,
+        conflicts_with = "config_file", group = "config_args",
+        value_enum,
+        default_value_t = BudgetPolicy::Warn,
+        value_name = "POLICY",
+    )]
+    budget_policy: BudgetPolicy,
+
+    /// Specify how many macro expansions can be nested within each other
+    #[arg(long,
+        conflicts_with = "config_file", group = "config_args",
+        default_value_t = config::DEFAULT_MAX_EXPANSION_DEPTH,
+        value_name = "DEPTH",
+    )]
+    max_expansion_depth: usize,
+
+    /// Specify how many macro definitions the input can contain
+    #[arg(long,
+        conflicts_with = "config_file", group = "config_args",
+        default_value_t = config::DEFAULT_MAX_MACROS,
+        value_name = "COUNT",
+    )]
+    max_macros: usize,
+
+    /// Specify how many tokens the bodies of the defined macros can store
+    #[arg(long,
+        conflicts_with = "config_file", group = "config_args",
+        default_value_t = config::DEFAULT_MAX_MACRO_TABLE_SIZE,
+        value_name = "TOKENS",
+    )]
+    max_macro_table_size: usize,
+
+    /// Make emitting the operator CHAR cost WEIGHT in the reported
+    /// cost of the output (1 by default), can be repeated
+    #[arg(long,
+        conflicts_with = "config_file", group = "config_args",
+        value_parser = parse_char_number,
+        value_name = "CHAR=WEIGHT",
+    )]
+    operator_weight: Vec<(char, usize)>,
+
+    /// Do not select the config by the input's extension
+    /// (.b, .bf, .bfp, .bfup for brainfuck, .ook for Ook!)
+    #[arg(long)]
+    no_auto_dialect: bool,
+
     /// Do not align output in a rectangle
     #[arg(short = 'n', long)]
     no_align: bool,
@@ -90,79 +454,2614 @@ struct Cli {
     #[arg(short = 'b', long)]
     no_newline: bool,
 
-    /// Specify max line width
+    /// Specify max line width, lines are only padded to it with --no-split-groups
     #[arg(short = 'l', long,
         conflicts_with = "no_align",
         default_value_t = DEFAULT_LINE_WIDTH,
         value_name = "WIDTH",
     )]
-    line_width: usize,
+    line_width: NonZeroUsize,
+
+    /// Prefix every line of the output with its number, written
+    /// in digits and spaces that brainfuck skips as comments
+    #[arg(long,
+        conflicts_with_all = [
+            "no_align", "checkpoint_every", "run", "dump_tokens", "trace_expansion", "plan", "emit",
+        ],
+    )]
+    gutter: bool,
+
+    /// Specify how the line width is measured
+    #[arg(long,
+        conflicts_with = "no_align",
+        value_enum,
+        default_value_t = AlignBy::Chars,
+        value_name = "METRIC",
+    )]
+    align_by: AlignBy,
+
+    /// Replace every '@{KEY}' in the input with VALUE before lexing, can be repeated
+    /// (the builtin version, version_major, version_minor, version_patch
+    /// and output_width are set with it, '@version' and '@output_width'
+    /// are also replaced without the braces)
+    #[arg(long, value_parser = parse_variable, value_name = "KEY=VALUE")]
+    set: Vec<(String, String)>,
+
+    /// Define the macro CHAR as TOKENS before the input is read, can be repeated
+    /// (each definition can use the macros defined before it)
+    #[arg(short = 'D', long, value_parser = parse_definition, value_name = "CHAR=TOKENS")]
+    define: Vec<(char, String)>,
+
+    /// Allow running the shell commands of the generators declared in the config
+    #[arg(long)]
+    allow_generators: bool,
+
+    /// Fail reading the input after N chars, for testing the error handling
+    #[arg(long, hide = true, value_name = "N")]
+    inject_io_error: Option<usize>,
+
+    /// Fail writing the output after M bytes, for testing the error handling
+    #[arg(long, hide = true, value_name = "M")]
+    inject_write_error: Option<usize>,
+
+    /// Check that the group delimiters are balanced before lexing,
+    /// failing fast at the first surplus one
+    #[arg(long)]
+    pre_scan: bool,
+
+    /// Specify the format of the displayed errors and warnings
+    #[arg(long, value_enum, default_value_t = MessageFormat::Human, value_name = "FORMAT")]
+    message_format: MessageFormat,
+
+    /// Specify max number of displayed errors
+    #[arg(long, value_name = "N")]
+    max_errors: Option<NonZeroUsize>,
+
+    /// Print the effective config in ron format and exit
+    #[arg(long)]
+    dump_config: bool,
+
+    /// Print the role of every configured character and exit
+    #[arg(long)]
+    print_roles: bool,
+
+    /// Warn when more than PERCENT (50 if not passed) of the input's
+    /// non-whitespace characters are unrecognized, which is off by default,
+    /// as the unrecognized characters are comments
+    #[arg(long,
+        value_parser = clap::value_parser!(u8).range(0..=100),
+        value_name = "PERCENT",
+        num_args = 0..=1,
+        default_missing_value = DEFAULT_UNRECOGNIZED_THRESHOLD,
+    )]
+    unrecognized_threshold: Option<u8>,
+
+    /// Print the tokens of the input one per line instead of preprocessing,
+    /// written back as source with macros expanded
+    #[arg(long, conflicts_with = "run")]
+    dump_tokens: bool,
+
+    /// Print every macro expansion in the input instead of preprocessing,
+    /// with the expansions it contains indented below it
+    #[arg(long, conflicts_with_all = ["run", "dump_tokens"])]
+    trace_expansion: bool,
+
+    /// Print every macro defined in the input instead of preprocessing,
+    /// with where it's defined and its body with the macros expanded
+    #[arg(long,
+        conflicts_with_all = [
+            "run", "dump_tokens", "trace_expansion", "plan", "emit", "record", "replay", "range",
+        ],
+    )]
+    list_macros: bool,
+
+    /// Specify what is written instead of the preprocessed output
+    #[arg(long,
+        conflicts_with_all = ["run", "dump_tokens", "trace_expansion", "plan"],
+        value_enum,
+        default_value_t = Emit::Output,
+        value_name = "KIND",
+    )]
+    emit: Emit,
+
+    /// Print the planned repetitions of the operators instead of preprocessing
+    #[arg(long, conflicts_with_all = ["run", "dump_tokens", "trace_expansion"])]
+    plan: bool,
+
+    /// Write the lexed tokens to FILE in the bincode format while preprocessing,
+    /// so that only their emission can be rerun with --replay
+    #[arg(long,
+        conflicts_with_all = ["run", "dump_tokens", "trace_expansion", "plan", "emit"],
+        value_name = "FILE",
+    )]
+    record: Option<PathBuf>,
+
+    /// Check that the number of emitted operators is the one planned and
+    /// the one the tokens should emit, failing with an internal error otherwise
+    #[arg(long, conflicts_with_all = ["run", "dump_tokens", "trace_expansion", "plan", "emit"])]
+    verify_size: bool,
+
+    /// Fail if the number of emitted operators doesn't fill every line of
+    /// the line width, reporting how many the last line is short
+    #[arg(long,
+        conflicts_with_all = ["no_align", "run", "dump_tokens", "trace_expansion", "plan", "emit"],
+    )]
+    exact_rectangle: bool,
+
+    /// Move the operators of a group without groups in it to the next line
+    /// if they don't fit in the rest of the line, padding it with spaces
+    #[arg(long,
+        conflicts_with_all = [
+            "no_align", "checkpoint_every", "exact_rectangle",
+            "run", "dump_tokens", "trace_expansion", "plan", "emit",
+        ],
+    )]
+    no_split_groups: bool,
+
+    /// Emit the tokens recorded with --record in FILE instead of lexing the input,
+    /// the config should be the one they were recorded with
+    #[arg(long,
+        conflicts_with_all = [
+            "input", "record", "run", "dump_tokens", "trace_expansion", "plan", "emit",
+            "pre_scan", "coverage", "batch",
+        ],
+        value_name = "FILE",
+    )]
+    replay: Option<PathBuf>,
+
+    /// Only emit the tokens within a region of the input, as lines ('12:20')
+    /// or byte offsets ('300b:420b'), with the macros defined before it expanded
+    #[arg(long,
+        conflicts_with_all = [
+            "record", "replay", "run", "dump_tokens", "trace_expansion", "plan", "emit",
+            "checkpoint_every", "batch",
+        ],
+        value_name = "START:END",
+    )]
+    range: Option<Region>,
+
+    /// Run the emitter pass loaded from the dylib FILE on the planned operators
+    /// before they're emitted, can be repeated (unstable, the dylib is trusted)
+    #[cfg(feature = "unstable-pass-plugins")]
+    #[arg(long,
+        conflicts_with_all = [
+            "no_split_groups", "run", "dump_tokens", "trace_expansion", "plan", "emit",
+        ],
+        value_name = "FILE",
+    )]
+    pass: Vec<PathBuf>,
+
+    /// Print statistics about the input, twice to also print
+    /// the statistics of the config cache
+    #[arg(short = 'v', long, action = ArgAction::Count)]
+    stats: u8,
+
+    /// Specify the format of the statistics, json adds the number of expansions
+    /// of every macro, the operators they emit and their share of the output
+    #[arg(long, value_enum, requires = "stats", default_value_t = StatsFormat::Human, value_name = "FORMAT")]
+    stats_format: StatsFormat,
+
+    /// Write the number of operators emitted by every operator token
+    /// in the input to FILE, in json format
+    #[arg(long, value_name = "FILE", conflicts_with = "run")]
+    coverage: Option<PathBuf>,
+
+    /// Save a checkpoint every N operators and keep the partial output
+    /// of a failed run, so that it can be resumed
+    #[arg(
+        long,
+        requires = "output",
+        conflicts_with = "coverage",
+        value_name = "N"
+    )]
+    checkpoint_every: Option<NonZeroUsize>,
+
+    /// Resume a failed run from its checkpoint after OFFSET operators
+    #[arg(long, requires = "checkpoint_every", value_name = "OFFSET")]
+    resume_from: Option<usize>,
+
+    /// Run the preprocessed program as brainfuck instead of printing it,
+    /// the program reads from stdin
+    #[arg(short = 'r', long,
+        requires = "input",
+        conflicts_with_all = ["output", "tee"],
+    )]
+    run: bool,
+
+    /// Print N most executed instructions of the run program,
+    /// and N macros and groups whose output executed the most instructions
+    #[arg(long,
+        requires = "run",
+        value_name = "N",
+        num_args = 0..=1,
+        default_missing_value = "10",
+    )]
+    profile: Option<usize>,
 
     /// Print license
     #[arg(short = 'L', long)]
     license: bool,
+
+    /// Make the output deterministic for the integration tests,
+    /// without colors and with a fixed version
+    #[arg(long, hide = true)]
+    test_mode: bool,
+}
+
+#[derive(Subcommand, Clone)]
+enum Command {
+    /// Compare the token trees of the OLD and NEW sources, with the macros expanded
+    Diff(DiffArgs),
+    /// Compare the outputs of the built-in and an external interpreter
+    /// running the preprocessed FILE
+    Difftest(DifftestArgs),
+    /// Check whether the OLD and NEW sources generate the same output,
+    /// printing where they first differ
+    Equiv(EquivArgs),
+    /// Print the expansion of the token or macro definition at a position
+    /// in FILE, with the number of operators it emits
+    ExpandAt(ExpandAtArgs),
+    /// Print the sizes of the macros and groups in FILE
+    /// and the estimated cost of its output
+    Metrics(MetricsArgs),
+    /// Suggest macros for the operators repeated in the output of FILE,
+    /// with the estimated number of chars they save
+    Suggest(SuggestArgs),
+    /// Manage config files
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Preprocess lines interactively, keeping the macros
+    /// defined in previous lines
+    Repl(ReplArgs),
+    /// Preprocess sources posted as json to '/preprocess' over HTTP
+    #[cfg(feature = "serve")]
+    Serve(ServeArgs),
+}
+
+#[derive(Subcommand, Clone)]
+enum ConfigCommand {
+    /// Interactively create a config file
+    Wizard(WizardArgs),
+}
+
+#[derive(Args, Clone)]
+struct WizardArgs {
+    /// File to write the config to
+    #[arg(value_name = "FILE", default_value = config::DISCOVERED_CONFIG_NAME)]
+    output: PathBuf,
+
+    /// Overwrite the file if it exists
+    #[arg(short = 'f', long)]
+    force: bool,
+}
+
+#[derive(Args, Clone)]
+struct ReplArgs {
+    /// File the entered lines are appended to [default: ~/.bfup_history]
+    #[arg(long, value_name = "FILE")]
+    history: Option<PathBuf>,
+
+    /// Don't save the entered lines
+    #[arg(long, conflicts_with = "history")]
+    no_history: bool,
+}
+
+#[cfg(feature = "serve")]
+#[derive(Args, Clone)]
+struct ServeArgs {
+    /// Port to listen on
+    #[arg(short = 'p', long, default_value_t = 8080)]
+    port: u16,
+
+    /// Address to listen on
+    #[arg(long, default_value = "127.0.0.1")]
+    host: std::net::IpAddr,
+
+    /// Read the macros defined in FILE once at startup, predefining them
+    /// in every request, can be repeated (the rest of FILE is ignored)
+    #[arg(long, value_name = "FILE")]
+    lib: Vec<PathBuf>,
+
+    /// Largest output of a request, in bytes
+    #[arg(long,
+        default_value_t = serve::DEFAULT_MAX_OUTPUT_LEN,
+        value_name = "BYTES",
+    )]
+    max_output: usize,
+
+    /// Seconds a request can take to be preprocessed
+    #[arg(long = "request-timeout",
+        default_value_t = serve::DEFAULT_TIMEOUT.as_secs(),
+        value_parser = clap::value_parser!(u64).range(1..),
+        value_name = "SECONDS",
+    )]
+    request_timeout: u64,
+}
+
+#[derive(Args, Clone)]
+struct DiffArgs {
+    /// Source to compare against
+    #[arg(value_name = "OLD")]
+    old: PathBuf,
+
+    /// Source compared against OLD
+    #[arg(value_name = "NEW")]
+    new: PathBuf,
+}
+
+#[derive(Args, Clone)]
+struct EquivArgs {
+    /// Source to compare against
+    #[arg(value_name = "OLD")]
+    old: PathBuf,
+
+    /// Source compared against OLD
+    #[arg(value_name = "NEW")]
+    new: PathBuf,
+}
+
+#[derive(Args, Clone)]
+struct DifftestArgs {
+    /// File to preprocess
+    #[arg(value_name = "FILE")]
+    input: PathBuf,
+
+    /// External interpreter command, '{}' is replaced with the program's path
+    /// [default: the path is appended]
+    #[arg(short = 'x', long, value_name = "COMMAND")]
+    interpreter: String,
+
+    /// Input passed to both interpreters, can be repeated [default: empty input]
+    #[arg(short = 'i', long = "input", value_name = "FILE")]
+    inputs: Vec<PathBuf>,
+
+    /// Seconds the external interpreter can run on an input before it's killed
+    #[arg(long,
+        default_value_t = DEFAULT_TIMEOUT.as_secs(),
+        value_parser = clap::value_parser!(u64).range(1..),
+        value_name = "SECONDS",
+    )]
+    timeout: u64,
+}
+
+#[derive(Args, Clone)]
+struct ExpandAtArgs {
+    /// File to preprocess
+    #[arg(value_name = "FILE")]
+    input: PathBuf,
+
+    /// Line of the position, starting at 1
+    #[arg(short = 'l', long, value_name = "L")]
+    line: usize,
+
+    /// Column of the position, starting at 1
+    #[arg(short = 'c', long, value_name = "C")]
+    col: usize,
+}
+
+#[derive(Args, Clone)]
+struct MetricsArgs {
+    /// File to preprocess
+    #[arg(value_name = "FILE")]
+    input: PathBuf,
+
+    /// Specify the number of the largest groups to print
+    #[arg(short = 'n', long, default_value_t = 10, value_name = "N")]
+    top: usize,
+}
+
+#[derive(Args, Clone)]
+struct SuggestArgs {
+    /// File to preprocess
+    #[arg(value_name = "FILE")]
+    input: PathBuf,
+
+    /// Look for the operators repeated in FILE as it's written,
+    /// instead of its output
+    #[arg(long)]
+    source: bool,
+
+    /// Specify the number of macros to suggest
+    #[arg(short = 'n', long, default_value_t = 10, value_name = "N")]
+    top: usize,
+
+    /// Specify the min number of operators a macro expands to
+    #[arg(long, default_value_t = 2, value_name = "N")]
+    min_len: usize,
+
+    /// Specify the max number of operators a macro expands to
+    #[arg(long, default_value_t = 32, value_name = "N")]
+    max_len: usize,
 }
 
-/// Read args from env and act on them accordingly.
-pub fn process_args() -> Result<()> {
-    let cli = Cli::parse();
+/// Read args from env and act on them accordingly,
+/// stopping once `cancellation` is cancelled.
+pub fn process_args(cancellation: &CancellationToken) -> Result<()> {
+    // checked before parsing, as it changes what --version prints
+    let test_mode = env::args_os().any(|arg| arg == "--test-mode");
+    let mut command = Cli::command();
+    if test_mode {
+        colored::control::set_override(false);
+        command = command.version(TEST_MODE_VERSION);
+    }
+
+    let matches = command.clone().get_matches();
+    let cli = Cli::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+    // configs are only discovered when the config isn't specified with args
+    command.build();
+    let config_args_passed = command
+        .get_groups()
+        .filter(|group| group.get_id() == "config_args")
+        .flat_map(|group| group.get_args())
+        .any(|id| matches.value_source(id.as_str()) == Some(ValueSource::CommandLine));
 
+    let uri = match &cli.input {
+        Some(path) => format!(
+            "file://{}",
+            fs::canonicalize(path).unwrap_or(path.clone()).display()
+        ),
+        None => String::from("stdin"),
+    };
+    let mut sink = cli.message_format.sink(io::stderr(), uri);
+
+    let result = match process(&cli, config_args_passed, &mut *sink, cancellation) {
+        // the human-readable errors are printed along with their causes by the caller
+        Err(error) if cli.message_format != MessageFormat::Human => {
+            for diagnostic in Diagnostic::from_error(&error) {
+                sink.report(diagnostic)
+                    .with_context(|| "failed to write the diagnostics")?;
+            }
+            Err(error.context(Reported))
+        }
+        result => result,
+    };
+    sink.finish()
+        .with_context(|| "failed to write the diagnostics")?;
+
+    result
+}
+
+/// Act on the args in `cli`, reporting the warnings into `sink`.
+fn process(
+    cli: &Cli,
+    config_args_passed: bool,
+    sink: &mut dyn DiagnosticSink,
+    cancellation: &CancellationToken,
+) -> Result<()> {
     if cli.license {
-        print_license();
+        let version = if cli.test_mode {
+            TEST_MODE_VERSION
+        } else {
+            env!("CARGO_PKG_VERSION")
+        };
+        print_license(version);
+        return Ok(());
+    }
+
+    if let Some(Command::Config(ConfigCommand::Wizard(args))) = &cli.command {
+        return run_wizard(args);
+    }
+
+    let mut config_cache = ConfigCache::default();
+    let config = read_config(cli, config_args_passed, &mut config_cache)?;
+    check_generators(cli, &config)?;
+    if cli.stats > 1 {
+        print_cache_stats(&config_cache);
+    }
+    let variables = variables(cli);
+    let macros = define_macros(cli, &config)?;
+
+    match &cli.command {
+        Some(Command::Diff(args)) => {
+            return print_diff(args, &variables, &config, &macros, cancellation)
+        }
+        Some(Command::Difftest(args)) => {
+            return run_difftest(args, &variables, &config, &macros, cancellation)
+        }
+        Some(Command::Equiv(args)) => {
+            return print_equivalence(args, &variables, &config, &macros, cancellation)
+        }
+        Some(Command::ExpandAt(args)) => {
+            return print_expansion_at(args, &variables, &config, &macros, cancellation)
+        }
+        Some(Command::Metrics(args)) => {
+            return print_metrics(args, &variables, &config, &macros, cancellation)
+        }
+        Some(Command::Suggest(args)) => {
+            return print_suggestions(args, &variables, &config, &macros, cancellation)
+        }
+        Some(Command::Repl(args)) => return run_repl(args, &config, cancellation),
+        #[cfg(feature = "serve")]
+        Some(Command::Serve(args)) => {
+            return serve(cli, args, &variables, config, &macros, cancellation)
+        }
+        Some(Command::Config(_)) | None => (),
+    }
+
+    if let Some(path) = &cli.batch {
+        return run_jobs(cli, path, &config, config_cache, sink, cancellation);
+    }
+
+    if cli.dump_config {
+        println!(
+            "{}",
+            config
+                .to_ron_string()
+                .with_context(|| "failed to serialize config")?
+        );
+        return Ok(());
+    }
+
+    if cli.print_roles {
+        print_roles(&config);
         return Ok(());
     }
 
+    if cli.run {
+        return run_program(cli, &variables, &config, &macros, sink, cancellation);
+    }
+
     let mut input: Box<dyn BufRead> = if let Some(path) = &cli.input {
-        Box::new(BufReader::new(File::open(path).with_context(|| {
-            format!("failed to open '{}'", path.display())
-        })?))
+        open_input(path)?
+    } else if let Some(path) = &cli.tee {
+        decompressed(BufReader::new(TeeReader {
+            reader: stdin().lock(),
+            copy: File::create(path)
+                .with_context(|| format!("failed to open '{}'", path.display()))?,
+        }))
+        .with_context(|| "failed to read stdin")?
     } else {
-        Box::new(stdin().lock())
+        decompressed(stdin().lock()).with_context(|| "failed to read stdin")?
     };
 
-    let mut output: Box<dyn Write> = if let Some(path) = &cli.output {
-        Box::new(BufWriter::new(File::create(path).with_context(|| {
-            format!("failed to open '{}'", path.display())
-        })?))
-    } else {
-        Box::new(stdout().lock())
-    };
+    if cli.pre_scan {
+        input = pre_scan(cli, &variables, input, &config, cancellation)?;
+    }
+
+    if cli.dump_tokens {
+        return dump_tokens(&mut input, &variables, &config, &macros, cancellation);
+    }
+
+    if cli.trace_expansion {
+        return trace_expansion(&mut input, &variables, &config, &macros, cancellation);
+    }
+
+    if cli.list_macros {
+        return list_macros(&mut input, &variables, &config, &macros, cancellation);
+    }
 
-    let config = if let Some(path) = &cli.config_file {
-        let config_reader = BufReader::new(
-            File::open(path)
-                .with_context(|| format!("failed to open config '{}'", path.display()))?,
+    if cli.emit == Emit::TokensCount {
+        return write_token_counts(
+            &mut input,
+            &mut stdout().lock(),
+            &variables,
+            &config,
+            &macros,
+            cancellation,
         );
+    }
 
-        Config::from_reader_ron(config_reader)
-            .with_context(|| format!("failed to parse config '{}'", path.display()))?
-    } else {
-        Config::new(
-            cli.operators.chars(),
-            cli.group_start_delimiter,
-            cli.group_end_delimiter,
-            cli.number_prefix,
-            cli.macro_prefix,
-            cli.escape_prefix,
-        )
-        .with_context(|| "invalid configuration")?
-    };
+    if cli.emit == Emit::Annotations {
+        return write_annotations(cli, &mut input, &variables, &config, &macros, cancellation);
+    }
 
-    if cli.no_align {
-        preprocess(input.chars_raw(), &mut output, &config)
-    } else {
-        preprocess_and_align(input.chars_raw(), &mut output, &config, cli.line_width)
+    if cli.plan {
+        return print_plan(&mut input, &variables, &config, &macros, cancellation);
     }
-    .with_context(|| "failure while preprocessing")?;
 
-    if !cli.no_newline {
-        writeln!(output).with_context(|| "write failure")?;
+    if let Some(every) = cli.checkpoint_every {
+        return write_resumable_output(sink, cli, &mut input, &config, every, cancellation);
     }
 
-    Ok(())
+    write_outputs(cli, cancellation, |mut output| {
+        write_output(
+            cli,
+            &mut input,
+            &mut output,
+            &config,
+            None,
+            sink,
+            cancellation,
+        )
+    })
+}
+
+/// Write the output with `write` to every output of `cli`, compressed
+/// if `--compress` is passed, and copy it or open it if requested.
+///
+/// The output files are only written if `write` succeeds.
+fn write_outputs(
+    cli: &Cli,
+    cancellation: &CancellationToken,
+    write: impl FnOnce(&mut dyn Write) -> Result<()>,
+) -> Result<()> {
+    let mut output_paths: Vec<PathBuf> = cli
+        .output
+        .iter()
+        .filter(|path| *path != Path::new(STDOUT_PATH))
+        .cloned()
+        .collect();
+    #[cfg(feature = "compress")]
+    if let Some(compression) = cli.compress {
+        for path in &mut output_paths {
+            *path = with_extension_appended(path, compression.extension());
+        }
+    }
+    output_paths.sort_unstable();
+    output_paths.dedup();
+
+    let mut outputs: Vec<Box<dyn Write>> = Vec::new();
+    if output_paths.len() < cli.output.len() || cli.output.is_empty() {
+        outputs.push(Box::new(stdout().lock()));
+    }
+    // the output files are written under temporary names,
+    // so that failed runs don't leave a partial output behind
+    for path in &output_paths {
+        let partial_path = partial_path(path);
+        cancellation.register_partial_path(partial_path.clone());
+        match File::create(&partial_path) {
+            Ok(file) => outputs.push(Box::new(BufWriter::new(file))),
+            Err(error) => {
+                remove_partial_outputs(&output_paths);
+                return Err(error)
+                    .with_context(|| format!("failed to open '{}'", partial_path.display()));
+            }
+        }
+    }
+    let copied = Rc::new(RefCell::new(Vec::new()));
+    #[cfg(feature = "clipboard")]
+    if cli.copy {
+        outputs.push(Box::new(SharedBuffer(Rc::clone(&copied))));
+    }
+    if cli.open.is_some() {
+        outputs.push(Box::new(SharedBuffer(Rc::clone(&copied))));
+    }
+    let mut output: Box<dyn Write> = if outputs.len() == 1 {
+        outputs.pop().expect("There should be an output.")
+    } else {
+        Box::new(TeeWriter { writers: outputs })
+    };
+
+    #[cfg(feature = "compress")]
+    let result = if let Some(compression) = cli.compress {
+        let mut encoder = compression
+            .encoder(output)
+            .with_context(|| "failed to start compression")?;
+        write(&mut encoder).and_then(|()| {
+            encoder
+                .finish()
+                .and_then(|mut output| output.flush())
+                .with_context(|| "write failure")
+        })
+    } else {
+        write(&mut output)
+    };
+    #[cfg(not(feature = "compress"))]
+    let result = write(&mut output);
+
+    if result.is_ok() {
+        for path in &output_paths {
+            fs::rename(partial_path(path), path)
+                .with_context(|| format!("failed to write '{}'", path.display()))?;
+        }
+        #[cfg(feature = "clipboard")]
+        if cli.copy {
+            copy_to_clipboard(&copied.borrow())?;
+        }
+        if let Some(template) = &cli.open {
+            let url = playground_url(template, &String::from_utf8_lossy(&copied.borrow()));
+            open_in_browser(&url).with_context(|| "failed to open the browser")?;
+        }
+    } else {
+        // the original error is more important
+        remove_partial_outputs(&output_paths);
+    }
+
+    result
+}
+
+/// Parse a `KEY=VALUE` variable.
+fn parse_variable(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((key, value)) => Ok((key.trim().to_string(), value.to_string())),
+        None => Err(String::from("expected KEY=VALUE")),
+    }
+}
+
+/// Parse a `CHAR=TOKENS` macro definition.
+fn parse_definition(arg: &str) -> Result<(char, String), String> {
+    let mut chars = arg.chars();
+    match (chars.next(), chars.next()) {
+        (Some(symbol), Some('=')) => Ok((symbol, chars.as_str().to_string())),
+        _ => Err(String::from("expected CHAR=TOKENS")),
+    }
+}
+
+/// Parse a `STARTEND` delimiter pair.
+fn parse_delimiter_pair(arg: &str) -> Result<(char, char), String> {
+    let mut chars = arg.chars();
+    match (chars.next(), chars.next(), chars.next()) {
+        (Some(start), Some(end), None) => Ok((start, end)),
+        _ => Err(String::from("expected two characters")),
+    }
+}
+
+/// Parse the `START END` delimiters of a block comment or a raw block.
+fn parse_block_delimiters(arg: &str) -> Result<(String, String), String> {
+    match arg.split_whitespace().collect::<Vec<&str>>()[..] {
+        [start, end] => Ok((start.to_string(), end.to_string())),
+        _ => Err(String::from("expected two delimiters separated by a space")),
+    }
+}
+
+/// Parse a `CHAR=NUMBER` assignment *(e.g. a macro budget)*.
+fn parse_char_number(arg: &str) -> Result<(char, usize), String> {
+    let mut chars = arg.chars();
+    match (chars.next(), chars.next()) {
+        (Some(ch), Some('=')) => chars
+            .as_str()
+            .parse()
+            .map(|number| (ch, number))
+            .map_err(|error| format!("invalid number: {error}")),
+        _ => Err(String::from("expected CHAR=NUMBER")),
+    }
+}
+
+/// Read the config file, build the config from args if any were `passed`,
+/// or discover the config files for the input *(see [`Config::discover`])*,
+/// falling back to the [`Dialect`] of the input's extension.
+///
+/// The peephole passes are then enabled by `--optimize`.
+fn read_config(
+    cli: &Cli,
+    config_args_passed: bool,
+    cache: &mut ConfigCache,
+) -> Result<Arc<Config>> {
+    let implicit = cli.config_file.is_none() && !config_args_passed;
+    let input = match &cli.command {
+        Some(Command::Diff(args)) => Some(&args.new),
+        Some(Command::Difftest(args)) => Some(&args.input),
+        Some(Command::Equiv(args)) => Some(&args.new),
+        Some(Command::ExpandAt(args)) => Some(&args.input),
+        Some(Command::Metrics(args)) => Some(&args.input),
+        Some(Command::Suggest(args)) => Some(&args.input),
+        Some(Command::Config(_) | Command::Repl(_)) => None,
+        #[cfg(feature = "serve")]
+        Some(Command::Serve(_)) => None,
+        None => cli.input.as_ref(),
+    };
+
+    let discovered = if implicit {
+        let dir = match input.and_then(|input| input.parent()) {
+            Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+            _ => std::env::current_dir().with_context(|| "failed to get the current directory")?,
+        };
+
+        Config::discover(&dir).with_context(|| "failed to parse a discovered config")?
+    } else {
+        None
+    };
+
+    let dialect = input
+        .filter(|_| implicit && !cli.no_auto_dialect)
+        .and_then(|input| input.extension())
+        .and_then(|extension| extension.to_str())
+        .and_then(Dialect::from_extension);
+
+    let config = if let Some(config) = discovered {
+        Arc::new(config)
+    } else if let Some(dialect) = dialect {
+        Arc::new(dialect.config())
+    } else if let Some(path) = &cli.config_file {
+        let content = fs::read(path)
+            .with_context(|| format!("failed to open config '{}'", path.display()))?;
+
+        cache
+            .get_or_read(&content, Some(path))
+            .with_context(|| format!("failed to parse config '{}'", path.display()))?
+    } else {
+        let (operators, graphemes) = config::split_operators(&cli.operators, cli.graphemes)
+            .with_context(|| "invalid configuration")?;
+        let (word_operators, graphemes) =
+            config::encode_word_operators(&cli.word_operators, graphemes)
+                .with_context(|| "invalid configuration")?;
+        let mut config = Config::new(
+            operators.into_iter().chain(word_operators),
+            cli.group_start_delimiter,
+            cli.group_end_delimiter,
+            cli.number_prefix,
+            cli.macro_prefix,
+            cli.escape_prefix,
+        )
+        .with_context(|| "invalid configuration")?;
+        config
+            .set_extra_delimiters(cli.delimiter_pair.iter().copied())
+            .with_context(|| "invalid configuration")?;
+        config
+            .set_case_insensitive(cli.case_insensitive)
+            .with_context(|| "invalid configuration")?;
+        config.set_whitespace_significant(cli.whitespace_significant);
+        config.set_line_macros(cli.line_macros);
+        config.set_includes(cli.includes);
+        config.set_conditionals(cli.conditionals);
+        config.set_counted_loops(cli.counted_loops);
+        config.set_constants(cli.constants);
+        config
+            .set_named_cells(cli.named_cells)
+            .with_context(|| "invalid configuration")?;
+        config.set_allow_empty_groups(cli.allow_empty_groups);
+        config
+            .set_strings(cli.strings)
+            .with_context(|| "invalid configuration")?;
+        config.set_unicode_digits(cli.unicode_digits);
+        config.set_number_separator((!cli.no_number_separator).then_some(cli.number_separator));
+        config
+            .set_line_comment(cli.line_comment)
+            .with_context(|| "invalid configuration")?;
+        config
+            .set_block_comment(cli.block_comment.clone())
+            .with_context(|| "invalid configuration")?;
+        config
+            .set_raw_block(cli.raw_block.clone())
+            .with_context(|| "invalid configuration")?;
+        config.set_variable_prefix(cli.variable_prefix);
+        config.set_allowed_chars(cli.allowed_chars.iter().flat_map(|chars| chars.chars()));
+        config.set_unrecognized_policy(match (cli.strict_chars, cli.deny) {
+            (true, true) => UnrecognizedPolicy::Deny,
+            (true, false) => UnrecognizedPolicy::Warn,
+            (false, _) => UnrecognizedPolicy::Skip,
+        });
+        config.set_eof_policy(cli.eof_policy);
+        config.set_multiplier_policy(cli.multiplier_policy);
+        config.set_escape_in_groups(cli.escape_in_groups);
+        config.set_macro_budgets(cli.macro_budget.iter().copied());
+        config.set_budget_policy(cli.budget_policy);
+        config.set_max_expansion_depth(cli.max_expansion_depth);
+        config.set_max_macros(cli.max_macros);
+        config.set_max_macro_table_size(cli.max_macro_table_size);
+        config
+            .set_operator_weights(cli.operator_weight.iter().copied())
+            .with_context(|| "invalid configuration")?;
+        config.set_graphemes(graphemes);
+        let pipeline = match &cli.passes {
+            Some(stages) => {
+                Pipeline::new(stages.iter().copied()).with_context(|| "invalid configuration")?
+            }
+            None => Pipeline::default(),
+        };
+        config
+            .set_pipeline(pipeline)
+            .with_context(|| "invalid configuration")?;
+
+        Arc::new(config)
+    };
+
+    // not a config arg, so it also applies to the read configs
+    if !cli.optimize {
+        return Ok(config);
+    }
+    let mut config = Arc::unwrap_or_clone(config);
+    let pipeline = config.get_pipeline().clone().with_peephole(true);
+    config
+        .set_pipeline(pipeline)
+        .with_context(|| "failed to enable --optimize")?;
+
+    Ok(Arc::new(config))
+}
+
+/// Preprocess `input` into `output` according to `cli`.
+fn write_output<W: Write>(
+    cli: &Cli,
+    input: &mut Box<dyn BufRead>,
+    output: &mut W,
+    config: &Config,
+    resumable: Option<Resumable>,
+    sink: &mut dyn DiagnosticSink,
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    let variables = variables(cli);
+    let input = FailingChars::new(input_chars(input, &variables, config), cli.inject_io_error);
+    let output = &mut FailingWriter::new(output, cli.inject_write_error);
+    if cli.gutter
+        && config
+            .get_operators()
+            .any(|operator| operator.is_ascii_digit() || operator == ' ')
+    {
+        bail!("the gutter can't be told apart from the operators, which include digits or spaces");
+    }
+    if cli.no_split_groups && config.get_operators().any(|operator| operator == ' ') {
+        bail!("the padding can't be told apart from the operators, which include spaces");
+    }
+    let output = &mut Gutter::new(output, cli.gutter);
+    let alignment = (!cli.no_align).then_some((cli.line_width.get(), cli.align_by));
+    if let Some((line_width, align_by)) = alignment {
+        report_line_overflow(line_width, align_by, config, sink)?;
+    }
+    let macros = define_macros(cli, config)?;
+    let coverage = wants_coverage(cli);
+    let result = if !keeps_tokens(cli, config) {
+        let mut lexer = Lexer::new(input, config)
+            .with_cancellation(cancellation)
+            .with_macros(macros.iter().cloned());
+        if coverage {
+            lexer = lexer.collect_coverage();
+        }
+        if wants_unrecognized(cli) {
+            lexer = lexer.collect_unrecognized();
+        }
+        stream_tokens(
+            &mut lexer,
+            output,
+            config,
+            alignment,
+            resumable,
+            cancellation,
+        )
+        .and_then(|emitted| {
+            if let (true, Some((line_width, _))) = (cli.exact_rectangle, alignment) {
+                check_rectangle(emitted, line_width)?;
+            }
+            Ok((Report::from(&mut lexer), None))
+        })
+    } else {
+        lex_output(cli, input, output, config, &macros, resumable, cancellation)
+    };
+    let (report, usage) = result
+        .map_err(
+            |error| match (error.downcast::<lex::Error>(), cli.max_errors) {
+                (Ok(lex::Error::Group(mut group)), Some(max_errors)) => {
+                    group.truncate(max_errors.get());
+                    lex::Error::Group(group).into()
+                }
+                (Ok(error), _) => error.into(),
+                (Err(error), _) => error,
+            },
+        )
+        .with_context(|| "failure while preprocessing")?;
+
+    report_warnings(cli, &report, sink)?;
+
+    if cli.stats > 0 {
+        print_stats(&report, config, usage.as_ref())?;
+    }
+
+    if let (Some(path), Some(coverage)) = (&cli.coverage, &report.coverage) {
+        write_coverage(path, cli.input.as_deref(), coverage)
+            .with_context(|| format!("failed to write '{}'", path.display()))?;
+    }
+
+    if !cli.no_newline {
+        writeln!(output).with_context(|| "write failure")?;
+    }
+    output.flush().with_context(|| "write failure")?;
+
+    Ok(())
+}
+
+/// Check whether the output of `cli` is planned from the tokens of the whole input,
+/// instead of written as they're read *(see [`stream_tokens`])*.
+fn keeps_tokens(cli: &Cli, config: &Config) -> bool {
+    #[cfg(feature = "unstable-pass-plugins")]
+    if !cli.pass.is_empty() {
+        return true;
+    }
+
+    cli.replay.is_some()
+        || cli.record.is_some()
+        || cli.range.is_some()
+        || cli.no_split_groups
+        || cli.verify_size
+        || (cli.stats > 0 && cli.stats_format == StatsFormat::Json)
+        || config.get_pipeline().runs_peephole()
+}
+
+/// Read every token of `input` and write the operators they emit to `output`
+/// according to `cli`, for when they're needed as a whole *(see [`keeps_tokens`])*.
+///
+/// Returns the [`Report`] with the [`Usage`] of the macros, if it's printed with the stats.
+fn lex_output<I, W, E>(
+    cli: &Cli,
+    input: I,
+    output: &mut W,
+    config: &Config,
+    macros: &[MacroDefinition],
+    resumable: Option<Resumable>,
+    cancellation: &CancellationToken,
+) -> Result<(Report, Option<Usage>)>
+where
+    I: Iterator<Item = std::result::Result<char, E>>,
+    W: Write,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let coverage = wants_coverage(cli);
+    let unrecognized = wants_unrecognized(cli);
+    let alignment = (!cli.no_align).then_some((cli.line_width.get(), cli.align_by));
+    match (&cli.replay, &cli.record) {
+        (Some(path), _) => replay(path).map(|tokens| {
+            let report = Report {
+                coverage: coverage.then(Coverage::default),
+                unrecognized: unrecognized.then(UnrecognizedChars::default),
+                ..Report::default()
+            };
+            (tokens, report)
+        }),
+        (_, Some(path)) => lex_tokens(input, config, macros, coverage, unrecognized, cancellation)
+            .and_then(|(tokens, report)| {
+                record(path, &tokens)?;
+                Ok((tokens, report))
+            }),
+        (None, None) => match cli.range {
+            Some(region) => lex_region(
+                input,
+                region,
+                config,
+                macros,
+                coverage,
+                unrecognized,
+                cancellation,
+            ),
+            None => lex_tokens(input, config, macros, coverage, unrecognized, cancellation),
+        },
+    }
+    .and_then(|(tokens, mut report)| {
+        if let Some(coverage) = &mut report.coverage {
+            record_coverage(coverage, &tokens, 1);
+        }
+        let (steps, removed) = plan_counted(&tokens, config.get_pipeline());
+        report.optimized = config.get_pipeline().runs_peephole().then_some(removed);
+        #[cfg(feature = "unstable-pass-plugins")]
+        let steps = run_plugin_passes(&cli.pass, steps)?;
+        let emitted = match alignment {
+            Some(alignment) if cli.no_split_groups => {
+                emit_unsplit(&tokens, output, config, alignment, cancellation)?
+            }
+            _ => emit_plan(&steps, output, config, alignment, resumable, cancellation)?,
+        };
+        if cli.verify_size {
+            verify_size(&tokens, &steps, emitted, config.get_pipeline())?;
+        }
+        if let (true, Some((line_width, _))) = (cli.exact_rectangle, alignment) {
+            check_rectangle(emitted, line_width)?;
+        }
+        Ok((report, stats_usage(cli, &tokens)))
+    })
+}
+
+/// Read the tokens of `input` like [`lex_tokens`] up to the end of the `region`,
+/// only keeping the ones within it.
+///
+/// The input after the region is only read to finish
+/// the tokens it ends in *(e.g. a group)*.
+fn lex_region<I, E>(
+    input: I,
+    region: Region,
+    config: &Config,
+    macros: &[MacroDefinition],
+    coverage: bool,
+    unrecognized: bool,
+    cancellation: &CancellationToken,
+) -> Result<(Vec<Token>, Report)>
+where
+    I: Iterator<Item = std::result::Result<char, E>>,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let mut input = input.peekable();
+    let mut text = String::new();
+    let mut lines = 0;
+    // the text up to the end of the region, for Region::chars
+    while let Some(ch) = input.next_if(|_| match region {
+        Region::Lines { end, .. } => lines < end,
+        Region::Bytes { end, .. } => text.len() < end,
+    }) {
+        let ch = ch?;
+        lines += usize::from(ch == '\n');
+        text.push(ch);
+    }
+    let chars = region.chars(&text);
+
+    let mut lexer = Lexer::new(text.chars().map(Ok).chain(input), config)
+        .with_cancellation(cancellation)
+        .with_macros(macros.iter().cloned());
+    if coverage {
+        lexer = lexer.collect_coverage();
+    }
+    if unrecognized {
+        lexer = lexer.collect_unrecognized();
+    }
+    let tokens = lexer.read_tokens_until(chars.end)?;
+    let report = Report::from(&mut lexer);
+
+    Ok((select(&tokens, &chars), report))
+}
+
+/// Hash `input`, `config` and the options of `cli` changing the output,
+/// so the checkpoints are only resumed by the run that saved them.
+///
+/// The input file is opened again to be hashed, while stdin is hashed
+/// as it's copied to `stdin_path` and `input` replaced with the copy.
+fn run_hash(
+    cli: &Cli,
+    input: &mut Box<dyn BufRead>,
+    config: &Config,
+    stdin_path: &Path,
+) -> Result<u64> {
+    let mut hasher = RunHasher::default();
+    match &cli.input {
+        Some(path) => {
+            let mut hashed = open_input(path)?;
+            hash_stream(&mut hashed, &mut hasher, &mut io::sink())
+                .with_context(|| format!("failed to read '{}'", path.display()))?;
+        }
+        None => {
+            let mut copy = File::create(stdin_path)
+                .map(BufWriter::new)
+                .with_context(|| format!("failed to open '{}'", stdin_path.display()))?;
+            hash_stream(input, &mut hasher, &mut copy)
+                .and_then(|()| copy.flush())
+                .with_context(|| "failed to copy stdin")?;
+            *input = Box::new(BufReader::new(
+                File::open(stdin_path)
+                    .with_context(|| format!("failed to open '{}'", stdin_path.display()))?,
+            ));
+        }
+    }
+    config.to_ron_string()?.hash(&mut hasher);
+    (cli.no_align, cli.line_width, cli.align_by, cli.gutter).hash(&mut hasher);
+    (cli.no_newline, &cli.set, &cli.define).hash(&mut hasher);
+
+    Ok(hasher.finish())
+}
+
+/// Feed every chunk read from `input` to `hasher` and write it to `output`.
+fn hash_stream(
+    input: &mut dyn BufRead,
+    hasher: &mut RunHasher,
+    output: &mut dyn Write,
+) -> io::Result<()> {
+    loop {
+        let chunk = input.fill_buf()?;
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        hasher.write(chunk);
+        output.write_all(chunk)?;
+        let len = chunk.len();
+        input.consume(len);
+    }
+}
+
+/// Preprocess `input` into the single output file according to `cli`,
+/// saving a [`Checkpoint`] every `every` operators and resuming
+/// from the one passed with `--resume-from`.
+///
+/// The partial output, the checkpoints and the copy of stdin
+/// are kept if the run fails.
+fn write_resumable_output(
+    sink: &mut dyn DiagnosticSink,
+    cli: &Cli,
+    input: &mut Box<dyn BufRead>,
+    config: &Config,
+    every: NonZeroUsize,
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    let path = match &cli.output[..] {
+        [path] if path != Path::new(STDOUT_PATH) => path,
+        _ => bail!("checkpoints require a single output file"),
+    };
+    let partial_path = partial_path(path);
+    let log_path = checkpoint::log_path(&partial_path);
+    let stdin_path = checkpoint::stdin_path(&partial_path);
+    let run = run_hash(cli, input, config, &stdin_path)?;
+
+    let (file, resume_from) = match cli.resume_from {
+        Some(offset) if offset > 0 => {
+            let checkpoints = CheckpointLog::read(&log_path, run)
+                .with_context(|| format!("failed to read '{}'", log_path.display()))?;
+            let Some(index) = checkpoints
+                .iter()
+                .position(|checkpoint| checkpoint.operators == offset)
+            else {
+                bail!(
+                    "no checkpoint at {offset} in '{}', the last one is at {}",
+                    log_path.display(),
+                    checkpoints
+                        .last()
+                        .map_or(0, |checkpoint| checkpoint.operators)
+                );
+            };
+            CheckpointLog::truncate(&log_path, run, &checkpoints[..=index])
+                .with_context(|| format!("failed to write '{}'", log_path.display()))?;
+
+            let mut file = OpenOptions::new()
+                .write(true)
+                .open(&partial_path)
+                .with_context(|| format!("failed to open '{}'", partial_path.display()))?;
+            file.set_len(checkpoints[index].bytes)
+                .and_then(|()| file.seek(SeekFrom::End(0)))
+                .with_context(|| format!("failed to truncate '{}'", partial_path.display()))?;
+
+            (file, checkpoints[index])
+        }
+        _ => {
+            CheckpointLog::truncate(&log_path, run, &[])
+                .with_context(|| format!("failed to write '{}'", log_path.display()))?;
+            let file = File::create(&partial_path)
+                .with_context(|| format!("failed to open '{}'", partial_path.display()))?;
+
+            (file, Checkpoint::default())
+        }
+    };
+
+    let mut log = CheckpointLog::open(&log_path)
+        .with_context(|| format!("failed to open '{}'", log_path.display()))?;
+    let mut last_checkpoint = resume_from.operators;
+    let mut save = |checkpoint: &Checkpoint| -> Result<()> {
+        log.append(checkpoint)
+            .with_context(|| format!("failed to write '{}'", log_path.display()))?;
+        last_checkpoint = checkpoint.operators;
+        Ok(())
+    };
+
+    let mut output = BufWriter::new(file);
+    let resumable = Resumable {
+        resume_from,
+        every,
+        save: &mut save,
+    };
+    let result = write_output(
+        cli,
+        input,
+        &mut output,
+        config,
+        Some(resumable),
+        sink,
+        cancellation,
+    );
+    drop(output);
+
+    if result.is_ok() {
+        fs::rename(&partial_path, path)
+            .with_context(|| format!("failed to write '{}'", path.display()))?;
+        let _ = fs::remove_file(&log_path);
+        let _ = fs::remove_file(&stdin_path);
+    } else {
+        eprintln!(
+            "{} resume with --resume-from {last_checkpoint}",
+            "note:".cyan().bold()
+        );
+    }
+
+    result
+}
+
+/// Preprocess the input file and run the output as a brainfuck [`Program`].
+fn run_program(
+    cli: &Cli,
+    variables: &Variables,
+    config: &Config,
+    macros: &[MacroDefinition],
+    sink: &mut dyn DiagnosticSink,
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    let path = cli.input.as_ref().expect("--run requires an input file.");
+    let mut input = open_input(path)?;
+
+    let mut code: Vec<u8> = Vec::new();
+    let (tokens, report) = lex_tokens(
+        input_chars(&mut input, variables, config),
+        config,
+        macros,
+        false,
+        wants_unrecognized(cli),
+        cancellation,
+    )
+    .and_then(|(tokens, report)| {
+        emit_tokens(&tokens, &mut code, config, None, None, None, cancellation)?;
+        Ok((tokens, report))
+    })
+    .with_context(|| "failure while preprocessing")?;
+
+    report_warnings(cli, &report, sink)?;
+
+    let code = String::from_utf8(code).with_context(|| "preprocessed program is not utf-8")?;
+    let program = Program::compile(code.chars())
+        .with_context(|| "invalid program")?
+        .with_arguments(&cli.args)
+        .with_context(|| "invalid program arguments")?;
+    if let Some(hotspots_len) = cli.profile {
+        let profile = program
+            .run_profiled(&mut stdin().lock(), &mut stdout().lock(), cancellation)
+            .with_context(|| "failure while running")?;
+
+        print_profile(
+            &profile,
+            &program,
+            &tokens,
+            config,
+            hotspots_len,
+            cancellation,
+        )?;
+    } else {
+        program
+            .run(&mut stdin().lock(), &mut stdout().lock(), cancellation)
+            .with_context(|| "failure while running")?;
+    }
+
+    if cli.stats > 0 {
+        print_stats(&report, config, stats_usage(cli, &tokens).as_ref())?;
+    }
+
+    Ok(())
+}
+
+/// Print the `len` most executed instructions of the `profile` of the `program`
+/// preprocessed from `tokens` to stderr, followed by the macro expansions
+/// and groups with the most executed instructions in their output.
+///
+/// Every instruction is traced back to the innermost macro expansion or group
+/// it was emitted within *(see [`annotate`])*, the output is unaligned,
+/// so the operator it was compiled from is on the first line.
+fn print_profile(
+    profile: &Profile,
+    program: &Program,
+    tokens: &[Token],
+    config: &Config,
+    len: usize,
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    let annotations = annotate(tokens, config, None, cancellation)?;
+    let innermost = |origin: usize| {
+        let position = OutputPosition {
+            line: 1,
+            column: origin + 1,
+        };
+        annotations
+            .iter()
+            .rposition(|annotation| annotation.contains(position))
+    };
+    let describe = |index: Option<usize>| match index.map(|index| &annotations[index]) {
+        Some(Annotation {
+            macro_symbol: Some(symbol),
+            span,
+            ..
+        }) => format!("macro '{symbol}' used at [{span}]"),
+        Some(Annotation { span, .. }) => format!("group at [{span}]"),
+        None => String::from("the top level"),
+    };
+
+    let hotspots = profile.hotspots(program, usize::MAX);
+    eprintln!("{} most executed instructions:", "profile:".cyan().bold());
+    for hotspot in hotspots.iter().take(len) {
+        eprintln!(
+            "  {:?} at operator {} in {}, executed {} time(s)",
+            hotspot.instruction,
+            hotspot.origin,
+            describe(innermost(hotspot.origin)),
+            hotspot.executions
+        );
+    }
+
+    // the macros expanded within a repeated group are annotated for every repetition
+    let mut executions: HashMap<String, u64> = HashMap::new();
+    for hotspot in &hotspots {
+        *executions
+            .entry(describe(innermost(hotspot.origin)))
+            .or_default() += hotspot.executions;
+    }
+    let mut executions: Vec<(String, u64)> = executions.into_iter().collect();
+    executions.sort_unstable_by(|(left, left_executions), (right, right_executions)| {
+        right_executions.cmp(left_executions).then(left.cmp(right))
+    });
+    eprintln!(
+        "{} most executed macros and groups:",
+        "profile:".cyan().bold()
+    );
+    for (origin, executions) in executions.into_iter().take(len) {
+        eprintln!("  {origin}, executed {executions} instruction(s)");
+    }
+
+    Ok(())
+}
+
+/// Preprocess the input file and compare its runs on the built-in
+/// and an external interpreter.
+fn run_difftest(
+    args: &DifftestArgs,
+    variables: &Variables,
+    config: &Config,
+    macros: &[MacroDefinition],
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    let path = &args.input;
+    let mut input = open_input(path)?;
+
+    let mut code: Vec<u8> = Vec::new();
+    preprocess_with_macros(
+        input_chars(&mut input, variables, config),
+        &mut code,
+        config,
+        macros,
+        None,
+        None,
+        cancellation,
+    )
+    .with_context(|| "failure while preprocessing")?;
+    let program = Program::compile(String::from_utf8_lossy(&code).chars())
+        .with_context(|| "invalid program")?;
+
+    let inputs = if args.inputs.is_empty() {
+        vec![(String::from("<empty>"), Vec::new())]
+    } else {
+        read_inputs(&args.inputs)?
+    };
+
+    let program_path = std::env::temp_dir().join(format!("bfup-difftest-{}.b", std::process::id()));
+    fs::write(&program_path, &code)
+        .with_context(|| format!("failed to write '{}'", program_path.display()))?;
+    let divergences = difftest(
+        &program,
+        &program_path,
+        &args.interpreter,
+        &inputs,
+        Duration::from_secs(args.timeout),
+        cancellation,
+    );
+    let _ = fs::remove_file(&program_path);
+    let divergences = divergences?;
+
+    for divergence in &divergences {
+        eprintln!(
+            "{} outputs for '{}' differ at byte {} (built-in: {} bytes, external: {} bytes)",
+            "divergence:".red().bold(),
+            divergence.input_name,
+            divergence.first_difference(),
+            divergence.builtin.len(),
+            divergence.external.len(),
+        );
+    }
+
+    if !divergences.is_empty() {
+        anyhow::bail!(
+            "{} of {} input(s) diverged",
+            divergences.len(),
+            inputs.len()
+        );
+    }
+    eprintln!(
+        "{} {} input(s) matched",
+        "difftest:".green().bold(),
+        inputs.len()
+    );
+
+    Ok(())
+}
+
+/// Prompt for a config with the [`Wizard`] and write it to the output file.
+fn run_wizard(args: &WizardArgs) -> Result<()> {
+    let path = &args.output;
+    if !args.force && path.exists() {
+        bail!(
+            "'{}' already exists, use --force to overwrite it",
+            path.display()
+        );
+    }
+
+    let config = Wizard::new(&mut stdin().lock(), &mut io::stderr()).run()?;
+    fs::write(
+        path,
+        config
+            .to_ron_string()
+            .with_context(|| "failed to serialize config")?,
+    )
+    .with_context(|| format!("failed to write '{}'", path.display()))?;
+    eprintln!("{} wrote '{}'", "config:".green().bold(), path.display());
+
+    Ok(())
+}
+
+/// Serve the preprocessor over HTTP until cancelled, with `config`
+/// used for the requests without one and the `macros` and the ones
+/// of the libraries predefined in every request.
+#[cfg(feature = "serve")]
+fn serve(
+    cli: &Cli,
+    args: &ServeArgs,
+    variables: &Variables,
+    config: Arc<Config>,
+    macros: &[MacroDefinition],
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    let mut preloaded = macros.to_vec();
+    for path in &args.lib {
+        preloaded = read_lib(path, variables, &config, preloaded, cancellation)?;
+    }
+    let mut server = Server::bind((args.host, args.port).into(), Arc::clone(&config))
+        .with_context(|| format!("failed to listen on {}:{}", args.host, args.port))?
+        .with_macros(preloaded)
+        .with_max_output_len(args.max_output)
+        .with_timeout(Duration::from_secs(args.request_timeout));
+    eprintln!(
+        "{} listening on http://{}{}",
+        "serve:".green().bold(),
+        server.local_addr()?,
+        serve::PREPROCESS_PATH
+    );
+
+    server.run(cancellation, &mut |cache| {
+        if cli.stats > 1 {
+            print_cache_stats(cache);
+        }
+    })
+}
+
+/// Read the macros defined in the library at `path`, with the `macros` predefined,
+/// returning them followed by the library's ones.
+#[cfg(feature = "serve")]
+fn read_lib(
+    path: &Path,
+    variables: &Variables,
+    config: &Config,
+    mut macros: Vec<MacroDefinition>,
+    cancellation: &CancellationToken,
+) -> Result<Vec<MacroDefinition>> {
+    let mut input = open_input(path)?;
+    let mut lexer = Lexer::new(input_chars(&mut input, variables, config), config)
+        .with_cancellation(cancellation)
+        .with_macros(macros.iter().cloned())
+        .keep_macro_definitions();
+    lexer
+        .read_all_tokens()
+        .with_context(|| format!("failed to read the library '{}'", path.display()))?;
+    let definitions = lexer.take_macro_definitions();
+    eprintln!(
+        "{} preloaded {} macros from '{}'",
+        "serve:".green().bold(),
+        definitions.len(),
+        path.display()
+    );
+    macros.extend(definitions);
+
+    Ok(macros)
+}
+
+/// Run the batch jobs listed in `path` and print the status of every one,
+/// reporting the warnings of the succeeded ones into `sink`.
+fn run_jobs(
+    cli: &Cli,
+    path: &Path,
+    config: &Config,
+    cache: ConfigCache,
+    sink: &mut dyn DiagnosticSink,
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    let jobs = read_jobs(path)?;
+    let cache = Mutex::new(cache);
+    let results = run_batch(&jobs, config, &cache, cli.parallel, &|job, config| {
+        // the jobs run in parallel report once they're done, in order
+        let mut diagnostics: Vec<Diagnostic> = Vec::new();
+        run_job(cli, job, config, &mut diagnostics, cancellation).map(|()| diagnostics)
+    });
+
+    let mut failed = 0;
+    for (job, result) in jobs.iter().zip(results) {
+        match result {
+            Ok(diagnostics) => {
+                for diagnostic in diagnostics {
+                    sink.report(diagnostic)
+                        .with_context(|| "failed to write the diagnostics")?;
+                }
+                eprintln!(
+                    "{} '{}' -> '{}'",
+                    "ok:".green().bold(),
+                    job.input.display(),
+                    job.output.display()
+                );
+            }
+            Err(error) => {
+                failed += 1;
+                eprintln!(
+                    "{} '{}': {error:#}",
+                    "failed:".red().bold(),
+                    job.input.display()
+                );
+            }
+        }
+    }
+    if cli.stats > 1 {
+        print_cache_stats(
+            &cache
+                .lock()
+                .expect("The config cache should not be poisoned."),
+        );
+    }
+
+    if failed > 0 {
+        bail!("{failed} of {} jobs failed", jobs.len());
+    }
+    Ok(())
+}
+
+/// Run a single batch `job` with its `config`, like its input and output
+/// passed on the command line along with the other args in `cli`.
+fn run_job(
+    cli: &Cli,
+    job: &Job,
+    config: &Config,
+    sink: &mut dyn DiagnosticSink,
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    check_generators(cli, config)?;
+    let mut cli = cli.clone();
+    cli.input = Some(job.input.clone());
+    cli.output = vec![job.output.clone()];
+    cli.emit = job.emit;
+    cli.no_align = job.width.is_none();
+    if let Some(width) = job.width {
+        cli.line_width =
+            NonZeroUsize::new(width).with_context(|| "the width must be greater than 0")?;
+    }
+    cli.open = None;
+    #[cfg(feature = "clipboard")]
+    {
+        cli.copy = false;
+    }
+
+    let variables = variables(&cli);
+    let macros = define_macros(&cli, config)?;
+    let mut input = open_input(&job.input)?;
+    write_outputs(&cli, cancellation, |mut output| match cli.emit {
+        Emit::Output => write_output(
+            &cli,
+            &mut input,
+            &mut output,
+            config,
+            None,
+            sink,
+            cancellation,
+        ),
+        Emit::TokensCount => write_token_counts(
+            &mut input,
+            output,
+            &variables,
+            config,
+            &macros,
+            cancellation,
+        ),
+        Emit::Annotations => {
+            let json =
+                annotations_json(&cli, &mut input, &variables, config, &macros, cancellation)?;
+            writeln!(output, "{json}").with_context(|| "write failure")
+        }
+    })
+}
+
+/// Check that the generators of `config` can run their commands
+/// *(see `--allow-generators`)*.
+fn check_generators(cli: &Cli, config: &Config) -> Result<()> {
+    if config.has_generators() && !cli.allow_generators {
+        bail!("the config declares generators, pass --allow-generators to run their commands");
+    }
+
+    Ok(())
+}
+
+/// Preprocess the lines of stdin with the [`Repl`].
+fn run_repl(args: &ReplArgs, config: &Config, cancellation: &CancellationToken) -> Result<()> {
+    let history_path = match &args.history {
+        _ if args.no_history => None,
+        Some(path) => Some(path.clone()),
+        None => env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_NAME)),
+    };
+    let history = match history_path {
+        Some(path) => History::open(&path)
+            .with_context(|| format!("failed to open the history '{}'", path.display()))?,
+        None => History::default(),
+    };
+
+    Repl::new(&mut stdin().lock(), &mut io::stderr(), config, cancellation)
+        .with_history(history)
+        .run()
+}
+
+/// Lex the input file and print its [`Metrics`].
+fn print_metrics(
+    args: &MetricsArgs,
+    variables: &Variables,
+    config: &Config,
+    macros: &[MacroDefinition],
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    let path = &args.input;
+    let mut input = open_input(path)?;
+
+    let mut lexer = Lexer::new(input_chars(&mut input, variables, config), config)
+        .with_cancellation(cancellation)
+        .with_macros(macros.iter().cloned())
+        .keep_macro_definitions();
+    let tokens = lexer
+        .read_all_tokens()
+        .with_context(|| "failure while preprocessing")?;
+    let metrics = Metrics::new(&tokens, &lexer.take_macro_definitions());
+
+    println!("{} {} operators", "cost:".cyan().bold(), metrics.operators);
+
+    println!("{} {}", "macros:".cyan().bold(), metrics.macros.len());
+    for macro_metrics in &metrics.macros {
+        println!(
+            "  '{}' [{}]: {} operators, depth {}",
+            macro_metrics.symbol, macro_metrics.span, macro_metrics.size, macro_metrics.depth
+        );
+    }
+
+    println!("{} {}", "groups:".cyan().bold(), metrics.groups.len());
+    for group in metrics.largest_groups(args.top) {
+        println!(
+            "  [{}]: {} operators, expanded {} times, depth {}",
+            group.span, group.size, group.expansions, group.depth
+        );
+    }
+
+    println!("{}", "depths:".cyan().bold());
+    for (depth, count) in &metrics.depths {
+        println!("  {depth}: {count} groups");
+    }
+
+    Ok(())
+}
+
+/// Print the differences between the token trees of `args.old` and `args.new`.
+fn print_diff(
+    args: &DiffArgs,
+    variables: &Variables,
+    config: &Config,
+    macros: &[MacroDefinition],
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    let mut sources = Vec::new();
+    for (id, path) in [&args.old, &args.new].into_iter().enumerate() {
+        let mut input = open_input(path)?;
+        let mut lexer = Lexer::new(input_chars(&mut input, variables, config), config)
+            .with_cancellation(cancellation)
+            .with_source(SourceId(id))
+            .with_macros(macros.iter().cloned())
+            .keep_macro_definitions();
+        let tokens = lexer
+            .read_all_tokens()
+            .with_context(|| format!("failure while preprocessing '{}'", path.display()))?;
+        sources.push((tokens, lexer.take_macro_definitions()));
+    }
+    let [(old_tokens, old_macros), (new_tokens, new_macros)] = &sources[..] else {
+        unreachable!("Both sources should be lexed.");
+    };
+
+    let differences = diff((old_tokens, old_macros), (new_tokens, new_macros), config);
+    println!("{} {}", "differences:".cyan().bold(), differences.len());
+    for difference in &differences {
+        println!("  {difference}");
+    }
+
+    Ok(())
+}
+
+/// Print whether `args.old` and `args.new` generate the same output,
+/// or the first bytes where they differ.
+fn print_equivalence(
+    args: &EquivArgs,
+    variables: &Variables,
+    config: &Config,
+    macros: &[MacroDefinition],
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    let mut sources = Vec::new();
+    for path in [&args.old, &args.new] {
+        let mut input = open_input(path)?;
+        let tokens = Lexer::new(input_chars(&mut input, variables, config), config)
+            .with_cancellation(cancellation)
+            .with_macros(macros.iter().cloned())
+            .read_all_tokens()
+            .with_context(|| format!("failure while preprocessing '{}'", path.display()))?;
+        sources.push(tokens);
+    }
+
+    // the alignment is left out, only the operators are compared
+    let result = equivalence(|side, mut output| {
+        let tokens = match side {
+            Side::Old => &sources[0],
+            Side::New => &sources[1],
+        };
+        emit_tokens(tokens, &mut output, config, None, None, None, cancellation)
+    })?;
+
+    match result {
+        Equivalence::Identical { len } => {
+            println!("{} identical, {len} bytes", "equivalence:".cyan().bold());
+            Ok(())
+        }
+        Equivalence::Divergent {
+            position,
+            old_len,
+            new_len,
+            old,
+            new,
+        } => {
+            const CONTEXT: usize = 32;
+            println!(
+                "{} outputs differ at byte {position}",
+                "equivalence:".cyan().bold()
+            );
+            for (label, len, bytes) in [("old:", old_len, old), ("new:", new_len, new)] {
+                let shown = &bytes[..bytes.len().min(CONTEXT)];
+                println!(
+                    "  {} {len} bytes, then '{}'",
+                    label.cyan().bold(),
+                    String::from_utf8_lossy(shown)
+                );
+            }
+            bail!("the outputs of the sources differ")
+        }
+    }
+}
+
+/// Print the expansion of the token at `args.line:args.col` in `args.input`,
+/// or of the macro defined there.
+fn print_expansion_at(
+    args: &ExpandAtArgs,
+    variables: &Variables,
+    config: &Config,
+    macros: &[MacroDefinition],
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    let path = &args.input;
+    let mut input = open_input(path)?;
+    let mut lexer = Lexer::new(input_chars(&mut input, variables, config), config)
+        .with_cancellation(cancellation)
+        .with_macros(macros.iter().cloned())
+        .keep_macro_definitions();
+    let tokens = lexer
+        .read_all_tokens()
+        .with_context(|| format!("failure while preprocessing '{}'", path.display()))?;
+
+    let (label, span, expansion, size) = match token_at(&tokens, args.line, args.col) {
+        Some(token) => {
+            let (label, span) = match token {
+                Token::Expanded(_, provenance) => (
+                    format!("macro '{}' used at", provenance.macro_symbol),
+                    provenance.use_site,
+                ),
+                Token::Number(.., span)
+                | Token::Operator(_, span)
+                | Token::Group(_, span)
+                | Token::Str(_, span)
+                | Token::Raw(_, span)
+                | Token::Cell(.., span) => (String::from("token at"), *span),
+            };
+            (label, span, token.to_source(config), token.expanded_len())
+        }
+        None => {
+            let Some(definition) = lexer
+                .take_macro_definitions()
+                .into_iter()
+                .rfind(|definition| definition.span.contains_location(args.line, args.col))
+            else {
+                bail!("no token or macro definition at {}:{}", args.line, args.col);
+            };
+            (
+                format!("macro '{}' defined at", definition.symbol),
+                definition.span,
+                definition
+                    .tokens
+                    .iter()
+                    .map(|token| token.to_source(config))
+                    .collect(),
+                definition.expanded_len(),
+            )
+        }
+    };
+
+    println!("{} {span}", label.cyan().bold());
+    println!("{} {expansion}", "expansion:".cyan().bold());
+    println!("{} {size}", "operators:".cyan().bold());
+
+    Ok(())
+}
+
+/// Print the macros suggested for the operators repeated in the output
+/// of `args.input` *(or the input itself with `args.source`)*.
+fn print_suggestions(
+    args: &SuggestArgs,
+    variables: &Variables,
+    config: &Config,
+    macros: &[MacroDefinition],
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    let path = &args.input;
+    let mut input = open_input(path)?;
+    let text = input_chars(&mut input, variables, config)
+        .collect::<std::result::Result<String, _>>()
+        .with_context(|| format!("failed to read '{}'", path.display()))?;
+
+    let operators: Vec<char> = if args.source {
+        text.chars().filter(|ch| config.is_operator(*ch)).collect()
+    } else {
+        let tokens = Lexer::new(text.chars().map(Ok::<char, Infallible>), config)
+            .with_cancellation(cancellation)
+            .with_macros(macros.iter().cloned())
+            .read_all_tokens()
+            .with_context(|| "failure while preprocessing")?;
+        let mut output = Vec::new();
+        emit_tokens(&tokens, &mut output, config, None, None, None, cancellation)?;
+        config
+            .get_graphemes()
+            .encode_str(&String::from_utf8_lossy(&output))
+            .chars()
+            .collect()
+    };
+
+    let suggestions = suggest(&operators, args.min_len..=args.max_len, args.top);
+    println!("{} {}", "suggestions:".cyan().bold(), suggestions.len());
+    for (suggestion, symbol) in suggestions.iter().zip(free_symbols(config, &text)) {
+        println!(
+            "  {}{symbol}{}{}{}: {} occurrences, saves {} chars",
+            config.get_value(&ConfigField::MacroPrefix),
+            config.get_value(&ConfigField::GroupStartDelimiter),
+            config.get_graphemes().decode_str(&suggestion.operators),
+            config.get_value(&ConfigField::GroupEndDelimiter),
+            suggestion.occurrences,
+            suggestion.savings
+        );
+    }
+
+    Ok(())
+}
+
+/// Write a recording of the lexed `tokens` to `path` *(see [`write_recording`])*.
+fn record(path: &Path, tokens: &[Token]) -> Result<()> {
+    let mut writer = BufWriter::new(
+        File::create(path).with_context(|| format!("failed to create '{}'", path.display()))?,
+    );
+    write_recording(&mut writer, tokens)
+        .with_context(|| format!("failed to write the recording '{}'", path.display()))?;
+    writer.flush().with_context(|| "write failure")
+}
+
+/// Same as [`preprocess_resumable`][crate::pre::preprocess_resumable],
+/// but with the `macros` predefined.
+fn preprocess_with_macros<I, W, E>(
+    input: I,
+    output: &mut W,
+    config: &Config,
+    macros: &[MacroDefinition],
+    alignment: Option<(usize, AlignBy)>,
+    resumable: Option<Resumable>,
+    cancellation: &CancellationToken,
+) -> Result<Report>
+where
+    I: Iterator<Item = std::result::Result<char, E>>,
+    W: Write,
+    E: std::error::Error + Send + Sync + 'static,
+{
+    let mut lexer = Lexer::new(input, config)
+        .with_cancellation(cancellation)
+        .with_macros(macros.iter().cloned());
+    stream_tokens(
+        &mut lexer,
+        output,
+        config,
+        alignment,
+        resumable,
+        cancellation,
+    )?;
+
+    Ok(Report::from(&mut lexer))
+}
+
+/// Get the macros defined with `--define`, each can use the ones defined before it.
+fn define_macros(cli: &Cli, config: &Config) -> Result<Vec<MacroDefinition>> {
+    let mut macros = Vec::new();
+    for (symbol, source) in &cli.define {
+        let definition = MacroDefinition::from_source(*symbol, source, &macros, config)
+            .with_context(|| format!("invalid definition of macro '{symbol}'"))?;
+        macros.push(definition);
+    }
+
+    Ok(macros)
+}
+
+/// Read the tokens recorded in `path`, emitted instead of the lexed input
+/// *(the report then only contains the coverage of the emitted operators)*.
+fn replay(path: &Path) -> Result<Vec<Token>> {
+    read_recording(&mut BufReader::new(
+        File::open(path).with_context(|| format!("failed to open '{}'", path.display()))?,
+    ))
+    .with_context(|| format!("failed to read the recording '{}'", path.display()))
+}
+
+/// Run the passes loaded from the dylibs at `paths` on the planned `steps`, in order.
+#[cfg(feature = "unstable-pass-plugins")]
+fn run_plugin_passes(paths: &[PathBuf], steps: Vec<Step>) -> Result<Vec<Step>> {
+    let mut passes = PassManager::empty();
+    for path in paths {
+        // SAFETY: the dylib was passed with --pass, its ABI can't be verified
+        passes.add(Box::new(unsafe { DylibPass::load(path) }?));
+    }
+
+    Ok(passes.run(steps)?)
+}
+
+/// Lex the input and print its tokens as source *(see [`lex::Token::to_source`])*.
+fn dump_tokens(
+    input: &mut Box<dyn BufRead>,
+    variables: &Variables,
+    config: &Config,
+    macros: &[MacroDefinition],
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    let tokens = Lexer::new(input_chars(input, variables, config), config)
+        .with_cancellation(cancellation)
+        .with_macros(macros.iter().cloned())
+        .read_all_tokens()
+        .with_context(|| "failure while preprocessing")?;
+
+    let mut output = stdout().lock();
+    for token in &tokens {
+        writeln!(output, "{}", token.to_source(config)).with_context(|| "write failure")?;
+    }
+
+    Ok(())
+}
+
+/// Lex the input and print the [`Provenance`] of every macro expansion in it.
+fn trace_expansion(
+    input: &mut Box<dyn BufRead>,
+    variables: &Variables,
+    config: &Config,
+    macros: &[MacroDefinition],
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    let tokens = Lexer::new(input_chars(input, variables, config), config)
+        .with_cancellation(cancellation)
+        .with_macros(macros.iter().cloned())
+        .read_all_tokens()
+        .with_context(|| "failure while preprocessing")?;
+
+    let mut output = stdout().lock();
+    for token in &tokens {
+        write_expansions(&mut output, token, 0, config).with_context(|| "write failure")?;
+    }
+
+    Ok(())
+}
+
+/// Lex the input and print every [`MacroDefinition`] read from it.
+fn list_macros(
+    input: &mut Box<dyn BufRead>,
+    variables: &Variables,
+    config: &Config,
+    macros: &[MacroDefinition],
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    let mut lexer = Lexer::new(input_chars(input, variables, config), config)
+        .with_cancellation(cancellation)
+        .with_macros(macros.iter().cloned())
+        .keep_macro_definitions();
+    lexer
+        .read_all_tokens()
+        .with_context(|| "failure while preprocessing")?;
+
+    let mut output = stdout().lock();
+    for definition in lexer.take_macro_definitions() {
+        let body: String = definition
+            .tokens
+            .iter()
+            .map(|token| token.to_source(config))
+            .collect();
+        writeln!(
+            output,
+            "'{}' at {}: {body} ({} operators)",
+            definition.symbol,
+            definition.span,
+            definition.expanded_len(),
+        )
+        .with_context(|| "write failure")?;
+    }
+
+    Ok(())
+}
+
+/// Count the tokens in the input and write the [`TokenCounts`] as json to `output`.
+fn write_token_counts(
+    input: &mut Box<dyn BufRead>,
+    output: &mut dyn Write,
+    variables: &Variables,
+    config: &Config,
+    macros: &[MacroDefinition],
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    let counts: TokenCounts = Lexer::new(input_chars(input, variables, config), config)
+        .with_cancellation(cancellation)
+        .with_macros(macros.iter().cloned())
+        .count_tokens()
+        .with_context(|| "failure while counting the tokens")?;
+
+    writeln!(output, "{}", serde_json::to_string(&counts)?).with_context(|| "write failure")
+}
+
+/// Lex the input and write the [`Annotations`][crate::pre::Annotation] of its output
+/// as json to every output file, aligned like the output.
+fn write_annotations(
+    cli: &Cli,
+    input: &mut Box<dyn BufRead>,
+    variables: &Variables,
+    config: &Config,
+    macros: &[MacroDefinition],
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    let json = annotations_json(cli, input, variables, config, macros, cancellation)?;
+
+    if cli.output.is_empty() {
+        println!("{json}");
+    }
+    for path in &cli.output {
+        if path == Path::new(STDOUT_PATH) {
+            println!("{json}");
+        } else {
+            fs::write(path, format!("{json}\n"))
+                .with_context(|| format!("failed to write '{}'", path.display()))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Lex the input and serialize the [`Annotations`][crate::pre::Annotation]
+/// of its output to json, aligned like the output.
+fn annotations_json(
+    cli: &Cli,
+    input: &mut Box<dyn BufRead>,
+    variables: &Variables,
+    config: &Config,
+    macros: &[MacroDefinition],
+    cancellation: &CancellationToken,
+) -> Result<String> {
+    let (tokens, _) = lex_tokens(
+        input_chars(input, variables, config),
+        config,
+        macros,
+        false,
+        wants_unrecognized(cli),
+        cancellation,
+    )
+    .with_context(|| "failure while preprocessing")?;
+    let alignment = (!cli.no_align).then_some((cli.line_width.get(), cli.align_by));
+    let annotations = annotate(&tokens, config, alignment, cancellation)?;
+
+    Ok(serde_json::to_string(&annotations)?)
+}
+
+/// Lex the input and print its planned [`Steps`][crate::plan::Step].
+fn print_plan(
+    input: &mut Box<dyn BufRead>,
+    variables: &Variables,
+    config: &Config,
+    macros: &[MacroDefinition],
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    let tokens = Lexer::new(input_chars(input, variables, config), config)
+        .with_cancellation(cancellation)
+        .with_macros(macros.iter().cloned())
+        .read_all_tokens()
+        .with_context(|| "failure while preprocessing")?;
+
+    write_plan(
+        &mut stdout().lock(),
+        &plan_with(&tokens, config.get_pipeline()),
+        0,
+    )
+    .with_context(|| "write failure")
+}
+
+/// Write the expansions within `token`, the nested ones indented by `depth`.
+fn write_expansions(
+    output: &mut impl Write,
+    token: &Token,
+    depth: usize,
+    config: &Config,
+) -> io::Result<()> {
+    match token {
+        Token::Expanded(expanded, provenance) => {
+            let Provenance {
+                macro_symbol,
+                definition,
+                use_site,
+            } = &**provenance;
+            writeln!(
+                output,
+                "{:indent$}'{macro_symbol}' at {use_site}, defined at {definition}: {}",
+                "",
+                expanded.to_source(config),
+                indent = depth * 2,
+            )?;
+            write_expansions(output, expanded, depth + 1, config)
+        }
+        Token::Group(group, _) => group
+            .iter()
+            .try_for_each(|token| write_expansions(output, token, depth, config)),
+        Token::Number(..)
+        | Token::Operator(..)
+        | Token::Str(..)
+        | Token::Raw(..)
+        | Token::Cell(..) => Ok(()),
+    }
+}
+
+/// Write `coverage` of the `source` file *(stdin if `None`)* to `path` as json.
+fn write_coverage(path: &Path, source: Option<&Path>, coverage: &Coverage) -> Result<()> {
+    let tokens: Vec<serde_json::Value> = coverage
+        .tokens()
+        .into_iter()
+        .map(|(span, operator, emitted)| {
+            serde_json::json!({
+                "line": span.start.lineno,
+                "column": span.start.colno,
+                "offset": span.start.offset,
+                "operator": operator,
+                "emitted": emitted,
+            })
+        })
+        .collect();
+    let coverage = serde_json::json!({
+        "source": source.map(|path| path.display().to_string()),
+        "total": coverage.total(),
+        "covered": coverage.covered(),
+        "tokens": tokens,
+    });
+
+    let mut writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer_pretty(&mut writer, &coverage)?;
+    writeln!(writer)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// Print the roles of the chars in `config`, flagging conflicts.
+fn print_roles(config: &Config) {
+    if config.is_case_insensitive() {
+        println!("{}", "chars are matched regardless of case".cyan().bold());
+    }
+    for (ch, roles) in config.roles() {
+        let (role, shadowed) = roles.split_first().expect("Every char should have a role.");
+        print!("{:<8} {}", format!("{ch:?}"), role);
+        for shadowed_role in shadowed {
+            print!(" {} shadows {}", "conflict:".yellow().bold(), shadowed_role);
+        }
+        println!();
+    }
+}
+
+/// Report the warnings from `report` into `sink`, warning about
+/// the config if too many chars were unrecognized.
+fn report_warnings(cli: &Cli, report: &Report, sink: &mut dyn DiagnosticSink) -> Result<()> {
+    for warning in &report.warnings {
+        sink.report(Diagnostic::from(warning))
+            .with_context(|| "failed to write the diagnostics")?;
+    }
+
+    let ratio = report
+        .unrecognized
+        .as_ref()
+        .map_or(0.0, UnrecognizedChars::ratio);
+    if cli
+        .unrecognized_threshold
+        .is_some_and(|threshold| ratio * 100.0 > threshold.into())
+    {
+        sink.report(Diagnostic::new(
+            Severity::Warning,
+            format!(
+                "{:.0}% of the input's characters are unrecognized, \
+                the config might be wrong for this input (see --print-roles).",
+                ratio * 100.0
+            ),
+        ))
+        .with_context(|| "failed to write the diagnostics")?;
+    }
+
+    Ok(())
+}
+
+/// Warn if the widest operator of `config` doesn't fit in `line_width`,
+/// as it would overflow the lines it's written on.
+fn report_line_overflow(
+    line_width: usize,
+    align_by: AlignBy,
+    config: &Config,
+    sink: &mut dyn DiagnosticSink,
+) -> Result<()> {
+    if let Some((operator, width)) = align_by
+        .widest_operator(config)
+        .filter(|(_, width)| *width > line_width)
+    {
+        sink.report(Diagnostic::new(
+            Severity::Warning,
+            format!(
+                "operator '{operator}' is {width} wide, over the line width of {line_width}, \
+                it will overflow the lines it's written on."
+            ),
+        ))
+        .with_context(|| "failed to write the diagnostics")?;
+    }
+
+    Ok(())
+}
+
+/// Print the statistics from `report` to stderr, as a json object
+/// along with the `usage` of the macros if it was collected.
+fn print_stats(report: &Report, config: &Config, usage: Option<&Usage>) -> Result<()> {
+    const TOP_UNRECOGNIZED_LEN: usize = 5;
+
+    let cost = report
+        .coverage
+        .as_ref()
+        .map_or(0, |coverage| coverage.weighted_cost(config));
+
+    if let Some(usage) = usage {
+        let macros: Vec<serde_json::Value> = usage
+            .macros
+            .iter()
+            .map(|macro_usage| {
+                serde_json::json!({
+                    "symbol": macro_usage.symbol,
+                    "line": macro_usage.definition.start.lineno,
+                    "column": macro_usage.definition.start.colno,
+                    "expansions": macro_usage.expansions,
+                    "operators": macro_usage.operators,
+                    "share": usage.share(macro_usage),
+                })
+            })
+            .collect();
+        let stats = serde_json::json!({
+            "operators": usage.operators,
+            "cost": cost,
+            "unrecognized": report.unrecognized.as_ref().map(UnrecognizedChars::total),
+            "optimized": report.optimized,
+            "macros": macros,
+        });
+        eprintln!("{}", serde_json::to_string(&stats)?);
+        return Ok(());
+    }
+
+    eprintln!(
+        "{} emitted operators cost {} (weighted by operator)",
+        "stats:".cyan().bold(),
+        cost
+    );
+    if let Some(unrecognized) = &report.unrecognized {
+        eprintln!(
+            "{} skipped {} unrecognized character(s)",
+            "stats:".cyan().bold(),
+            unrecognized.total()
+        );
+        for (ch, count, span) in unrecognized.most_common(TOP_UNRECOGNIZED_LEN) {
+            eprintln!("  {ch:?} x {count}, first at [{span}]");
+        }
+    }
+    if let Some(optimized) = report.optimized {
+        eprintln!(
+            "{} optimizing removed {} operator(s)",
+            "stats:".cyan().bold(),
+            optimized
+        );
+    }
+
+    Ok(())
+}
+
+/// Check whether the [`Coverage`] is needed, to be written
+/// with `--coverage` or to weight the cost printed with the stats.
+fn wants_coverage(cli: &Cli) -> bool {
+    cli.coverage.is_some() || cli.stats > 0
+}
+
+/// Check whether the [`UnrecognizedChars`] are needed, to be printed
+/// with the stats or compared to `--unrecognized-threshold`.
+fn wants_unrecognized(cli: &Cli) -> bool {
+    cli.stats > 0 || cli.unrecognized_threshold.is_some()
+}
+
+/// Collect the [`Usage`] of the macros in `tokens` if it's printed with the stats.
+fn stats_usage(cli: &Cli, tokens: &[Token]) -> Option<Usage> {
+    (cli.stats > 0 && cli.stats_format == StatsFormat::Json).then(|| Usage::new(tokens))
+}
+
+/// Print the statistics of the config `cache` to stderr.
+fn print_cache_stats(cache: &ConfigCache) {
+    eprintln!(
+        "{} config cache: {} hit(s), {} miss(es), {} cached",
+        "stats:".cyan().bold(),
+        cache.hits(),
+        cache.misses(),
+        cache.len()
+    );
+}
+
+/// Path of the temporary file `path` is written to before completion.
+fn partial_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".part");
+
+    path.with_file_name(file_name)
+}
+
+/// Append `extension` to `path`, unless it already has it.
+#[cfg(feature = "compress")]
+fn with_extension_appended(path: &Path, extension: &str) -> PathBuf {
+    if path
+        .extension()
+        .is_some_and(|existing| existing == extension)
+    {
+        return path.to_path_buf();
+    }
+
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".");
+    file_name.push(extension);
+
+    path.with_file_name(file_name)
+}
+
+/// Check the group delimiters of `input` are balanced *(see [`Lexer::scan_delimiters`])*,
+/// returns the input to be lexed.
+///
+/// An input file is scanned by opening it again, stdin is buffered in memory.
+fn pre_scan(
+    cli: &Cli,
+    variables: &Variables,
+    mut input: Box<dyn BufRead>,
+    config: &Config,
+    cancellation: &CancellationToken,
+) -> Result<Box<dyn BufRead>> {
+    let (mut scanned, input): (Box<dyn BufRead>, Box<dyn BufRead>) = match &cli.input {
+        Some(path) => (open_input(path)?, input),
+        None => {
+            let mut buffer = Vec::new();
+            input
+                .read_to_end(&mut buffer)
+                .with_context(|| "failed to read stdin")?;
+            (
+                Box::new(Cursor::new(buffer.clone())),
+                Box::new(Cursor::new(buffer)),
+            )
+        }
+    };
+
+    // the generator groups are balanced, so the generators don't have to run twice
+    Lexer::new(
+        Interpolating::new(
+            scanned.chars_raw(),
+            variables.clone(),
+            variables.prefix(config),
+        ),
+        config,
+    )
+    .with_cancellation(cancellation)
+    .scan_delimiters()
+    .with_context(|| "pre-scan found unbalanced group delimiters")?;
+
+    Ok(input)
+}
+
+/// Open the file at `path`, decompressing it if it's compressed.
+fn open_input(path: &Path) -> Result<Box<dyn BufRead>> {
+    decompressed(BufReader::new(
+        File::open(path).with_context(|| format!("failed to open '{}'", path.display()))?,
+    ))
+    .with_context(|| format!("failed to read '{}'", path.display()))
+}
+
+/// Read `reader` as it is, the inputs are only decompressed with the `compress` feature.
+#[cfg(not(feature = "compress"))]
+fn decompressed<'a, R: BufRead + 'a>(reader: R) -> io::Result<Box<dyn BufRead + 'a>> {
+    Ok(Box::new(reader))
+}
+
+/// Read the chars of `input` with the `variables` interpolated *(see [`Interpolating`])*,
+/// the graphemes of `config` encoded *(see [`Segmenting`])* and its generators run
+/// *(see [`Generating`])*.
+fn input_chars<'a>(
+    input: &'a mut Box<dyn BufRead>,
+    variables: &Variables,
+    config: &'a Config,
+) -> impl Iterator<Item = std::result::Result<char, generate::Error<template::Error<ReadCharError>>>> + 'a
+{
+    Generating::new(
+        Segmenting::new(
+            Interpolating::new(
+                input.chars_raw(),
+                variables.clone(),
+                variables.prefix(config),
+            ),
+            config.get_graphemes(),
+        ),
+        config,
+    )
+}
+
+/// Get the variables interpolated into the input,
+/// the builtin ones overridden by the ones set with args.
+fn variables(cli: &Cli) -> Variables {
+    Variables::new(
+        (!cli.no_align).then_some(cli.line_width.get()),
+        cli.set.iter().cloned(),
+    )
+}
+
+/// Remove the temporary files of every output in `paths`, ignoring errors.
+fn remove_partial_outputs(paths: &[PathBuf]) {
+    for path in paths {
+        let _ = fs::remove_file(partial_path(path));
+    }
+}
+
+/// Writer writing everything into every one of `writers`.
+struct TeeWriter {
+    writers: Vec<Box<dyn Write>>,
+}
+
+impl Write for TeeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for writer in &mut self.writers {
+            writer.write_all(buf)?;
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writers.iter_mut().try_for_each(Write::flush)
+    }
+}
+
+/// Writer appending everything into a buffer shared with its creator.
+struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Place `output` on the system clipboard.
+#[cfg(feature = "clipboard")]
+fn copy_to_clipboard(output: &[u8]) -> Result<()> {
+    let text = String::from_utf8_lossy(output).into_owned();
+
+    arboard::Clipboard::new()
+        .and_then(|mut clipboard| clipboard.set_text(text))
+        .with_context(|| "failed to copy the output to the clipboard")
+}
+
+/// Reader copying everything read from `reader` into `copy`.
+struct TeeReader<R: Read, W: Write> {
+    reader: R,
+    copy: W,
+}
+
+impl<R: Read, W: Write> Read for TeeReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.reader.read(buf)?;
+        self.copy.write_all(&buf[..read])?;
+
+        Ok(read)
+    }
 }
 
-fn print_license() {
+fn print_license(version: &str) {
     const LICENSE: &str =
         "This is free software. You may redistribute copies of it under the terms of
 the GNU General Public License <https://www.gnu.org/licenses/gpl.html>.
@@ -176,7 +3075,7 @@ There is NO WARRANTY, to the extent permitted by law.";
     println!(
         "{} {}\n{}\n\n{}",
         env!("CARGO_PKG_NAME"),
-        env!("CARGO_PKG_VERSION"),
+        version,
         env!("CARGO_PKG_AUTHORS"),
         LICENSE
     );
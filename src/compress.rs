@@ -0,0 +1,122 @@
+use std::io::{self, BufRead, BufReader, Write};
+
+use flate2::bufread::GzDecoder;
+use flate2::write::GzEncoder;
+
+/// Magic bytes at the start of a gzip stream.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+/// Magic bytes at the start of a zstd frame.
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Compression format of the output.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Get the file extension of the format.
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Compression::Gzip => "gz",
+            Compression::Zstd => "zst",
+        }
+    }
+
+    /// Wrap `writer` in an [`Encoder`] of the format.
+    pub fn encoder<W: Write>(&self, writer: W) -> io::Result<Encoder<W>> {
+        Ok(match self {
+            Compression::Gzip => {
+                Encoder::Gzip(GzEncoder::new(writer, flate2::Compression::default()))
+            }
+            Compression::Zstd => Encoder::Zstd(zstd::Encoder::new(writer, 0)?),
+        })
+    }
+}
+
+/// Writer compressing everything written into the inner writer.
+///
+/// The compressed stream is only complete after calling `finish()`.
+pub enum Encoder<W: Write> {
+    Gzip(GzEncoder<W>),
+    Zstd(zstd::Encoder<'static, W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Complete the compressed stream, returning the inner writer.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.write(buf),
+            Encoder::Zstd(encoder) => encoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Encoder::Gzip(encoder) => encoder.flush(),
+            Encoder::Zstd(encoder) => encoder.flush(),
+        }
+    }
+}
+
+/// Wrap `reader` in a decoder, if its contents start
+/// with the magic bytes of a supported [`Compression`].
+pub fn decompressed<'a, R: BufRead + 'a>(mut reader: R) -> io::Result<Box<dyn BufRead + 'a>> {
+    let start = reader.fill_buf()?;
+
+    Ok(if start.starts_with(GZIP_MAGIC) {
+        Box::new(BufReader::new(GzDecoder::new(reader)))
+    } else if start.starts_with(ZSTD_MAGIC) {
+        Box::new(BufReader::new(zstd::Decoder::with_buffer(reader)?))
+    } else {
+        Box::new(reader)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use anyhow::Result;
+
+    use super::*;
+
+    #[test]
+    fn compress_round_trip() -> Result<()> {
+        const INPUT: &str = "+++[>++<-]>.";
+
+        for compression in [Compression::Gzip, Compression::Zstd] {
+            let mut encoder = compression.encoder(Vec::new())?;
+            encoder.write_all(INPUT.as_bytes())?;
+            let compressed = encoder.finish()?;
+
+            let mut output = String::new();
+            decompressed(compressed.as_slice())?.read_to_string(&mut output)?;
+
+            assert!(
+                output == INPUT,
+                "\"{output}\" should be decompressed to \"{INPUT}\" with {compression:?}."
+            );
+        }
+
+        let mut output = String::new();
+        decompressed(INPUT.as_bytes())?.read_to_string(&mut output)?;
+
+        assert!(
+            output == INPUT,
+            "Uncompressed input should be read as it is."
+        );
+
+        Ok(())
+    }
+}
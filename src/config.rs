@@ -1,18 +1,66 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::fmt;
-use std::hash::Hash;
-use std::io::Read;
+use std::fs::{self, File};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 
 use ron::error::SpannedError as RonError;
-use serde::Deserialize;
+use ron::extensions::Extensions;
+use ron::ser::PrettyConfig;
+use serde::{Deserialize, Serialize};
+
+use crate::grapheme::{self, Graphemes};
+use crate::pass::{Pipeline, Stage};
 
 /// Error type returned when constructing a [`Config`]
 #[derive(thiserror::Error, fmt::Debug)]
 pub enum Error {
     #[error("{0} cannot be {1}.")]
     NotUnique(String, String),
+    #[error("Operator '{0}' is more than one char, set graphemes to use it.")]
+    MultiCodepoint(String),
+    #[error("Word operator '{0}' must be at least two chars, without newlines.")]
+    WordOperator(String),
+    #[error("Block comment delimiter '{0}' must be one or two chars.")]
+    CommentDelimiter(String),
+    #[error("Raw block delimiter '{0}' must be one or two chars.")]
+    RawDelimiter(String),
+    #[error("Stage '{0}' cannot be left out of the passes.")]
+    MissingStage(String),
+    #[error("Stage '{0}' cannot be run after '{1}'.")]
+    StageOrder(String, String),
+    #[error("The output can only be optimized with brainfuck's operators '{DEFAULT_OPERATORS}'.")]
+    OptimizeOperators,
+    #[error("Strings can only be expanded with brainfuck's operators '{DEFAULT_OPERATORS}'.")]
+    StringOperators,
+    #[error("Cells can only be named with brainfuck's operators '{DEFAULT_OPERATORS}'.")]
+    NamedCellOperators,
+    #[error("'{0}' is not an operator, so it cannot be weighted.")]
+    OperatorWeight(char),
     #[error("{0}")]
     FromRon(String),
+    #[error("{0}")]
+    ToRon(String),
+    #[error("'{child}' extends '{parent}', which cannot be read: {error}.")]
+    ExtendsUnreadable {
+        child: String,
+        parent: String,
+        error: io::Error,
+    },
+    #[error("'{child}' extends '{parent}', which already extends it.")]
+    ExtendsCycle { child: String, parent: String },
+    #[error("'{path}' cannot be read: {error}.")]
+    Unreadable { path: String, error: io::Error },
+    #[error("'{path}': {error}")]
+    Discovered { path: String, error: Box<Error> },
+    #[error("'{child}' extends '{parent}': {error}")]
+    Extends {
+        child: String,
+        parent: String,
+        error: Box<Error>,
+    },
 }
 
 impl From<RonError> for Error {
@@ -24,15 +72,154 @@ impl From<RonError> for Error {
     }
 }
 
+impl From<ron::Error> for Error {
+    fn from(ron_error: ron::Error) -> Self {
+        Error::ToRon(ron_error.to_string())
+    }
+}
+
 pub const DEFAULT_OPERATORS: &str = "+-<>[].,";
 pub const DEFAULT_GROUP_START_DELIMITER: char = '(';
 pub const DEFAULT_GROUP_END_DELIMITER: char = ')';
 pub const DEFAULT_NUMBER_PREFIX: char = '#';
 pub const DEFAULT_MACRO_PREFIX: char = '$';
 pub const DEFAULT_ESCAPE_PREFIX: char = '\\';
+/// Separator of the digits of a number *(`#1_000`)*, set by default.
+pub const DEFAULT_NUMBER_SEPARATOR: char = '_';
+/// Name of the config files found by [`Config::discover`].
+pub const DISCOVERED_CONFIG_NAME: &str = ".bfup.ron";
+/// Chars treated as operators when whitespace is significant.
+pub const WHITESPACE_OPERATORS: &str = " \t\r\n";
+/// Number of macro expansions that can be nested within each other by default.
+pub const DEFAULT_MAX_EXPANSION_DEPTH: usize = 256;
+/// Number of macro definitions the input can contain by default.
+pub const DEFAULT_MAX_MACROS: usize = 1 << 16;
+/// Number of tokens the bodies of the defined macros can store by default.
+pub const DEFAULT_MAX_MACRO_TABLE_SIZE: usize = 1 << 22;
+/// Symbol following the macro prefix in an include *(`$%"lib.bfup"`)*.
+pub const INCLUDE_SYMBOL: char = '%';
+/// Symbol following the macro prefix in a conditional *(`$?x(...)`)*.
+pub const CONDITIONAL_SYMBOL: char = '?';
+/// Symbol between the number and the counter of a counted loop *(`#5@i(#i+>)`)*.
+pub const COUNTER_SYMBOL: char = '@';
+/// Symbol following the macro prefix in a constant definition *(`$=WIDTH#32`)*.
+pub const CONSTANT_SYMBOL: char = '=';
+/// Symbol following the macro prefix in a cell definition *(`$&counter#3`)*
+/// and starting the moves to the cell *(`&counter`)*.
+pub const CELL_SYMBOL: char = '&';
+/// Delimiter of the string literals printed by the output *(`"Hello"`)*.
+pub const STRING_DELIMITER: char = '"';
+
+/// Operators of the [`Dialect::Ook`] config, the words are kept,
+/// so that the output is still valid Ook!.
+pub const OOK_OPERATORS: &str = "Ook.?!";
+
+/// Languages with a predefined [`Config`], selected by the input's extension.
+#[derive(Clone, Copy, PartialEq, Eq, fmt::Debug, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Dialect {
+    Brainfuck,
+    Ook,
+}
+
+impl Dialect {
+    /// Get the dialect of files with `extension`, if it's known.
+    pub fn from_extension(extension: &str) -> Option<Dialect> {
+        match extension {
+            "b" | "bf" | "bfp" | "bfup" => Some(Dialect::Brainfuck),
+            "ook" => Some(Dialect::Ook),
+            _ => None,
+        }
+    }
+
+    /// Get the [`Config`] of the dialect.
+    pub fn config(&self) -> Config {
+        match self {
+            Dialect::Brainfuck => Config::default(),
+            Dialect::Ook => Config::new(
+                OOK_OPERATORS.chars(),
+                DEFAULT_GROUP_START_DELIMITER,
+                DEFAULT_GROUP_END_DELIMITER,
+                DEFAULT_NUMBER_PREFIX,
+                DEFAULT_MACRO_PREFIX,
+                DEFAULT_ESCAPE_PREFIX,
+            )
+            .expect("Ook config shouldn't fail."),
+        }
+    }
+}
+
+/// Behavior of the [`Lexer`][crate::lex::Lexer] when the input
+/// ends inside of an unterminated construct.
+#[derive(
+    Clone, Copy, Default, PartialEq, Eq, fmt::Debug, Serialize, Deserialize, clap::ValueEnum,
+)]
+pub enum EofPolicy {
+    /// Unclosed groups and dangling macro prefixes are errors.
+    #[default]
+    Strict,
+    /// Unclosed groups are closed at the end of input and dangling
+    /// macro prefixes are treated as literals, both with a warning.
+    Lenient,
+}
+
+/// Behavior of the [`Lexer`][crate::lex::Lexer] when it encounters
+/// a `char` not assigned to any field, that isn't skippable
+/// *(whitespace or an allowed char)*.
+#[derive(
+    Clone, Copy, Default, PartialEq, Eq, fmt::Debug, Serialize, Deserialize, clap::ValueEnum,
+)]
+pub enum UnrecognizedPolicy {
+    /// Unrecognized chars are skipped.
+    #[default]
+    Skip,
+    /// Unrecognized chars are skipped with a warning.
+    Warn,
+    /// Unrecognized chars are errors.
+    Deny,
+}
+
+/// Behavior of the [`Lexer`][crate::lex::Lexer] when a macro's
+/// expansion exceeds its budget *(see [`Config::set_macro_budgets`])*.
+#[derive(
+    Clone, Copy, Default, PartialEq, Eq, fmt::Debug, Serialize, Deserialize, clap::ValueEnum,
+)]
+pub enum BudgetPolicy {
+    /// Macros exceeding their budget are defined with a warning.
+    #[default]
+    Warn,
+    /// Macros exceeding their budget are errors.
+    Deny,
+}
+
+/// How the [`Lexer`][crate::lex::Lexer] combines consecutive numbers
+/// into the multiplier of the token following them.
+#[derive(
+    Clone, Copy, Default, PartialEq, Eq, fmt::Debug, Serialize, Deserialize, clap::ValueEnum,
+)]
+pub enum MultiplierPolicy {
+    /// The last number replaces the ones before it *(`#2#3+` emits 3 `+`)*.
+    #[default]
+    Replace,
+    /// The numbers are multiplied together *(`#2#3+` emits 6 `+`)*.
+    Compose,
+}
+
+/// How the [`Lexer`][crate::lex::Lexer] reads a group delimiter preceded
+/// by the escape prefix, which never opens or closes a group.
+#[derive(
+    Clone, Copy, Default, PartialEq, Eq, fmt::Debug, Serialize, Deserialize, clap::ValueEnum,
+)]
+pub enum EscapeInGroups {
+    /// The escaped delimiter is skipped, like any other escaped `char`.
+    #[default]
+    Skip,
+    /// The escaped delimiter is emitted as it is *(`\)` emits `)`)*.
+    Literal,
+}
 
 /// The type of a field contained within the [`Config`]
-#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, PartialEq, Eq, Hash, fmt::Debug)]
 pub enum ConfigField {
     Operator,
     GroupStartDelimiter,
@@ -59,6 +246,33 @@ impl fmt::Display for ConfigField {
     }
 }
 
+/// Role of a `char` within the [`Config`].
+#[derive(Clone, Copy, PartialEq, Eq, fmt::Debug)]
+pub enum Role {
+    /// The `char` is assigned to a field.
+    Field(ConfigField),
+    /// The `char` is whitespace treated as an operator.
+    WhitespaceOperator,
+    /// The `char` is always skipped.
+    Allowed,
+    /// The `char` starts a comment until the end of the line.
+    LineComment,
+    /// The `char` separates the digits of a number.
+    NumberSeparator,
+}
+
+impl fmt::Display for Role {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Role::Field(field) => write!(f, "{field}"),
+            Role::WhitespaceOperator => write!(f, "operator (whitespace)"),
+            Role::Allowed => write!(f, "allowed (skipped)"),
+            Role::LineComment => write!(f, "line comment"),
+            Role::NumberSeparator => write!(f, "number separator"),
+        }
+    }
+}
+
 /// Struct containing config information for the
 /// [`Lexer`][crate::lex::Lexer]. The possible
 /// fields are defined within the [`ConfigField`] enum.
@@ -66,9 +280,134 @@ impl fmt::Display for ConfigField {
 /// Use `get_field()` to check whether a field contains the passed value.
 ///
 /// Use 'get_value()` to get a field's value.
+///
+/// The behavior at the end of input can be changed with `set_eof_policy()`.
+///
+/// Use `set_multiplier_policy()` to make consecutive numbers multiply
+/// each other *(`#2#3+` emitting 6 `+`)* instead of the last one replacing the rest.
+///
+/// Use `set_escape_in_groups()` to emit escaped group delimiters *(`\)`)*
+/// as they are, instead of skipping them.
+///
+/// Use `set_case_insensitive()` to match the values regardless of their case
+/// *(folded with [`char::to_lowercase`])*, `resolve()` maps a matched `char`
+/// back to the value it matched.
+///
+/// Use `set_extra_delimiters()` to add group delimiter pairs interchangeable
+/// with the main one, `get_delimiter_pair()` gets the pair a delimiter belongs to.
+///
+/// Use `set_whitespace_significant()` to treat [whitespace][WHITESPACE_OPERATORS]
+/// not assigned to any field as operators, instead of skipping it.
+///
+/// Use `set_line_macros()` to allow macro definitions spanning the rest of a line
+/// *(`$x = tokens...`)*, spliced where they're used without a group.
+///
+/// Use `set_includes()` to allow reading the macros and tokens
+/// of other files *(`$%"lib.bfup"`)*.
+///
+/// Use `set_conditionals()` to allow tokens read only if a macro
+/// is defined *(`$?x(...)`)*.
+///
+/// Use `set_counted_loops()` to allow repeating a group with the number
+/// of the repetition available within it *(`#5@i(#i+>)`)*.
+///
+/// Use `set_constants()` to allow named numbers, defined with
+/// the macro prefix *(`$=WIDTH#32`)* and used after the number prefix *(`#WIDTH`)*.
+///
+/// Use `set_named_cells()` to allow naming the cells of the tape *(`$&counter#3`)*
+/// and moving the pointer to them *(`&counter`)*.
+///
+/// Use `set_allow_empty_groups()` to read empty groups *(`()`)* as no-ops.
+///
+/// Use `set_strings()` to expand string literals into code printing them *(`"Hello"`)*.
+///
+/// Use `set_unicode_digits()` to read numbers written with the decimal digits
+/// of any script *(e.g. Arabic-Indic `#٣+`)*.
+///
+/// Use `set_number_separator()` to change or unset the `char` separating
+/// the digits of a number *(`#1_000`)*.
+///
+/// Use `set_line_comment()` to skip everything from a `char` until the end of the line.
+///
+/// Use `set_block_comment()` to skip everything between a pair of delimiters *(`{- -}`)*.
+///
+/// Use `set_raw_block()` to copy everything between a pair of delimiters
+/// to the output as it is *(`{{ ... }}`)*.
+///
+/// Use `set_variable_prefix()` to replace the variable references *(`@{key}`)*
+/// in the input with their values before it's read
+/// *(see [`Interpolating`][crate::template::Interpolating])*.
+///
+/// Use `set_macro_budgets()` to limit the number of operators
+/// a single expansion of a macro can emit.
+///
+/// Use `set_max_expansion_depth()` to limit the number of macro expansions
+/// nested within each other *(macros used in the bodies of other macros)*.
+///
+/// Use `set_max_macros()` and `set_max_macro_table_size()` to limit the number
+/// of macro definitions and the tokens stored in their bodies, so an input can't
+/// exhaust the memory by defining macros.
+///
+/// Use `set_operator_weights()` to make some operators cost more than others
+/// when the cost of the output is reported.
+///
+/// Use [`split_operators`] with `set_graphemes()` to configure operators made of
+/// multiple chars *(e.g. emoji with modifiers)*, [`Segmenting`][crate::grapheme::Segmenting]
+/// encodes them in the input. Use [`encode_word_operators`] for operators made of
+/// multiple graphemes *(e.g. `Ook.` of Ook!)*, matched the longest first.
+///
+/// Use `set_generators()` to splice the operators written by external commands
+/// into the input *(see [`Generating`][crate::generate::Generating])*.
+///
+/// Use `is_whitespace_operator()` to check whether a `char` not assigned to any field
+/// is an operator, and `is_skippable()` whether it's expected to be skipped,
+/// the rest is handled according to the [`UnrecognizedPolicy`].
+///
+/// Use `to_ron_string()` to serialize the `Config` into a form
+/// readable by `from_reader_ron()`.
+///
+/// Two configs are equal when every option is the same,
+/// use `is_equivalent()` to only compare the recognized chars.
+///
+/// A `Config` is `Send + Sync` and only read while preprocessing,
+/// so a single one can be shared by lexers running on different threads.
+#[derive(Clone)]
 pub struct Config {
     values_to_fields: HashMap<char, ConfigField>,
     fields_to_values: HashMap<ConfigField, char>,
+    /// Folded values mapped to the values, only set when case-insensitive.
+    folded_to_values: Option<HashMap<char, char>>,
+    /// Group delimiter pairs besides the main one, also set in `values_to_fields`.
+    extra_delimiters: Vec<(char, char)>,
+    whitespace_significant: bool,
+    line_macros: bool,
+    includes: bool,
+    conditionals: bool,
+    counted_loops: bool,
+    constants: bool,
+    named_cells: bool,
+    allow_empty_groups: bool,
+    strings: bool,
+    unicode_digits: bool,
+    number_separator: Option<char>,
+    line_comment: Option<char>,
+    block_comment: Option<(String, String)>,
+    raw_block: Option<(String, String)>,
+    variable_prefix: Option<char>,
+    allowed_chars: HashSet<char>,
+    unrecognized_policy: UnrecognizedPolicy,
+    eof_policy: EofPolicy,
+    multiplier_policy: MultiplierPolicy,
+    escape_in_groups: EscapeInGroups,
+    macro_budgets: HashMap<char, usize>,
+    budget_policy: BudgetPolicy,
+    max_expansion_depth: usize,
+    max_macros: usize,
+    max_macro_table_size: usize,
+    operator_weights: HashMap<char, usize>,
+    generators: HashMap<char, String>,
+    graphemes: Graphemes,
+    pipeline: Pipeline,
 }
 
 impl Default for Config {
@@ -85,6 +424,410 @@ impl Default for Config {
     }
 }
 
+impl PartialEq for Config {
+    fn eq(&self, other: &Self) -> bool {
+        self.is_equivalent(other)
+            && self.line_macros == other.line_macros
+            && self.includes == other.includes
+            && self.conditionals == other.conditionals
+            && self.counted_loops == other.counted_loops
+            && self.constants == other.constants
+            && self.named_cells == other.named_cells
+            && self.allow_empty_groups == other.allow_empty_groups
+            && self.strings == other.strings
+            && self.unicode_digits == other.unicode_digits
+            && self.get_number_separator() == other.get_number_separator()
+            && self.line_comment == other.line_comment
+            && self.block_comment == other.block_comment
+            && self.raw_block == other.raw_block
+            && self.variable_prefix == other.variable_prefix
+            && self.allowed_chars == other.allowed_chars
+            && self.unrecognized_policy == other.unrecognized_policy
+            && self.eof_policy == other.eof_policy
+            && self.multiplier_policy == other.multiplier_policy
+            && self.escape_in_groups == other.escape_in_groups
+            && self.macro_budgets == other.macro_budgets
+            && self.budget_policy == other.budget_policy
+            && self.max_expansion_depth == other.max_expansion_depth
+            && self.max_macros == other.max_macros
+            && self.max_macro_table_size == other.max_macro_table_size
+            && self.operator_weights == other.operator_weights
+            && self.generators == other.generators
+            && self.pipeline == other.pipeline
+    }
+}
+
+impl Eq for Config {}
+
+/// Representation of a [`Config`] within a ron file.
+// TODO: generate from ConfigFields with procmacro?
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Config", default)]
+struct ConfigRon {
+    operators: String,
+    group_start_delimiter: char,
+    group_end_delimiter: char,
+    number_prefix: char,
+    macro_prefix: char,
+    escape_prefix: char,
+    extra_delimiters: Vec<(char, char)>,
+    case_insensitive: bool,
+    whitespace_significant: bool,
+    line_macros: bool,
+    includes: bool,
+    conditionals: bool,
+    counted_loops: bool,
+    constants: bool,
+    named_cells: bool,
+    allow_empty_groups: bool,
+    strings: bool,
+    unicode_digits: bool,
+    number_separator: Option<char>,
+    line_comment: Option<char>,
+    block_comment: Option<(String, String)>,
+    raw_block: Option<(String, String)>,
+    variable_prefix: Option<char>,
+    allowed_chars: String,
+    unrecognized_policy: UnrecognizedPolicy,
+    eof_policy: EofPolicy,
+    multiplier_policy: MultiplierPolicy,
+    escape_in_groups: EscapeInGroups,
+    macro_budgets: BTreeMap<char, usize>,
+    budget_policy: BudgetPolicy,
+    max_expansion_depth: usize,
+    max_macros: usize,
+    max_macro_table_size: usize,
+    operator_weights: BTreeMap<char, usize>,
+    generators: BTreeMap<char, String>,
+    graphemes: bool,
+    word_operators: Vec<String>,
+    passes: Vec<Stage>,
+    optimize: bool,
+}
+
+impl Default for ConfigRon {
+    fn default() -> Self {
+        ConfigRon {
+            operators: String::from(DEFAULT_OPERATORS),
+            group_start_delimiter: DEFAULT_GROUP_START_DELIMITER,
+            group_end_delimiter: DEFAULT_GROUP_END_DELIMITER,
+            number_prefix: DEFAULT_NUMBER_PREFIX,
+            macro_prefix: DEFAULT_MACRO_PREFIX,
+            escape_prefix: DEFAULT_ESCAPE_PREFIX,
+            extra_delimiters: Vec::new(),
+            case_insensitive: false,
+            whitespace_significant: false,
+            line_macros: false,
+            includes: false,
+            conditionals: false,
+            counted_loops: false,
+            constants: false,
+            named_cells: false,
+            allow_empty_groups: false,
+            strings: false,
+            unicode_digits: false,
+            number_separator: Some(DEFAULT_NUMBER_SEPARATOR),
+            line_comment: None,
+            block_comment: None,
+            raw_block: None,
+            variable_prefix: None,
+            allowed_chars: String::new(),
+            unrecognized_policy: UnrecognizedPolicy::default(),
+            eof_policy: EofPolicy::default(),
+            multiplier_policy: MultiplierPolicy::default(),
+            escape_in_groups: EscapeInGroups::default(),
+            macro_budgets: BTreeMap::new(),
+            budget_policy: BudgetPolicy::default(),
+            max_expansion_depth: DEFAULT_MAX_EXPANSION_DEPTH,
+            max_macros: DEFAULT_MAX_MACROS,
+            max_macro_table_size: DEFAULT_MAX_MACRO_TABLE_SIZE,
+            operator_weights: BTreeMap::new(),
+            generators: BTreeMap::new(),
+            graphemes: false,
+            word_operators: Vec::new(),
+            passes: Stage::ALL.to_vec(),
+            optimize: false,
+        }
+    }
+}
+
+impl From<&Config> for ConfigRon {
+    fn from(config: &Config) -> Self {
+        let mut operators: Vec<char> = config
+            .get_operators()
+            .filter(|ch| {
+                config.graphemes.decode(*ch).is_none_or(|operator| {
+                    !config.graphemes.words().iter().any(|word| word == operator)
+                })
+            })
+            .collect();
+        operators.sort_unstable();
+        let mut word_operators = config.graphemes.words().to_vec();
+        word_operators.sort_unstable();
+
+        let mut allowed_chars: Vec<char> = config.allowed_chars.iter().copied().collect();
+        allowed_chars.sort_unstable();
+
+        ConfigRon {
+            operators: config
+                .graphemes
+                .decode_str(&operators.into_iter().collect::<String>()),
+            group_start_delimiter: *config.get_value(&ConfigField::GroupStartDelimiter),
+            group_end_delimiter: *config.get_value(&ConfigField::GroupEndDelimiter),
+            number_prefix: *config.get_value(&ConfigField::NumberPrefix),
+            macro_prefix: *config.get_value(&ConfigField::MacroPrefix),
+            escape_prefix: *config.get_value(&ConfigField::EscapePrefix),
+            extra_delimiters: config.extra_delimiters.clone(),
+            case_insensitive: config.is_case_insensitive(),
+            whitespace_significant: config.whitespace_significant,
+            line_macros: config.line_macros,
+            includes: config.includes,
+            conditionals: config.conditionals,
+            counted_loops: config.counted_loops,
+            constants: config.constants,
+            named_cells: config.named_cells,
+            allow_empty_groups: config.allow_empty_groups,
+            strings: config.strings,
+            unicode_digits: config.unicode_digits,
+            number_separator: config.get_number_separator(),
+            line_comment: config.line_comment,
+            block_comment: config.block_comment.clone(),
+            raw_block: config.raw_block.clone(),
+            variable_prefix: config.variable_prefix,
+            allowed_chars: allowed_chars.into_iter().collect(),
+            unrecognized_policy: config.unrecognized_policy,
+            eof_policy: config.eof_policy,
+            multiplier_policy: config.multiplier_policy,
+            escape_in_groups: config.escape_in_groups,
+            macro_budgets: config.macro_budgets.clone().into_iter().collect(),
+            budget_policy: config.budget_policy,
+            max_expansion_depth: config.max_expansion_depth,
+            max_macros: config.max_macros,
+            max_macro_table_size: config.max_macro_table_size,
+            operator_weights: config.operator_weights.clone().into_iter().collect(),
+            generators: config.generators.clone().into_iter().collect(),
+            graphemes: config.graphemes.has_graphemes(),
+            word_operators,
+            passes: config.pipeline.stages().to_vec(),
+            optimize: config.pipeline.runs_peephole(),
+        }
+    }
+}
+
+/// Representation of a ron file, which can `extend` another one,
+/// overriding its options.
+#[derive(Default, Deserialize)]
+#[serde(rename = "Config", default)]
+struct ConfigRonLayer {
+    /// Path of the extended file, relative to the extending one.
+    extends: Option<PathBuf>,
+    /// Whether discovery stops at this file *(see [`Config::discover`])*.
+    root: Option<bool>,
+    operators: Option<String>,
+    group_start_delimiter: Option<char>,
+    group_end_delimiter: Option<char>,
+    number_prefix: Option<char>,
+    macro_prefix: Option<char>,
+    escape_prefix: Option<char>,
+    extra_delimiters: Option<Vec<(char, char)>>,
+    case_insensitive: Option<bool>,
+    whitespace_significant: Option<bool>,
+    line_macros: Option<bool>,
+    includes: Option<bool>,
+    conditionals: Option<bool>,
+    counted_loops: Option<bool>,
+    constants: Option<bool>,
+    named_cells: Option<bool>,
+    allow_empty_groups: Option<bool>,
+    strings: Option<bool>,
+    unicode_digits: Option<bool>,
+    /// Set to `None` to unset the separator of the extended layers.
+    #[serde(deserialize_with = "deserialize_some")]
+    number_separator: Option<Option<char>>,
+    line_comment: Option<char>,
+    block_comment: Option<(String, String)>,
+    raw_block: Option<(String, String)>,
+    variable_prefix: Option<char>,
+    allowed_chars: Option<String>,
+    unrecognized_policy: Option<UnrecognizedPolicy>,
+    eof_policy: Option<EofPolicy>,
+    multiplier_policy: Option<MultiplierPolicy>,
+    escape_in_groups: Option<EscapeInGroups>,
+    macro_budgets: Option<BTreeMap<char, usize>>,
+    budget_policy: Option<BudgetPolicy>,
+    max_expansion_depth: Option<usize>,
+    max_macros: Option<usize>,
+    max_macro_table_size: Option<usize>,
+    operator_weights: Option<BTreeMap<char, usize>>,
+    generators: Option<BTreeMap<char, String>>,
+    graphemes: Option<bool>,
+    word_operators: Option<Vec<String>>,
+    passes: Option<Vec<Stage>>,
+    optimize: Option<bool>,
+}
+
+/// Deserialize an option that's present, so that `None` is told apart from
+/// a missing one *(which is left as `None` by `#[serde(default)]`)*.
+fn deserialize_some<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+/// Set the options missing from `$into` to the ones from `$from`.
+macro_rules! merge_options {
+    { $into:ident, $from:ident => $( $option:ident ),+ } => {
+        $(
+        $into.$option = $into.$option.or($from.$option);
+        )+
+    };
+}
+
+/// Set the options of `$into` to the ones set in `$from`.
+macro_rules! set_options {
+    { $into:ident, $from:ident => $( $option:ident ),+ } => {
+        $(
+        if let Some(value) = $from.$option {
+            $into.$option = value;
+        }
+        )+
+    };
+}
+
+impl ConfigRonLayer {
+    /// Read a layer from `reader` read from `path` *(`None` if not from a file)*,
+    /// merged with every layer it extends.
+    ///
+    /// `chain` contains the canonical paths of the files extending this one.
+    fn read<R: Read>(
+        reader: R,
+        path: Option<&Path>,
+        chain: &mut Vec<PathBuf>,
+    ) -> Result<ConfigRonLayer, Error> {
+        let mut layer: ConfigRonLayer = ron::Options::default()
+            .with_default_extension(Extensions::IMPLICIT_SOME)
+            .from_reader(reader)?;
+
+        let Some(extends) = layer.extends.take() else {
+            return Ok(layer);
+        };
+        let parent_path = path
+            .and_then(Path::parent)
+            .unwrap_or(Path::new(""))
+            .join(extends);
+        let child = path.map_or(String::from("<config>"), |path| path.display().to_string());
+        let parent = parent_path.display().to_string();
+
+        let canonical_path =
+            fs::canonicalize(&parent_path).map_err(|error| Error::ExtendsUnreadable {
+                child: child.clone(),
+                parent: parent.clone(),
+                error,
+            })?;
+        if chain.contains(&canonical_path) {
+            return Err(Error::ExtendsCycle { child, parent });
+        }
+        let file = File::open(&parent_path).map_err(|error| Error::ExtendsUnreadable {
+            child: child.clone(),
+            parent: parent.clone(),
+            error,
+        })?;
+
+        chain.push(canonical_path);
+        let parent_layer = ConfigRonLayer::read(BufReader::new(file), Some(&parent_path), chain)
+            .map_err(|error| Error::Extends {
+                child,
+                parent,
+                error: Box::new(error),
+            })?;
+        chain.pop();
+
+        Ok(layer.merge(parent_layer))
+    }
+
+    /// Set the options missing from the layer to the ones from `parent`.
+    fn merge(mut self, parent: ConfigRonLayer) -> ConfigRonLayer {
+        merge_options! { self, parent =>
+            operators, group_start_delimiter, group_end_delimiter,
+            number_prefix, macro_prefix, escape_prefix, extra_delimiters,
+            case_insensitive, whitespace_significant, line_macros, includes, conditionals, counted_loops,
+            constants, named_cells, allow_empty_groups, strings,
+            unicode_digits, number_separator, line_comment, block_comment, raw_block, variable_prefix, allowed_chars, unrecognized_policy, eof_policy, multiplier_policy, escape_in_groups,
+            macro_budgets, budget_policy, max_expansion_depth, max_macros, max_macro_table_size,
+            operator_weights, generators, graphemes, word_operators, passes, optimize
+        };
+
+        self
+    }
+}
+
+impl From<ConfigRonLayer> for ConfigRon {
+    fn from(layer: ConfigRonLayer) -> Self {
+        let mut config_ron = ConfigRon::default();
+
+        set_options! { config_ron, layer =>
+            operators, group_start_delimiter, group_end_delimiter,
+            number_prefix, macro_prefix, escape_prefix, extra_delimiters,
+            case_insensitive, whitespace_significant, line_macros, includes, conditionals, counted_loops,
+            constants, named_cells, allow_empty_groups, strings,
+            unicode_digits, number_separator, allowed_chars, unrecognized_policy, eof_policy, multiplier_policy, escape_in_groups, macro_budgets, budget_policy,
+            max_expansion_depth, max_macros, max_macro_table_size,
+            operator_weights, generators, graphemes, word_operators, passes, optimize
+        };
+        config_ron.line_comment = layer.line_comment;
+        config_ron.block_comment = layer.block_comment;
+        config_ron.raw_block = layer.raw_block;
+        config_ron.variable_prefix = layer.variable_prefix;
+
+        config_ron
+    }
+}
+
+/// Split `operators` into the chars passed to [`Config::new`], with every
+/// multi-codepoint grapheme encoded *(see [`Graphemes`])* if `graphemes`.
+///
+/// Returns error if `operators` contains a multi-codepoint grapheme
+/// and `graphemes` is not set, rather than splitting it into separate operators.
+pub fn split_operators(operators: &str, graphemes: bool) -> Result<(Vec<char>, Graphemes), Error> {
+    let multi_codepoint = grapheme::multi_codepoint(operators);
+    if let (false, Some(grapheme)) = (graphemes, multi_codepoint.first()) {
+        return Err(Error::MultiCodepoint(grapheme.to_string()));
+    }
+
+    let graphemes = Graphemes::new(multi_codepoint);
+    let operators = graphemes.encode_str(operators).chars().collect();
+
+    Ok((operators, graphemes))
+}
+
+/// Encode the `words` *(operators made of multiple chars, e.g. `Ook.`)* with
+/// the `graphemes` of [`split_operators`], returning the chars they're encoded as,
+/// passed to [`Config::new`] with the rest of the operators.
+///
+/// Returns error if a word is a single char or contains a newline.
+pub fn encode_word_operators<S: AsRef<str>>(
+    words: &[S],
+    graphemes: Graphemes,
+) -> Result<(Vec<char>, Graphemes), Error> {
+    if let Some(word) = words
+        .iter()
+        .map(AsRef::as_ref)
+        .find(|word| word.chars().nth(1).is_none() || word.contains('\n'))
+    {
+        return Err(Error::WordOperator(word.to_string()));
+    }
+
+    let graphemes = graphemes.with_words(words.iter().map(AsRef::as_ref));
+    let operators = words
+        .iter()
+        .filter_map(|word| graphemes.encode(word.as_ref()))
+        .collect();
+
+    Ok((operators, graphemes))
+}
+
 /// Return error if the char is already assigned to a field.
 macro_rules! try_insert_fields {
     { $map:expr => $( ( $ch:expr, $field:expr ) ),+ } => {
@@ -125,51 +868,272 @@ impl Config {
         Ok(Config {
             fields_to_values: field_map.iter().map(|(ch, field)| (*field, *ch)).collect(),
             values_to_fields: field_map,
+            folded_to_values: None,
+            extra_delimiters: Vec::new(),
+            whitespace_significant: false,
+            line_macros: false,
+            includes: false,
+            conditionals: false,
+            counted_loops: false,
+            constants: false,
+            named_cells: false,
+            allow_empty_groups: false,
+            strings: false,
+            unicode_digits: false,
+            number_separator: Some(DEFAULT_NUMBER_SEPARATOR),
+            line_comment: None,
+            block_comment: None,
+            raw_block: None,
+            variable_prefix: None,
+            allowed_chars: HashSet::new(),
+            unrecognized_policy: UnrecognizedPolicy::default(),
+            eof_policy: EofPolicy::default(),
+            multiplier_policy: MultiplierPolicy::default(),
+            escape_in_groups: EscapeInGroups::default(),
+            macro_budgets: HashMap::new(),
+            budget_policy: BudgetPolicy::default(),
+            max_expansion_depth: DEFAULT_MAX_EXPANSION_DEPTH,
+            max_macros: DEFAULT_MAX_MACROS,
+            max_macro_table_size: DEFAULT_MAX_MACRO_TABLE_SIZE,
+            operator_weights: HashMap::new(),
+            generators: HashMap::new(),
+            graphemes: Graphemes::default(),
+            pipeline: Pipeline::default(),
         })
     }
 
-    /// Deserialize a `Config` struct from reader containing ron specification.
-    pub fn from_reader_ron<R: Read>(reader: R) -> Result<Config, Error> {
-        // TODO: generate from ConfigFields with procmacro?
-        #[derive(Deserialize)]
-        #[serde(rename = "Config", default)]
-        struct ConfigDe {
-            operators: String,
-            group_start_delimiter: char,
-            group_end_delimiter: char,
-            number_prefix: char,
-            macro_prefix: char,
-            escape_prefix: char,
-        }
-
-        impl Default for ConfigDe {
-            fn default() -> Self {
-                ConfigDe {
-                    operators: String::from(DEFAULT_OPERATORS),
-                    group_start_delimiter: DEFAULT_GROUP_START_DELIMITER,
-                    group_end_delimiter: DEFAULT_GROUP_END_DELIMITER,
-                    number_prefix: DEFAULT_NUMBER_PREFIX,
-                    macro_prefix: DEFAULT_MACRO_PREFIX,
-                    escape_prefix: DEFAULT_ESCAPE_PREFIX,
+    /// Deserialize a `Config` struct from reader containing ron specification,
+    /// read from the file at `path` *(`None` if not from a file)*.
+    ///
+    /// The specification can `extend` another file, overriding its options,
+    /// its path is relative to `path` *(or the current directory)*.
+    pub fn from_reader_ron<R: Read>(reader: R, path: Option<&Path>) -> Result<Config, Error> {
+        let mut chain: Vec<PathBuf> = path
+            .and_then(|path| fs::canonicalize(path).ok())
+            .into_iter()
+            .collect();
+
+        Config::from_ron_layer(ConfigRonLayer::read(reader, path, &mut chain)?)
+    }
+
+    /// Find the [config files][DISCOVERED_CONFIG_NAME] in `dir` and its ancestors,
+    /// where the ones in deeper directories override the options of the ones above,
+    /// returns `None` if there are none.
+    ///
+    /// The discovery stops at a file with the `root` option set.
+    pub fn discover(dir: &Path) -> Result<Option<Config>, Error> {
+        let mut discovered: Option<ConfigRonLayer> = None;
+        for dir in dir.ancestors() {
+            let path = dir.join(DISCOVERED_CONFIG_NAME);
+            let file = match File::open(&path) {
+                Ok(file) => file,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+                Err(error) => {
+                    return Err(Error::Unreadable {
+                        path: path.display().to_string(),
+                        error,
+                    })
                 }
+            };
+
+            let mut chain: Vec<PathBuf> = fs::canonicalize(&path).into_iter().collect();
+            let layer = ConfigRonLayer::read(BufReader::new(file), Some(&path), &mut chain)
+                .map_err(|error| Error::Discovered {
+                    path: path.display().to_string(),
+                    error: Box::new(error),
+                })?;
+            let root = layer.root.unwrap_or(false);
+
+            discovered = Some(match discovered {
+                Some(child) => child.merge(layer),
+                None => layer,
+            });
+            if root {
+                break;
             }
         }
 
-        let de: ConfigDe = ron::de::from_reader(reader)?;
+        discovered.map(Config::from_ron_layer).transpose()
+    }
 
-        Config::new(
-            de.operators.chars(),
+    /// Build a `Config` from a [`ConfigRonLayer`] with every extended layer merged.
+    fn from_ron_layer(layer: ConfigRonLayer) -> Result<Config, Error> {
+        let de = ConfigRon::from(layer);
+        let (operators, graphemes) = split_operators(&de.operators, de.graphemes)?;
+        let (word_operators, graphemes) = encode_word_operators(&de.word_operators, graphemes)?;
+
+        let mut config = Config::new(
+            operators.into_iter().chain(word_operators),
             de.group_start_delimiter,
             de.group_end_delimiter,
             de.number_prefix,
             de.macro_prefix,
             de.escape_prefix,
-        )
+        )?;
+        config.set_extra_delimiters(de.extra_delimiters)?;
+        config.set_case_insensitive(de.case_insensitive)?;
+        config.set_whitespace_significant(de.whitespace_significant);
+        config.set_line_macros(de.line_macros);
+        config.set_includes(de.includes);
+        config.set_conditionals(de.conditionals);
+        config.set_counted_loops(de.counted_loops);
+        config.set_constants(de.constants);
+        config.set_named_cells(de.named_cells)?;
+        config.set_allow_empty_groups(de.allow_empty_groups);
+        config.set_strings(de.strings)?;
+        config.set_unicode_digits(de.unicode_digits);
+        config.set_number_separator(de.number_separator);
+        config.set_line_comment(de.line_comment)?;
+        config.set_block_comment(de.block_comment)?;
+        config.set_raw_block(de.raw_block)?;
+        config.set_variable_prefix(de.variable_prefix);
+        config.set_allowed_chars(de.allowed_chars.chars());
+        config.set_unrecognized_policy(de.unrecognized_policy);
+        config.set_eof_policy(de.eof_policy);
+        config.set_multiplier_policy(de.multiplier_policy);
+        config.set_escape_in_groups(de.escape_in_groups);
+        config.set_macro_budgets(de.macro_budgets);
+        config.set_budget_policy(de.budget_policy);
+        config.set_max_expansion_depth(de.max_expansion_depth);
+        config.set_max_macros(de.max_macros);
+        config.set_max_macro_table_size(de.max_macro_table_size);
+        config.set_operator_weights(de.operator_weights)?;
+        config.set_generators(de.generators)?;
+        config.set_graphemes(graphemes);
+        config.set_pipeline(Pipeline::new(de.passes)?.with_peephole(de.optimize))?;
+
+        Ok(config)
+    }
+
+    /// Serialize the `Config` into a ron specification.
+    pub fn to_ron_string(&self) -> Result<String, Error> {
+        Ok(ron::ser::to_string_pretty(
+            &ConfigRon::from(self),
+            PrettyConfig::new().struct_names(true),
+        )?)
+    }
+
+    /// Check whether both configs assign the same chars to the same fields
+    /// and skip or copy the same comments and raw blocks.
+    pub fn is_equivalent(&self, other: &Config) -> bool {
+        self.values_to_fields == other.values_to_fields
+            && self.extra_delimiters == other.extra_delimiters
+            && self.is_case_insensitive() == other.is_case_insensitive()
+            && self.whitespace_significant == other.whitespace_significant
+            && self.line_comment == other.line_comment
+            && self.block_comment == other.block_comment
+            && self.raw_block == other.raw_block
+            && self.graphemes == other.graphemes
     }
 
     /// Get the field associated with the passed value (if there is one).
     pub fn get_field(&self, ch: &char) -> Option<&ConfigField> {
-        self.values_to_fields.get(ch)
+        self.values_to_fields.get(&self.resolve(*ch))
+    }
+
+    /// Check whether `ch`, when not assigned to any field, is an operator
+    /// because whitespace is significant.
+    pub fn is_whitespace_operator(&self, ch: char) -> bool {
+        self.whitespace_significant && WHITESPACE_OPERATORS.contains(ch)
+    }
+
+    /// Check whether `ch` is an operator, either assigned to the field
+    /// or a [whitespace operator][Config::is_whitespace_operator].
+    pub fn is_operator(&self, ch: char) -> bool {
+        self.get_field(&ch) == Some(&ConfigField::Operator) || self.is_whitespace_operator(ch)
+    }
+
+    /// Get the value matched by `ch`, which is
+    /// `ch` itself, unless the `Config` is case-insensitive.
+    pub fn resolve(&self, ch: char) -> char {
+        match &self.folded_to_values {
+            Some(folded_to_values) => *folded_to_values.get(&Config::fold_case(ch)).unwrap_or(&ch),
+            None => ch,
+        }
+    }
+
+    /// Fold `ch` to lowercase, if it maps to a single `char`.
+    fn fold_case(ch: char) -> char {
+        let mut lowercase = ch.to_lowercase();
+
+        match (lowercase.next(), lowercase.next()) {
+            (Some(folded), None) => folded,
+            _ => ch,
+        }
+    }
+
+    /// Set the group delimiter pairs interchangeable with the main one,
+    /// returns error if the delimiters are already assigned to a field.
+    pub fn set_extra_delimiters<P: IntoIterator<Item = (char, char)>>(
+        &mut self,
+        pairs: P,
+    ) -> Result<(), Error> {
+        for (start, end) in self.extra_delimiters.drain(..) {
+            self.values_to_fields.remove(&start);
+            self.values_to_fields.remove(&end);
+        }
+
+        for (start, end) in pairs {
+            try_insert_fields! {
+                self.values_to_fields =>
+                    (start, ConfigField::GroupStartDelimiter),
+                    (end, ConfigField::GroupEndDelimiter)
+            };
+            self.extra_delimiters.push((start, end));
+        }
+
+        // the folded values have to include the new delimiters
+        self.set_case_insensitive(self.is_case_insensitive())
+    }
+
+    /// Get the `(start, end)` group delimiter pair containing the delimiter `ch`,
+    /// the main pair if `ch` isn't in any other.
+    pub fn get_delimiter_pair(&self, ch: &char) -> (char, char) {
+        let ch = self.resolve(*ch);
+        self.extra_delimiters
+            .iter()
+            .find(|(start, end)| *start == ch || *end == ch)
+            .copied()
+            .unwrap_or((
+                *self.get_value(&ConfigField::GroupStartDelimiter),
+                *self.get_value(&ConfigField::GroupEndDelimiter),
+            ))
+    }
+
+    /// Make the values match regardless of their case,
+    /// returns error if two values of different fields
+    /// become indistinguishable.
+    ///
+    /// Operators differing only in case are matched as the lowest of them.
+    pub fn set_case_insensitive(&mut self, case_insensitive: bool) -> Result<(), Error> {
+        if !case_insensitive {
+            self.folded_to_values = None;
+            return Ok(());
+        }
+
+        let mut values: Vec<&char> = self.values_to_fields.keys().collect();
+        values.sort_unstable();
+
+        let mut folded_to_values: HashMap<char, char> = HashMap::new();
+        for value in values {
+            if let Some(other) = folded_to_values.get(&Config::fold_case(*value)) {
+                let (field, other_field) =
+                    (&self.values_to_fields[value], &self.values_to_fields[other]);
+                if field != other_field {
+                    return Err(Error::NotUnique(field.to_string(), other_field.to_string()));
+                }
+            } else {
+                folded_to_values.insert(Config::fold_case(*value), *value);
+            }
+        }
+        self.folded_to_values = Some(folded_to_values);
+
+        Ok(())
+    }
+
+    /// Check whether the values are matched regardless of their case.
+    pub fn is_case_insensitive(&self) -> bool {
+        self.folded_to_values.is_some()
     }
 
     /// Get the value associated with the passed field.
@@ -178,4 +1142,942 @@ impl Config {
             .get(field)
             .expect("Every field should be set.")
     }
+
+    /// Treat whitespace not assigned to any field as operators.
+    pub fn set_whitespace_significant(&mut self, whitespace_significant: bool) {
+        self.whitespace_significant = whitespace_significant;
+    }
+
+    /// Allow macro definitions where the macro symbol is followed by `=`
+    /// and the tokens until the end of the line *(`$x = tokens...`)*.
+    ///
+    /// The tokens are spliced where the macro is used, rather than wrapped
+    /// in a group, so `#3x` only repeats the first one.
+    pub fn set_line_macros(&mut self, line_macros: bool) {
+        self.line_macros = line_macros;
+    }
+
+    /// Check whether macro definitions can span the rest of a line.
+    pub fn has_line_macros(&self) -> bool {
+        self.line_macros
+    }
+
+    /// Allow includes, where the macro prefix is followed by [`INCLUDE_SYMBOL`]
+    /// and a quoted path *(`$%"lib.bfup"`)*, instead of a macro definition.
+    ///
+    /// The included file is lexed with the same `Config`, its macros are defined
+    /// and its tokens are spliced in place of the include.
+    pub fn set_includes(&mut self, includes: bool) {
+        self.includes = includes;
+    }
+
+    /// Check whether other files can be included.
+    pub fn has_includes(&self) -> bool {
+        self.includes
+    }
+
+    /// Allow the macro prefix followed by [`CONDITIONAL_SYMBOL`], a macro symbol
+    /// and a token *(`$?x(...)`)*, which is only read if the macro is defined.
+    pub fn set_conditionals(&mut self, conditionals: bool) {
+        self.conditionals = conditionals;
+    }
+
+    /// Check whether conditionals are allowed.
+    pub fn has_conditionals(&self) -> bool {
+        self.conditionals
+    }
+
+    /// Allow a number followed by [`COUNTER_SYMBOL`], a counter and a group
+    /// *(`#5@i(#i+>)`)*, which repeats the group with the counter written after
+    /// the number prefix within it read as the number of the repetition, from 0.
+    pub fn set_counted_loops(&mut self, counted_loops: bool) {
+        self.counted_loops = counted_loops;
+    }
+
+    /// Check whether counted loops are allowed.
+    pub fn has_counted_loops(&self) -> bool {
+        self.counted_loops
+    }
+
+    /// Allow the macro prefix followed by [`CONSTANT_SYMBOL`], a name and a number
+    /// *(`$=WIDTH#32`)*, defining a constant read as that number wherever
+    /// its name follows the number prefix *(`#WIDTH(+)`)*.
+    ///
+    /// The names start with a letter, followed by letters, digits or `_`.
+    pub fn set_constants(&mut self, constants: bool) {
+        self.constants = constants;
+    }
+
+    /// Check whether constants are allowed.
+    pub fn has_constants(&self) -> bool {
+        self.constants
+    }
+
+    /// Allow the macro prefix followed by [`CELL_SYMBOL`], a name and a number
+    /// *(`$&counter#3`)*, naming the cell of that index, and [`CELL_SYMBOL`]
+    /// followed by the name *(`&counter`)*, moving the pointer to the cell
+    /// with the `>` or `<` needed from where it's modeled to be,
+    /// unless the symbol is assigned to a field.
+    ///
+    /// The names start with a letter, followed by letters, digits or `_`.
+    ///
+    /// Returns error if `named_cells` and the operators aren't brainfuck's,
+    /// which the pointer is moved and modeled with.
+    pub fn set_named_cells(&mut self, named_cells: bool) -> Result<(), Error> {
+        if named_cells && !self.has_brainfuck_operators() {
+            return Err(Error::NamedCellOperators);
+        }
+
+        self.named_cells = named_cells;
+        Ok(())
+    }
+
+    /// Check whether named cells are allowed.
+    pub fn has_named_cells(&self) -> bool {
+        self.named_cells
+    }
+
+    /// Allow empty groups *(`()`)*, which emit nothing, as placeholders
+    /// in generated code. Otherwise, they're an error.
+    pub fn set_allow_empty_groups(&mut self, allow_empty_groups: bool) {
+        self.allow_empty_groups = allow_empty_groups;
+    }
+
+    /// Check whether empty groups are allowed.
+    pub fn allows_empty_groups(&self) -> bool {
+        self.allow_empty_groups
+    }
+
+    /// Allow string literals quoted with [`STRING_DELIMITER`] *(`"Hello"`)*,
+    /// expanded into the code printing them, unless the delimiter
+    /// is assigned to a field.
+    ///
+    /// Returns error if `strings` and the operators aren't brainfuck's,
+    /// which the printing code is written with.
+    pub fn set_strings(&mut self, strings: bool) -> Result<(), Error> {
+        if strings && !self.has_brainfuck_operators() {
+            return Err(Error::StringOperators);
+        }
+
+        self.strings = strings;
+        Ok(())
+    }
+
+    /// Check whether string literals are expanded.
+    pub fn has_strings(&self) -> bool {
+        self.strings
+    }
+
+    /// Allow numbers in base 10 to be written with the decimal digits of any
+    /// script *(see [`decimal_digit`][crate::lex::decimal_digit])*,
+    /// instead of just the ASCII ones.
+    pub fn set_unicode_digits(&mut self, unicode_digits: bool) {
+        self.unicode_digits = unicode_digits;
+    }
+
+    /// Check whether numbers can be written with non-ASCII digits.
+    pub fn has_unicode_digits(&self) -> bool {
+        self.unicode_digits
+    }
+
+    /// Set the `char` separating the digits of a number *(`#1_000`)*, skipped when
+    /// reading it, `None` to not allow separators.
+    ///
+    /// The separator only has to follow a digit, it's ignored while
+    /// it's assigned to a field.
+    pub fn set_number_separator(&mut self, number_separator: Option<char>) {
+        self.number_separator = number_separator;
+    }
+
+    /// Get the `char` separating the digits of a number, if there is one
+    /// not assigned to a field.
+    pub fn get_number_separator(&self) -> Option<char> {
+        self.number_separator
+            .filter(|ch| self.get_field(ch).is_none())
+    }
+
+    /// Set the `char` starting a comment, which is skipped with the rest of
+    /// the line *(the newline is kept)*, returns error if it's assigned to a field.
+    ///
+    /// The comment takes precedence over a macro with the same symbol.
+    pub fn set_line_comment(&mut self, line_comment: Option<char>) -> Result<(), Error> {
+        if let Some((ch, field)) = line_comment.and_then(|ch| Some((ch, self.get_field(&ch)?))) {
+            return Err(Error::NotUnique(
+                format!("Line comment '{ch}'"),
+                field.to_string(),
+            ));
+        }
+        self.line_comment = line_comment;
+
+        Ok(())
+    }
+
+    /// Get the `char` starting a comment until the end of the line, if there is one.
+    pub fn get_line_comment(&self) -> Option<char> {
+        self.line_comment
+    }
+
+    /// Set the `(start, end)` delimiters of the comments skipped as a whole
+    /// *(e.g. `{-` and `-}`)*, returns error if a delimiter isn't one or two chars,
+    /// or if a single char start is assigned to a field.
+    ///
+    /// A start of two chars takes precedence over the field of its first char
+    /// *(e.g. `(*` starts a comment, while `(` alone starts a group)*.
+    pub fn set_block_comment(
+        &mut self,
+        block_comment: Option<(String, String)>,
+    ) -> Result<(), Error> {
+        if let Some((start, end)) = &block_comment {
+            self.check_block_delimiters("Block comment", start, end, Error::CommentDelimiter)?;
+        }
+        self.block_comment = block_comment;
+
+        Ok(())
+    }
+
+    /// Return error if a delimiter of a block named `name` isn't one or two chars
+    /// *(wrapped in `invalid`)*, or if `start` is a single char assigned to a field.
+    fn check_block_delimiters(
+        &self,
+        name: &str,
+        start: &str,
+        end: &str,
+        invalid: fn(String) -> Error,
+    ) -> Result<(), Error> {
+        for delimiter in [start, end] {
+            if !(1..=2).contains(&delimiter.chars().count()) {
+                return Err(invalid(delimiter.to_string()));
+            }
+        }
+        let mut start_chars = start.chars();
+        if let (Some(ch), None) = (start_chars.next(), start_chars.next()) {
+            if let Some(field) = self.get_field(&ch) {
+                return Err(Error::NotUnique(
+                    format!("{name} start '{ch}'"),
+                    field.to_string(),
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Get the `(start, end)` delimiters of the block comments, if there are any.
+    pub fn get_block_comment(&self) -> Option<(&str, &str)> {
+        self.block_comment
+            .as_ref()
+            .map(|(start, end)| (start.as_str(), end.as_str()))
+    }
+
+    /// Set the `(start, end)` delimiters of the raw blocks, whose text is copied to
+    /// the output as it is, with the same restrictions as [`Config::set_block_comment`].
+    ///
+    /// A block comment starting with the same delimiter takes precedence.
+    pub fn set_raw_block(&mut self, raw_block: Option<(String, String)>) -> Result<(), Error> {
+        if let Some((start, end)) = &raw_block {
+            self.check_block_delimiters("Raw block", start, end, Error::RawDelimiter)?;
+        }
+        self.raw_block = raw_block;
+
+        Ok(())
+    }
+
+    /// Get the `(start, end)` delimiters of the raw blocks, if there are any.
+    pub fn get_raw_block(&self) -> Option<(&str, &str)> {
+        self.raw_block
+            .as_ref()
+            .map(|(start, end)| (start.as_str(), end.as_str()))
+    }
+
+    /// Set the `char` starting the variable references *(`@{key}`)* replaced
+    /// with their values before the input is read, or `None` to read them as they are.
+    ///
+    /// The references are also replaced when variables are set explicitly, starting
+    /// with [`DEFAULT_VARIABLE_PREFIX`][crate::template::DEFAULT_VARIABLE_PREFIX]
+    /// if unset *(see [`Variables::prefix`][crate::template::Variables::prefix])*.
+    pub fn set_variable_prefix(&mut self, variable_prefix: Option<char>) {
+        self.variable_prefix = variable_prefix;
+    }
+
+    /// Get the `char` starting the variable references, if they're replaced.
+    pub fn get_variable_prefix(&self) -> Option<char> {
+        self.variable_prefix
+    }
+
+    /// Check whether `ch`, when not assigned to any field, is expected to be skipped
+    /// *(it's either whitespace that isn't an operator or an allowed char)*.
+    pub fn is_skippable(&self, ch: char) -> bool {
+        (ch.is_whitespace() && !self.is_whitespace_operator(ch)) || self.allowed_chars.contains(&ch)
+    }
+
+    /// Set the chars skipped regardless of the [`UnrecognizedPolicy`]
+    /// *(e.g. chars used in comments)*.
+    pub fn set_allowed_chars<C: IntoIterator<Item = char>>(&mut self, allowed_chars: C) {
+        self.allowed_chars = allowed_chars.into_iter().collect();
+    }
+
+    /// Set the behavior on chars that are not assigned to any field and aren't skippable.
+    pub fn set_unrecognized_policy(&mut self, unrecognized_policy: UnrecognizedPolicy) {
+        self.unrecognized_policy = unrecognized_policy;
+    }
+
+    /// Get the behavior on chars that are not assigned to any field and aren't skippable.
+    pub fn get_unrecognized_policy(&self) -> UnrecognizedPolicy {
+        self.unrecognized_policy
+    }
+
+    /// Set the behavior at the end of input.
+    pub fn set_eof_policy(&mut self, eof_policy: EofPolicy) {
+        self.eof_policy = eof_policy;
+    }
+
+    /// Get the behavior at the end of input.
+    pub fn get_eof_policy(&self) -> EofPolicy {
+        self.eof_policy
+    }
+
+    /// Set how consecutive numbers combine into the multiplier of the next token.
+    pub fn set_multiplier_policy(&mut self, multiplier_policy: MultiplierPolicy) {
+        self.multiplier_policy = multiplier_policy;
+    }
+
+    /// Get how consecutive numbers combine into the multiplier of the next token.
+    pub fn get_multiplier_policy(&self) -> MultiplierPolicy {
+        self.multiplier_policy
+    }
+
+    /// Set how escaped group delimiters are read.
+    pub fn set_escape_in_groups(&mut self, escape_in_groups: EscapeInGroups) {
+        self.escape_in_groups = escape_in_groups;
+    }
+
+    /// Get how escaped group delimiters are read.
+    pub fn get_escape_in_groups(&self) -> EscapeInGroups {
+        self.escape_in_groups
+    }
+
+    /// Set the max number of operators emitted by a single expansion
+    /// of the macros defined as the passed chars.
+    pub fn set_macro_budgets<B: IntoIterator<Item = (char, usize)>>(&mut self, macro_budgets: B) {
+        self.macro_budgets = macro_budgets.into_iter().collect();
+    }
+
+    /// Get the max number of operators emitted by a single expansion
+    /// of the macro `macro_symbol`, if it has a budget.
+    pub fn get_macro_budget(&self, macro_symbol: &char) -> Option<usize> {
+        self.macro_budgets.get(macro_symbol).copied()
+    }
+
+    /// Set the cost of emitting each of the operators,
+    /// the ones without a weight cost 1.
+    ///
+    /// Returns an error if a weighted char is not an operator.
+    pub fn set_operator_weights<W: IntoIterator<Item = (char, usize)>>(
+        &mut self,
+        weights: W,
+    ) -> Result<(), Error> {
+        let operator_weights: HashMap<char, usize> = weights.into_iter().collect();
+        if let Some(ch) = operator_weights.keys().find(|ch| !self.is_operator(**ch)) {
+            return Err(Error::OperatorWeight(*ch));
+        }
+        self.operator_weights = operator_weights;
+
+        Ok(())
+    }
+
+    /// Get the cost of emitting `operator`.
+    pub fn get_operator_weight(&self, operator: &char) -> usize {
+        self.operator_weights.get(operator).copied().unwrap_or(1)
+    }
+
+    /// Set the shell commands run by the generators with the passed symbols,
+    /// returns error if a symbol is assigned to a field.
+    pub fn set_generators<G: IntoIterator<Item = (char, String)>>(
+        &mut self,
+        generators: G,
+    ) -> Result<(), Error> {
+        let generators: HashMap<char, String> = generators.into_iter().collect();
+        for symbol in generators.keys() {
+            if let Some(field) = self.get_field(symbol) {
+                return Err(Error::NotUnique(
+                    format!("Generator '{symbol}'"),
+                    field.to_string(),
+                ));
+            }
+        }
+        self.generators = generators;
+
+        Ok(())
+    }
+
+    /// Get the shell command run by the generator `symbol`, if it is one.
+    pub fn get_generator(&self, symbol: &char) -> Option<&str> {
+        self.generators.get(symbol).map(String::as_str)
+    }
+
+    /// Check whether any generators are set.
+    pub fn has_generators(&self) -> bool {
+        !self.generators.is_empty()
+    }
+
+    /// Set the multi-codepoint operators encoded in the operators
+    /// *(see [`split_operators`])*.
+    pub fn set_graphemes(&mut self, graphemes: Graphemes) {
+        self.graphemes = graphemes;
+    }
+
+    pub fn get_graphemes(&self) -> &Graphemes {
+        &self.graphemes
+    }
+
+    /// Get the chars assigned to [`ConfigField::Operator`], in no particular order.
+    pub fn get_operators(&self) -> impl Iterator<Item = char> + '_ {
+        self.values_to_fields
+            .iter()
+            .filter(|(_, field)| **field == ConfigField::Operator)
+            .map(|(ch, _)| *ch)
+    }
+
+    /// Check whether the operators are brainfuck's [`DEFAULT_OPERATORS`], in any order.
+    pub fn has_brainfuck_operators(&self) -> bool {
+        let mut operators: Vec<char> = self.get_operators().collect();
+        operators.sort_unstable();
+        let mut brainfuck: Vec<char> = DEFAULT_OPERATORS.chars().collect();
+        brainfuck.sort_unstable();
+
+        operators == brainfuck
+    }
+
+    /// Set the [`Stages`][Stage] run while preprocessing.
+    ///
+    /// Returns error if the pipeline [runs the peephole passes][Pipeline::runs_peephole],
+    /// which assume brainfuck's operators, and the operators are different.
+    pub fn set_pipeline(&mut self, pipeline: Pipeline) -> Result<(), Error> {
+        if pipeline.runs_peephole() && !self.has_brainfuck_operators() {
+            return Err(Error::OptimizeOperators);
+        }
+
+        self.pipeline = pipeline;
+        Ok(())
+    }
+
+    pub fn get_pipeline(&self) -> &Pipeline {
+        &self.pipeline
+    }
+
+    /// Get every configured `char` with its [`Roles`][Role], ordered from the one
+    /// in effect to the ones it shadows *(more than one role is a conflict)*.
+    pub fn roles(&self) -> Vec<(char, Vec<Role>)> {
+        let mut roles: BTreeMap<char, Vec<Role>> = BTreeMap::new();
+        for (ch, field) in &self.values_to_fields {
+            roles.entry(*ch).or_default().push(Role::Field(*field));
+        }
+        if self.whitespace_significant {
+            for ch in WHITESPACE_OPERATORS.chars() {
+                let ch_roles = roles.entry(ch).or_default();
+                if ch_roles.is_empty() {
+                    ch_roles.push(Role::WhitespaceOperator);
+                }
+            }
+        }
+        for ch in &self.allowed_chars {
+            roles.entry(*ch).or_default().push(Role::Allowed);
+        }
+        if let Some(ch) = self.line_comment {
+            roles.entry(ch).or_default().insert(0, Role::LineComment);
+        }
+        if let Some(ch) = self.number_separator {
+            roles.entry(ch).or_default().push(Role::NumberSeparator);
+        }
+
+        roles.into_iter().collect()
+    }
+
+    /// Set the behavior on macros exceeding their budget.
+    pub fn set_budget_policy(&mut self, budget_policy: BudgetPolicy) {
+        self.budget_policy = budget_policy;
+    }
+
+    /// Get the behavior on macros exceeding their budget.
+    pub fn get_budget_policy(&self) -> BudgetPolicy {
+        self.budget_policy
+    }
+
+    /// Set the number of macro expansions that can be nested within each other.
+    pub fn set_max_expansion_depth(&mut self, max_expansion_depth: usize) {
+        self.max_expansion_depth = max_expansion_depth;
+    }
+
+    pub fn get_max_expansion_depth(&self) -> usize {
+        self.max_expansion_depth
+    }
+
+    /// Set the number of macro definitions the input can contain,
+    /// redefinitions included.
+    pub fn set_max_macros(&mut self, max_macros: usize) {
+        self.max_macros = max_macros;
+    }
+
+    pub fn get_max_macros(&self) -> usize {
+        self.max_macros
+    }
+
+    /// Set the number of tokens the bodies of the defined macros can store
+    /// *(see [`MacroDefinition::stored_len`][crate::lex::MacroDefinition::stored_len])*.
+    pub fn set_max_macro_table_size(&mut self, max_macro_table_size: usize) {
+        self.max_macro_table_size = max_macro_table_size;
+    }
+
+    pub fn get_max_macro_table_size(&self) -> usize {
+        self.max_macro_table_size
+    }
+}
+
+/// Cache of the [`Configs`][Config] read from ron, keyed by the hash of their
+/// content and path, so that modes reading the same config over and over
+/// don't validate it again.
+///
+/// Only the content of the config itself is hashed, changes to the files
+/// it extends are seen once the extending config changes.
+#[derive(Default)]
+pub struct ConfigCache {
+    configs: HashMap<u64, Arc<Config>>,
+    hits: usize,
+    misses: usize,
+}
+
+impl ConfigCache {
+    /// Get the cached config read from `content` at `path`,
+    /// reading it with [`Config::from_reader_ron`] if it isn't cached.
+    pub fn get_or_read(
+        &mut self,
+        content: &[u8],
+        path: Option<&Path>,
+    ) -> Result<Arc<Config>, Error> {
+        let mut hasher = DefaultHasher::new();
+        content.hash(&mut hasher);
+        path.hash(&mut hasher);
+        let key = hasher.finish();
+
+        if let Some(config) = self.configs.get(&key) {
+            self.hits += 1;
+            return Ok(Arc::clone(config));
+        }
+
+        self.misses += 1;
+        let config = Arc::new(Config::from_reader_ron(content, path)?);
+        self.configs.insert(key, Arc::clone(&config));
+
+        Ok(config)
+    }
+
+    /// Get the number of configs found in the cache.
+    pub fn hits(&self) -> usize {
+        self.hits
+    }
+
+    /// Get the number of configs read because they weren't cached.
+    pub fn misses(&self) -> usize {
+        self.misses
+    }
+
+    /// Get the number of cached configs.
+    pub fn len(&self) -> usize {
+        self.configs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.configs.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn config_cache() -> Result<()> {
+        let mut cache = ConfigCache::default();
+        let first = cache.get_or_read(b"(operators: \"+-\")", None)?;
+        let second = cache.get_or_read(b"(operators: \"+-\")", None)?;
+        assert!(
+            Arc::ptr_eq(&first, &second),
+            "The unchanged config should be cached."
+        );
+
+        cache.get_or_read(b"(operators: \"<>\")", None)?;
+        assert!(
+            cache.hits() == 1 && cache.misses() == 2 && cache.len() == 2,
+            "The changed config should be read again."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn config_shared_between_threads() -> Result<()> {
+        let config = &Config::default();
+        let outputs = std::thread::scope(|scope| {
+            let handles: Vec<_> = ["+", "#2-", "$x>x"]
+                .into_iter()
+                .map(|input| scope.spawn(move || testing::preprocessed(input, config)))
+                .collect();
+            handles
+                .into_iter()
+                .map(|handle| handle.join().expect("The thread should not panic."))
+                .collect::<Result<Vec<String>>>()
+        })?;
+
+        assert!(
+            outputs == ["+", "--", ">"],
+            "{outputs:?} should be preprocessed with the shared config."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn config_ron_round_trip() -> Result<()> {
+        let mut config = Config::new("abc".chars(), '{', '}', '*', '@', '!')?;
+        config.set_eof_policy(EofPolicy::Lenient);
+        config.set_macro_budgets([('x', 512), ('y', 0)]);
+        config.set_max_expansion_depth(8);
+        config.set_max_macros(100);
+        config.set_max_macro_table_size(1000);
+        config.set_includes(true);
+        config.set_conditionals(true);
+        config.set_counted_loops(true);
+        config.set_constants(true);
+        config.set_allow_empty_groups(true);
+        config.set_unicode_digits(true);
+        config.set_number_separator(Some('\''));
+        config.set_line_comment(Some(';'))?;
+        config.set_block_comment(Some((String::from("{-"), String::from("-}"))))?;
+        config.set_raw_block(Some((String::from("{{"), String::from("}}"))))?;
+        config.set_variable_prefix(Some('%'));
+        config.set_operator_weights([('a', 3)])?;
+        config.set_extra_delimiters([('[', ']'), ('<', '>')])?;
+        config.set_generators([('g', String::from("figlet | bfgen"))])?;
+        config.set_pipeline(Pipeline::new([
+            Stage::Lex,
+            Stage::MacroExpand,
+            Stage::Layout,
+            Stage::Emit,
+        ])?)?;
+
+        let ron = config.to_ron_string()?;
+        let reloaded = Config::from_reader_ron(ron.as_bytes(), None)?;
+
+        assert!(config == reloaded, "\"{ron}\" should reproduce the config.");
+
+        Ok(())
+    }
+
+    #[test]
+    fn config_graphemes() -> Result<()> {
+        let result = Config::from_reader_ron("Config(operators: \"👍🏽👎🏿\")".as_bytes(), None);
+        assert!(
+            matches!(&result, Err(Error::MultiCodepoint(grapheme)) if grapheme == "👍🏽"),
+            "Multi-codepoint operators should be errors without graphemes."
+        );
+
+        let config = Config::from_reader_ron(
+            "Config(operators: \"👍🏽👎🏿+\", graphemes: true)".as_bytes(),
+            None,
+        )?;
+        let ron = config.to_ron_string()?;
+        assert!(
+            ron.contains("👍🏽👎🏿") && Config::from_reader_ron(ron.as_bytes(), None)? == config,
+            "\"{ron}\" should contain the decoded graphemes."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn config_word_operators() -> Result<()> {
+        let result = Config::from_reader_ron("Config(word_operators: [\"O\"])".as_bytes(), None);
+        assert!(
+            matches!(&result, Err(Error::WordOperator(word)) if word == "O"),
+            "A single char shouldn't be a word operator."
+        );
+
+        let config = Config::from_reader_ron(
+            "Config(operators: \"+\", word_operators: [\"Ook.\", \"Ook!\"])".as_bytes(),
+            None,
+        )?;
+        let ook = config
+            .get_graphemes()
+            .encode("Ook.")
+            .expect("The word should be encoded.");
+        assert!(
+            config.get_field(&ook) == Some(&ConfigField::Operator),
+            "The word should be an operator."
+        );
+
+        let ron = config.to_ron_string()?;
+        assert!(
+            ron.contains("operators: \"+\"")
+                && ron.contains("graphemes: false")
+                && Config::from_reader_ron(ron.as_bytes(), None)? == config,
+            "\"{ron}\" should list the words apart from the operators."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn config_optimize() -> Result<()> {
+        let config = Config::from_reader_ron("Config(optimize: true)".as_bytes(), None)?;
+        assert!(
+            config.get_pipeline().runs_peephole(),
+            "The output should be optimized with brainfuck's operators."
+        );
+
+        let result = Config::from_reader_ron(
+            "Config(operators: \"+-<>\", optimize: true)".as_bytes(),
+            None,
+        );
+        assert!(
+            matches!(result, Err(Error::OptimizeOperators)),
+            "The output shouldn't be optimized with other operators."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn config_operator_weights() -> Result<()> {
+        let config =
+            Config::from_reader_ron("Config(operator_weights: {'-': 4})".as_bytes(), None)?;
+        assert!(
+            config.get_operator_weight(&'-') == 4 && config.get_operator_weight(&'+') == 1,
+            "'-' should weigh 4 and the other operators 1."
+        );
+
+        let result = Config::from_reader_ron("Config(operator_weights: {'x': 4})".as_bytes(), None);
+        assert!(
+            matches!(result, Err(Error::OperatorWeight('x'))),
+            "Chars that aren't operators shouldn't be weighted."
+        );
+
+        let result =
+            Config::from_reader_ron("Config(operator_weights: {'-': -4})".as_bytes(), None);
+        assert!(
+            matches!(result, Err(Error::FromRon(_))),
+            "Negative weights should be errors."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn config_strings() -> Result<()> {
+        let config = Config::from_reader_ron("Config(strings: true)".as_bytes(), None)?;
+        let ron = config.to_ron_string()?;
+        assert!(
+            config.has_strings() && Config::from_reader_ron(ron.as_bytes(), None)? == config,
+            "Strings should be expanded with brainfuck's operators."
+        );
+
+        let result = Config::from_reader_ron(
+            "Config(operators: \"abcdefgh\", strings: true)".as_bytes(),
+            None,
+        );
+        assert!(
+            matches!(result, Err(Error::StringOperators)),
+            "Strings shouldn't be expanded with other operators."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn config_named_cells() -> Result<()> {
+        let config = Config::from_reader_ron("Config(named_cells: true)".as_bytes(), None)?;
+        let ron = config.to_ron_string()?;
+        assert!(
+            config.has_named_cells() && Config::from_reader_ron(ron.as_bytes(), None)? == config,
+            "Cells should be named with brainfuck's operators."
+        );
+
+        let result = Config::from_reader_ron(
+            "Config(operators: \"abcdefgh\", named_cells: true)".as_bytes(),
+            None,
+        );
+        assert!(
+            matches!(result, Err(Error::NamedCellOperators)),
+            "Cells shouldn't be named with other operators."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn config_extends() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("bfup-config-extends-{}", std::process::id()));
+        fs::create_dir_all(dir.join("project"))?;
+        fs::write(
+            dir.join("base.ron"),
+            "Config(operators: \"abc\", eof_policy: Lenient)",
+        )?;
+        fs::write(
+            dir.join("project/child.ron"),
+            "Config(extends: \"../base.ron\", operators: \"xyz\")",
+        )?;
+        fs::write(dir.join("cycle.ron"), "Config(extends: \"cycle.ron\")")?;
+
+        let child_path = dir.join("project/child.ron");
+        let config = Config::from_reader_ron(File::open(&child_path)?, Some(&child_path));
+        let cycle_path = dir.join("cycle.ron");
+        let cycle = Config::from_reader_ron(File::open(&cycle_path)?, Some(&cycle_path));
+        fs::remove_dir_all(&dir)?;
+
+        let config = config?;
+        assert!(
+            config.get_field(&'x') == Some(&ConfigField::Operator)
+                && config.get_field(&'a').is_none(),
+            "Operators should be overridden."
+        );
+        assert!(
+            config.get_eof_policy() == EofPolicy::Lenient,
+            "The EOF policy should be extended."
+        );
+        assert!(
+            matches!(cycle, Err(Error::ExtendsCycle { .. })),
+            "Cycles should be detected."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn config_discover() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("bfup-config-discover-{}", std::process::id()));
+        fs::create_dir_all(dir.join("project/libs/ook"))?;
+        fs::write(
+            dir.join(DISCOVERED_CONFIG_NAME),
+            "Config(operators: \"abc\")",
+        )?;
+        fs::write(
+            dir.join("project").join(DISCOVERED_CONFIG_NAME),
+            "Config(root: true, eof_policy: Lenient)",
+        )?;
+        fs::write(
+            dir.join("project/libs/ook").join(DISCOVERED_CONFIG_NAME),
+            "Config(operators: \"!?.\")",
+        )?;
+
+        let ook = Config::discover(&dir.join("project/libs/ook"));
+        let project = Config::discover(&dir.join("project/libs"));
+        fs::remove_dir_all(&dir)?;
+
+        let ook = ook?.expect("The ook config should be discovered.");
+        assert!(
+            ook.get_field(&'?') == Some(&ConfigField::Operator)
+                && ook.get_eof_policy() == EofPolicy::Lenient,
+            "The ook config should override the project config."
+        );
+
+        let project = project?.expect("The project config should be discovered.");
+        assert!(
+            project.get_field(&'a').is_none() && project.get_field(&'+').is_some(),
+            "The discovery should stop at the root config."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn config_roles() -> Result<()> {
+        let mut config = Config::new("+\t".chars(), '(', ')', '#', '$', '\\')?;
+        config.set_whitespace_significant(true);
+        config.set_allowed_chars("+/".chars());
+        let roles = config.roles();
+
+        assert!(
+            roles.contains(&('+', vec![Role::Field(ConfigField::Operator), Role::Allowed])),
+            "'+' should be an operator shadowing an allowed char."
+        );
+        assert!(
+            roles.contains(&(' ', vec![Role::WhitespaceOperator]))
+                && roles.contains(&('\t', vec![Role::Field(ConfigField::Operator)])),
+            "Whitespace should be operators."
+        );
+        assert!(
+            roles.contains(&('/', vec![Role::Allowed])),
+            "'/' should be allowed."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn config_dialect() {
+        assert!(
+            Dialect::from_extension("ook") == Some(Dialect::Ook)
+                && Dialect::from_extension("txt").is_none(),
+            "Dialects should be selected by extension."
+        );
+        assert!(
+            Dialect::Ook.config().get_field(&'?') == Some(&ConfigField::Operator)
+                && Dialect::Ook.config().get_field(&'+').is_none(),
+            "Ook! should have its own operators."
+        );
+    }
+
+    #[test]
+    fn config_case_insensitive() -> Result<()> {
+        let mut config = Config::new("oO!".chars(), '(', ')', '#', '$', '\\')?;
+        config.set_case_insensitive(true)?;
+
+        assert!(
+            config.get_field(&'o') == Some(&ConfigField::Operator),
+            "'o' should be an operator."
+        );
+        assert!(config.resolve('o') == 'O', "'o' should match 'O'.");
+
+        let mut config = Config::new("a".chars(), '(', ')', '#', 'A', '\\')?;
+        assert!(
+            config.set_case_insensitive(true).is_err(),
+            "'a' and 'A' shouldn't be indistinguishable."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn config_equivalence() -> Result<()> {
+        let mut config = Config::default();
+        config.set_eof_policy(EofPolicy::Lenient);
+        let reordered = Config::new(
+            DEFAULT_OPERATORS.chars().rev(),
+            DEFAULT_GROUP_START_DELIMITER,
+            DEFAULT_GROUP_END_DELIMITER,
+            DEFAULT_NUMBER_PREFIX,
+            DEFAULT_MACRO_PREFIX,
+            DEFAULT_ESCAPE_PREFIX,
+        )?;
+
+        assert!(
+            config.is_equivalent(&reordered),
+            "Configs should be equivalent."
+        );
+        assert!(config != reordered, "Configs shouldn't be equal.");
+        let mut commented = Config::default();
+        commented.set_line_comment(Some(';'))?;
+        assert!(
+            !config.is_equivalent(&commented),
+            "Configs skipping different comments shouldn't be equivalent."
+        );
+        assert!(
+            !config.is_equivalent(&Config::new("+".chars(), '(', ')', '#', '$', '\\')?),
+            "Configs shouldn't be equivalent."
+        );
+
+        Ok(())
+    }
 }
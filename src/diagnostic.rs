@@ -0,0 +1,277 @@
+use std::fmt;
+use std::io::{self, Write};
+
+use colored::Colorize;
+use serde_json::{json, Value};
+
+use crate::lex::{self, Warning};
+use crate::span::Span;
+
+/// Severity of a [`Diagnostic`].
+#[derive(Clone, Copy, PartialEq, Eq, fmt::Debug)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// Error or warning reported into a [`DiagnosticSink`], with the [`Span`]
+/// of the input it concerns *(if it concerns any part in particular)*.
+#[derive(Clone, PartialEq, Eq, fmt::Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: Option<Span>,
+}
+
+impl Diagnostic {
+    /// Create a diagnostic not concerning any particular part of the input.
+    pub fn new(severity: Severity, message: String) -> Self {
+        Diagnostic {
+            severity,
+            message,
+            span: None,
+        }
+    }
+
+    /// Get a diagnostic for every error in `error`, a group is split into its errors.
+    pub fn from_lex_error(error: &lex::Error) -> Vec<Diagnostic> {
+        match error {
+            lex::Error::Group(group) => group
+                .errors()
+                .iter()
+                .flat_map(Diagnostic::from_lex_error)
+                .collect(),
+            error => vec![Diagnostic {
+                severity: Severity::Error,
+                message: error.to_string(),
+                span: error.span().copied(),
+            }],
+        }
+    }
+
+    /// Get the diagnostics for `error`, split into the lexing errors
+    /// it was caused by, or a single one with its whole chain of causes.
+    pub fn from_error(error: &anyhow::Error) -> Vec<Diagnostic> {
+        if let Some(error) = error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<lex::Error>())
+        {
+            return Diagnostic::from_lex_error(error);
+        }
+
+        let message = error
+            .chain()
+            .map(ToString::to_string)
+            .collect::<Vec<String>>()
+            .join(": ");
+        vec![Diagnostic::new(Severity::Error, message)]
+    }
+
+    /// Get the diagnostic as a json object, with 1-based inclusive span positions.
+    pub fn to_json(&self) -> Value {
+        let severity = match self.severity {
+            Severity::Error => "error",
+            Severity::Warning => "warning",
+        };
+        let span = self.span.map(|span| {
+            json!({
+                "start": { "line": span.start.lineno, "column": span.start.colno },
+                "end": { "line": span.end.lineno, "column": span.end.colno },
+            })
+        });
+
+        json!({
+            "severity": severity,
+            "message": self.message,
+            "span": span,
+        })
+    }
+}
+
+impl From<&Warning> for Diagnostic {
+    fn from(warning: &Warning) -> Self {
+        Diagnostic {
+            severity: Severity::Warning,
+            message: warning.to_string(),
+            span: Some(*warning.span()),
+        }
+    }
+}
+
+/// Error returned after the failure was already reported into a [`DiagnosticSink`].
+#[derive(thiserror::Error, fmt::Debug)]
+#[error("the errors were reported as diagnostics")]
+pub struct Reported;
+
+/// Receiver of the [`Diagnostics`][Diagnostic] produced while preprocessing.
+pub trait DiagnosticSink {
+    /// Report `diagnostic` into the sink.
+    fn report(&mut self, diagnostic: Diagnostic) -> io::Result<()>;
+
+    /// Write out the diagnostics held back until every one was reported.
+    fn finish(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl DiagnosticSink for Vec<Diagnostic> {
+    fn report(&mut self, diagnostic: Diagnostic) -> io::Result<()> {
+        self.push(diagnostic);
+        Ok(())
+    }
+}
+
+/// Format the diagnostics are written in.
+#[derive(Clone, Copy, Default, PartialEq, Eq, fmt::Debug, clap::ValueEnum)]
+pub enum MessageFormat {
+    /// Colored messages, one per line
+    #[default]
+    Human,
+    /// A json object per line
+    Json,
+    /// A json object with the parameters of an LSP publishDiagnostics notification
+    Lsp,
+}
+
+impl MessageFormat {
+    /// Create a sink writing into `writer` in the format,
+    /// `uri` identifies the input for the LSP sink.
+    pub fn sink<W: Write + 'static>(self, writer: W, uri: String) -> Box<dyn DiagnosticSink> {
+        match self {
+            MessageFormat::Human => Box::new(HumanSink { writer }),
+            MessageFormat::Json => Box::new(JsonSink { writer }),
+            MessageFormat::Lsp => Box::new(LspSink {
+                writer,
+                uri,
+                diagnostics: Vec::new(),
+            }),
+        }
+    }
+}
+
+/// Sink writing colored diagnostics, one per line.
+pub struct HumanSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> DiagnosticSink for HumanSink<W> {
+    fn report(&mut self, diagnostic: Diagnostic) -> io::Result<()> {
+        let label = match diagnostic.severity {
+            Severity::Error => "error:".red().bold(),
+            Severity::Warning => "warning:".yellow().bold(),
+        };
+
+        writeln!(self.writer, "{label} {}", diagnostic.message)
+    }
+}
+
+/// Sink writing every diagnostic as a json object on its own line.
+pub struct JsonSink<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> DiagnosticSink for JsonSink<W> {
+    fn report(&mut self, diagnostic: Diagnostic) -> io::Result<()> {
+        writeln!(self.writer, "{}", diagnostic.to_json())
+    }
+}
+
+/// Sink collecting the diagnostics into the parameters
+/// of an LSP `textDocument/publishDiagnostics` notification.
+pub struct LspSink<W: Write> {
+    writer: W,
+    uri: String,
+    diagnostics: Vec<Value>,
+}
+
+impl<W: Write> DiagnosticSink for LspSink<W> {
+    fn report(&mut self, diagnostic: Diagnostic) -> io::Result<()> {
+        // LSP positions are 0-based, with exclusive ends
+        let (start, end) = match diagnostic.span {
+            Some(span) => (
+                (span.start.lineno - 1, span.start.colno.saturating_sub(1)),
+                (span.end.lineno - 1, span.end.colno),
+            ),
+            None => ((0, 0), (0, 0)),
+        };
+        let severity = match diagnostic.severity {
+            Severity::Error => 1,
+            Severity::Warning => 2,
+        };
+
+        self.diagnostics.push(json!({
+            "range": {
+                "start": { "line": start.0, "character": start.1 },
+                "end": { "line": end.0, "character": end.1 },
+            },
+            "severity": severity,
+            "source": env!("CARGO_PKG_NAME"),
+            "message": diagnostic.message,
+        }));
+
+        Ok(())
+    }
+
+    fn finish(&mut self) -> io::Result<()> {
+        let params = json!({
+            "uri": self.uri,
+            "diagnostics": std::mem::take(&mut self.diagnostics),
+        });
+
+        writeln!(self.writer, "{params}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::config::{Config, EofPolicy};
+    use crate::testing;
+
+    #[test]
+    fn diagnostic_sinks() -> Result<()> {
+        let mut config = Config::default();
+        config.set_eof_policy(EofPolicy::Lenient);
+        let warnings = testing::warnings("\n+(-", &config)?;
+
+        let mut diagnostics: Vec<Diagnostic> = Vec::new();
+        for warning in &warnings {
+            diagnostics.report(Diagnostic::from(warning))?;
+        }
+        assert!(
+            matches!(
+                &diagnostics[..],
+                [Diagnostic {
+                    severity: Severity::Warning,
+                    span: Some(_),
+                    ..
+                }]
+            ),
+            "{diagnostics:?} should contain the unclosed group warning."
+        );
+
+        let mut lsp = LspSink {
+            writer: Vec::new(),
+            uri: String::from("file:///in.bf"),
+            diagnostics: Vec::new(),
+        };
+        lsp.report(diagnostics[0].clone())?;
+        lsp.finish()?;
+        let params: Value = serde_json::from_slice(&lsp.writer)?;
+        assert!(
+            params["uri"] == "file:///in.bf" && params["diagnostics"][0]["severity"] == 2,
+            "{params} should be a publishDiagnostics notification's parameters."
+        );
+
+        let errors = testing::tokens(")(", &Config::default()).expect_err("Lexing should fail.");
+        let diagnostics = Diagnostic::from_lex_error(&errors);
+        assert!(
+            diagnostics.len() == 2,
+            "{diagnostics:?} should contain both errors."
+        );
+
+        Ok(())
+    }
+}
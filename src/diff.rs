@@ -0,0 +1,389 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::config::{Config, ConfigField};
+use crate::lex::{MacroDefinition, Token};
+use crate::span::Span;
+
+/// Semantic difference between the token trees of an old and a new source,
+/// the spans point into the source they're named after.
+#[derive(Clone, PartialEq, Eq, fmt::Debug)]
+pub enum Difference {
+    /// Macro only defined in the new source.
+    MacroAdded { symbol: char, new: Span },
+    /// Macro only defined in the old source.
+    MacroRemoved { symbol: char, old: Span },
+    /// Macro defined with a different body in each source.
+    MacroChanged {
+        symbol: char,
+        old: Span,
+        new: Span,
+        old_body: String,
+        new_body: String,
+    },
+    /// Token repeated a different number of times.
+    MultiplierChanged {
+        old: Span,
+        new: Span,
+        from: usize,
+        to: usize,
+    },
+    /// Tokens only in the new source.
+    Added { new: Span, source: String },
+    /// Tokens only in the old source.
+    Removed { old: Span, source: String },
+}
+
+impl fmt::Display for Difference {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Difference::MacroAdded { symbol, new } => write!(f, "[{new}]: macro '{symbol}' added"),
+            Difference::MacroRemoved { symbol, old } => {
+                write!(f, "[{old}]: macro '{symbol}' removed")
+            }
+            Difference::MacroChanged {
+                symbol,
+                old,
+                new,
+                old_body,
+                new_body,
+            } => write!(
+                f,
+                "[{old}] -> [{new}]: macro '{symbol}' changed from {old_body} to {new_body}"
+            ),
+            Difference::MultiplierChanged { old, new, from, to } => {
+                write!(
+                    f,
+                    "[{old}] -> [{new}]: multiplier changed from {from} to {to}"
+                )
+            }
+            Difference::Added { new, source } => write!(f, "[{new}]: added {source}"),
+            Difference::Removed { old, source } => write!(f, "[{old}]: removed {source}"),
+        }
+    }
+}
+
+/// Token with the number before it, the number of times it's repeated.
+struct Item<'a> {
+    times: usize,
+    token: &'a Token,
+    /// The token written as source, compared between the sources.
+    source: String,
+}
+
+impl Item<'_> {
+    /// Get the [`Span`] of the token where it's written in the source.
+    fn span(&self) -> Span {
+        match self.token {
+            Token::Expanded(_, provenance) => provenance.use_site,
+            Token::Operator(_, span)
+            | Token::Group(_, span)
+            | Token::Str(_, span)
+            | Token::Raw(_, span)
+            | Token::Cell(.., span) => *span,
+            Token::Number(..) => unreachable!("Numbers should be multipliers."),
+        }
+    }
+
+    fn to_source(&self, config: &Config) -> String {
+        match self.times {
+            1 => self.source.clone(),
+            times => format!(
+                "{}{times}{}",
+                config.get_value(&ConfigField::NumberPrefix),
+                self.source
+            ),
+        }
+    }
+}
+
+/// Compare the tokens and macro definitions of the `old` and `new` sources,
+/// lexed with `config`.
+///
+/// The tokens are compared with the macros expanded, ignoring everything
+/// the lexer skips, so a reformatted source or a macro use replaced with
+/// its expansion has no differences. The macros are compared by their
+/// symbols, the n-th definition of a symbol with its n-th redefinition.
+pub fn diff(
+    (old_tokens, old_macros): (&[Token], &[MacroDefinition]),
+    (new_tokens, new_macros): (&[Token], &[MacroDefinition]),
+    config: &Config,
+) -> Vec<Difference> {
+    let mut differences = diff_macros(old_macros, new_macros, config);
+    diff_tokens(old_tokens, new_tokens, config, &mut differences);
+
+    differences
+}
+
+/// Compare the macros defined in both sources.
+fn diff_macros(
+    old: &[MacroDefinition],
+    new: &[MacroDefinition],
+    config: &Config,
+) -> Vec<Difference> {
+    let body = |definition: &MacroDefinition| -> String {
+        definition
+            .tokens
+            .iter()
+            .map(|token| token.to_source(config))
+            .collect()
+    };
+    let old = keyed(old);
+    let new = keyed(new);
+    let new_by_key: HashMap<(char, usize), &MacroDefinition> = new.iter().copied().collect();
+
+    let mut differences: Vec<Difference> = Vec::new();
+    for (key, old_definition) in &old {
+        match new_by_key.get(key) {
+            Some(new_definition) if body(old_definition) != body(new_definition) => differences
+                .push(Difference::MacroChanged {
+                    symbol: key.0,
+                    old: old_definition.span,
+                    new: new_definition.span,
+                    old_body: body(old_definition),
+                    new_body: body(new_definition),
+                }),
+            Some(_) => (),
+            None => differences.push(Difference::MacroRemoved {
+                symbol: key.0,
+                old: old_definition.span,
+            }),
+        }
+    }
+    let old_keys: Vec<(char, usize)> = old.iter().map(|(key, _)| *key).collect();
+    differences.extend(new.iter().filter(|(key, _)| !old_keys.contains(key)).map(
+        |(key, definition)| Difference::MacroAdded {
+            symbol: key.0,
+            new: definition.span,
+        },
+    ));
+
+    differences
+}
+
+/// Key the `definitions` by their symbols and the number
+/// of times the symbol was defined up to them.
+fn keyed(definitions: &[MacroDefinition]) -> Vec<((char, usize), &MacroDefinition)> {
+    let mut redefinitions: HashMap<char, usize> = HashMap::new();
+    definitions
+        .iter()
+        .map(|definition| {
+            let index = redefinitions.entry(definition.symbol).or_insert(0);
+            *index += 1;
+            ((definition.symbol, *index), definition)
+        })
+        .collect()
+}
+
+/// Get the `tokens` paired with the numbers before them,
+/// leaving out the trailing numbers, which emit nothing.
+fn items<'a>(tokens: &'a [Token], config: &Config) -> Vec<Item<'a>> {
+    let mut items: Vec<Item> = Vec::new();
+    let mut times: usize = 1;
+    for token in tokens {
+        match token.without_provenance() {
+            Token::Number(number, ..) => times = *number,
+            _ => {
+                items.push(Item {
+                    times,
+                    token,
+                    source: token.to_source(config),
+                });
+                times = 1;
+            }
+        }
+    }
+
+    items
+}
+
+/// Compare the `old` and `new` tokens, pushing their differences to `differences`.
+///
+/// The tokens written the same in both are matched by their longest common
+/// subsequence, the groups left between the matched tokens are compared
+/// pairwise, while the rest is reported as added or removed.
+fn diff_tokens(old: &[Token], new: &[Token], config: &Config, differences: &mut Vec<Difference>) {
+    let old = items(old, config);
+    let new = items(new, config);
+
+    // the common prefix and suffix are matched without building the table
+    let prefix = old
+        .iter()
+        .zip(&new)
+        .take_while(|(old, new)| old.source == new.source)
+        .count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(old, new)| old.source == new.source)
+        .count();
+
+    let mut matched: Vec<(usize, usize)> = (0..prefix).map(|i| (i, i)).collect();
+    matched.extend(
+        common_subsequence(
+            &old[prefix..old.len() - suffix],
+            &new[prefix..new.len() - suffix],
+        )
+        .into_iter()
+        .map(|(i, j)| (prefix + i, prefix + j)),
+    );
+    matched.extend((0..suffix).map(|k| (old.len() - suffix + k, new.len() - suffix + k)));
+
+    let (mut old_start, mut new_start) = (0, 0);
+    for (i, j) in matched.into_iter().chain([(old.len(), new.len())]) {
+        diff_unmatched(&old[old_start..i], &new[new_start..j], config, differences);
+        if let (Some(old), Some(new)) = (old.get(i), new.get(j)) {
+            if old.times != new.times {
+                differences.push(Difference::MultiplierChanged {
+                    old: old.span(),
+                    new: new.span(),
+                    from: old.times,
+                    to: new.times,
+                });
+            }
+        }
+        (old_start, new_start) = (i + 1, j + 1);
+    }
+}
+
+/// Get the indices of the longest common subsequence of the
+/// `old` and `new` items written the same.
+fn common_subsequence(old: &[Item], new: &[Item]) -> Vec<(usize, usize)> {
+    // lengths of the common subsequences of the suffixes
+    let width = new.len() + 1;
+    let mut lengths: Vec<u32> = vec![0; (old.len() + 1) * width];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            lengths[i * width + j] = if old[i].source == new[j].source {
+                lengths[(i + 1) * width + j + 1] + 1
+            } else {
+                lengths[(i + 1) * width + j].max(lengths[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut matched: Vec<(usize, usize)> = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while i < old.len() && j < new.len() {
+        if old[i].source == new[j].source {
+            matched.push((i, j));
+            (i, j) = (i + 1, j + 1);
+        } else if lengths[(i + 1) * width + j] >= lengths[i * width + j + 1] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+
+    matched
+}
+
+/// Compare the `old` and `new` items left between two matched ones,
+/// the groups are paired in order and compared recursively.
+fn diff_unmatched(old: &[Item], new: &[Item], config: &Config, differences: &mut Vec<Difference>) {
+    let is_group = |item: &&Item| matches!(item.token.without_provenance(), Token::Group(..));
+    let old_groups: Vec<usize> = (0..old.len()).filter(|i| is_group(&&old[*i])).collect();
+    let new_groups: Vec<usize> = (0..new.len()).filter(|j| is_group(&&new[*j])).collect();
+
+    let (mut old_start, mut new_start) = (0, 0);
+    let paired = old_groups.iter().zip(&new_groups).map(|(i, j)| (*i, *j));
+    for (i, j) in paired.chain([(old.len(), new.len())]) {
+        push_changed(&old[old_start..i], &new[new_start..j], config, differences);
+        if let (Some(old), Some(new)) = (old.get(i), new.get(j)) {
+            if old.times != new.times {
+                differences.push(Difference::MultiplierChanged {
+                    old: old.span(),
+                    new: new.span(),
+                    from: old.times,
+                    to: new.times,
+                });
+            }
+            if let (Token::Group(old, _), Token::Group(new, _)) = (
+                old.token.without_provenance(),
+                new.token.without_provenance(),
+            ) {
+                diff_tokens(old, new, config, differences);
+            }
+        }
+        (old_start, new_start) = (i + 1, j + 1);
+    }
+}
+
+/// Report the `old` items as removed and the `new` ones as added.
+fn push_changed(old: &[Item], new: &[Item], config: &Config, differences: &mut Vec<Difference>) {
+    let source =
+        |items: &[Item]| -> String { items.iter().map(|item| item.to_source(config)).collect() };
+    if let (Some(first), Some(last)) = (old.first(), old.last()) {
+        differences.push(Difference::Removed {
+            old: first.span().merge(&last.span()),
+            source: source(old),
+        });
+    }
+    if let (Some(first), Some(last)) = (new.first(), new.last()) {
+        differences.push(Difference::Added {
+            new: first.span().merge(&last.span()),
+            source: source(new),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn diff_token_trees() -> Result<()> {
+        let config = Config::default();
+        let lex = |input: &str| -> Result<(Vec<Token>, Vec<MacroDefinition>)> {
+            let mut lexer = testing::lexer(input, &config).keep_macro_definitions();
+            let tokens = lexer.read_all_tokens()?;
+            Ok((tokens, lexer.take_macro_definitions()))
+        };
+        let (old_tokens, old_macros) = lex("$w(+) $x(+>) #3x (<.-) $y- y")?;
+        let (new_tokens, new_macros) = lex("$w(++) $x( + > )\n  #4x (< . - ,)\n - $z(.)")?;
+
+        let differences = diff(
+            (&old_tokens, &old_macros),
+            (&new_tokens, &new_macros),
+            &config,
+        );
+        let found = |expected: fn(&Difference) -> bool| differences.iter().any(expected);
+        assert!(
+            found(
+                |difference| matches!(difference, Difference::MacroChanged { symbol: 'w', new_body, .. } if new_body == "(++)")
+            ),
+            "{differences:?} should contain the changed body of 'w'."
+        );
+        assert!(
+            found(|difference| matches!(
+                difference,
+                Difference::MultiplierChanged { from: 3, to: 4, .. }
+            )),
+            "{differences:?} should contain the changed multiplier."
+        );
+        assert!(
+            found(
+                |difference| matches!(difference, Difference::Added { source, .. } if source == ",")
+            ),
+            "{differences:?} should contain the operator added to the group."
+        );
+        assert!(
+            found(|difference| matches!(difference, Difference::MacroRemoved { symbol: 'y', .. }))
+                && found(|difference| matches!(
+                    difference,
+                    Difference::MacroAdded { symbol: 'z', .. }
+                )),
+            "{differences:?} should contain the removed and added macros."
+        );
+        assert!(
+            differences.len() == 5,
+            "{differences:?} should ignore the formatting and the macro replaced with its body."
+        );
+
+        Ok(())
+    }
+}
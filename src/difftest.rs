@@ -0,0 +1,203 @@
+use std::fs;
+use std::io::{Read, Write};
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+
+use crate::cancel::CancellationToken;
+use crate::run::Program;
+
+/// Placeholder replaced with the program's path in the interpreter command.
+pub const PROGRAM_PATH_PLACEHOLDER: &str = "{}";
+/// Time the external interpreter can run on an input by default.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+/// Interval of checking whether the external interpreter exited.
+const WAIT_INTERVAL: Duration = Duration::from_millis(10);
+
+/// An input for which the outputs of the interpreters differ.
+pub struct Divergence {
+    pub input_name: String,
+    pub builtin: Vec<u8>,
+    pub external: Vec<u8>,
+}
+
+impl Divergence {
+    /// Get the index of the first differing byte.
+    pub fn first_difference(&self) -> usize {
+        self.builtin
+            .iter()
+            .zip(&self.external)
+            .position(|(builtin, external)| builtin != external)
+            .unwrap_or(self.builtin.len().min(self.external.len()))
+    }
+}
+
+/// Run `program` with every input on both the built-in interpreter
+/// and the external `interpreter` command, returning every [`Divergence`].
+///
+/// `interpreter` is split on whitespace, [`PROGRAM_PATH_PLACEHOLDER`] is replaced
+/// with `program_path`, which is appended at the end if there is no placeholder.
+/// The input is passed to both interpreters through stdin, the external one
+/// is killed if it runs longer than `timeout` on an input.
+pub fn difftest(
+    program: &Program,
+    program_path: &Path,
+    interpreter: &str,
+    inputs: &[(String, Vec<u8>)],
+    timeout: Duration,
+    cancellation: &CancellationToken,
+) -> Result<Vec<Divergence>> {
+    let mut divergences: Vec<Divergence> = Vec::new();
+
+    for (input_name, input) in inputs {
+        let mut builtin: Vec<u8> = Vec::new();
+        program
+            .run(&mut input.as_slice(), &mut builtin, cancellation)
+            .with_context(|| format!("built-in interpreter failed on '{input_name}'"))?;
+
+        let external = run_external(program_path, interpreter, input, timeout, cancellation)
+            .with_context(|| format!("'{interpreter}' failed on '{input_name}'"))?;
+
+        if builtin != external {
+            divergences.push(Divergence {
+                input_name: input_name.clone(),
+                builtin,
+                external,
+            });
+        }
+    }
+
+    Ok(divergences)
+}
+
+/// Run the external interpreter on `program_path`, returning its stdout.
+///
+/// The input is written from another thread while the output is read,
+/// so an interpreter writing before it reads everything doesn't block.
+fn run_external(
+    program_path: &Path,
+    interpreter: &str,
+    input: &[u8],
+    timeout: Duration,
+    cancellation: &CancellationToken,
+) -> Result<Vec<u8>> {
+    let mut args: Vec<String> = interpreter.split_whitespace().map(String::from).collect();
+    if args.is_empty() {
+        bail!("interpreter command is empty");
+    }
+
+    let program_path = program_path.display().to_string();
+    if args
+        .iter()
+        .any(|arg| arg.contains(PROGRAM_PATH_PLACEHOLDER))
+    {
+        for arg in &mut args {
+            *arg = arg.replace(PROGRAM_PATH_PLACEHOLDER, &program_path);
+        }
+    } else {
+        args.push(program_path);
+    }
+
+    let mut child = Command::new(&args[0])
+        .args(&args[1..])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to start '{}'", args[0]))?;
+
+    let mut stdin = child.stdin.take().expect("stdin should be piped.");
+    let mut stdout = child.stdout.take().expect("stdout should be piped.");
+    thread::scope(|scope| {
+        // the interpreter may exit without reading everything,
+        // stdin is closed once written
+        scope.spawn(move || {
+            let _ = stdin.write_all(input);
+        });
+        let reader = scope.spawn(move || {
+            let mut output = Vec::new();
+            stdout.read_to_end(&mut output).map(|_| output)
+        });
+
+        let started = Instant::now();
+        while child.try_wait()?.is_none() {
+            if cancellation.is_cancelled() || started.elapsed() > timeout {
+                // the pipes are closed with the interpreter, ending the threads
+                let _ = child.kill();
+                let _ = child.wait();
+                cancellation.check()?;
+                bail!("timed out after {}s", timeout.as_secs_f64());
+            }
+            thread::sleep(WAIT_INTERVAL);
+        }
+
+        Ok(reader.join().expect("The output reader shouldn't panic.")?)
+    })
+}
+
+/// Read the corpus of inputs from `paths`, named after the paths.
+pub fn read_inputs<P: AsRef<Path>>(paths: &[P]) -> Result<Vec<(String, Vec<u8>)>> {
+    paths
+        .iter()
+        .map(|path| {
+            let path = path.as_ref();
+            let input =
+                fs::read(path).with_context(|| format!("failed to open '{}'", path.display()))?;
+
+            Ok((path.display().to_string(), input))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(unix)]
+    fn difftest_external() -> Result<()> {
+        // more than a pipe buffer, echoed before the rest is read
+        let input = vec![b'+'; 1 << 20];
+        let output = run_external(
+            Path::new("program.b"),
+            "sh -c cat",
+            &input,
+            DEFAULT_TIMEOUT,
+            &CancellationToken::new(),
+        )?;
+        assert!(
+            output == input,
+            "The large input should be echoed by the interpreter."
+        );
+
+        let result = run_external(
+            Path::new("10"),
+            "sleep",
+            &[],
+            Duration::from_millis(50),
+            &CancellationToken::new(),
+        );
+        assert!(
+            result.is_err_and(|error| error.to_string().contains("timed out")),
+            "The interpreter should be killed once it times out."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn divergence_first_difference() {
+        let divergence = Divergence {
+            input_name: String::from("input"),
+            builtin: b"Hello".to_vec(),
+            external: b"Help".to_vec(),
+        };
+
+        assert!(
+            divergence.first_difference() == 3,
+            "outputs should differ at 3."
+        );
+    }
+}
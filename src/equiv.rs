@@ -0,0 +1,204 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::Hasher;
+use std::io::{self, Write};
+
+use anyhow::Result;
+
+/// Number of output bytes hashed together, the divergences are searched
+/// for in the first chunk whose hashes differ.
+pub const CHUNK_SIZE: usize = 4096;
+
+/// One of the two compared sources.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Side {
+    Old,
+    New,
+}
+
+/// Result of comparing the outputs of two sources.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Equivalence {
+    /// Both outputs are the same `len` bytes.
+    Identical { len: usize },
+    /// The outputs first differ at the byte at `position`.
+    Divergent {
+        position: usize,
+        old_len: usize,
+        new_len: usize,
+        /// Bytes of the old output starting at `position`, at most [`CHUNK_SIZE`].
+        old: Vec<u8>,
+        /// Bytes of the new output starting at `position`, at most [`CHUNK_SIZE`].
+        new: Vec<u8>,
+    },
+}
+
+/// Compare the outputs `emit` writes for both [`Side`]s without keeping them.
+///
+/// The outputs are hashed in chunks of [`CHUNK_SIZE`] bytes, so the
+/// memory used doesn't depend on their length. If the hashes differ,
+/// both outputs are emitted again, keeping only the first differing chunk.
+pub fn equivalence<F>(mut emit: F) -> Result<Equivalence>
+where
+    F: FnMut(Side, &mut dyn Write) -> Result<()>,
+{
+    let mut old = ChunkHasher::default();
+    emit(Side::Old, &mut old)?;
+    let mut new = ChunkHasher::default();
+    emit(Side::New, &mut new)?;
+    let (old_len, old_hashes) = old.finish();
+    let (new_len, new_hashes) = new.finish();
+
+    let Some(chunk) = old_hashes
+        .iter()
+        .zip(&new_hashes)
+        .position(|(old, new)| old != new)
+        .or((old_len != new_len).then(|| old_hashes.len().min(new_hashes.len())))
+    else {
+        return Ok(Equivalence::Identical { len: old_len });
+    };
+
+    let start = chunk * CHUNK_SIZE;
+    let mut old = Window::new(start);
+    emit(Side::Old, &mut old)?;
+    let mut new = Window::new(start);
+    emit(Side::New, &mut new)?;
+    let offset = old
+        .bytes
+        .iter()
+        .zip(&new.bytes)
+        .position(|(old, new)| old != new)
+        .unwrap_or(old.bytes.len().min(new.bytes.len()));
+
+    Ok(Equivalence::Divergent {
+        position: start + offset,
+        old_len,
+        new_len,
+        old: old.bytes.split_off(offset),
+        new: new.bytes.split_off(offset),
+    })
+}
+
+/// Writer hashing the written bytes in chunks of [`CHUNK_SIZE`].
+#[derive(Default)]
+struct ChunkHasher {
+    len: usize,
+    hasher: DefaultHasher,
+    hashes: Vec<u64>,
+}
+
+impl ChunkHasher {
+    /// Get the number of written bytes and the hashes of their chunks.
+    fn finish(mut self) -> (usize, Vec<u64>) {
+        if !self.len.is_multiple_of(CHUNK_SIZE) {
+            self.hashes.push(self.hasher.finish());
+        }
+        (self.len, self.hashes)
+    }
+}
+
+impl Write for ChunkHasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let taken = rest.len().min(CHUNK_SIZE - self.len % CHUNK_SIZE);
+            self.hasher.write(&rest[..taken]);
+            self.len += taken;
+            rest = &rest[taken..];
+            if self.len.is_multiple_of(CHUNK_SIZE) {
+                self.hashes.push(self.hasher.finish());
+                self.hasher = DefaultHasher::new();
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Writer keeping only the [`CHUNK_SIZE`] bytes written from `start`.
+struct Window {
+    start: usize,
+    written: usize,
+    bytes: Vec<u8>,
+}
+
+impl Window {
+    fn new(start: usize) -> Window {
+        Window {
+            start,
+            written: 0,
+            bytes: Vec::new(),
+        }
+    }
+}
+
+impl Write for Window {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let end = self.start + CHUNK_SIZE;
+        let from = self.start.clamp(self.written, self.written + buf.len());
+        let to = end.clamp(self.written, self.written + buf.len());
+        self.bytes
+            .extend_from_slice(&buf[from - self.written..to - self.written]);
+        self.written += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn compare(old: &[u8], new: &[u8]) -> Equivalence {
+        equivalence(|side, output| {
+            // written in small pieces to cross the chunk boundaries
+            for piece in match side {
+                Side::Old => old,
+                Side::New => new,
+            }
+            .chunks(1000)
+            {
+                output.write_all(piece)?;
+            }
+            Ok(())
+        })
+        .expect("Writing to the comparing writers should succeed.")
+    }
+
+    #[test]
+    fn equiv_outputs() {
+        let old = "+>".repeat(CHUNK_SIZE * 2).into_bytes();
+        let result = compare(&old, &old);
+        assert!(
+            result == Equivalence::Identical { len: old.len() },
+            "{result:?} should report the same outputs as identical."
+        );
+
+        let mut new = old.clone();
+        new[CHUNK_SIZE + 10] = b'-';
+        let result = compare(&old, &new);
+        assert!(
+            matches!(
+                &result,
+                Equivalence::Divergent { position, old, new, .. }
+                    if *position == CHUNK_SIZE + 10 && old[0] == b'+' && new[0] == b'-'
+            ),
+            "{result:?} should find the changed operator."
+        );
+
+        let result = compare(&old, &old[..old.len() - 1]);
+        assert!(
+            matches!(
+                &result,
+                Equivalence::Divergent { position, new, .. }
+                    if *position == old.len() - 1 && new.is_empty()
+            ),
+            "{result:?} should find where the shorter output ends."
+        );
+    }
+}
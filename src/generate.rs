@@ -0,0 +1,261 @@
+use std::error::Error as ErrorTrait;
+use std::fmt;
+use std::io::{self, Write};
+use std::iter::Peekable;
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
+
+use crate::config::{Config, ConfigField};
+
+/// Error type for reading the input with the generators run,
+/// the generator errors contain the line of the generator's symbol.
+#[derive(thiserror::Error, fmt::Debug)]
+pub enum Error<E: ErrorTrait + 'static> {
+    #[error("{0}")]
+    Input(#[source] E),
+    #[error("line {lineno}: generator '{symbol}' is not followed by a closed group")]
+    Unclosed { symbol: char, lineno: usize },
+    #[error("line {lineno}: generator '{symbol}' could not be run: {error}")]
+    Spawn {
+        symbol: char,
+        lineno: usize,
+        #[source]
+        error: io::Error,
+    },
+    #[error("line {lineno}: generator '{symbol}' failed ({status}): {stderr}")]
+    Failed {
+        symbol: char,
+        lineno: usize,
+        status: ExitStatus,
+        stderr: String,
+    },
+}
+
+/// Run the shell `command` with `text` as its input, returning its output.
+fn run_generator(command: &str, text: &str) -> io::Result<std::process::Output> {
+    let mut shell = if cfg!(target_os = "windows") {
+        let mut shell = Command::new("cmd");
+        shell.arg("/C");
+        shell
+    } else {
+        let mut shell = Command::new("sh");
+        shell.arg("-c");
+        shell
+    };
+    let mut child = shell
+        .arg(command)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()?;
+
+    let mut stdin = child.stdin.take().expect("The stdin should be piped.");
+    // written from another thread, so that a generator writing before
+    // it has read the whole input can't block
+    thread::scope(|scope| {
+        let writer = scope.spawn(move || stdin.write_all(text.as_bytes()));
+        let output = child.wait_with_output()?;
+        match writer.join().expect("Writing the input should not panic.") {
+            // the generator doesn't have to read its input
+            Err(error) if error.kind() != io::ErrorKind::BrokenPipe => Err(error),
+            _ => Ok(output),
+        }
+    })
+}
+
+/// Iterator over the chars of an input with every generator group
+/// *(a generator's symbol followed by a group, e.g. `g(text)`)* replaced
+/// with the operators written by the generator's command *(see [`Config::set_generators`])*.
+///
+/// The text of the group is passed to the command as its input, with
+/// nested groups kept as they are. Only the operators of the command's
+/// output are kept *(with the [graphemes][crate::grapheme::Graphemes] encoded)*,
+/// everything else it writes is dropped.
+///
+/// A symbol directly after the macro or escape prefix is left as it is,
+/// so that macros can be defined as a generator's symbol.
+pub struct Generating<'a, I: Iterator> {
+    chars: Peekable<I>,
+    config: &'a Config,
+    /// Operators written by the generator being spliced, reversed.
+    generated: Vec<char>,
+    /// Whether the last char was the macro or escape prefix.
+    after_prefix: bool,
+    lineno: usize,
+}
+
+impl<'a, I, E> Generating<'a, I>
+where
+    E: ErrorTrait + 'static,
+    I: Iterator<Item = Result<char, E>>,
+{
+    pub fn new(chars: I, config: &'a Config) -> Self {
+        Generating {
+            chars: chars.peekable(),
+            config,
+            generated: Vec::new(),
+            after_prefix: false,
+            lineno: 1,
+        }
+    }
+
+    /// Read the text of a generator group up to the matching end delimiter,
+    /// with the start delimiter already consumed.
+    fn read_group(&mut self, symbol: char) -> Result<String, Error<E>> {
+        let lineno = self.lineno;
+        let mut text = String::new();
+        let mut depth: usize = 0;
+        loop {
+            let ch = match self.chars.next() {
+                Some(Ok(ch)) => ch,
+                Some(Err(error)) => return Err(Error::Input(error)),
+                None => return Err(Error::Unclosed { symbol, lineno }),
+            };
+            match self.config.get_field(&ch) {
+                Some(ConfigField::GroupStartDelimiter) => depth += 1,
+                Some(ConfigField::GroupEndDelimiter) if depth == 0 => return Ok(text),
+                Some(ConfigField::GroupEndDelimiter) => depth -= 1,
+                _ => (),
+            }
+            if ch == '\n' {
+                self.lineno += 1;
+            }
+            text.push(ch);
+        }
+    }
+
+    /// Run the generator `symbol` from line `lineno` with `text`,
+    /// returning the operators it wrote.
+    fn generate(
+        &self,
+        symbol: char,
+        lineno: usize,
+        command: &str,
+        text: &str,
+    ) -> Result<Vec<char>, Error<E>> {
+        let output = run_generator(command, text).map_err(|error| Error::Spawn {
+            symbol,
+            lineno,
+            error,
+        })?;
+        if !output.status.success() {
+            return Err(Error::Failed {
+                symbol,
+                lineno,
+                status: output.status,
+                stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            });
+        }
+
+        let output = String::from_utf8_lossy(&output.stdout);
+        Ok(self
+            .config
+            .get_graphemes()
+            .encode_str(&output)
+            .chars()
+            .filter(|ch| self.config.is_operator(*ch))
+            .collect())
+    }
+}
+
+impl<I, E> Iterator for Generating<'_, I>
+where
+    E: ErrorTrait + 'static,
+    I: Iterator<Item = Result<char, E>>,
+{
+    type Item = Result<char, Error<E>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(ch) = self.generated.pop() {
+            return Some(Ok(ch));
+        }
+
+        let ch = match self.chars.next()? {
+            Ok(ch) => ch,
+            Err(error) => return Some(Err(Error::Input(error))),
+        };
+        let after_prefix = std::mem::replace(
+            &mut self.after_prefix,
+            matches!(
+                self.config.get_field(&ch),
+                Some(ConfigField::MacroPrefix | ConfigField::EscapePrefix)
+            ),
+        );
+
+        match self.config.get_generator(&ch) {
+            Some(command)
+                if !after_prefix
+                    && matches!(
+                        self.chars.peek(),
+                        Some(Ok(next))
+                            if self.config.get_field(next) == Some(&ConfigField::GroupStartDelimiter)
+                    ) =>
+            {
+                self.chars.next();
+                let lineno = self.lineno;
+                let generated = self
+                    .read_group(ch)
+                    .and_then(|text| self.generate(ch, lineno, command, &text));
+                match generated {
+                    Ok(generated) => self.generated = generated.into_iter().rev().collect(),
+                    Err(error) => return Some(Err(error)),
+                }
+                self.next()
+            }
+            _ => {
+                if ch == '\n' {
+                    self.lineno += 1;
+                }
+                Some(Ok(ch))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use bfup_derive::as_char_results;
+
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn generate_generators() -> Result<()> {
+        let mut config = Config::default();
+        config.set_generators([
+            ('g', String::from("tr a-z +")),
+            ('f', String::from("echo oops >&2; exit 3")),
+        ])?;
+
+        let input = as_char_results!("g(ab(c)) $g(.) #2g\ng(abc");
+        let generated = Generating::new(input.into_iter(), &config)
+            .take_while(Result::is_ok)
+            .collect::<Result<String, _>>()?;
+        assert!(
+            generated == "+++ $g(.) #2g\n",
+            "\"{generated}\" should have the generator group replaced."
+        );
+
+        let result = Generating::new(input.into_iter(), &config).collect::<Result<String, _>>();
+        assert!(
+            matches!(
+                result,
+                Err(Error::Unclosed {
+                    symbol: 'g',
+                    lineno: 2
+                })
+            ),
+            "Unclosed generator groups should be errors, got {result:?}."
+        );
+
+        let input = as_char_results!("+\nf()");
+        let result = Generating::new(input.into_iter(), &config).collect::<Result<String, _>>();
+        assert!(
+            matches!(&result, Err(Error::Failed { symbol: 'f', lineno: 2, stderr, .. }) if stderr == "oops"),
+            "Failed generators should be errors, got {result:?}."
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,267 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::iter::Peekable;
+
+use unicode_segmentation::UnicodeSegmentation;
+
+/// `char` the first multi-codepoint grapheme is encoded as,
+/// the start of the Supplementary Private Use Area-A.
+pub const FIRST_ENCODED: char = '\u{F0000}';
+
+/// Multi-codepoint grapheme clusters *(e.g. emoji with modifiers)* used as operators,
+/// each encoded as a single `char` from the private use area, so that the
+/// [`Lexer`][crate::lex::Lexer] can match them like any other operator.
+///
+/// Operators made of multiple chars *(words, e.g. `Ook.` of Ook!)* are
+/// encoded the same way, see [`Graphemes::with_words`].
+///
+/// The graphemes are encoded in sorted order, followed by the words,
+/// so the same graphemes and words are always encoded as the same chars.
+#[derive(Clone, Default, PartialEq, Eq, fmt::Debug)]
+pub struct Graphemes {
+    encoded: HashMap<String, char>,
+    decoded: HashMap<char, String>,
+    /// Words, the longest first.
+    words: Vec<String>,
+}
+
+impl Graphemes {
+    /// Encode every one of the multi-codepoint `graphemes`.
+    pub fn new<'a, G: IntoIterator<Item = &'a str>>(graphemes: G) -> Self {
+        let graphemes: BTreeSet<&str> = graphemes.into_iter().collect();
+        let (encoded, decoded) = graphemes
+            .into_iter()
+            .zip((FIRST_ENCODED as u32..).filter_map(char::from_u32))
+            .map(|(grapheme, ch)| ((grapheme.to_string(), ch), (ch, grapheme.to_string())))
+            .unzip();
+
+        Graphemes {
+            encoded,
+            decoded,
+            words: Vec::new(),
+        }
+    }
+
+    /// Also encode every one of the `words`, matched in the text before the graphemes,
+    /// the longest first, so they can contain graphemes and other operators.
+    ///
+    /// The words aren't expected to contain newlines, as the input
+    /// is [segmented][Segmenting] one line at a time.
+    pub fn with_words<'a, W: IntoIterator<Item = &'a str>>(mut self, words: W) -> Self {
+        let words: BTreeSet<&str> = words
+            .into_iter()
+            .filter(|word| !self.encoded.contains_key(*word))
+            .collect();
+        let first = FIRST_ENCODED as u32 + self.encoded.len() as u32;
+        for (word, ch) in words.into_iter().zip((first..).filter_map(char::from_u32)) {
+            self.encoded.insert(word.to_string(), ch);
+            self.decoded.insert(ch, word.to_string());
+            self.words.push(word.to_string());
+        }
+        self.words.sort_by_key(|word| Reverse(word.len()));
+
+        self
+    }
+
+    /// Get the words, the longest first.
+    pub fn words(&self) -> &[String] {
+        &self.words
+    }
+
+    /// Check whether there are any multi-codepoint graphemes besides the words.
+    pub fn has_graphemes(&self) -> bool {
+        self.encoded.len() > self.words.len()
+    }
+
+    /// Get the `char` `grapheme` is encoded as, if it's one of the graphemes.
+    pub fn encode(&self, grapheme: &str) -> Option<char> {
+        self.encoded.get(grapheme).copied()
+    }
+
+    /// Get the grapheme encoded as `ch`, if it's one of the graphemes.
+    pub fn decode(&self, ch: char) -> Option<&str> {
+        self.decoded.get(&ch).map(String::as_str)
+    }
+
+    /// Get `text` with every one of the graphemes encoded.
+    pub fn encode_str(&self, text: &str) -> String {
+        if self.is_empty() {
+            return text.to_string();
+        }
+
+        let mut encoded = String::with_capacity(text.len());
+        // end of the last matched word
+        let mut matched = 0;
+        for (offset, grapheme) in text.grapheme_indices(true) {
+            if offset < matched {
+                // the rest of a grapheme cut by the end of a word
+                encoded.push_str(
+                    text.get(matched..offset + grapheme.len())
+                        .unwrap_or_default(),
+                );
+                continue;
+            }
+
+            let rest = &text[offset..];
+            if let Some(word) = self
+                .words
+                .iter()
+                .find(|word| rest.starts_with(word.as_str()))
+            {
+                encoded.push(self.encoded[word]);
+                matched = offset + word.len();
+                continue;
+            }
+            match self.encode(grapheme) {
+                Some(ch) => encoded.push(ch),
+                None => encoded.push_str(grapheme),
+            }
+        }
+
+        encoded
+    }
+
+    /// Get `text` with every encoded grapheme decoded.
+    pub fn decode_str(&self, text: &str) -> String {
+        text.chars()
+            .fold(String::with_capacity(text.len()), |mut decoded, ch| {
+                match self.decode(ch) {
+                    Some(grapheme) => decoded.push_str(grapheme),
+                    None => decoded.push(ch),
+                }
+                decoded
+            })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.encoded.is_empty()
+    }
+}
+
+/// Split `operators` into grapheme clusters, returning the multi-codepoint ones.
+pub fn multi_codepoint(operators: &str) -> Vec<&str> {
+    operators
+        .graphemes(true)
+        .filter(|grapheme| grapheme.chars().nth(1).is_some())
+        .collect()
+}
+
+/// Iterator over the chars of an input with the [`Graphemes`] encoded.
+///
+/// The input is segmented one line at a time, as no grapheme spans lines.
+/// The chars are passed through as they are when there are no graphemes.
+pub struct Segmenting<'a, I: Iterator> {
+    chars: Peekable<I>,
+    graphemes: &'a Graphemes,
+    /// Chars of the segmented line, reversed.
+    line: Vec<char>,
+}
+
+impl<'a, I, E> Segmenting<'a, I>
+where
+    I: Iterator<Item = Result<char, E>>,
+{
+    pub fn new(chars: I, graphemes: &'a Graphemes) -> Self {
+        Segmenting {
+            chars: chars.peekable(),
+            graphemes,
+            line: Vec::new(),
+        }
+    }
+
+    /// Read the rest of the line, up to and including the newline.
+    fn read_line(&mut self) -> Result<String, E> {
+        let mut line = String::new();
+        while let Some(ch) = self.chars.next_if(|ch| !matches!(ch, Ok('\n'))) {
+            line.push(ch?);
+        }
+        if let Some(newline) = self.chars.next() {
+            line.push(newline?);
+        }
+
+        Ok(line)
+    }
+}
+
+impl<I, E> Iterator for Segmenting<'_, I>
+where
+    I: Iterator<Item = Result<char, E>>,
+{
+    type Item = Result<char, E>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.graphemes.is_empty() {
+            return self.chars.next();
+        }
+        if let Some(ch) = self.line.pop() {
+            return Some(Ok(ch));
+        }
+
+        self.chars.peek()?;
+        let line = match self.read_line() {
+            Ok(line) => line,
+            Err(error) => return Some(Err(error)),
+        };
+        self.line = self.graphemes.encode_str(&line).chars().rev().collect();
+
+        self.next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use bfup_derive::as_char_results;
+
+    use super::*;
+
+    #[test]
+    fn grapheme_segmenting() -> Result<()> {
+        let graphemes = Graphemes::new(multi_codepoint("👍🏽+👎🏿"));
+        let thumbs_up = graphemes.encode("👍🏽").expect("👍🏽 should be encoded.");
+        let thumbs_down = graphemes.encode("👎🏿").expect("👎🏿 should be encoded.");
+        assert!(
+            thumbs_up == FIRST_ENCODED,
+            "The graphemes should be encoded in sorted order."
+        );
+
+        let input = as_char_results!("#2(👍🏽👍)\n👎🏿");
+        let segmented =
+            Segmenting::new(input.into_iter(), &graphemes).collect::<Result<String, _>>()?;
+        assert!(
+            segmented == format!("#2({thumbs_up}👍)\n{thumbs_down}"),
+            "\"{segmented}\" should have the graphemes encoded."
+        );
+        assert!(
+            graphemes.decode_str(&segmented) == "#2(👍🏽👍)\n👎🏿",
+            "The graphemes should be decoded."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn grapheme_words() -> Result<()> {
+        let graphemes =
+            Graphemes::new(multi_codepoint("👍🏽")).with_words(["Ook.", "Ook. Ook?", "👍🏽!"]);
+        let encoded = graphemes.encode_str("#2(Ook. Ook?)Ook. Ook.\n👍🏽!👍🏽");
+        let [long, short, thumbs_up_bang] = ["Ook. Ook?", "Ook.", "👍🏽!"].map(|word| {
+            graphemes
+                .encode(word)
+                .expect("Every word should be encoded.")
+        });
+        let thumbs_up = graphemes.encode("👍🏽").expect("👍🏽 should be encoded.");
+        assert!(
+            encoded == format!("#2({long}){short} {short}\n{thumbs_up_bang}{thumbs_up}"),
+            "\"{encoded}\" should have the longest words encoded."
+        );
+        assert!(
+            graphemes.has_graphemes()
+                && graphemes.words().first().map(String::as_str) == Some("Ook. Ook?"),
+            "The words should be sorted longest first."
+        );
+
+        Ok(())
+    }
+}
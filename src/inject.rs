@@ -0,0 +1,138 @@
+use std::error::Error as ErrorTrait;
+use std::fmt;
+use std::io::{self, Write};
+
+/// Error type of the input chars with a failure injected.
+#[derive(thiserror::Error, fmt::Debug)]
+pub enum Error<E: ErrorTrait + 'static> {
+    #[error("{0}")]
+    Input(#[source] E),
+    #[error("injected input failure after {0} chars")]
+    Injected(usize),
+}
+
+/// Iterator over the input chars failing with [`Error::Injected`]
+/// after `fail_after` chars *(never if `None`)*.
+pub struct FailingChars<I> {
+    chars: I,
+    fail_after: Option<usize>,
+    read: usize,
+}
+
+impl<I> FailingChars<I> {
+    pub fn new(chars: I, fail_after: Option<usize>) -> Self {
+        FailingChars {
+            chars,
+            fail_after,
+            read: 0,
+        }
+    }
+}
+
+impl<I, E> Iterator for FailingChars<I>
+where
+    E: ErrorTrait + 'static,
+    I: Iterator<Item = Result<char, E>>,
+{
+    type Item = Result<char, Error<E>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.fail_after == Some(self.read) {
+            return Some(Err(Error::Injected(self.read)));
+        }
+        self.read += 1;
+
+        self.chars.next().map(|ch| ch.map_err(Error::Input))
+    }
+}
+
+/// Writer failing once more than `fail_after` bytes
+/// would be written into `writer` *(never if `None`)*.
+pub struct FailingWriter<W: Write> {
+    writer: W,
+    fail_after: Option<usize>,
+    written: usize,
+}
+
+impl<W: Write> FailingWriter<W> {
+    pub fn new(writer: W, fail_after: Option<usize>) -> Self {
+        FailingWriter {
+            writer,
+            fail_after,
+            written: 0,
+        }
+    }
+}
+
+impl<W: Write> Write for FailingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = match self.fail_after {
+            Some(fail_after) if self.written == fail_after && !buf.is_empty() => {
+                return Err(io::Error::other(format!(
+                    "injected write failure after {fail_after} bytes"
+                )))
+            }
+            Some(fail_after) => buf.len().min(fail_after - self.written),
+            None => buf.len(),
+        };
+
+        let written = self.writer.write(&buf[..len])?;
+        self.written += written;
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use bfup_derive::as_char_results;
+
+    use super::*;
+    use crate::cancel::CancellationToken;
+    use crate::config::Config;
+    use crate::pre::{preprocess_and_align, AlignBy};
+
+    #[test]
+    fn inject_failures() -> Result<()> {
+        let input = as_char_results!("#4(+>)");
+
+        let mut output: Vec<u8> = Vec::new();
+        let result = preprocess_and_align(
+            FailingChars::new(input.into_iter(), Some(3)),
+            &mut output,
+            &Config::default(),
+            4,
+            AlignBy::Chars,
+            &CancellationToken::new(),
+        );
+        assert!(
+            result.is_err_and(|error| error.to_string().contains("after 3 chars")),
+            "The injected input failure should be reported."
+        );
+
+        let mut output = FailingWriter::new(Vec::new(), Some(6));
+        let result = preprocess_and_align(
+            FailingChars::new(input.into_iter(), None),
+            &mut output,
+            &Config::default(),
+            4,
+            AlignBy::Chars,
+            &CancellationToken::new(),
+        );
+        assert!(
+            result.is_err(),
+            "The injected write failure mid-alignment should be reported."
+        );
+        assert!(
+            output.writer == b"+>+>\n+",
+            "Only the bytes before the failure should be written."
+        );
+
+        Ok(())
+    }
+}
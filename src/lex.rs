@@ -1,25 +1,86 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::convert::Infallible;
 use std::error::Error as ErrorTrait;
 use std::fmt;
+use std::fs;
 use std::iter::Peekable;
+use std::path::{Path, PathBuf};
 use std::result::Result as StdResult;
+use std::sync::Arc;
 
-use crate::config::{Config, ConfigField::*};
+use serde::{Deserialize, Serialize};
+
+use crate::cancel::{CancellationToken, Cancelled};
+use crate::config::{
+    BudgetPolicy, Config, ConfigField::*, EofPolicy, EscapeInGroups, MultiplierPolicy,
+    UnrecognizedPolicy, CELL_SYMBOL, CONDITIONAL_SYMBOL, CONSTANT_SYMBOL, COUNTER_SYMBOL,
+    INCLUDE_SYMBOL, STRING_DELIMITER,
+};
+use crate::pre::printing_code;
+use crate::span::{Position, SourceId, Span};
 use bfup_derive::enum_fields;
 
 /// Result type used within the [`Lexer`].
-pub type Result<T, E> = std::result::Result<T, Error<E>>;
+pub type Result<T> = std::result::Result<T, Error>;
 
 /// Struct representing a group of [`Errors`][Error].
 /// When displayed, every error is printed sequentially, followed by a newline.
+///
+/// Nested groups are flattened, the errors are sorted by their
+/// position in the input and identical errors are only kept once.
 #[derive(fmt::Debug)]
-pub struct ErrorGroup<E: ErrorTrait>(Vec<Error<E>>);
+pub struct ErrorGroup {
+    errors: Vec<Error>,
+    omitted: usize,
+}
+
+impl ErrorGroup {
+    /// Create a new `ErrorGroup`, flattening, sorting and de-duplicating `errors`.
+    pub fn new(errors: Vec<Error>) -> Self {
+        let mut errors: Vec<(String, Error)> = errors
+            .into_iter()
+            .flat_map(|error| match error {
+                // nested groups are already flat
+                Error::Group(group) => group.errors,
+                error => vec![error],
+            })
+            .map(|error| (error.to_string(), error))
+            .collect();
+        errors.sort_by(|(left_message, left), (right_message, right)| {
+            let left_start = left.span().map(|span| span.start);
+            let right_start = right.span().map(|span| span.start);
+
+            left_start
+                .cmp(&right_start)
+                .then_with(|| left_message.cmp(right_message))
+        });
+        errors.dedup_by(|(left_message, _), (right_message, _)| left_message == right_message);
+
+        ErrorGroup {
+            errors: errors.into_iter().map(|(_, error)| error).collect(),
+            omitted: 0,
+        }
+    }
+
+    /// Get the errors in the group, ordered by their position.
+    pub fn errors(&self) -> &[Error] {
+        &self.errors
+    }
+
+    /// Keep at most `max` errors, the rest are only counted when displayed.
+    pub fn truncate(&mut self, max: usize) {
+        if self.errors.len() > max {
+            self.omitted += self.errors.len() - max;
+            self.errors.truncate(max);
+        }
+    }
+}
 
-impl<E: ErrorTrait> fmt::Display for ErrorGroup<E> {
+impl fmt::Display for ErrorGroup {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        assert!(!self.0.is_empty(), "ErrorGroup shouldn't be empty.");
+        assert!(!self.errors.is_empty(), "ErrorGroup shouldn't be empty.");
 
-        let mut error_iter = self.0.iter().peekable();
+        let mut error_iter = self.errors.iter().peekable();
 
         while let Some(error) = error_iter.next() {
             write!(f, "{error}")?;
@@ -31,54 +92,842 @@ impl<E: ErrorTrait> fmt::Display for ErrorGroup<E> {
             }
         }
 
+        if self.omitted > 0 {
+            write!(f, "\n... and {} more error(s).", self.omitted)?;
+        }
+
         Ok(())
     }
 }
 
 /// Error type returned by the [`Lexer`].
-/// Every error variant (except `Input`) contains the line and column
-/// numbers specifying where in the input it occured.
-#[enum_fields(![Input, Group]
-    lineno: usize,
-    colno: usize
+/// Errors of the input are boxed in the `Input` variant, so the type
+/// doesn't depend on the input iterator.
+/// Every error variant (except `Input`, `Cancelled` and `Group`) contains the [`Span`]
+/// specifying where in the input it occured.
+#[enum_fields(![Input, Cancelled, Group]
+    span: Span
 )]
-#[enum_fields(![Input, NumberMissing, MacroMissing, Group]
+#[enum_fields(![Input, Cancelled, NumberMissing, CharLiteralUnclosed, MacroMissing, MacroOverBudget, MacroTooDeep, MacroTableOverflow, IncludeMissing, IncludeFailed, ConditionalMissing, CounterMissing, ConstantMissing, ConstantUndefined, CellMissing, CellUndefined, CommentUnclosed, RawUnclosed, StringUnclosed, CharUnrecognized, Group]
     group_start_delimiter: char,
     group_end_delimiter: char
 )]
 #[derive(thiserror::Error, fmt::Debug)]
-pub enum Error<E: ErrorTrait> {
+pub enum Error {
     #[error("{0}.")]
-    Input(#[from] E),
-    #[error("[{lineno}:{colno}]: '{group_end_delimiter}' must have a preceding '{group_start_delimiter}'.")]
+    Input(#[source] Box<dyn ErrorTrait + Send + Sync>),
+    #[error("{0}")]
+    Cancelled(#[source] Cancelled),
+    #[error("[{span}]: '{group_end_delimiter}' must have a preceding '{group_start_delimiter}'.")]
     DelimiterUnopened,
-    #[error("[{lineno}:{colno}]: expected '{group_end_delimiter}'.")]
+    #[error("[{span}]: expected '{group_end_delimiter}'.")]
     DelimiterUnclosed,
-    #[error("[{lineno}:{colno}]: number prefix '{number_prefix}' must be followed by number.")]
+    #[error("[{span}]: number prefix '{number_prefix}' must be followed by number.")]
     NumberMissing { number_prefix: char },
-    #[error("[{lineno}:{colno}]: macro_prefix '{macro_prefix}' must be followed by a character and a token.")]
+    #[error(
+        "[{span}]: char literal after '{number_prefix}' must be a single char closed with '''."
+    )]
+    CharLiteralUnclosed { number_prefix: char },
+    #[error(
+        "[{span}]: macro_prefix '{macro_prefix}' must be followed by a character and a token."
+    )]
     MacroMissing { macro_prefix: char },
+    #[error("[{span}]: macro '{macro_symbol}' expands to {size} operators, over its budget of {budget}.")]
+    MacroOverBudget {
+        macro_symbol: char,
+        size: usize,
+        budget: usize,
+    },
+    /// The span is the use of the outermost macro, where the runaway expansion started,
+    /// `chain` lists the macros of the deepest nesting *(e.g. `c > b > a`)*.
+    #[error("[{span}]: macro '{macro_symbol}' nests {depth} expansions ({chain}), over the limit of {limit}.")]
+    MacroTooDeep {
+        macro_symbol: char,
+        depth: usize,
+        limit: usize,
+        chain: String,
+    },
+    /// The span is the definition which didn't fit into the table,
+    /// `macros` and `size` are what the table would hold with it.
+    #[error("[{span}]: defining macro '{macro_symbol}' makes {macros} macros with {size} stored tokens, over the limit of {max_macros} macros with {max_size} tokens.")]
+    MacroTableOverflow {
+        macro_symbol: char,
+        macros: usize,
+        size: usize,
+        max_macros: usize,
+        max_size: usize,
+    },
+    #[error(
+        "[{span}]: include '{macro_prefix}{}' must be followed by a quoted path.",
+        INCLUDE_SYMBOL
+    )]
+    IncludeMissing { macro_prefix: char },
+    #[error(
+        "[{span}]: conditional '{macro_prefix}{}' must be followed by a macro symbol and a token.",
+        CONDITIONAL_SYMBOL
+    )]
+    ConditionalMissing { macro_prefix: char },
+    /// The span is from the number prefix to where the counter or the group was expected.
+    #[error(
+        "[{span}]: counted loop '{number_prefix}N{}' must be followed by a counter and a group.",
+        COUNTER_SYMBOL
+    )]
+    CounterMissing { number_prefix: char },
+    #[error(
+        "[{span}]: constant '{macro_prefix}{}' must be followed by a name and a number.",
+        CONSTANT_SYMBOL
+    )]
+    ConstantMissing { macro_prefix: char },
+    /// The span is from the number prefix to the end of the name.
+    #[error("[{span}]: constant '{name}' is not defined.")]
+    ConstantUndefined { name: String },
+    #[error(
+        "[{span}]: cell '{macro_prefix}{}' must be followed by a name and a number.",
+        CELL_SYMBOL
+    )]
+    CellMissing { macro_prefix: char },
+    /// The span is from the cell symbol to the end of the name.
+    #[error("[{span}]: cell '{name}' is not defined.")]
+    CellUndefined { name: String },
+    /// The `message` is the error of reading or lexing the included file.
+    #[error("[{span}]: failed to include '{path}': {message}")]
+    IncludeFailed { path: String, message: String },
+    /// The span is the start of the comment.
+    #[error("[{span}]: block comment must be closed with '{comment_end}'.")]
+    CommentUnclosed { comment_end: String },
+    /// The span is the start of the raw block.
+    #[error("[{span}]: raw block must be closed with '{raw_end}'.")]
+    RawUnclosed { raw_end: String },
+    /// The span is the start of the string.
+    #[error("[{span}]: string must be closed with '{}'.", STRING_DELIMITER)]
+    StringUnclosed,
+    /// The `macro_symbol` is the one of the innermost macro defined
+    /// with the group, if there is one.
     #[error(
-        "[{lineno}:{colno}]: group is empty ('{group_start_delimiter}{group_end_delimiter}')."
+        "[{span}]: group is empty ('{group_start_delimiter}{group_end_delimiter}'){}.",
+        .macro_symbol.map(|symbol| format!(" in the definition of macro '{symbol}'")).unwrap_or_default()
     )]
-    GroupEmpty,
+    GroupEmpty { macro_symbol: Option<char> },
+    #[error("[{span}]: unrecognized character '{ch}'.")]
+    CharUnrecognized { ch: char },
     #[error("{0}")]
-    Group(ErrorGroup<E>),
+    Group(ErrorGroup),
+}
+
+impl Error {
+    /// Get the [`Span`] specifying where in the input the error occured, if there is one.
+    pub fn span(&self) -> Option<&Span> {
+        match self {
+            Error::DelimiterUnopened { span, .. }
+            | Error::DelimiterUnclosed { span, .. }
+            | Error::NumberMissing { span, .. }
+            | Error::CharLiteralUnclosed { span, .. }
+            | Error::MacroMissing { span, .. }
+            | Error::MacroOverBudget { span, .. }
+            | Error::MacroTooDeep { span, .. }
+            | Error::MacroTableOverflow { span, .. }
+            | Error::IncludeMissing { span, .. }
+            | Error::IncludeFailed { span, .. }
+            | Error::ConditionalMissing { span, .. }
+            | Error::CounterMissing { span, .. }
+            | Error::ConstantMissing { span, .. }
+            | Error::ConstantUndefined { span, .. }
+            | Error::CellMissing { span, .. }
+            | Error::CellUndefined { span, .. }
+            | Error::CommentUnclosed { span, .. }
+            | Error::RawUnclosed { span, .. }
+            | Error::StringUnclosed { span, .. }
+            | Error::GroupEmpty { span, .. }
+            | Error::CharUnrecognized { span, .. } => Some(span),
+            Error::Input(_) | Error::Cancelled(_) | Error::Group(_) => None,
+        }
+    }
+}
+
+/// Warning type collected by the [`Lexer`] when it recovers
+/// from an unterminated construct *(see [`EofPolicy::Lenient`])*
+/// skips an unrecognized char *(see [`UnrecognizedPolicy::Warn`])*
+/// or defines a macro over its budget *(see [`BudgetPolicy::Warn`])*.
+/// Every variant contains the [`Span`]
+/// specifying where in the input it occured.
+#[enum_fields(span: Span)]
+#[derive(thiserror::Error, fmt::Debug)]
+pub enum Warning {
+    #[error("[{span}]: unclosed group was closed at the end of input, expected '{group_end_delimiter}'.")]
+    DelimiterUnclosed { group_end_delimiter: char },
+    #[error("[{span}]: dangling macro prefix '{macro_prefix}' was treated as a literal.")]
+    MacroDangling { macro_prefix: char },
+    #[error("[{span}]: macro '{macro_symbol}' expands to {size} operators, over its budget of {budget}.")]
+    MacroOverBudget {
+        macro_symbol: char,
+        size: usize,
+        budget: usize,
+    },
+    #[error("[{span}]: unrecognized character '{ch}' was skipped.")]
+    CharUnrecognized { ch: char },
+}
+
+impl Warning {
+    /// Get the [`Span`] specifying where in the input the warning occured.
+    pub fn span(&self) -> &Span {
+        match self {
+            Warning::DelimiterUnclosed { span, .. }
+            | Warning::MacroDangling { span, .. }
+            | Warning::MacroOverBudget { span, .. }
+            | Warning::CharUnrecognized { span, .. } => span,
+        }
+    }
+}
+
+/// Statistics of the unrecognized chars skipped by the [`Lexer`]
+/// *(chars not assigned to any field that are not [skippable][Config::is_skippable])*.
+#[derive(Default, fmt::Debug)]
+pub struct UnrecognizedChars {
+    total: usize,
+    /// Number of occurences and the first occurence of every char.
+    chars: HashMap<char, (usize, Span)>,
+    /// Number of every read char.
+    read: usize,
+    /// Number of the read chars that were skippable.
+    skippable: usize,
+}
+
+impl UnrecognizedChars {
+    /// Count an occurence of `ch` at `span`.
+    pub fn record(&mut self, ch: char, span: Span) {
+        self.total += 1;
+        self.chars.entry(ch).or_insert((0, span)).0 += 1;
+    }
+
+    /// Get the number of every skipped unrecognized char.
+    pub fn total(&self) -> usize {
+        self.total
+    }
+
+    /// Get the fraction of the read, non-skippable chars that were unrecognized.
+    pub fn ratio(&self) -> f64 {
+        match self.read.saturating_sub(self.skippable) {
+            0 => 0.0,
+            significant => self.total as f64 / significant as f64,
+        }
+    }
+
+    /// Get at most `n` of the most common chars, with their
+    /// number of occurences and the [`Span`] of the first occurence.
+    pub fn most_common(&self, n: usize) -> Vec<(char, usize, Span)> {
+        let mut chars: Vec<(char, usize, Span)> = self
+            .chars
+            .iter()
+            .map(|(ch, (count, span))| (*ch, *count, *span))
+            .collect();
+        chars.sort_unstable_by(|(left_ch, left_count, _), (right_ch, right_count, _)| {
+            right_count.cmp(left_count).then(left_ch.cmp(right_ch))
+        });
+        chars.truncate(n);
+
+        chars
+    }
+}
+
+/// Number of the tokens of every kind in an input,
+/// counted by [`Lexer::count_tokens`].
+#[derive(Clone, Copy, Default, PartialEq, Eq, fmt::Debug, Serialize)]
+pub struct TokenCounts {
+    pub operators: usize,
+    pub numbers: usize,
+    pub groups: usize,
+    pub macro_definitions: usize,
+    pub macro_uses: usize,
+    pub strings: usize,
+    pub raw_blocks: usize,
+    pub cells: usize,
+}
+
+/// Number of operators emitted from every operator [`Token`]
+/// read by the [`Lexer`] *(including ones from unused macro definitions)*.
+#[derive(Default, fmt::Debug)]
+pub struct Coverage {
+    /// The operator and the number of emitted operators for every token's [`Span`].
+    operators: HashMap<Span, (char, usize)>,
+}
+
+impl Coverage {
+    /// Record an operator token `operator` read at `span`.
+    pub fn record(&mut self, operator: char, span: Span) {
+        self.operators.entry(span).or_insert((operator, 0));
+    }
+
+    /// Count `times` operators emitted from the token at `span`.
+    pub fn emit(&mut self, span: &Span, times: usize) {
+        if let Some((_, emitted)) = self.operators.get_mut(span) {
+            *emitted += times;
+        }
+    }
+
+    /// Get the number of recorded operator tokens.
+    pub fn total(&self) -> usize {
+        self.operators.len()
+    }
+
+    /// Get the number of operator tokens that emitted anything.
+    pub fn covered(&self) -> usize {
+        self.operators
+            .values()
+            .filter(|(_, emitted)| *emitted > 0)
+            .count()
+    }
+
+    /// Get the cost of every emitted operator,
+    /// weighted by [`Config::get_operator_weight`].
+    pub fn weighted_cost(&self, config: &Config) -> usize {
+        self.operators
+            .values()
+            .map(|(operator, emitted)| emitted.saturating_mul(config.get_operator_weight(operator)))
+            .fold(0, usize::saturating_add)
+    }
+
+    /// Get every recorded token's [`Span`], operator and the number
+    /// of operators it emitted, ordered by their position.
+    pub fn tokens(&self) -> Vec<(Span, char, usize)> {
+        let mut tokens: Vec<(Span, char, usize)> = self
+            .operators
+            .iter()
+            .map(|(span, (operator, emitted))| (*span, *operator, *emitted))
+            .collect();
+        tokens.sort_unstable_by_key(|(span, _, _)| span.start);
+
+        tokens
+    }
+}
+
+/// A macro definition read by the [`Lexer`].
+///
+/// The body is usually a single token, while the body of a line macro
+/// *(see [`Config::set_line_macros`])* can consist of many tokens, spliced
+/// into the input where the macro is used *(so a number before the macro
+/// only multiplies its first token)*.
+#[derive(Clone, fmt::Debug)]
+pub struct MacroDefinition {
+    pub symbol: char,
+    pub tokens: Vec<Token>,
+    /// [`Span`] from the macro prefix to the end of the body.
+    pub span: Span,
+    /// Number of expansions nested within the body *(see [`Token::expansion_chain`])*.
+    pub depth: usize,
+}
+
+impl MacroDefinition {
+    /// Define `symbol` as `tokens`, defined at `span`.
+    pub fn new(symbol: char, tokens: Vec<Token>, span: Span) -> Self {
+        MacroDefinition {
+            symbol,
+            depth: expansion_chain(&tokens).len(),
+            tokens,
+            span,
+        }
+    }
+
+    /// Get the number of operators emitted by a single expansion of the macro.
+    pub fn expanded_len(&self) -> usize {
+        expanded_len(&self.tokens)
+    }
+
+    /// Get the number of tokens stored in the body of the macro, with the
+    /// tokens of groups counted and expanded macros counted as one, since they
+    /// share the body of their definition.
+    pub fn stored_len(&self) -> usize {
+        fn stored_len(tokens: &[Token]) -> usize {
+            tokens
+                .iter()
+                .map(|token| match token {
+                    Token::Group(group, _) => 1 + stored_len(group),
+                    _ => 1,
+                })
+                .sum()
+        }
+        stored_len(&self.tokens)
+    }
+
+    /// Write the definition back as it would be written in the input
+    /// according to `config`, with macros expanded *(see [`Token::to_source`])*.
+    ///
+    /// A body of many tokens is written as a line macro, which has to be
+    /// followed by a newline.
+    pub fn to_source(&self, config: &Config) -> String {
+        let body: String = self
+            .tokens
+            .iter()
+            .map(|token| token.to_source(config))
+            .collect();
+        match self.tokens.len() {
+            1 => format!("{}{}{body}", config.get_value(&MacroPrefix), self.symbol),
+            _ => format!("{}{}={body}", config.get_value(&MacroPrefix), self.symbol),
+        }
+    }
+
+    /// Define `symbol` as the tokens of `source`, lexed with `config` and
+    /// the `defined` macros *(a body of many tokens becomes a group)*.
+    ///
+    /// The spans point into [`SourceId::COMMAND_LINE`].
+    pub fn from_source(
+        symbol: char,
+        source: &str,
+        defined: &[MacroDefinition],
+        config: &Config,
+    ) -> Result<MacroDefinition> {
+        let mut lexer = Lexer::new(source.chars().map(Ok::<char, Infallible>), config)
+            .with_source(SourceId::COMMAND_LINE)
+            .with_macros(defined.iter().cloned());
+        let mut tokens = lexer.read_all_tokens()?;
+        let span = Span::new(
+            SourceId::COMMAND_LINE,
+            Position::new(0, 1, 1),
+            lexer.position,
+        );
+
+        let token = match tokens.len() {
+            1 => tokens.remove(0),
+            _ => Token::Group(tokens.into(), span),
+        };
+
+        Ok(MacroDefinition::new(symbol, vec![token], span))
+    }
 }
 
 /// A group of [Tokens][Token].
-pub type Group = Vec<Token>;
+///
+/// Groups are `Send + Sync`, so the tokens can be moved to or shared
+/// with other threads once read.
+///
+/// Groups are immutable once read, so the occurences of a macro
+/// share the group of its definition instead of copying it.
+pub type Group = Arc<[Token]>;
+
+/// First digit *(zero)* of every run of ten decimal digits in Unicode 15.0,
+/// sorted by codepoint.
+const DECIMAL_ZEROS: [char; 68] = [
+    '\u{30}',
+    '\u{660}',
+    '\u{6F0}',
+    '\u{7C0}',
+    '\u{966}',
+    '\u{9E6}',
+    '\u{A66}',
+    '\u{AE6}',
+    '\u{B66}',
+    '\u{BE6}',
+    '\u{C66}',
+    '\u{CE6}',
+    '\u{D66}',
+    '\u{DE6}',
+    '\u{E50}',
+    '\u{ED0}',
+    '\u{F20}',
+    '\u{1040}',
+    '\u{1090}',
+    '\u{17E0}',
+    '\u{1810}',
+    '\u{1946}',
+    '\u{19D0}',
+    '\u{1A80}',
+    '\u{1A90}',
+    '\u{1B50}',
+    '\u{1BB0}',
+    '\u{1C40}',
+    '\u{1C50}',
+    '\u{A620}',
+    '\u{A8D0}',
+    '\u{A900}',
+    '\u{A9D0}',
+    '\u{A9F0}',
+    '\u{AA50}',
+    '\u{ABF0}',
+    '\u{FF10}',
+    '\u{104A0}',
+    '\u{10D30}',
+    '\u{11066}',
+    '\u{110F0}',
+    '\u{11136}',
+    '\u{111D0}',
+    '\u{112F0}',
+    '\u{11450}',
+    '\u{114D0}',
+    '\u{11650}',
+    '\u{116C0}',
+    '\u{11730}',
+    '\u{118E0}',
+    '\u{11950}',
+    '\u{11C50}',
+    '\u{11D50}',
+    '\u{11DA0}',
+    '\u{11F50}',
+    '\u{16A60}',
+    '\u{16AC0}',
+    '\u{16B50}',
+    '\u{1D7CE}',
+    '\u{1D7D8}',
+    '\u{1D7E2}',
+    '\u{1D7EC}',
+    '\u{1D7F6}',
+    '\u{1E140}',
+    '\u{1E2F0}',
+    '\u{1E4F0}',
+    '\u{1E950}',
+    '\u{1FBF0}',
+];
+
+/// Get the value of `ch` if it's a decimal digit of any script *(Unicode
+/// category `Nd`, e.g. the Arabic-Indic `٣` is 3)*, unlike [`char::to_digit`],
+/// which only reads the ASCII ones.
+pub fn decimal_digit(ch: char) -> Option<u32> {
+    let zero = match DECIMAL_ZEROS.binary_search(&ch) {
+        Ok(index) => DECIMAL_ZEROS[index],
+        Err(0) => return None,
+        Err(index) => DECIMAL_ZEROS[index - 1],
+    };
+
+    Some(ch as u32 - zero as u32).filter(|value| *value < 10)
+}
+
+/// Base of a number literal, written after a `0` *(`#0x10`)*,
+/// except for decimal numbers, the codepoints of char literals *(`#'A'`)*
+/// and the counters of counted loops *(`#i`, see [`Config::set_counted_loops`])*.
+#[derive(Clone, Copy, Default, PartialEq, Eq, fmt::Debug, Serialize, Deserialize)]
+pub enum NumberBase {
+    #[default]
+    Decimal,
+    Binary,
+    Octal,
+    Hexadecimal,
+    Char,
+    /// A counter, the number of the repetition once the loop is unrolled.
+    Counter(char),
+}
+
+impl NumberBase {
+    /// Get the base marked by `ch` written after a `0`.
+    fn from_marker(ch: char) -> Option<NumberBase> {
+        match ch {
+            'b' => Some(NumberBase::Binary),
+            'o' => Some(NumberBase::Octal),
+            'x' => Some(NumberBase::Hexadecimal),
+            _ => None,
+        }
+    }
+
+    fn radix(&self) -> u32 {
+        match self {
+            NumberBase::Decimal => 10,
+            NumberBase::Binary => 2,
+            NumberBase::Octal => 8,
+            NumberBase::Hexadecimal => 16,
+            NumberBase::Char | NumberBase::Counter(_) => {
+                unreachable!("Char literals and counters shouldn't be read as digits.")
+            }
+        }
+    }
+
+    /// Same as [`format`][NumberBase::format], but with `separator`
+    /// written at the positions of the `separators`.
+    pub fn format_separated(
+        &self,
+        number: usize,
+        separators: Separators,
+        separator: char,
+    ) -> String {
+        if separators.is_empty() || matches!(self, NumberBase::Char | NumberBase::Counter(_)) {
+            return self.format(number);
+        }
+
+        let (marker, digits) = match self {
+            NumberBase::Binary => ("0b", format!("{number:b}")),
+            NumberBase::Octal => ("0o", format!("{number:o}")),
+            NumberBase::Hexadecimal => ("0x", format!("{number:x}")),
+            _ => ("", format!("{number}")),
+        };
+        let mut literal = String::from(marker);
+        for (index, digit) in digits.chars().enumerate() {
+            if index > 0 && separators.before(digits.len() - index) {
+                literal.push(separator);
+            }
+            literal.push(digit);
+        }
+        if separators.before(0) {
+            literal.push(separator);
+        }
+
+        literal
+    }
+
+    /// Write `number` in the base, as it would be written in the input.
+    pub fn format(&self, number: usize) -> String {
+        match self {
+            NumberBase::Decimal => format!("{number}"),
+            NumberBase::Binary => format!("0b{number:b}"),
+            NumberBase::Octal => format!("0o{number:o}"),
+            NumberBase::Hexadecimal => format!("0x{number:x}"),
+            NumberBase::Char => u32::try_from(number)
+                .ok()
+                .and_then(char::from_u32)
+                .map_or_else(|| format!("{number}"), |ch| format!("'{ch}'")),
+            NumberBase::Counter(counter) => counter.to_string(),
+        }
+    }
+}
+
+/// Positions of the separators between the digits of a number *(`#1_000`)*,
+/// kept to write the number back the same way.
+///
+/// The bit `i` is set for a separator followed by `i` digits.
+#[derive(Clone, Copy, Default, PartialEq, Eq, fmt::Debug, Serialize, Deserialize)]
+pub struct Separators(u64);
+
+impl Separators {
+    /// Get the separators of a number of `digits` digits, written
+    /// after the digits at every one of the `positions`.
+    fn new<P: IntoIterator<Item = usize>>(digits: usize, positions: P) -> Self {
+        Separators(positions.into_iter().fold(0, |separators, position| {
+            separators | 1u64.checked_shl((digits - position) as u32).unwrap_or(0)
+        }))
+    }
+
+    /// Check whether there's a separator followed by `digits` digits.
+    fn before(&self, digits: usize) -> bool {
+        self.0 & 1u64.checked_shl(digits as u32).unwrap_or(0) != 0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+}
 
 /// A token enum returned by the [Lexer].
-#[derive(Clone, fmt::Debug)]
+#[derive(Clone, fmt::Debug, Serialize, Deserialize)]
 pub enum Token {
-    /// Decimal number preceded by a prefix specified
-    /// in the [Config].
-    Number(usize),
-    /// Operator specified in the [Config].
-    Operator(char),
-    /// A group of Tokens.
-    Group(Group),
+    /// Number preceded by a prefix specified in the [Config],
+    /// with the [`NumberBase`] and [`Separators`] it was written with
+    /// and the [`Span`] from its prefix to its last digit.
+    Number(usize, NumberBase, Separators, Span),
+    /// Operator specified in the [Config], with the [`Span`]
+    /// it was read from.
+    Operator(char, Span),
+    /// A group of Tokens, with the [`Span`] from its start
+    /// to its end delimiter.
+    Group(Group, Span),
+    /// String literal *(see [`Config::set_strings`])*, emitted as the
+    /// code printing it *(see [`printing_code`])*, with the [`Span`] from
+    /// its start to its end delimiter.
+    Str(Arc<str>, Span),
+    /// Text of a raw block *(see [`Config::set_raw_block`])*, emitted
+    /// as it is, with the [`Span`] from its start to its end delimiter.
+    Raw(Arc<str>, Span),
+    /// Move to a [`NamedCell`] *(see [`Config::set_named_cells`])*, with the [`Span`]
+    /// from the cell symbol to the end of the name. The moves are planned from
+    /// the modeled position of the pointer *(see [`Pointer`][crate::plan::Pointer])*.
+    Cell(Arc<NamedCell>, Span),
+    /// A Token expanded from a macro, with the [`Provenance`] of the expansion.
+    ///
+    /// Both are boxed, as the spans of the provenance would
+    /// otherwise make every `Token` as large as this variant.
+    Expanded(Box<Token>, Box<Provenance>),
+}
+
+/// A cell of the tape named in the input *(`$&counter#3`)*,
+/// shared by the [`Tokens`][Token] moving to it.
+#[derive(Clone, PartialEq, Eq, fmt::Debug, Serialize, Deserialize)]
+pub struct NamedCell {
+    pub name: String,
+    /// Index of the cell on the tape.
+    pub index: usize,
+}
+
+/// Where a [`Token`] expanded from a macro came from.
+#[derive(Clone, Copy, fmt::Debug, Serialize, Deserialize)]
+pub struct Provenance {
+    pub macro_symbol: char,
+    /// [`Span`] of the macro's definition.
+    pub definition: Span,
+    /// [`Span`] of the macro symbol the token was expanded from.
+    pub use_site: Span,
+}
+
+impl Token {
+    /// Get the `Token` without the [`Provenances`][Provenance] it's wrapped in.
+    pub fn without_provenance(&self) -> &Token {
+        match self {
+            Token::Expanded(token, _) => token.without_provenance(),
+            token => token,
+        }
+    }
+
+    /// Get the [`Span`] the `Token` was read from,
+    /// the use of the macro for an expanded one.
+    pub fn span(&self) -> &Span {
+        match self {
+            Token::Number(.., span)
+            | Token::Operator(_, span)
+            | Token::Group(_, span)
+            | Token::Str(_, span)
+            | Token::Raw(_, span)
+            | Token::Cell(.., span) => span,
+            Token::Expanded(_, provenance) => &provenance.use_site,
+        }
+    }
+
+    /// Get the number of operators emitted by the `Token`
+    /// *(a lone number emits nothing, and neither do the moves to a named cell,
+    /// which depend on the position of the pointer)*.
+    pub fn expanded_len(&self) -> usize {
+        match self {
+            Token::Number(..) | Token::Cell(..) => 0,
+            Token::Operator(..) => 1,
+            Token::Str(text, _) => printing_code(text).len(),
+            Token::Raw(text, _) => text.chars().count(),
+            Token::Expanded(token, _) => token.expanded_len(),
+            Token::Group(group, _) => expanded_len(group),
+        }
+    }
+
+    /// Get the symbols of the macros along the deepest nesting
+    /// of expansions within the `Token`, the outermost first.
+    pub fn expansion_chain(&self) -> Vec<char> {
+        match self {
+            Token::Number(..)
+            | Token::Operator(..)
+            | Token::Str(..)
+            | Token::Raw(..)
+            | Token::Cell(..) => Vec::new(),
+            Token::Expanded(token, provenance) => {
+                let mut chain = vec![provenance.macro_symbol];
+                chain.extend(token.expansion_chain());
+                chain
+            }
+            Token::Group(group, _) => expansion_chain(group),
+        }
+    }
+
+    /// Write the `Token` back as it would be written in the input
+    /// according to `config`, with macros expanded.
+    pub fn to_source(&self, config: &Config) -> String {
+        match self {
+            Token::Number(number, base, separators, _) => {
+                let literal = match config.get_number_separator() {
+                    Some(separator) => base.format_separated(*number, *separators, separator),
+                    None => base.format(*number),
+                };
+                format!("{}{literal}", config.get_value(&NumberPrefix))
+            }
+            Token::Operator(operator, _) => match config.get_graphemes().decode(*operator) {
+                Some(grapheme) => grapheme.to_string(),
+                None => operator.to_string(),
+            },
+            Token::Str(text, _) => {
+                let escape_prefix = *config.get_value(&EscapePrefix);
+                let mut source = STRING_DELIMITER.to_string();
+                for ch in text.chars() {
+                    if ch == STRING_DELIMITER || ch == escape_prefix {
+                        source.push(escape_prefix);
+                    }
+                    source.push(ch);
+                }
+                source.push(STRING_DELIMITER);
+                source
+            }
+            Token::Raw(text, _) => match config.get_raw_block() {
+                Some((start, end)) => format!("{start}{text}{end}"),
+                // only an escaped group delimiter is raw without raw blocks
+                None => format!("{}{text}", config.get_value(&EscapePrefix)),
+            },
+            Token::Cell(cell, _) => format!("{CELL_SYMBOL}{}", cell.name),
+            Token::Expanded(token, _) => token.to_source(config),
+            Token::Group(group, _) => {
+                let mut source = config.get_value(&GroupStartDelimiter).to_string();
+                source.extend(group.iter().map(|token| token.to_source(config)));
+                source.push(*config.get_value(&GroupEndDelimiter));
+                source
+            }
+        }
+    }
+}
+
+/// Get the number of operators emitted by `tokens`, each multiplied
+/// by the number before it.
+fn expanded_len(tokens: &[Token]) -> usize {
+    let mut len: usize = 0;
+    let mut multiplier: usize = 1;
+    for token in tokens {
+        match token.without_provenance() {
+            Token::Number(number, ..) => multiplier = *number,
+            token => {
+                len = len.saturating_add(token.expanded_len().saturating_mul(multiplier));
+                multiplier = 1;
+            }
+        }
+    }
+
+    len
+}
+
+/// Get the deepest [`Token::expansion_chain`] within `tokens`.
+fn expansion_chain(tokens: &[Token]) -> Vec<char> {
+    tokens
+        .iter()
+        .map(Token::expansion_chain)
+        .max_by_key(Vec::len)
+        .unwrap_or_default()
+}
+
+/// Check whether `counter` is read as a number within `token`.
+fn uses_counter(token: &Token, counter: char) -> bool {
+    match token {
+        Token::Number(_, NumberBase::Counter(ch), ..) => *ch == counter,
+        Token::Group(group, _) => group.iter().any(|token| uses_counter(token, counter)),
+        Token::Expanded(token, _) => uses_counter(token, counter),
+        _ => false,
+    }
+}
+
+/// Get `token` with `counter` replaced by the decimal number `value` within it.
+fn substitute_counter(token: &Token, counter: char, value: usize) -> Token {
+    match token {
+        Token::Number(_, NumberBase::Counter(ch), _, span) if *ch == counter => {
+            Token::Number(value, NumberBase::Decimal, Separators::default(), *span)
+        }
+        Token::Group(group, span) => Token::Group(
+            group
+                .iter()
+                .map(|token| substitute_counter(token, counter, value))
+                .collect(),
+            *span,
+        ),
+        Token::Expanded(token, provenance) => Token::Expanded(
+            Box::new(substitute_counter(token, counter, value)),
+            provenance.clone(),
+        ),
+        token => token.clone(),
+    }
+}
+
+/// Get the innermost of the `tokens` read at line `lineno` and column `colno`.
+///
+/// An expanded macro is found at its use as a whole, as the spans
+/// of its tokens point into its definition.
+pub fn token_at(tokens: &[Token], lineno: usize, colno: usize) -> Option<&Token> {
+    tokens.iter().find_map(|token| match token {
+        Token::Number(.., span)
+        | Token::Operator(_, span)
+        | Token::Str(_, span)
+        | Token::Raw(_, span)
+        | Token::Cell(.., span) => span.contains_location(lineno, colno).then_some(token),
+        Token::Group(group, span) if span.contains_location(lineno, colno) => {
+            token_at(group, lineno, colno).or(Some(token))
+        }
+        Token::Expanded(_, provenance) => provenance
+            .use_site
+            .contains_location(lineno, colno)
+            .then_some(token),
+        Token::Group(..) => None,
+    })
 }
 
 /// Iterator over the [`Tokens`][Token]
@@ -95,9 +944,11 @@ pub enum Token {
 /// Every `char` not defined as an operator, prefix, group delimiter or macro
 /// is completely skipped
 /// *(operators, prefixes and group delimiters are specified in the [`Config`]
-/// passed to the `Lexer` when initializing)*.
+/// passed to the `Lexer` when initializing)*, unless the [`UnrecognizedPolicy`]
+/// says otherwise *(whitespace and allowed chars are always skipped)*.
 /// In addition, specific characters can be escaped *(skipped by the `Lexer`)* when
-/// preceded by an escape prefix.
+/// preceded by an escape prefix. Escaped group delimiters never open or close a group,
+/// and are emitted as they are if the [`Config`] says so *(see [`EscapeInGroups`])*.
 ///
 /// ## Operators
 ///
@@ -107,14 +958,28 @@ pub enum Token {
 ///
 /// When a number prefix is encountered, the `Lexer` will try to
 /// read the next chars as a base-10 number, yielding it as a [`Token`].
-/// If the prefix is not followed by at least one decimal digit,
+/// Numbers written after `0x`, `0b` or `0o` are read in base 16, 2 or 8
+/// *(`#0x10`, `#0b1010`)*, see [`NumberBase`].
+/// Only ASCII digits are read, unless [`Config::set_unicode_digits`] allows
+/// base-10 numbers with the digits of any script *(see [`decimal_digit`])*.
+/// A char quoted with `'` is read as its codepoint *(`#'A'` is `#65`)*,
+/// an unclosed one is an [`Error::CharLiteralUnclosed`].
+/// If the prefix is not followed by at least one digit of the base,
 /// an [`Error::NumberMissing`] will be yielded.
 ///
+/// With [`Config::set_counted_loops`], a number followed by [`COUNTER_SYMBOL`],
+/// a counter `char` and a group *(`#5@i(#i+>)`)* is yielded as a group of the
+/// group repeated that many times, with the counter written after a number prefix
+/// within it *(`#i`)* read as the number of the repetition, from 0.
+/// A loop missing its counter or group is an [`Error::CounterMissing`].
+///
 /// ## Groups
 ///
 /// Groups are a collection of [`Tokens`][Token] enclosed in group delimiters.
 /// The `Lexer` will try to yield the group as a whole, returning an [`Error::Group`]
 /// if any tokens in it were erroneous.
+/// An empty group is an [`Error::GroupEmpty`], naming the macro defined with it,
+/// unless allowed with [`Config::set_allow_empty_groups`].
 ///
 /// ## Macros
 ///
@@ -125,24 +990,130 @@ pub enum Token {
 ///
 /// Be wary, that ***every*** `char` can be defined as a macro, even
 /// operators, prefixes and group delimiters.
-#[cfg_attr(feature = "integration-tests", visibility::make(pub))]
+///
+/// ## Includes
+///
+/// With [`Config::set_includes`], a macro prefix followed by [`INCLUDE_SYMBOL`]
+/// and a quoted path *(`$%"lib.bfup"`)* lexes the file with the same [`Config`].
+/// Its macros are defined, and its tokens are spliced in place of the include.
+/// Paths within included files are relative to them, while the ones in the input
+/// are relative to the directory passed to [`with_include_dir()`][Lexer::with_include_dir]
+/// *(or the current one)*. The spans of the included tokens point into [`SourceId::INCLUDED`].
+///
+/// ## Conditionals
+///
+/// With [`Config::set_conditionals`], a macro prefix followed by [`CONDITIONAL_SYMBOL`],
+/// a macro symbol and a token *(`$?x(...)`)* yields the token only if the macro
+/// is defined at that point *(e.g. predefined with [`Lexer::with_macros`])*.
+/// Otherwise, the token is skipped, along with the macros defined within it.
+/// A conditional cut off by the end of input is an [`Error::ConditionalMissing`].
+///
+/// ## Constants
+///
+/// With [`Config::set_constants`], a macro prefix followed by [`CONSTANT_SYMBOL`],
+/// a name and a number *(`$=WIDTH#32`)* defines a constant. Its name following
+/// a number prefix *(`#WIDTH`)* is read as the number, so it can multiply a token.
+/// A definition missing its name or number is an [`Error::ConstantMissing`],
+/// a name used before it's defined is an [`Error::ConstantUndefined`].
+///
+/// ## Named cells
+///
+/// With [`Config::set_named_cells`], a macro prefix followed by [`CELL_SYMBOL`],
+/// a name and a number *(`$&counter#3`)* names the cell of that index.
+/// [`CELL_SYMBOL`] followed by the name *(`&counter`)* is yielded as a [`Token::Cell`],
+/// emitted as the moves to the cell from where the pointer is modeled to be
+/// *(see [`Pointer`][crate::plan::Pointer])*. A definition missing its name or number
+/// is an [`Error::CellMissing`], a name used before it's defined is an [`Error::CellUndefined`].
+///
+/// ## Comments
+///
+/// With [`Config::set_line_comment`], the comment `char` and the rest of its line
+/// are skipped, so comments don't need their operators escaped. The newline
+/// is kept, so a comment doesn't extend a line macro.
+///
+/// With [`Config::set_block_comment`], everything between the comment delimiters
+/// is skipped, an unclosed block comment is an [`Error::CommentUnclosed`] regardless
+/// of the [`EofPolicy`]. Block comments don't nest.
+///
+/// With [`Config::set_raw_block`], everything between the raw block delimiters
+/// is yielded as a [`Token::Raw`], copied to the output as it is, even the chars
+/// that aren't operators. An unclosed raw block is an [`Error::RawUnclosed`].
+///
+/// A first line starting with `#!` is skipped as a shebang *(`#!/usr/bin/env -S bfup --run`)*,
+/// so the input can be an executable script.
+///
+/// ## Strings
+///
+/// With [`Config::set_strings`], text quoted with [`STRING_DELIMITER`] is yielded
+/// as a [`Token::Str`], emitted as the code printing it. Within it, the escape prefix
+/// followed by `n` or `t` stands for a newline or tab, followed by any other `char`
+/// for that `char`. An unclosed string is an [`Error::StringUnclosed`].
+///
+/// ## End of input
+///
+/// By default, unclosed groups and macro prefixes not followed by a
+/// definition are errors. With [`EofPolicy::Lenient`] set in the [`Config`],
+/// unclosed groups are closed at the end of input, while dangling macro
+/// prefixes are treated as literal chars, and a [`Warning`] is collected instead.
+///
+/// ## Cancellation
+///
+/// A `Lexer` created [`with_cancellation()`][Lexer::with_cancellation]
+/// checks the [`CancellationToken`] before reading every `char`,
+/// yielding [`Error::Cancelled`] once the cancellation is requested.
+///
+/// ## Threads
+///
+/// A `Lexer` only borrows its [`Config`], and is `Send` and `Sync`
+/// whenever its input iterator is.
 pub struct Lexer<'a, I, E>
 where
-    E: ErrorTrait,
+    E: ErrorTrait + Send + Sync + 'static,
     I: Iterator<Item = StdResult<char, E>>,
 {
     config: &'a Config,
     char_iter: Peekable<I>,
 
-    macro_symbol_table: HashMap<char, Token>,
+    cancellation: Option<&'a CancellationToken>,
 
-    lineno: usize,
-    colno: usize,
+    macro_symbol_table: HashMap<char, MacroDefinition>,
+
+    warnings: Vec<Warning>,
+    /// Statistics of the unrecognized chars, collected only when asked for.
+    unrecognized: Option<UnrecognizedChars>,
+    /// Coverage of the operator tokens, collected only when asked for.
+    coverage: Option<Coverage>,
+    /// Definitions read from the input, kept only when asked for.
+    macro_definitions: Option<Vec<MacroDefinition>>,
+    /// Number of macros defined in the input and the tokens stored in
+    /// their bodies, limited by the [`Config`].
+    defined_macros: usize,
+    macro_table_size: usize,
+    /// Rest of the tokens of a spliced macro body, read before the input.
+    spliced: VecDeque<Token>,
+    /// Token read after a number to compose the multipliers, read before the rest.
+    peeked: Option<Result<Token>>,
+    /// Counters of the counted loops being read, the innermost last.
+    counters: Vec<char>,
+    /// Values of the constants defined so far.
+    constants: HashMap<String, usize>,
+    /// Indices of the named cells defined so far.
+    cells: HashMap<String, Arc<NamedCell>>,
+    /// Symbols of the macros whose bodies are being read, the innermost last.
+    defining: Vec<char>,
+    /// Directory the included paths are relative to, the current one if `None`.
+    include_dir: Option<PathBuf>,
+    /// Canonical paths of the files including the input, to detect cycles.
+    included: Vec<PathBuf>,
+
+    source: SourceId,
+    position: Position,
+    next_offset: usize,
 }
 
 impl<'a, I, E> Lexer<'a, I, E>
 where
-    E: ErrorTrait,
+    E: ErrorTrait + Send + Sync + 'static,
     I: Iterator<Item = StdResult<char, E>>,
 {
     /// Create a new `Lexer` with the given input and [`Config`].
@@ -150,21 +1121,113 @@ where
         Lexer {
             config,
             char_iter: input.peekable(),
+            cancellation: None,
             macro_symbol_table: HashMap::new(),
-            lineno: 1,
-            colno: 0,
+            warnings: Vec::new(),
+            unrecognized: None,
+            coverage: None,
+            macro_definitions: None,
+            defined_macros: 0,
+            macro_table_size: 0,
+            spliced: VecDeque::new(),
+            peeked: None,
+            counters: Vec::new(),
+            constants: HashMap::new(),
+            cells: HashMap::new(),
+            defining: Vec::new(),
+            include_dir: None,
+            included: Vec::new(),
+            source: SourceId::default(),
+            position: Position::new(0, 1, 0),
+            next_offset: 0,
         }
     }
 
+    /// Make the `Lexer` stop with [`Error::Cancelled`] once
+    /// `cancellation` is cancelled.
+    pub fn with_cancellation(mut self, cancellation: &'a CancellationToken) -> Self {
+        self.cancellation = Some(cancellation);
+        self
+    }
+
+    /// Make the spans of the tokens point into `source`.
+    pub fn with_source(mut self, source: SourceId) -> Self {
+        self.source = source;
+        self
+    }
+
+    /// Resolve the paths of the includes relative to `dir`
+    /// *(usually the directory of the input)*.
+    pub fn with_include_dir<P: Into<PathBuf>>(mut self, dir: P) -> Self {
+        self.include_dir = Some(dir.into());
+        self
+    }
+
+    /// Expand the macros of `definitions` as if they were defined
+    /// before the input *(e.g. the ones defined on the command line)*.
+    ///
+    /// They aren't returned by `take_macro_definitions()`,
+    /// as they aren't read from the input.
+    pub fn with_macros<D: IntoIterator<Item = MacroDefinition>>(mut self, definitions: D) -> Self {
+        self.macro_symbol_table.extend(
+            definitions
+                .into_iter()
+                .map(|definition| (definition.symbol, definition)),
+        );
+        self
+    }
+
+    /// Collect the [`Coverage`] of the operator tokens read,
+    /// to be taken with `take_coverage()`.
+    pub fn collect_coverage(mut self) -> Self {
+        self.coverage = Some(Coverage::default());
+        self
+    }
+
+    /// Collect the [`UnrecognizedChars`] skipped,
+    /// to be taken with `take_unrecognized()`.
+    pub fn collect_unrecognized(mut self) -> Self {
+        self.unrecognized = Some(UnrecognizedChars::default());
+        self
+    }
+
+    /// Keep the [`MacroDefinitions`][MacroDefinition] read from the input,
+    /// to be taken with `take_macro_definitions()`.
+    pub fn keep_macro_definitions(mut self) -> Self {
+        self.macro_definitions = Some(Vec::new());
+        self
+    }
+
     /// Try to read every token in the `Lexer`'s input into a [`Vec<Token>`].
-    pub fn read_all_tokens(&mut self) -> Result<Vec<Token>, E> {
+    pub fn read_all_tokens(&mut self) -> Result<Vec<Token>> {
+        self.read_tokens_until(usize::MAX)
+    }
+
+    /// Same as [`read_all_tokens`][Lexer::read_all_tokens], but stops reading
+    /// at the first token *(or error)* starting at or after the char at `offset`,
+    /// which is left out.
+    ///
+    /// A token starting before `offset` is read to its end *(e.g. a group ending after it)*.
+    pub fn read_tokens_until(&mut self, offset: usize) -> Result<Vec<Token>> {
         const TOKEN_STOR_INIT_SIZE: usize = 32;
 
+        let after_offset =
+            |span: &Span, source: SourceId| span.source == source && span.start.offset >= offset;
         let mut tokens: Vec<Token> = Vec::with_capacity(TOKEN_STOR_INIT_SIZE);
-        let mut errors: Vec<Error<E>> = Vec::new();
-        loop {
+        let mut errors: Vec<Error> = Vec::new();
+        while self.next_offset < offset {
             match self.read_token() {
                 Some(Err(Error::Input(error))) => return Err(Error::Input(error)),
+                Some(Err(Error::Cancelled(error))) => return Err(Error::Cancelled(error)),
+                // reached while skipping the chars before the token
+                Some(Ok(token)) if after_offset(token.span(), self.source) => break,
+                Some(Err(error))
+                    if error
+                        .span()
+                        .is_some_and(|span| after_offset(span, self.source)) =>
+                {
+                    break
+                }
                 Some(Ok(token)) => tokens.push(token),
                 Some(Err(error)) => errors.push(error),
                 None => break,
@@ -172,70 +1235,652 @@ where
         }
 
         if !errors.is_empty() {
-            return Err(Error::Group(ErrorGroup(errors)));
+            return Err(Error::Group(ErrorGroup::new(errors)));
         }
 
         Ok(tokens)
     }
 
-    /// Try to read a [`Token`].
-    pub fn read_token(&mut self) -> Option<Result<Token, E>> {
-        loop {
-            let ch = match self.next_char() {
-                Some(Ok(ch)) => ch,
-                Some(Err(error)) => return Some(Err(error)),
-                None => return None,
-            };
-
-            if let Some(macro_token) = self.macro_symbol_table.get(&ch) {
-                return Some(Ok(macro_token.clone()));
+    /// Check that the group delimiters in the `Lexer`'s input are balanced
+    /// without reading any tokens, which is much faster than lexing.
+    ///
+    /// Yields [`Error::DelimiterUnopened`] at the first surplus end delimiter,
+    /// or [`Error::DelimiterUnclosed`] at the outermost group left open.
+    pub fn scan_delimiters(&mut self) -> Result<()> {
+        let mut open: Vec<(Span, (char, char))> = Vec::new();
+        while let Some(ch) = self.next_char().transpose()? {
+            if self.starts_shebang(ch) {
+                self.skip_shebang()?;
+                continue;
+            }
+            if Some(ch) == self.config.get_line_comment() {
+                self.skip_line()?;
+                continue;
+            }
+            if self.starts_block_comment(ch) {
+                self.skip_block_comment()?;
+                continue;
+            }
+            if self.starts_raw_block(ch) {
+                self.read_raw_block()?;
+                continue;
             }
 
             match self.config.get_field(&ch) {
-                Some(EscapePrefix) => {
-                    // skip the next character
-                    self.next_char();
-                    continue;
+                // neither the escaped char nor the macro symbol can be a delimiter
+                Some(EscapePrefix | MacroPrefix) => {
+                    self.next_char().transpose()?;
+                }
+                Some(GroupStartDelimiter) => {
+                    open.push((self.span(), self.config.get_delimiter_pair(&ch)));
                 }
-                Some(NumberPrefix) => match self.read_number() {
-                    Ok(number) => return Some(Ok(Token::Number(number))),
-                    Err(error) => return Some(Err(error)),
-                },
-                Some(MacroPrefix) => match self.read_macro_definition() {
-                    Ok(_) => continue,
-                    Err(error) => return Some(Err(error)),
-                },
-                Some(GroupStartDelimiter) => match self.read_group() {
-                    Ok(group) => return Some(Ok(Token::Group(group))),
-                    Err(error) => return Some(Err(error)),
-                },
                 Some(GroupEndDelimiter) => {
-                    return Some(Err(Error::DelimiterUnopened {
-                        lineno: self.lineno,
-                        colno: self.colno,
-                        group_start_delimiter: *self.config.get_value(&GroupStartDelimiter),
-                        group_end_delimiter: *self.config.get_value(&GroupEndDelimiter),
-                    }));
+                    let pair = self.config.get_delimiter_pair(&ch);
+                    if open.last().map(|(_, open_pair)| *open_pair) != Some(pair) {
+                        return Err(Error::DelimiterUnopened {
+                            span: self.span(),
+                            group_start_delimiter: pair.0,
+                            group_end_delimiter: pair.1,
+                        });
+                    }
+                    open.pop();
                 }
-                Some(Operator) => {
-                    return Some(Ok(Token::Operator(ch)));
+                None if self.starts_string(ch) => {
+                    self.read_string(self.span())?;
                 }
-                None => (),
+                _ => (),
             }
         }
-    }
-
-    /// Try to read a base 10 number from input.
-    fn read_number(&mut self) -> Result<usize, E> {
-        const NUMBER_STOR_INIT_SIZE: usize = 8;
 
-        let mut number_string = String::with_capacity(NUMBER_STOR_INIT_SIZE);
-
-        loop {
-            if let Some(Ok(next_ch)) = self.char_iter.peek() {
-                if !next_ch.is_ascii_digit() {
-                    break;
-                }
+        match open.first() {
+            Some((span, (group_start_delimiter, group_end_delimiter))) => {
+                Err(Error::DelimiterUnclosed {
+                    span: *span,
+                    group_start_delimiter: *group_start_delimiter,
+                    group_end_delimiter: *group_end_delimiter,
+                })
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Count the tokens of every kind in the `Lexer`'s input
+    /// in a single pass, without building any tokens.
+    ///
+    /// The tokens are counted as written, so the tokens within macro
+    /// definitions are counted once, while their uses count as
+    /// [`TokenCounts::macro_uses`]. The delimiters aren't checked
+    /// to be balanced *(see [`Lexer::scan_delimiters`])*.
+    pub fn count_tokens(&mut self) -> Result<TokenCounts> {
+        let mut counts = TokenCounts::default();
+        let mut macro_symbols: HashSet<char> = HashSet::new();
+        while let Some(ch) = self.next_char().transpose()? {
+            if self.starts_shebang(ch) {
+                self.skip_shebang()?;
+                continue;
+            }
+            if Some(ch) == self.config.get_line_comment() {
+                self.skip_line()?;
+                continue;
+            }
+            if self.starts_block_comment(ch) {
+                self.skip_block_comment()?;
+                continue;
+            }
+            if self.starts_raw_block(ch) {
+                self.read_raw_block()?;
+                counts.raw_blocks += 1;
+                continue;
+            }
+            if macro_symbols.contains(&ch) {
+                counts.macro_uses += 1;
+                continue;
+            }
+
+            match self.config.get_field(&ch) {
+                Some(EscapePrefix) => {
+                    self.next_char().transpose()?;
+                }
+                Some(MacroPrefix) => match self.next_char().transpose()? {
+                    // the tested macro symbol isn't a definition
+                    Some(CONDITIONAL_SYMBOL) if self.config.has_conditionals() => {
+                        self.next_char().transpose()?;
+                    }
+                    // the value is counted as a number
+                    Some(CONSTANT_SYMBOL) if self.config.has_constants() => {
+                        let name = self.read_name()?;
+                        self.constants.insert(name, 0);
+                    }
+                    Some(CELL_SYMBOL) if self.config.has_named_cells() => {
+                        self.read_name()?;
+                    }
+                    Some(symbol) => {
+                        macro_symbols.insert(symbol);
+                        counts.macro_definitions += 1;
+                    }
+                    None => (),
+                },
+                Some(NumberPrefix) => {
+                    self.read_number()?;
+                    counts.numbers += 1;
+                    // the counter is read as a number within the rest of the input
+                    if self.starts_counted_loop() {
+                        self.next_char().transpose()?;
+                        if let Some(counter) = self.next_char().transpose()? {
+                            self.counters.push(counter);
+                        }
+                    }
+                }
+                Some(GroupStartDelimiter) => counts.groups += 1,
+                Some(Operator) => counts.operators += 1,
+                None if self.config.is_whitespace_operator(ch) => counts.operators += 1,
+                None if self.starts_string(ch) => {
+                    self.read_string(self.span())?;
+                    counts.strings += 1;
+                }
+                None if self.starts_cell(ch) => {
+                    self.read_name()?;
+                    counts.cells += 1;
+                }
+                Some(GroupEndDelimiter) | None => (),
+            }
+        }
+
+        Ok(counts)
+    }
+
+    /// Take the [`Warnings`][Warning] collected so far.
+    pub fn take_warnings(&mut self) -> Vec<Warning> {
+        std::mem::take(&mut self.warnings)
+    }
+
+    /// Take the [`UnrecognizedChars`] skipped so far,
+    /// if they're collected *(see `collect_unrecognized()`)*.
+    pub fn take_unrecognized(&mut self) -> Option<UnrecognizedChars> {
+        let mut unrecognized = self.unrecognized.take()?;
+        unrecognized.read = self.next_offset;
+        Some(unrecognized)
+    }
+
+    /// Take the [`Coverage`] of the operator tokens read so far,
+    /// if it's collected *(see `collect_coverage()`)*.
+    pub fn take_coverage(&mut self) -> Option<Coverage> {
+        self.coverage.take()
+    }
+
+    /// Get the [`Coverage`] of the operator tokens read so far, if it's collected,
+    /// to count the operators emitted from them.
+    pub fn coverage_mut(&mut self) -> Option<&mut Coverage> {
+        self.coverage.as_mut()
+    }
+
+    /// Take the [`MacroDefinitions`][MacroDefinition] read so far,
+    /// empty unless they're kept *(see `keep_macro_definitions()`)*.
+    pub fn take_macro_definitions(&mut self) -> Vec<MacroDefinition> {
+        self.macro_definitions
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    /// Try to read a [`Token`].
+    ///
+    /// Consecutive numbers are read as one, multiplied together
+    /// if the [`MultiplierPolicy`] is [`Compose`][MultiplierPolicy::Compose].
+    pub fn read_token(&mut self) -> Option<Result<Token>> {
+        let token = self.read_single_token()?;
+        if self.config.get_multiplier_policy() == MultiplierPolicy::Replace {
+            return Some(token);
+        }
+        let (mut product, span) = match token {
+            Ok(ref number) => match number.without_provenance() {
+                Token::Number(value, base, _, span) if !matches!(base, NumberBase::Counter(_)) => {
+                    (*value, *span)
+                }
+                _ => return Some(token),
+            },
+            Err(_) => return Some(token),
+        };
+
+        let mut composed = false;
+        while let Some(next) = self.read_single_token() {
+            match next {
+                Ok(ref number) => match number.without_provenance() {
+                    Token::Number(value, base, ..) if !matches!(base, NumberBase::Counter(_)) => {
+                        product = product.saturating_mul(*value);
+                        composed = true;
+                    }
+                    _ => {
+                        self.peeked = Some(next);
+                        break;
+                    }
+                },
+                Err(_) => {
+                    self.peeked = Some(next);
+                    break;
+                }
+            }
+        }
+
+        if !composed {
+            return Some(token);
+        }
+        Some(Ok(Token::Number(
+            product,
+            NumberBase::Decimal,
+            Separators::default(),
+            span.merge(&self.span()),
+        )))
+    }
+
+    /// Try to read a [`Token`], without composing it with the numbers following it.
+    fn read_single_token(&mut self) -> Option<Result<Token>> {
+        if let Some(token) = self.peeked.take() {
+            return Some(token);
+        }
+        if let Some(token) = self.spliced.pop_front() {
+            return Some(Ok(token));
+        }
+
+        loop {
+            let ch = match self.next_char() {
+                Some(Ok(ch)) => ch,
+                Some(Err(error)) => return Some(Err(error)),
+                None => return None,
+            };
+
+            if let Some(token) = self.read_token_starting_with(ch) {
+                return Some(token);
+            }
+        }
+    }
+
+    /// Try to read a [`Token`] starting with the already consumed `ch`.
+    /// Returns `None` if `ch` does not begin a token.
+    fn read_token_starting_with(&mut self, ch: char) -> Option<Result<Token>> {
+        if self.starts_shebang(ch) {
+            return self.skip_shebang().err().map(Err);
+        }
+        if Some(ch) == self.config.get_line_comment() {
+            return self.skip_line().err().map(Err);
+        }
+        if self.starts_block_comment(ch) {
+            return self.skip_block_comment().err().map(Err);
+        }
+        if self.starts_raw_block(ch) {
+            let raw_start = self.span();
+            return Some(
+                self.read_raw_block()
+                    .map(|text| Token::Raw(text.into(), raw_start.merge(&self.span()))),
+            );
+        }
+
+        if let Some(definition) = self.macro_symbol_table.get(&ch) {
+            let limit = self.config.get_max_expansion_depth();
+            if definition.depth >= limit {
+                let chain = std::iter::once(ch)
+                    .chain(expansion_chain(&definition.tokens))
+                    .map(String::from)
+                    .collect::<Vec<String>>()
+                    .join(" > ");
+                return Some(Err(Error::MacroTooDeep {
+                    span: self.span(),
+                    macro_symbol: ch,
+                    depth: definition.depth + 1,
+                    limit,
+                    chain,
+                }));
+            }
+            let provenance = Provenance {
+                macro_symbol: ch,
+                definition: definition.span,
+                use_site: self.span(),
+            };
+            let mut expanded = definition
+                .tokens
+                .iter()
+                .map(|token| Token::Expanded(Box::new(token.clone()), Box::new(provenance)));
+            let first = expanded.next();
+            self.spliced.extend(expanded);
+            return first.map(Ok);
+        }
+
+        match self.config.get_field(&ch) {
+            Some(EscapePrefix) => {
+                let escape_start = self.span();
+                match self.next_char() {
+                    Some(Ok(escaped))
+                        if self.config.get_escape_in_groups() == EscapeInGroups::Literal
+                            && matches!(
+                                self.config.get_field(&escaped),
+                                Some(GroupStartDelimiter | GroupEndDelimiter)
+                            ) =>
+                    {
+                        Some(Ok(Token::Raw(
+                            escaped.to_string().into(),
+                            escape_start.merge(&self.span()),
+                        )))
+                    }
+                    // skip the next character
+                    _ => None,
+                }
+            }
+            Some(NumberPrefix) => {
+                let number_start = self.span();
+                match self.read_number() {
+                    // a counter can't be the number of repetitions
+                    Ok((number, base, _))
+                        if !matches!(base, NumberBase::Counter(_))
+                            && self.starts_counted_loop() =>
+                    {
+                        Some(self.read_counted_loop(number, number_start))
+                    }
+                    Ok((number, base, separators)) => Some(Ok(Token::Number(
+                        number,
+                        base,
+                        separators,
+                        number_start.merge(&self.span()),
+                    ))),
+                    Err(error) => Some(Err(error)),
+                }
+            }
+            Some(MacroPrefix) => match self.read_macro_definition() {
+                Ok(token) => token.map(Ok),
+                Err(error) => Some(Err(error)),
+            },
+            Some(GroupStartDelimiter) => {
+                let group_start = self.span();
+                match self.read_group(self.config.get_delimiter_pair(&ch)) {
+                    Ok(group) => Some(Ok(Token::Group(group, group_start.merge(&self.span())))),
+                    Err(error) => Some(Err(error)),
+                }
+            }
+            Some(GroupEndDelimiter) => {
+                let (group_start_delimiter, group_end_delimiter) =
+                    self.config.get_delimiter_pair(&ch);
+                Some(Err(Error::DelimiterUnopened {
+                    span: self.span(),
+                    group_start_delimiter,
+                    group_end_delimiter,
+                }))
+            }
+            Some(Operator) => Some(Ok(self.read_operator(ch))),
+            None if self.config.is_whitespace_operator(ch) => Some(Ok(self.read_operator(ch))),
+            None if self.starts_string(ch) => {
+                let string_start = self.span();
+                match self.read_string(string_start) {
+                    Ok(text) => Some(Ok(Token::Str(
+                        text.into(),
+                        string_start.merge(&self.span()),
+                    ))),
+                    Err(error) => Some(Err(error)),
+                }
+            }
+            None if self.starts_cell(ch) => Some(self.read_cell()),
+            None if self.config.is_skippable(ch) => {
+                if let Some(unrecognized) = &mut self.unrecognized {
+                    unrecognized.skippable += 1;
+                }
+                None
+            }
+            None => self.unrecognized_char(ch),
+        }
+    }
+
+    /// Turn the already consumed `ch` into an operator.
+    fn read_operator(&mut self, ch: char) -> Token {
+        let operator = self.config.resolve(ch);
+        let span = self.span();
+        if let Some(coverage) = &mut self.coverage {
+            coverage.record(operator, span);
+        }
+        Token::Operator(operator, span)
+    }
+
+    /// Check whether the already consumed `ch`, not assigned to any field,
+    /// starts the move to a named cell.
+    fn starts_cell(&mut self, ch: char) -> bool {
+        ch == CELL_SYMBOL
+            && self.config.has_named_cells()
+            && matches!(self.char_iter.peek(), Some(Ok(next)) if next.is_alphabetic())
+    }
+
+    /// Read the name of the cell moved to, after the cell symbol.
+    fn read_cell(&mut self) -> Result<Token> {
+        let cell_start = self.span();
+        let name = self.read_name()?;
+        let span = cell_start.merge(&self.span());
+        match self.cells.get(&name) {
+            Some(cell) => Ok(Token::Cell(Arc::clone(cell), span)),
+            None => Err(Error::CellUndefined { span, name }),
+        }
+    }
+
+    /// Check whether the already consumed `ch`, not assigned to any field, starts a string.
+    fn starts_string(&self, ch: char) -> bool {
+        ch == STRING_DELIMITER && self.config.has_strings()
+    }
+
+    /// Read the text of a string started at `string_start`, up to and including
+    /// its end delimiter.
+    ///
+    /// The escape prefix followed by `n` or `t` is read as a newline or tab,
+    /// followed by any other `char` *(e.g. the delimiter)* as that `char`.
+    fn read_string(&mut self, string_start: Span) -> Result<String> {
+        let escape_prefix = *self.config.get_value(&EscapePrefix);
+        let unclosed = || Error::StringUnclosed { span: string_start };
+
+        let mut text = String::new();
+        loop {
+            match self.next_char().transpose()?.ok_or_else(unclosed)? {
+                STRING_DELIMITER => return Ok(text),
+                ch if ch == escape_prefix => {
+                    match self.next_char().transpose()?.ok_or_else(unclosed)? {
+                        'n' => text.push('\n'),
+                        't' => text.push('\t'),
+                        escaped => text.push(escaped),
+                    }
+                }
+                ch => text.push(ch),
+            }
+        }
+    }
+
+    /// Skip the rest of the line, up to the newline.
+    fn skip_line(&mut self) -> Result<()> {
+        while !matches!(self.char_iter.peek(), Some(Ok('\n')) | None) {
+            self.next_char().transpose()?;
+        }
+
+        Ok(())
+    }
+
+    /// Check whether the already consumed `ch` is the first char
+    /// of the input, followed by the `!` of a shebang.
+    fn starts_shebang(&mut self, ch: char) -> bool {
+        ch == '#' && self.next_offset == 1 && matches!(self.char_iter.peek(), Some(Ok('!')))
+    }
+
+    /// Skip the shebang line, including its newline.
+    fn skip_shebang(&mut self) -> Result<()> {
+        self.skip_line()?;
+        self.next_char().transpose()?;
+
+        Ok(())
+    }
+
+    /// Check whether the already consumed `ch` starts a block comment,
+    /// consuming the rest of its start delimiter if it does.
+    fn starts_block_comment(&mut self, ch: char) -> bool {
+        match self.config.get_block_comment() {
+            Some((start, _)) => self.starts_delimiter(ch, start),
+            None => false,
+        }
+    }
+
+    /// Check whether the already consumed `ch` starts a raw block,
+    /// consuming the rest of its start delimiter if it does.
+    fn starts_raw_block(&mut self, ch: char) -> bool {
+        match self.config.get_raw_block() {
+            Some((start, _)) => self.starts_delimiter(ch, start),
+            None => false,
+        }
+    }
+
+    /// Check whether the already consumed `ch` starts the `start` delimiter
+    /// of one or two chars, consuming the rest of it if it does.
+    fn starts_delimiter(&mut self, ch: char, start: &str) -> bool {
+        let mut start = start.chars();
+        if start.next() != Some(ch) {
+            return false;
+        }
+        match start.next() {
+            None => true,
+            Some(second) if matches!(self.char_iter.peek(), Some(Ok(next)) if *next == second) => {
+                self.next_char();
+                true
+            }
+            Some(_) => false,
+        }
+    }
+
+    /// Skip a block comment after its start delimiter, up to and including its end.
+    fn skip_block_comment(&mut self) -> Result<()> {
+        let comment_start = self.span();
+        let (_, end) = self
+            .config
+            .get_block_comment()
+            .expect("A block comment should be configured.");
+
+        match self.read_until(end, |_| ())? {
+            true => Ok(()),
+            false => Err(Error::CommentUnclosed {
+                span: comment_start,
+                comment_end: end.to_string(),
+            }),
+        }
+    }
+
+    /// Read the text of a raw block after its start delimiter,
+    /// up to its end, which is consumed, but not included.
+    fn read_raw_block(&mut self) -> Result<String> {
+        let raw_start = self.span();
+        let (_, end) = self
+            .config
+            .get_raw_block()
+            .expect("A raw block should be configured.");
+
+        let mut text = String::new();
+        match self.read_until(end, |ch| text.push(ch))? {
+            true => Ok(text),
+            false => Err(Error::RawUnclosed {
+                span: raw_start,
+                raw_end: end.to_string(),
+            }),
+        }
+    }
+
+    /// Read the input up to and including the `end` delimiter of one or two chars,
+    /// passing every char before it to `on_char`.
+    ///
+    /// Returns whether the delimiter was found before the end of input.
+    fn read_until<F: FnMut(char)>(&mut self, end: &str, mut on_char: F) -> Result<bool> {
+        let end: Vec<char> = end.chars().collect();
+        // last char read, held back while it can start the delimiter
+        let mut previous: Option<char> = None;
+        while let Some(ch) = self.next_char().transpose()? {
+            match end[..] {
+                [last] if ch == last => return Ok(true),
+                [_] => on_char(ch),
+                [first, last] if previous == Some(first) && ch == last => return Ok(true),
+                [_, _] => {
+                    if let Some(previous) = previous.replace(ch) {
+                        on_char(previous);
+                    }
+                }
+                _ => unreachable!("Block delimiters should be one or two chars."),
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Handle a `char` that is not recognized, according to the [`UnrecognizedPolicy`].
+    fn unrecognized_char(&mut self, ch: char) -> Option<Result<Token>> {
+        let span = self.span();
+        if let Some(unrecognized) = &mut self.unrecognized {
+            unrecognized.record(ch, span);
+        }
+
+        match self.config.get_unrecognized_policy() {
+            UnrecognizedPolicy::Skip => None,
+            UnrecognizedPolicy::Warn => {
+                self.warnings.push(Warning::CharUnrecognized {
+                    span: self.span(),
+                    ch,
+                });
+                None
+            }
+            UnrecognizedPolicy::Deny => Some(Err(Error::CharUnrecognized {
+                span: self.span(),
+                ch,
+            })),
+        }
+    }
+
+    /// Try to read a number from input, in base 10
+    /// unless marked otherwise *(see [`NumberBase`])*.
+    fn read_number(&mut self) -> Result<(usize, NumberBase, Separators)> {
+        const NUMBER_STOR_INIT_SIZE: usize = 8;
+
+        if let Some(Ok('\'')) = self.char_iter.peek() {
+            return self.read_char_literal();
+        }
+        if let Some(&Ok(next_ch)) = self.char_iter.peek() {
+            if self.counters.contains(&next_ch) {
+                self.next_char();
+                return Ok((0, NumberBase::Counter(next_ch), Separators::default()));
+            }
+            if next_ch.is_alphabetic() && self.config.has_constants() {
+                let number_start = self.span();
+                let name = self.read_name()?;
+                return match self.constants.get(&name) {
+                    Some(value) => Ok((*value, NumberBase::Decimal, Separators::default())),
+                    None => Err(Error::ConstantUndefined {
+                        span: number_start.merge(&self.span()),
+                        name,
+                    }),
+                };
+            }
+        }
+
+        let mut number_string = String::with_capacity(NUMBER_STOR_INIT_SIZE);
+        let mut base = NumberBase::Decimal;
+        // number of digits before every separator
+        let mut separated: Vec<usize> = Vec::new();
+
+        // the end of input isn't consumed, so the span ends at the last digit
+        while self.char_iter.peek().is_some() {
+            if let Some(&Ok(next_ch)) = self.char_iter.peek() {
+                let marked_base = (number_string == "0" && base == NumberBase::Decimal)
+                    .then(|| NumberBase::from_marker(next_ch))
+                    .flatten();
+                if let Some(marked_base) = marked_base {
+                    base = marked_base;
+                    number_string.clear();
+                    separated.clear();
+                    self.next_char();
+                    continue;
+                }
+                if !number_string.is_empty() && Some(next_ch) == self.config.get_number_separator()
+                {
+                    separated.push(number_string.chars().count());
+                    self.next_char();
+                    continue;
+                }
+                if !next_ch.is_digit(base.radix()) && self.unicode_digit(next_ch, base).is_none() {
+                    break;
+                }
             }
 
             match self.next_char() {
@@ -245,222 +1890,1755 @@ where
             }
         }
 
-        if let Ok(number) = number_string.parse::<usize>() {
-            Ok(number)
-        } else {
-            Err(Error::NumberMissing {
-                lineno: self.lineno,
-                colno: self.colno,
-                number_prefix: *self.config.get_value(&NumberPrefix),
-            })
-        }
+        // only an ASCII zero is followed by a base marker, so the digits are read as ASCII last
+        let number_string: String = number_string
+            .chars()
+            .map(|ch| self.unicode_digit(ch, base).unwrap_or(ch))
+            .collect();
+        if let Ok(number) = usize::from_str_radix(&number_string, base.radix()) {
+            let digits = number_string.chars().count();
+            Ok((number, base, Separators::new(digits, separated)))
+        } else {
+            Err(Error::NumberMissing {
+                span: self.span(),
+                number_prefix: *self.config.get_value(&NumberPrefix),
+            })
+        }
+    }
+
+    /// Get the ASCII digit with the value of a non-ASCII decimal digit `ch`,
+    /// if they're allowed and the number is in base 10.
+    fn unicode_digit(&self, ch: char, base: NumberBase) -> Option<char> {
+        if ch.is_ascii() || base != NumberBase::Decimal || !self.config.has_unicode_digits() {
+            return None;
+        }
+
+        decimal_digit(ch).and_then(|value| char::from_digit(value, 10))
+    }
+
+    /// Read the codepoint of a char literal *(`'A'`)*, the char
+    /// between the quotes can be any, including a quote.
+    fn read_char_literal(&mut self) -> Result<(usize, NumberBase, Separators)> {
+        self.next_char();
+        let literal = match self.next_char().transpose()? {
+            Some(ch) => match self.next_char().transpose()? {
+                Some('\'') => Some(ch),
+                _ => None,
+            },
+            None => None,
+        };
+
+        literal
+            .map(|ch| (ch as usize, NumberBase::Char, Separators::default()))
+            .ok_or_else(|| Error::CharLiteralUnclosed {
+                span: self.span(),
+                number_prefix: *self.config.get_value(&NumberPrefix),
+            })
+    }
+
+    /// Check whether the number just read is followed by the [`COUNTER_SYMBOL`]
+    /// of a counted loop, if they're allowed.
+    fn starts_counted_loop(&mut self) -> bool {
+        self.config.has_counted_loops() && matches!(self.char_iter.peek(), Some(Ok(COUNTER_SYMBOL)))
+    }
+
+    /// Read the counter and the group of a counted loop after its `number`,
+    /// returning the group unrolled `number` times, with the counter read
+    /// as the number of every repetition.
+    ///
+    /// A group not using the counter is just multiplied by the `number`.
+    fn read_counted_loop(&mut self, number: usize, number_start: Span) -> Result<Token> {
+        let number_span = number_start.merge(&self.span());
+        let missing = |lexer: &Self| Error::CounterMissing {
+            span: number_start.merge(&lexer.span()),
+            number_prefix: *lexer.config.get_value(&NumberPrefix),
+        };
+        self.next_char();
+        let counter = match self.next_char().transpose()? {
+            Some(ch) => ch,
+            None => return Err(missing(self)),
+        };
+
+        self.counters.push(counter);
+        let body = self.read_single_token();
+        self.counters.pop();
+        let body = match body {
+            Some(Ok(token)) if matches!(token.without_provenance(), Token::Group(..)) => token,
+            Some(Err(error)) => return Err(error),
+            _ => return Err(missing(self)),
+        };
+
+        let span = number_start.merge(&self.span());
+        if !uses_counter(&body, counter) {
+            let multiplier = Token::Number(
+                number,
+                NumberBase::Decimal,
+                Separators::default(),
+                number_span,
+            );
+            return Ok(Token::Group([multiplier, body].into(), span));
+        }
+        let unrolled: Group = (0..number)
+            .map(|value| substitute_counter(&body, counter, value))
+            .collect();
+
+        Ok(Token::Group(unrolled, span))
+    }
+
+    /// Try to read a macro definition and set it into the symbol table.
+    ///
+    /// If the definition is cut off by the end of input and the
+    /// [`EofPolicy`] is lenient, the macro symbol is lexed as if the prefix
+    /// was a literal, possibly returning a [`Token`].
+    fn read_macro_definition(&mut self) -> Result<Option<Token>> {
+        let macro_start = self.span();
+        let macro_symbol = match self.next_char() {
+            Some(Ok(ch)) => ch,
+            Some(Err(error)) => return Err(error),
+            None => return self.dangling_macro_prefix(None),
+        };
+
+        if macro_symbol == INCLUDE_SYMBOL && self.config.has_includes() {
+            return self.read_include(macro_start);
+        }
+        if macro_symbol == CONDITIONAL_SYMBOL && self.config.has_conditionals() {
+            return self.read_conditional(macro_start);
+        }
+        if macro_symbol == CONSTANT_SYMBOL && self.config.has_constants() {
+            return self.read_constant(macro_start);
+        }
+        if macro_symbol == CELL_SYMBOL && self.config.has_named_cells() {
+            return self.read_cell_definition(macro_start);
+        }
+
+        if self.config.has_line_macros() {
+            while let Some(Ok(' ' | '\t')) = self.char_iter.peek() {
+                self.next_char();
+            }
+        }
+
+        self.defining.push(macro_symbol);
+        let body =
+            if self.config.has_line_macros() && matches!(self.char_iter.peek(), Some(Ok('='))) {
+                self.next_char();
+                self.read_line_macro_body(macro_start).map(Some)
+            } else {
+                match self.read_single_token() {
+                    // the whole body of a spliced macro
+                    Some(Ok(token)) => Ok(Some(
+                        std::iter::once(token)
+                            .chain(self.spliced.drain(..))
+                            .collect(),
+                    )),
+                    Some(Err(error)) => Err(error),
+                    None => Ok(None),
+                }
+            };
+        self.defining.pop();
+        let macro_tokens = match body? {
+            Some(tokens) => tokens,
+            None => return self.dangling_macro_prefix(Some(macro_symbol)),
+        };
+
+        let span = macro_start.merge(&self.span());
+        let definition = MacroDefinition::new(macro_symbol, macro_tokens, span);
+        if let Some(budget) = self.config.get_macro_budget(&macro_symbol) {
+            let size = definition.expanded_len();
+            if size > budget {
+                match self.config.get_budget_policy() {
+                    BudgetPolicy::Warn => self.warnings.push(Warning::MacroOverBudget {
+                        span,
+                        macro_symbol,
+                        size,
+                        budget,
+                    }),
+                    BudgetPolicy::Deny => {
+                        return Err(Error::MacroOverBudget {
+                            span,
+                            macro_symbol,
+                            size,
+                            budget,
+                        })
+                    }
+                }
+            }
+        }
+
+        self.store_macro(&definition)?;
+        if let Some(macro_definitions) = &mut self.macro_definitions {
+            macro_definitions.push(definition.clone());
+        }
+        self.macro_symbol_table.insert(macro_symbol, definition);
+
+        Ok(None)
+    }
+
+    /// Read the macro symbol and the token of a conditional, returning
+    /// the token if the macro is defined.
+    ///
+    /// Otherwise, the token is read and dropped with the macros,
+    /// constants and cells defined within it.
+    fn read_conditional(&mut self, macro_start: Span) -> Result<Option<Token>> {
+        let missing = |lexer: &Self| Error::ConditionalMissing {
+            span: macro_start.merge(&lexer.span()),
+            macro_prefix: *lexer.config.get_value(&MacroPrefix),
+        };
+        let macro_symbol = match self.next_char().transpose()? {
+            Some(ch) => ch,
+            None => return Err(missing(self)),
+        };
+
+        let defined = self.macro_symbol_table.contains_key(&macro_symbol);
+        let macros = (!defined).then(|| {
+            (
+                self.macro_symbol_table.clone(),
+                self.macro_definitions.as_ref().map(Vec::len),
+                self.defined_macros,
+                self.macro_table_size,
+                self.constants.clone(),
+                self.cells.clone(),
+            )
+        });
+        let token = match self.read_single_token() {
+            Some(token) => token?,
+            None => return Err(missing(self)),
+        };
+
+        match macros {
+            None => Ok(Some(token)),
+            Some((
+                macro_symbol_table,
+                macro_definitions,
+                defined_macros,
+                macro_table_size,
+                constants,
+                cells,
+            )) => {
+                self.spliced.clear();
+                self.macro_symbol_table = macro_symbol_table;
+                if let (Some(kept), Some(len)) = (&mut self.macro_definitions, macro_definitions) {
+                    kept.truncate(len);
+                }
+                self.defined_macros = defined_macros;
+                self.macro_table_size = macro_table_size;
+                self.constants = constants;
+                self.cells = cells;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Read the name and the number of a constant, defining it.
+    fn read_constant(&mut self, macro_start: Span) -> Result<Option<Token>> {
+        let missing = |lexer: &Self| Error::ConstantMissing {
+            span: macro_start.merge(&lexer.span()),
+            macro_prefix: *lexer.config.get_value(&MacroPrefix),
+        };
+        let (name, value) = self.read_named_number().ok_or_else(|| missing(self))??;
+        self.constants.insert(name, value);
+
+        Ok(None)
+    }
+
+    /// Read the name and the index of a named cell, defining it.
+    fn read_cell_definition(&mut self, macro_start: Span) -> Result<Option<Token>> {
+        let missing = |lexer: &Self| Error::CellMissing {
+            span: macro_start.merge(&lexer.span()),
+            macro_prefix: *lexer.config.get_value(&MacroPrefix),
+        };
+        let (name, index) = self.read_named_number().ok_or_else(|| missing(self))??;
+        self.cells
+            .insert(name.clone(), Arc::new(NamedCell { name, index }));
+
+        Ok(None)
+    }
+
+    /// Read a name followed by a number, returning `None`
+    /// if either is missing.
+    fn read_named_number(&mut self) -> Option<Result<(String, usize)>> {
+        let name = match self.read_name() {
+            Ok(name) if name.is_empty() => return None,
+            Ok(name) => name,
+            Err(error) => return Some(Err(error)),
+        };
+
+        match self.read_single_token()? {
+            Ok(token) => match token.without_provenance() {
+                Token::Number(value, base, ..) if !matches!(base, NumberBase::Counter(_)) => {
+                    Some(Ok((name, *value)))
+                }
+                _ => None,
+            },
+            Err(error) => Some(Err(error)),
+        }
+    }
+
+    /// Read the name of a constant or cell, a letter followed by letters, digits or `_`,
+    /// empty if the input doesn't start with a letter.
+    fn read_name(&mut self) -> Result<String> {
+        let mut name = String::new();
+        while let Some(&Ok(ch)) = self.char_iter.peek() {
+            let continues = !name.is_empty() && (ch.is_alphanumeric() || ch == '_');
+            if !ch.is_alphabetic() && !continues {
+                break;
+            }
+            self.next_char().transpose()?;
+            name.push(ch);
+        }
+
+        Ok(name)
+    }
+
+    /// Count the `definition` into the macro table,
+    /// checking it stays within the limits of the [`Config`].
+    fn store_macro(&mut self, definition: &MacroDefinition) -> Result<()> {
+        let macros = self.defined_macros + 1;
+        let size = self.macro_table_size + definition.stored_len();
+        let max_macros = self.config.get_max_macros();
+        let max_size = self.config.get_max_macro_table_size();
+        if macros > max_macros || size > max_size {
+            return Err(Error::MacroTableOverflow {
+                span: definition.span,
+                macro_symbol: definition.symbol,
+                macros,
+                size,
+                max_macros,
+                max_size,
+            });
+        }
+
+        self.defined_macros = macros;
+        self.macro_table_size = size;
+        Ok(())
+    }
+
+    /// Read the tokens of a line macro until the end of the line,
+    /// the newline itself is consumed.
+    fn read_line_macro_body(&mut self, macro_start: Span) -> Result<Vec<Token>> {
+        let mut body: Vec<Token> = Vec::new();
+        loop {
+            let ch = match self.next_char() {
+                Some(Ok('\n')) | None => break,
+                Some(Ok(ch)) => ch,
+                Some(Err(error)) => return Err(error),
+            };
+
+            match self.read_token_starting_with(ch) {
+                Some(Ok(token)) => {
+                    body.push(token);
+                    body.extend(self.spliced.drain(..));
+                }
+                Some(Err(error)) => return Err(error),
+                None => (),
+            }
+        }
+
+        if body.is_empty() {
+            return Err(Error::MacroMissing {
+                span: macro_start.merge(&self.span()),
+                macro_prefix: *self.config.get_value(&MacroPrefix),
+            });
+        }
+
+        Ok(body)
+    }
+
+    /// Lex the file of an include, defining its macros and
+    /// returning the first of its tokens, while the rest is spliced.
+    ///
+    /// The warnings of the included file are left out, as their spans
+    /// point into [`SourceId::INCLUDED`].
+    fn read_include(&mut self, macro_start: Span) -> Result<Option<Token>> {
+        let path = self.read_include_path(macro_start)?;
+        let span = macro_start.merge(&self.span());
+        let failed = |message: String| Error::IncludeFailed {
+            span,
+            path: path.clone(),
+            message,
+        };
+
+        let full_path = match &self.include_dir {
+            Some(dir) => dir.join(&path),
+            None => PathBuf::from(&path),
+        };
+        let canonical_path =
+            fs::canonicalize(&full_path).map_err(|error| failed(error.to_string()))?;
+        if self.included.contains(&canonical_path) {
+            return Err(failed(String::from("the file includes itself")));
+        }
+        let text = fs::read_to_string(&full_path).map_err(|error| failed(error.to_string()))?;
+
+        let mut lexer = Lexer::new(text.chars().map(Ok::<char, Infallible>), self.config)
+            .with_source(SourceId::INCLUDED)
+            .with_macros(self.macro_symbol_table.values().cloned())
+            .keep_macro_definitions();
+        lexer.cancellation = self.cancellation;
+        lexer.include_dir = full_path.parent().map(Path::to_path_buf);
+        lexer.included = self.included.clone();
+        lexer.included.push(canonical_path);
+        // the included macros count towards the same table
+        lexer.defined_macros = self.defined_macros;
+        lexer.macro_table_size = self.macro_table_size;
+        lexer.constants = self.constants.clone();
+        lexer.cells = self.cells.clone();
+        let tokens = match lexer.read_all_tokens() {
+            Ok(tokens) => tokens,
+            Err(Error::Cancelled(error)) => return Err(Error::Cancelled(error)),
+            Err(error) => return Err(failed(error.to_string())),
+        };
+
+        self.defined_macros = lexer.defined_macros;
+        self.macro_table_size = lexer.macro_table_size;
+        self.constants = std::mem::take(&mut lexer.constants);
+        self.cells = std::mem::take(&mut lexer.cells);
+        for definition in lexer.take_macro_definitions() {
+            if let Some(macro_definitions) = &mut self.macro_definitions {
+                macro_definitions.push(definition.clone());
+            }
+            self.macro_symbol_table
+                .insert(definition.symbol, definition);
+        }
+
+        let mut tokens = tokens.into_iter();
+        let first = tokens.next();
+        self.spliced.extend(tokens);
+
+        Ok(first)
+    }
+
+    /// Read the path of an include, quoted with `"` within a single line.
+    fn read_include_path(&mut self, macro_start: Span) -> Result<String> {
+        while let Some(Ok(' ' | '\t')) = self.char_iter.peek() {
+            self.next_char();
+        }
+
+        let mut path = String::new();
+        let mut quoted = false;
+        loop {
+            match self.next_char() {
+                Some(Ok('"')) if !quoted => quoted = true,
+                Some(Ok('"')) if !path.is_empty() => return Ok(path),
+                Some(Ok(ch)) if quoted && ch != '"' && ch != '\n' => path.push(ch),
+                Some(Err(error)) => return Err(error),
+                _ => {
+                    return Err(Error::IncludeMissing {
+                        span: macro_start.merge(&self.span()),
+                        macro_prefix: *self.config.get_value(&MacroPrefix),
+                    })
+                }
+            }
+        }
+    }
+
+    /// Handle a macro prefix not followed by a whole definition,
+    /// according to the [`EofPolicy`].
+    fn dangling_macro_prefix(&mut self, macro_symbol: Option<char>) -> Result<Option<Token>> {
+        let macro_prefix = *self.config.get_value(&MacroPrefix);
+
+        if self.config.get_eof_policy() == EofPolicy::Strict {
+            return Err(Error::MacroMissing {
+                span: self.span(),
+                macro_prefix,
+            });
+        }
+
+        self.warnings.push(Warning::MacroDangling {
+            span: self.span(),
+            macro_prefix,
+        });
+
+        match macro_symbol.and_then(|ch| self.read_token_starting_with(ch)) {
+            Some(Ok(token)) => Ok(Some(token)),
+            Some(Err(error)) => Err(error),
+            None => Ok(None),
+        }
+    }
+
+    /// Try to read a group delimited by the `(start, end)` pair,
+    /// yields [`Error::Group`] on error.
+    ///
+    /// End delimiters of the other pairs are errors within the group.
+    fn read_group(
+        &mut self,
+        (group_start_delimiter, group_end_delimiter): (char, char),
+    ) -> Result<Group> {
+        const GROUP_STOR_INIT_SIZE: usize = 16;
+
+        let group_start = self.span();
+        let mut group_tokens: Vec<Token> = Vec::with_capacity(GROUP_STOR_INIT_SIZE);
+        let mut errors: Vec<Error> = Vec::new();
+        loop {
+            match self.read_token() {
+                Some(Ok(token)) => group_tokens.push(token),
+                Some(Err(Error::DelimiterUnopened {
+                    group_end_delimiter: end,
+                    ..
+                })) if end == group_end_delimiter => break,
+                Some(Err(Error::Input(error))) => return Err(Error::Input(error)),
+                Some(Err(Error::Cancelled(error))) => return Err(Error::Cancelled(error)),
+                Some(Err(error)) => errors.push(error),
+                None if self.config.get_eof_policy() == EofPolicy::Lenient => {
+                    self.warnings.push(Warning::DelimiterUnclosed {
+                        span: self.span(),
+                        group_end_delimiter,
+                    });
+                    break;
+                }
+                None => {
+                    errors.push(Error::DelimiterUnclosed {
+                        span: self.span(),
+                        group_start_delimiter,
+                        group_end_delimiter,
+                    });
+                    break;
+                }
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(Error::Group(ErrorGroup::new(errors)));
+        }
+
+        if !group_tokens.is_empty() || self.config.allows_empty_groups() {
+            Ok(group_tokens.into())
+        } else {
+            Err(Error::GroupEmpty {
+                span: group_start.merge(&self.span()),
+                macro_symbol: self.defining.last().copied(),
+                group_start_delimiter,
+                group_end_delimiter,
+            })
+        }
+    }
+
+    /// Get the [`Span`] of the last read `char`.
+    fn span(&self) -> Span {
+        Span::at(self.source, self.position)
+    }
+
+    /// Advance the input iterator.
+    fn next_char(&mut self) -> Option<Result<char>> {
+        if let Some(Err(error)) = self.cancellation.map(CancellationToken::check) {
+            return Some(Err(Error::Cancelled(error)));
+        }
+
+        let next_char = self.char_iter.next();
+
+        self.position.offset = self.next_offset;
+        self.position.colno += 1;
+
+        match next_char {
+            Some(Ok('\n')) => {
+                self.next_offset += 1;
+                self.position.lineno += 1;
+                self.position.colno = 0;
+                Some(Ok('\n'))
+            }
+            Some(Ok(ch)) => {
+                self.next_offset += 1;
+                Some(Ok(ch))
+            }
+            Some(Err(error)) => Some(Err(Error::Input(Box::new(error)))),
+            None => None,
+        }
+    }
+}
+
+impl<'a, I, E> Iterator for Lexer<'a, I, E>
+where
+    E: ErrorTrait + Send + Sync + 'static,
+    I: Iterator<Item = StdResult<char, E>>,
+{
+    type Item = Result<Token>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.read_token()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+
+    use anyhow::Result;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::testing;
+    use bfup_derive::as_char_results;
+
+    #[test]
+    fn lex_operator() -> Result<()> {
+        let input = as_char_results!('+');
+        let token = Lexer::new(input.into_iter(), &Config::default())
+            .next()
+            .expect("The lexer should not be empty.")?;
+
+        assert!(
+            matches!(token, Token::Operator('+', _)),
+            "Operators don't match."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_number() -> Result<()> {
+        let input = as_char_results!("#2137");
+        let token = Lexer::new(input.into_iter(), &Config::default())
+            .next()
+            .expect("The lexer should not be empty.")?;
+
+        assert!(
+            matches!(token, Token::Number(2137, NumberBase::Decimal, _, span)
+                if span.start.colno == 1 && span.end.colno == 5),
+            "Numbers don't match: {token:?}."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_number_at_end_of_input() -> Result<()> {
+        let config = Config::default();
+        let tokens = Lexer::new(as_char_results!("+#12").into_iter(), &config).read_all_tokens()?;
+        assert!(
+            matches!(&tokens[..], [Token::Operator('+', _), Token::Number(12, _, _, span)]
+                if span.start.offset == 1 && span.end.offset == 3 && span.end.colno == 4),
+            "The number should end at its last digit: {tokens:?}."
+        );
+
+        let tokens =
+            Lexer::new(as_char_results!("#0x1f_").into_iter(), &config).read_all_tokens()?;
+        assert!(
+            matches!(&tokens[..], [Token::Number(0x1f, NumberBase::Hexadecimal, _, span)]
+                if span.end.offset == 5),
+            "The number should end at its trailing separator: {tokens:?}."
+        );
+
+        let result = Lexer::new(as_char_results!("+#0x").into_iter(), &config).read_all_tokens();
+        assert!(
+            result.is_err(),
+            "A base marker without digits shouldn't be a number."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_number_separators() -> Result<()> {
+        let mut config = Config::default();
+        let input = as_char_results!("#1_000_000+#0xff_ff_+#_1+");
+        let tokens = Lexer::new(input.into_iter(), &config).read_all_tokens();
+        assert!(tokens.is_err(), "A separator shouldn't be the first digit.");
+
+        let input = as_char_results!("#1_000_000+#0xff_ff_+");
+        let tokens = Lexer::new(input.into_iter(), &config).read_all_tokens()?;
+        let source: String = tokens
+            .iter()
+            .map(|token| token.to_source(&config))
+            .collect();
+        assert!(
+            matches!(
+                tokens[..],
+                [
+                    Token::Number(1_000_000, NumberBase::Decimal, ..),
+                    Token::Operator('+', _),
+                    Token::Number(0xffff, NumberBase::Hexadecimal, ..),
+                    Token::Operator('+', _),
+                ]
+            ) && source == "#1_000_000+#0xff_ff_+",
+            "The separators should be skipped and written back: {tokens:?}."
+        );
+
+        config.set_number_separator(None);
+        let tokens =
+            Lexer::new(as_char_results!("#1_0+").into_iter(), &config).read_all_tokens()?;
+        assert!(
+            matches!(tokens[..], [Token::Number(1, ..), Token::Operator('+', _)]),
+            "The separator should end the number when unset: {tokens:?}."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_unicode_digits() -> Result<()> {
+        let mut config = Config::default();
+        let result = Lexer::new(as_char_results!("#٣+").into_iter(), &config).read_all_tokens();
+        assert!(
+            result.is_err(),
+            "Only ASCII digits should be read by default."
+        );
+
+        config.set_unicode_digits(true);
+        let input = as_char_results!("#١2+#०x10");
+        let tokens = Lexer::new(input.into_iter(), &config).read_all_tokens()?;
+        assert!(
+            matches!(
+                tokens[..],
+                [
+                    Token::Number(12, NumberBase::Decimal, ..),
+                    Token::Operator('+', _),
+                    Token::Number(0, NumberBase::Decimal, ..),
+                ]
+            ),
+            "Numbers don't match: {tokens:?}."
+        );
+        assert!(
+            decimal_digit('\u{669}') == Some(9)
+                && decimal_digit('\u{66A}').is_none()
+                && decimal_digit('\u{1D7FF}') == Some(9)
+                && decimal_digit('a').is_none(),
+            "Only the decimal digits should have a value."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_number_to_source() {
+        let config = Config::default();
+        let span = Span::at(SourceId::COMMAND_LINE, Position::new(0, 1, 1));
+        let tokens = [
+            Token::Number(31, NumberBase::Hexadecimal, Separators::default(), span),
+            Token::Number(5, NumberBase::Binary, Separators::default(), span),
+            Token::Number(15, NumberBase::Octal, Separators::default(), span),
+            Token::Number(0, NumberBase::Decimal, Separators::default(), span),
+        ];
+
+        let source: String = tokens
+            .iter()
+            .map(|token| token.to_source(&config))
+            .collect();
+        assert!(
+            source == "#0x1f#0b101#0o17#0",
+            "\"{source}\" should be written in the bases of the numbers."
+        );
+    }
+
+    #[test]
+    fn lex_number_bases() -> Result<()> {
+        let config = Config::default();
+        let input = as_char_results!("#0x1f#0b101#0o17#0");
+        let tokens = Lexer::new(input.into_iter(), &config).read_all_tokens()?;
+
+        assert!(
+            matches!(
+                tokens[..],
+                [
+                    Token::Number(31, NumberBase::Hexadecimal, ..),
+                    Token::Number(5, NumberBase::Binary, ..),
+                    Token::Number(15, NumberBase::Octal, ..),
+                    Token::Number(0, NumberBase::Decimal, ..),
+                ]
+            ),
+            "Numbers don't match: {tokens:?}."
+        );
+
+        let source: String = tokens
+            .iter()
+            .map(|token| token.to_source(&config))
+            .collect();
+        assert!(
+            source == "#0x1f#0b101#0o17#0",
+            "\"{source}\" should keep the bases."
+        );
+        testing::assert_error("#0b2+", &config, "must be followed by number");
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_char_literals() -> Result<()> {
+        let config = Config::default();
+        let tokens = testing::tokens("#'A'+ #'''-", &config)?;
+        let source: String = tokens
+            .iter()
+            .map(|token| token.to_source(&config))
+            .collect();
+        assert!(
+            matches!(tokens[..2], [Token::Number(65, NumberBase::Char, ..), _])
+                && source == "#'A'+#'''-",
+            "{tokens:?} should read the codepoints of the chars."
+        );
+        let output = testing::preprocessed("#'\u{3}'(+>)", &config)?;
+        assert!(
+            output == "+>+>+>",
+            "\"{output}\" should be repeated by the codepoint of the char."
+        );
+        testing::assert_error(
+            "#'AB'+",
+            &config,
+            "char literal after '#' must be a single char",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_group() -> Result<()> {
+        let input = as_char_results!("(#42-)");
+        let token = Lexer::new(input.into_iter(), &Config::default())
+            .next()
+            .expect("The lexer should not be empty.")?;
+
+        if let Token::Group(group, _) = token {
+            match group.first() {
+                Some(Token::Number(42, NumberBase::Decimal, ..)) => (),
+                _ => panic!("Numbers don't match."),
+            }
+            match group.get(1) {
+                Some(Token::Operator('-', _)) => (),
+                _ => panic!("Operators don't match."),
+            }
+        } else {
+            panic!("The token should be Token::Group.")
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_delimiter_pairs() -> Result<()> {
+        let mut config = Config::new("+-".chars(), '(', ')', '#', '$', '\\')?;
+        config.set_extra_delimiters([('{', '}')])?;
+
+        let input = as_char_results!("{+(-)}(+{-})");
+        let tokens = Lexer::new(input.into_iter(), &config).read_all_tokens()?;
+        assert!(
+            matches!(tokens[..], [Token::Group(..), Token::Group(..)]),
+            "Both pairs should delimit groups."
+        );
+
+        let input = as_char_results!("{+)");
+        let result = Lexer::new(input.into_iter(), &config).read_all_tokens();
+        assert!(
+            result.is_err(),
+            "Groups closed by another pair should be errors."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_scan_delimiters() -> Result<()> {
+        let config = Config::default();
+        Lexer::new(as_char_results!("(+\\)$)-)").into_iter(), &config).scan_delimiters()?;
+
+        let result = Lexer::new(as_char_results!("(+))(").into_iter(), &config).scan_delimiters();
+        assert!(
+            matches!(&result, Err(Error::DelimiterUnopened { span, .. }) if span.start.colno == 4),
+            "The first surplus ')' should be reported, got {result:?}."
+        );
+
+        let result = Lexer::new(as_char_results!("(+(-)").into_iter(), &config).scan_delimiters();
+        assert!(
+            matches!(&result, Err(Error::DelimiterUnclosed { span, .. }) if span.start.colno == 1),
+            "The outermost unclosed '(' should be reported, got {result:?}."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_macro() -> Result<()> {
+        let input = as_char_results!("$m+m");
+        let token = Lexer::new(input.into_iter(), &Config::default())
+            .next()
+            .expect("The lexer should not be empty.")?;
+
+        assert!(
+            matches!(token.without_provenance(), Token::Operator('+', _)),
+            "Operators don't match."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_escape() -> Result<()> {
+        let input = as_char_results!("thiswillnotbelexed\\+\\#\\(\\)");
+        let token = Lexer::new(input.into_iter(), &Config::default()).next();
+
+        assert!(token.is_none(), "The lexer should be empty");
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_escaped_delimiters() -> Result<()> {
+        let mut config = Config::default();
+        config.set_extra_delimiters([('{', '}')])?;
+        let input = r"#2(+\){\(-\}})>";
+        Lexer::new(as_char_results!(r"#2(+\){\(-\}})>").into_iter(), &config).scan_delimiters()?;
+
+        let output = testing::preprocessed(input, &config)?;
+        assert!(
+            output == "+-+->",
+            "\"{output}\" should skip the escaped delimiters."
+        );
+        testing::assert_error(r"+\))", &config, "must have a preceding '('");
+        testing::assert_error(r"(+\)", &config, "expected ')'");
+
+        config.set_escape_in_groups(EscapeInGroups::Literal);
+        let output = testing::preprocessed(input, &config)?;
+        assert!(
+            output == "+)(-}+)(-}>",
+            "\"{output}\" should emit the escaped delimiters."
+        );
+        let source: Vec<String> = testing::tokens(r"(\)\{)\+", &config)?
+            .iter()
+            .map(|token| token.to_source(&config))
+            .collect();
+        assert!(
+            source == [r"(\)\{)"],
+            "{source:?} should escape the delimiters and skip the other escaped chars."
+        );
+        testing::assert_error(r"+\))", &config, "must have a preceding '('");
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_nothing() -> Result<()> {
+        let input: [Result<char, std::convert::Infallible>; 0] = as_char_results!("");
+        let token = Lexer::new(input.into_iter(), &Config::default()).next();
+
+        assert!(token.is_none(), "The lexer should be empty");
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_unclosed_group_strict() -> Result<()> {
+        let input = as_char_results!("(+");
+        let token = Lexer::new(input.into_iter(), &Config::default())
+            .next()
+            .expect("The lexer should not be empty.");
+
+        assert!(token.is_err(), "Unclosed group should be an error.");
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_unclosed_group_lenient() -> Result<()> {
+        let input = as_char_results!("(+");
+        let mut config = Config::default();
+        config.set_eof_policy(EofPolicy::Lenient);
+
+        let mut lexer = Lexer::new(input.into_iter(), &config);
+        let token = lexer.next().expect("The lexer should not be empty.")?;
+
+        if let Token::Group(group, _) = token {
+            assert!(
+                matches!(group[..], [Token::Operator('+', _)]),
+                "Operators don't match."
+            );
+        } else {
+            panic!("The token should be Token::Group.")
+        }
+        assert!(
+            matches!(
+                lexer.take_warnings()[..],
+                [Warning::DelimiterUnclosed { .. }]
+            ),
+            "Unclosed group should be a warning."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_dangling_macro_lenient() -> Result<()> {
+        let input = as_char_results!("$+");
+        let mut config = Config::default();
+        config.set_eof_policy(EofPolicy::Lenient);
+
+        let mut lexer = Lexer::new(input.into_iter(), &config);
+        let token = lexer.next().expect("The lexer should not be empty.")?;
+
+        assert!(
+            matches!(token.without_provenance(), Token::Operator('+', _)),
+            "Operators don't match."
+        );
+        assert!(
+            matches!(lexer.take_warnings()[..], [Warning::MacroDangling { .. }]),
+            "Dangling macro prefix should be a warning."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_cancelled() -> Result<()> {
+        let input = as_char_results!("+");
+        let cancellation = CancellationToken::new();
+        cancellation.cancel();
+
+        let token = Lexer::new(input.into_iter(), &Config::default())
+            .with_cancellation(&cancellation)
+            .next()
+            .expect("The lexer should not be empty.");
+
+        assert!(
+            matches!(token, Err(Error::Cancelled(_))),
+            "The lexer should be cancelled."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_error_group_flattened() -> Result<()> {
+        let input = as_char_results!("(#(#)) (#)");
+        let error = Lexer::new(input.into_iter(), &Config::default())
+            .read_all_tokens()
+            .expect_err("The input should be erroneous.");
+
+        if let Error::Group(group) = error {
+            assert!(
+                group
+                    .errors
+                    .iter()
+                    .all(|error| !matches!(error, Error::Group(_))),
+                "The group should be flat."
+            );
+            assert!(
+                group
+                    .errors
+                    .windows(2)
+                    .all(|pair| pair[0].span().unwrap().start <= pair[1].span().unwrap().start),
+                "The errors should be sorted."
+            );
+        } else {
+            panic!("The error should be Error::Group.")
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_error_group_deduplicated() -> Result<()> {
+        let error = |offset| Error::NumberMissing {
+            span: Span::at(SourceId::default(), Position::new(offset, 1, offset + 1)),
+            number_prefix: '#',
+        };
+
+        let mut group = ErrorGroup::new(vec![
+            error(1),
+            Error::Group(ErrorGroup::new(vec![error(0), error(1)])),
+        ]);
+        assert!(
+            group.errors.len() == 2,
+            "Identical errors should be removed."
+        );
+
+        group.truncate(1);
+        assert!(
+            group.to_string().ends_with("1 more error(s)."),
+            "Truncated errors should be counted."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_whitespace_significant() -> Result<()> {
+        let input = as_char_results!("+ \t\r\n-");
+        let mut config = Config::default();
+        config.set_whitespace_significant(true);
+
+        let tokens = Lexer::new(input.into_iter(), &config).read_all_tokens()?;
+
+        assert!(
+            matches!(
+                tokens[..],
+                [
+                    Token::Operator('+', _),
+                    Token::Operator(' ', _),
+                    Token::Operator('\t', _),
+                    Token::Operator('\r', _),
+                    Token::Operator('\n', _),
+                    Token::Operator('-', _)
+                ]
+            ),
+            "Whitespace should be lexed as operators."
+        );
+        assert!(
+            config.get_field(&'\r').is_none()
+                && config.is_whitespace_operator('\r')
+                && !config.is_skippable('\r'),
+            "Whitespace operators shouldn't be assigned to a field or skipped."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_provenance() -> Result<()> {
+        let input = as_char_results!("$a+\n$ba\nb");
+        let tokens = Lexer::new(input.into_iter(), &Config::default()).read_all_tokens()?;
+
+        let Some(Token::Expanded(inner, outer)) = tokens.first() else {
+            panic!("{tokens:?} should start with an expansion.");
+        };
+        assert!(
+            outer.macro_symbol == 'b'
+                && outer.definition.start.lineno == 2
+                && outer.use_site.start.lineno == 3,
+            "{outer:?} should link to the use and definition of 'b'."
+        );
+        assert!(
+            matches!(&**inner, Token::Expanded(_, provenance)
+                if provenance.macro_symbol == 'a' && provenance.use_site.start.lineno == 2),
+            "{inner:?} should be expanded from 'a' within the definition of 'b'."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_shared_groups() -> Result<()> {
+        let input = as_char_results!("$x(+>)xx");
+        let tokens = Lexer::new(input.into_iter(), &Config::default()).read_all_tokens()?;
+
+        let groups: Vec<&Group> = tokens
+            .iter()
+            .filter_map(|token| match token.without_provenance() {
+                Token::Group(group, _) => Some(group),
+                _ => None,
+            })
+            .collect();
+        assert!(
+            matches!(groups[..], [first, second] if Arc::ptr_eq(first, second)),
+            "The occurences of the macro should share its group."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_token_size() {
+        let size = std::mem::size_of::<Token>();
+        assert!(
+            size <= std::mem::size_of::<(Group, Span)>() + std::mem::size_of::<usize>(),
+            "Token ({size} bytes) should be no larger than a tagged group."
+        );
+    }
+
+    #[test]
+    fn lex_count_tokens() -> Result<()> {
+        let input = as_char_results!("$x(+>)#3x\\+ (#2-x)");
+        let counts = Lexer::new(input.into_iter(), &Config::default()).count_tokens()?;
+
+        assert!(
+            counts
+                == TokenCounts {
+                    operators: 3,
+                    numbers: 2,
+                    groups: 2,
+                    macro_definitions: 1,
+                    macro_uses: 2,
+                    strings: 0,
+                    raw_blocks: 0,
+                    cells: 0,
+                },
+            "{counts:?} should count the tokens as written."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_conditionals() -> Result<()> {
+        let mut config = Config::default();
+        config.set_conditionals(true);
+        let bf = MacroDefinition::from_source('b', "+", &[], &config)?;
+        let mut lexer = testing::lexer("$?b(>) $?w(<$y(-)) $?y.", &config)
+            .with_macros([bf])
+            .keep_macro_definitions();
+        let source: String = lexer
+            .read_all_tokens()?
+            .iter()
+            .map(|token| token.to_source(&config))
+            .collect();
+        assert!(
+            source == "(>)",
+            "\"{source}\" should only contain the tokens of the defined macros."
+        );
+        assert!(
+            lexer.take_macro_definitions().is_empty(),
+            "The macros defined in the skipped tokens should be dropped."
+        );
+        testing::assert_error(
+            "+$?b",
+            &config,
+            "must be followed by a macro symbol and a token",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_counted_loops() -> Result<()> {
+        let mut config = Config::default();
+        config.set_counted_loops(true);
+        let output = testing::preprocessed("#4@i(#i+>) #2@j(#3@k(#j-#k<))", &config)?;
+        assert!(
+            output == ">+>++>+++><<<--<-<<",
+            "\"{output}\" should repeat the groups with their counters."
+        );
+        let output = testing::preprocessed("#3@i(+)", &config)?;
+        assert!(
+            output == "+++",
+            "\"{output}\" should multiply a group without the counter."
+        );
+        testing::assert_error(
+            "#2@i+",
+            &config,
+            "must be followed by a counter and a group",
+        );
+
+        config.set_counted_loops(false);
+        testing::assert_error("#2@i(#i+)", &config, "must be followed by number");
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_constants() -> Result<()> {
+        let mut config = Config::default();
+        config.set_constants(true);
+        let output = testing::preprocessed("$=W#3 $=Two_2#W #W+ #Two_2(>-)", &config)?;
+        assert!(
+            output == "+++>->->-",
+            "\"{output}\" should be multiplied by the constants."
+        );
+        testing::assert_error("#W+", &config, "constant 'W' is not defined");
+        testing::assert_error("$=#3", &config, "must be followed by a name and a number");
+        testing::assert_error("$=W+", &config, "must be followed by a name and a number");
+
+        Ok(())
     }
 
-    /// Try to read a macro definition and set it into the symbol table.
-    fn read_macro_definition(&mut self) -> Result<(), E> {
-        let macro_symbol = match self.next_char() {
-            Some(Ok(ch)) => ch,
-            Some(Err(error)) => return Err(error),
-            None => {
-                return Err(Error::MacroMissing {
-                    lineno: self.lineno,
-                    colno: self.colno,
-                    macro_prefix: *self.config.get_value(&MacroPrefix),
-                })
-            }
-        };
+    #[test]
+    fn lex_named_cells() -> Result<()> {
+        let mut config = Config::default();
+        config.set_named_cells(true)?;
+        let output = testing::preprocessed("$&a#2 $&b#5 &b+ &a- >> &a. #3(&b+>)", &config)?;
+        assert!(
+            output == ">>>>>+<<<->><<.>>>+><+><+>",
+            "\"{output}\" should move to the cells from the modeled position."
+        );
+        testing::assert_error("&c+", &config, "cell 'c' is not defined");
+        testing::assert_error("$&c+", &config, "must be followed by a name and a number");
 
-        let macro_token = match self.read_token() {
-            Some(Ok(token)) => token,
-            Some(Err(error)) => return Err(error),
-            None => {
-                return Err(Error::MacroMissing {
-                    lineno: self.lineno,
-                    colno: self.colno,
-                    macro_prefix: *self.config.get_value(&MacroPrefix),
-                })
-            }
-        };
+        Ok(())
+    }
 
-        self.macro_symbol_table.insert(macro_symbol, macro_token);
+    #[test]
+    fn lex_empty_groups() -> Result<()> {
+        let mut config = Config::default();
+        testing::assert_error("+()", &config, "group is empty ('()').");
+        testing::assert_error(
+            "$a(+$b(())) a",
+            &config,
+            "group is empty ('()') in the definition of macro 'b'.",
+        );
+
+        config.set_allow_empty_groups(true);
+        let output = testing::preprocessed("$p() +#3()p(p)>", &config)?;
+        assert!(output == "+>", "\"{output}\" should skip the empty groups.");
 
         Ok(())
     }
 
-    /// Try to read a group, yields [`Error::Group`] on error.
-    fn read_group(&mut self) -> Result<Group, E> {
-        const GROUP_STOR_INIT_SIZE: usize = 16;
+    #[test]
+    fn lex_multiplier_policy() -> Result<()> {
+        let mut config = Config::default();
+        let output = testing::preprocessed("#2#3+", &config)?;
+        assert!(
+            output == "+++",
+            "\"{output}\" should use the last multiplier."
+        );
 
-        let mut group_tokens: Vec<Token> = Vec::with_capacity(GROUP_STOR_INIT_SIZE);
-        let mut errors: Vec<Error<E>> = Vec::new();
-        loop {
-            match self.read_token() {
-                Some(Ok(token)) => group_tokens.push(token),
-                Some(Err(Error::DelimiterUnopened { .. })) => break,
-                Some(Err(error)) => errors.push(error),
-                None => {
-                    errors.push(Error::DelimiterUnclosed {
-                        lineno: self.lineno,
-                        colno: self.colno,
-                        group_start_delimiter: *self.config.get_value(&GroupStartDelimiter),
-                        group_end_delimiter: *self.config.get_value(&GroupEndDelimiter),
-                    });
-                    break;
-                }
-            }
-        }
+        config.set_multiplier_policy(MultiplierPolicy::Compose);
+        let output = testing::preprocessed("#2 #3+ $x#2 #2x- (#2)#0>", &config)?;
+        assert!(
+            output == "++++++----",
+            "\"{output}\" should multiply the consecutive multipliers."
+        );
+        config.set_counted_loops(true);
+        let output = testing::preprocessed("#2#3@i(#i+)", &config)?;
+        assert!(
+            output == "++++++",
+            "\"{output}\" should repeat the counted loop."
+        );
 
-        if !errors.is_empty() {
-            return Err(Error::Group(ErrorGroup(errors)));
-        }
+        Ok(())
+    }
 
-        if !group_tokens.is_empty() {
-            Ok(group_tokens)
-        } else {
-            Err(Error::GroupEmpty {
-                lineno: self.lineno,
-                colno: self.colno,
-                group_start_delimiter: *self.config.get_value(&GroupStartDelimiter),
-                group_end_delimiter: *self.config.get_value(&GroupEndDelimiter),
+    #[test]
+    fn lex_predefined_macros() -> Result<()> {
+        let config = Config::default();
+        let x = MacroDefinition::from_source('x', "#2+", &[], &config)?;
+        let y = MacroDefinition::from_source('y', "x>", std::slice::from_ref(&x), &config)?;
+        assert!(
+            y.span.source == SourceId::COMMAND_LINE,
+            "{:?} should point into the command line.",
+            y.span
+        );
+
+        let input = as_char_results!("y x");
+        let mut lexer = Lexer::new(input.into_iter(), &config)
+            .with_macros([x, y])
+            .keep_macro_definitions();
+        let source: Vec<String> = lexer
+            .read_all_tokens()?
+            .iter()
+            .map(|token| token.to_source(&config))
+            .collect();
+        assert!(
+            source == ["((#2+)>)", "(#2+)"],
+            "{source:?} should have the predefined macros expanded."
+        );
+        assert!(
+            lexer.take_macro_definitions().is_empty(),
+            "The predefined macros should not be read from the input."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_send_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+
+        assert_send_sync::<Token>();
+        assert_send_sync::<Error>();
+        assert_send_sync::<Lexer<std::vec::IntoIter<Result<char, Infallible>>, Infallible>>();
+    }
+
+    #[test]
+    fn lex_line_macro() -> Result<()> {
+        let input = as_char_results!("$x = +> -\n$y-\n x y");
+        let mut config = Config::default();
+        config.set_line_macros(true);
+
+        let tokens = Lexer::new(input.into_iter(), &config).read_all_tokens()?;
+        let operators: String = tokens
+            .iter()
+            .filter_map(|token| match token.without_provenance() {
+                Token::Operator(op, _) => Some(*op),
+                _ => None,
             })
-        }
+            .collect();
+
+        assert!(
+            operators == "+>--",
+            "Line macro should be spliced as the whole line, got {operators:?}."
+        );
+
+        let input = as_char_results!("$x =\n+");
+        let result = Lexer::new(input.into_iter(), &config).read_all_tokens();
+        assert!(result.is_err(), "Empty line macros should be errors.");
+
+        Ok(())
     }
 
-    /// Advance the input iterator.
-    fn next_char(&mut self) -> Option<Result<char, E>> {
-        let next_char = self.char_iter.next();
+    #[test]
+    fn lex_line_macro_splicing() -> Result<()> {
+        let mut config = Config::default();
+        config.set_line_macros(true);
 
-        self.colno += 1;
+        let tokens = testing::tokens("$x = +>\nx", &config)?;
+        let spliced: Vec<&Token> = tokens.iter().map(Token::without_provenance).collect();
+        assert!(
+            matches!(
+                spliced[..],
+                [Token::Operator('+', _), Token::Operator('>', _)]
+            ),
+            "The body of a line macro should be spliced without a group: {tokens:?}."
+        );
 
-        match next_char {
-            Some(Ok('\n')) => {
-                self.lineno += 1;
-                self.colno = 0;
-                Some(Ok('\n'))
-            }
-            Some(Ok(ch)) => Some(Ok(ch)),
-            Some(Err(error)) => Some(Err(Error::Input(error))),
-            None => None,
-        }
+        let output = testing::preprocessed("$x = +>\n$y x\n#3x #2(x) #2y", &config)?;
+        assert!(
+            output == "+++>+>+>++>",
+            "A number before a line macro should only multiply its first token, got {output:?}."
+        );
+
+        Ok(())
     }
-}
 
-impl<'a, I, E> Iterator for Lexer<'a, I, E>
-where
-    E: ErrorTrait,
-    I: Iterator<Item = StdResult<char, E>>,
-{
-    type Item = Result<Token, E>;
+    #[test]
+    fn lex_macro_over_budget() -> Result<()> {
+        let input = as_char_results!("$x(#4(+-)>) $y(+)");
+        let mut config = Config::default();
+        config.set_macro_budgets([('x', 8), ('y', 1)]);
 
-    fn next(&mut self) -> Option<Self::Item> {
-        self.read_token()
+        let mut lexer = Lexer::new(input.into_iter(), &config);
+        lexer.read_all_tokens()?;
+        let warnings = lexer.take_warnings();
+
+        assert!(
+            matches!(
+                warnings[..],
+                [Warning::MacroOverBudget {
+                    macro_symbol: 'x',
+                    size: 9,
+                    budget: 8,
+                    ..
+                }]
+            ),
+            "{warnings:?} should only warn about 'x'."
+        );
+
+        config.set_budget_policy(BudgetPolicy::Deny);
+        let result = Lexer::new(input.into_iter(), &config).read_all_tokens();
+
+        assert!(
+            matches!(result, Err(Error::Group(_))),
+            "Macros over budget should be errors."
+        );
+
+        Ok(())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use anyhow::Result;
+    #[test]
+    fn lex_expansion_depth() -> Result<()> {
+        let input = as_char_results!("$a(+) $b(a-) $c(#2b) c\n$d(c) d");
+        let mut config = Config::default();
+        config.set_max_expansion_depth(3);
 
-    use super::*;
-    use crate::config::Config;
-    use bfup_derive::as_char_results;
+        let error = Lexer::new(input.into_iter(), &config)
+            .read_all_tokens()
+            .expect_err("Nesting 4 expansions should be an error.");
+        let Error::Group(group) = error else {
+            panic!("The error should be Error::Group.")
+        };
+        assert!(
+            matches!(
+                &group.errors[..],
+                [Error::MacroTooDeep { span, macro_symbol: 'd', depth: 4, limit: 3, chain }]
+                    if chain == "d > c > b > a" && span.start.lineno == 2
+            ),
+            "{:?} should point at the use of 'd'.",
+            group.errors
+        );
+
+        config.set_max_expansion_depth(4);
+        Lexer::new(input.into_iter(), &config).read_all_tokens()?;
+
+        Ok(())
+    }
 
     #[test]
-    fn lex_operator() -> Result<()> {
-        let input = as_char_results!('+');
-        let token = Lexer::new(input.into_iter(), &Config::default())
-            .next()
-            .expect("The lexer should not be empty.")?;
+    fn lex_macro_table_overflow() -> Result<()> {
+        let mut config = Config::default();
+        config.set_max_macros(2);
+        testing::tokens("$a+ $b(-<) ab", &config)?;
+        testing::assert_error(
+            "$a+ $b- $a>",
+            &config,
+            "defining macro 'a' makes 3 macros with 3 stored tokens, over the limit of 2 macros",
+        );
 
+        config.set_max_macro_table_size(3);
+        testing::assert_error(
+            "$a+ $b(-<)",
+            &config,
+            "defining macro 'b' makes 2 macros with 4 stored tokens",
+        );
+        config.set_max_macro_table_size(6);
+        let mut lexer = testing::lexer("$a(+>) $b(a-)", &config).keep_macro_definitions();
+        lexer.read_all_tokens()?;
+        let sizes: Vec<usize> = lexer
+            .take_macro_definitions()
+            .iter()
+            .map(MacroDefinition::stored_len)
+            .collect();
         assert!(
-            if let Token::Operator('+') = token {
-                true
-            } else {
-                false
-            },
-            "Operators don't match."
+            sizes == [3, 3],
+            "{sizes:?} should count the expanded macro as a single token."
+        );
+        let mut lexer = testing::lexer("$a(+>) $b(a-)", &config);
+        lexer.read_all_tokens()?;
+        assert!(
+            lexer.take_macro_definitions().is_empty(),
+            "The definitions should only be kept when asked for."
         );
 
         Ok(())
     }
 
     #[test]
-    fn lex_number() -> Result<()> {
-        let input = as_char_results!("#2137");
-        let token = Lexer::new(input.into_iter(), &Config::default())
-            .next()
-            .expect("The lexer should not be empty.")?;
+    fn lex_include() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("bfup-lex-include-{}", std::process::id()));
+        fs::create_dir_all(dir.join("lib"))?;
+        fs::write(dir.join("lib/std.bfup"), "$a(+>) $%\"more.bfup\" -")?;
+        fs::write(dir.join("lib/more.bfup"), "$b(a<)")?;
+        fs::write(dir.join("cycle.bfup"), "$%\"cycle.bfup\"")?;
+        let mut config = Config::default();
+        config.set_includes(true);
+
+        let library = dir.join("lib/std.bfup");
+        let output = testing::preprocessed(&format!("$%\"{}\" #2a b", library.display()), &config);
+        let cycle = testing::tokens(
+            &format!("$%\"{}\"", dir.join("cycle.bfup").display()),
+            &config,
+        );
+        let missing = testing::tokens("$% lib/std.bfup", &config);
+        fs::remove_dir_all(&dir)?;
 
+        let output = output?;
         assert!(
-            if let Token::Number(2137) = token {
-                true
-            } else {
-                false
-            },
-            "Numbers don't match."
+            output == "-+>+>+><",
+            "\"{output}\" should use the macros of both included files."
+        );
+        assert!(
+            matches!(&cycle, Err(error) if error.to_string().contains("the file includes itself")),
+            "{cycle:?} should detect the cycle."
+        );
+        assert!(
+            matches!(&missing, Err(Error::Group(group)) if matches!(group.errors[..], [Error::IncludeMissing { .. }])),
+            "{missing:?} should require a quoted path."
         );
 
+        config.set_includes(false);
+        let output = testing::preprocessed("$%(+)%", &config)?;
+        assert!(output == "+", "'%' should be a macro without includes.");
+
         Ok(())
     }
 
     #[test]
-    fn lex_group() -> Result<()> {
-        let input = as_char_results!("(#42-)");
-        let token = Lexer::new(input.into_iter(), &Config::default())
-            .next()
-            .expect("The lexer should not be empty.")?;
+    fn lex_token_at() -> Result<()> {
+        let tokens = testing::tokens("$a(+>)\n#2a (-\n<.)", &Config::default())?;
 
-        if let Token::Group(group) = token {
-            match group.get(0) {
-                Some(Token::Number(42)) => (),
-                _ => panic!("Numbers don't match."),
-            }
-            match group.get(1) {
-                Some(Token::Operator('-')) => (),
-                _ => panic!("Operators don't match."),
-            }
-        } else {
-            panic!("The token should be Token::Group.")
-        }
+        let found = token_at(&tokens, 2, 3).map(|token| token.to_source(&Config::default()));
+        assert!(
+            found.as_deref() == Some("(+>)"),
+            "{found:?} should be the expanded macro."
+        );
+        let found = token_at(&tokens, 3, 2).map(|token| token.to_source(&Config::default()));
+        assert!(
+            found.as_deref() == Some("."),
+            "{found:?} should be the operator within the group."
+        );
+        let found = token_at(&tokens, 3, 3).map(|token| token.to_source(&Config::default()));
+        assert!(
+            found.as_deref() == Some("(-<.)"),
+            "{found:?} should be the group at its end delimiter."
+        );
+        let found = token_at(&tokens, 2, 2).map(|token| token.to_source(&Config::default()));
+        assert!(
+            found.as_deref() == Some("#2"),
+            "{found:?} should be the number."
+        );
+        assert!(
+            token_at(&tokens, 1, 2).is_none(),
+            "Macro definitions aren't tokens."
+        );
 
         Ok(())
     }
 
     #[test]
-    fn lex_macro() -> Result<()> {
-        let input = as_char_results!("$m+m");
-        let token = Lexer::new(input.into_iter(), &Config::default())
-            .next()
-            .expect("The lexer should not be empty.")?;
+    fn lex_line_comment() -> Result<()> {
+        let mut config = Config::default();
+        config.set_line_macros(true);
+        config.set_line_comment(Some(';'))?;
 
+        let output = testing::preprocessed("$x = +> ; moves right, (once)\nx ; -\\;\nx", &config)?;
         assert!(
-            if let Token::Operator('+') = token {
-                true
-            } else {
-                false
-            },
-            "Operators don't match."
+            output == "+>+>",
+            "\"{output}\" should skip the comments up to the newlines."
+        );
+        Lexer::new(as_char_results!("(+ ; )\n)").into_iter(), &config).scan_delimiters()?;
+        let counts = testing::lexer("+ ; $x -", &config).count_tokens()?;
+        assert!(
+            counts.operators == 1 && counts.macro_definitions == 0,
+            "{counts:?} should leave out the comment."
+        );
+        assert!(
+            config.set_line_comment(Some('+')).is_err(),
+            "An operator can't start a comment."
         );
 
         Ok(())
     }
 
     #[test]
-    fn lex_escape() -> Result<()> {
-        let input = as_char_results!("thiswillnotbelexed\\+\\#\\(\\)");
-        let token = Lexer::new(input.into_iter(), &Config::default()).next();
+    fn lex_block_comment() -> Result<()> {
+        let mut config = Config::default();
+        config.set_block_comment(Some((String::from("(*"), String::from("*)"))))?;
 
-        assert!(token.is_none(), "The lexer should be empty");
+        let output = testing::preprocessed("+(* skipped (\n -> **)#2(<)(*)*).", &config)?;
+        assert!(
+            output == "+<<.",
+            "\"{output}\" should skip the comments, keeping the groups."
+        );
+        let tokens = testing::tokens("(* a\nb *)\n  +", &config)?;
+        assert!(
+            matches!(tokens[..], [Token::Operator('+', span)] if span.start.lineno == 3 && span.start.colno == 3),
+            "{tokens:?} should be at 3:3 after the comment."
+        );
+        Lexer::new(as_char_results!("(*)*)(+)").into_iter(), &config).scan_delimiters()?;
+        testing::assert_error(
+            "+ (* unclosed",
+            &config,
+            "block comment must be closed with '*)'",
+        );
+        assert!(
+            config
+                .set_block_comment(Some((String::from("/**"), String::from("*/"))))
+                .is_err(),
+            "Delimiters can't be longer than two chars."
+        );
 
         Ok(())
     }
 
     #[test]
-    fn lex_nothing() -> Result<()> {
-        let input: [Result<char, std::convert::Infallible>; 0] = as_char_results!("");
-        let token = Lexer::new(input.into_iter(), &Config::default()).next();
+    fn lex_raw_block() -> Result<()> {
+        let mut config = Config::default();
+        config.set_raw_block(Some((String::from("{{"), String::from("}}"))))?;
 
-        assert!(token.is_none(), "The lexer should be empty");
+        let output = testing::preprocessed("+#2{{ a!}(\n}}-", &config)?;
+        assert!(
+            output == "+ a!}(\n a!}(\n-",
+            "\"{output}\" should repeat the raw text as it is."
+        );
+        let tokens = testing::tokens("{{#}}", &config)?;
+        assert!(
+            matches!(&tokens[..], [Token::Raw(text, _)] if &**text == "#")
+                && tokens[0].to_source(&config) == "{{#}}",
+            "{tokens:?} should be the raw text, written back with its delimiters."
+        );
+        Lexer::new(as_char_results!("{{)}}(+)").into_iter(), &config).scan_delimiters()?;
+        testing::assert_error(
+            "+ {{ unclosed }",
+            &config,
+            "raw block must be closed with '}}'",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_shebang() -> Result<()> {
+        let mut config = Config::default();
+        config.set_whitespace_significant(true);
+        let tokens = testing::tokens("#!/usr/bin/env -S bfup --run\n+", &config)?;
+        assert!(
+            matches!(tokens[..], [Token::Operator('+', span)] if span.start.lineno == 2),
+            "{tokens:?} should skip the shebang line with its newline."
+        );
+        testing::assert_error(
+            "+\n#!",
+            &Config::default(),
+            "number prefix '#' must be followed by number",
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_string() -> Result<()> {
+        let mut config = Config::default();
+        let tokens = testing::tokens("\"+\"", &config)?;
+        assert!(
+            matches!(tokens[..], [Token::Operator('+', _)]),
+            "{tokens:?} shouldn't contain strings unless they're enabled."
+        );
+
+        config.set_strings(true)?;
+        let tokens = testing::tokens("#2\"a\\\"b\\n\"+", &config)?;
+        assert!(
+            matches!(&tokens[..], [Token::Number(2, ..), Token::Str(text, span), Token::Operator('+', _)]
+                if &**text == "a\"b\n" && span.end.colno == 10),
+            "{tokens:?} should contain the unescaped string."
+        );
+        let source = tokens[1].to_source(&config);
+        assert!(
+            source == "\"a\\\"b\n\"",
+            "\"{source}\" should escape the delimiter."
+        );
+        Lexer::new(as_char_results!("\"(\"+").into_iter(), &config).scan_delimiters()?;
+        testing::assert_error("+\"a", &config, "string must be closed with '\"'");
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_unrecognized_chars() -> Result<()> {
+        let input = as_char_results!("+ }/");
+        let mut config = Config::default();
+        config.set_allowed_chars("/".chars());
+
+        config.set_unrecognized_policy(UnrecognizedPolicy::Warn);
+        let mut lexer = Lexer::new(input.into_iter(), &config);
+        lexer.read_all_tokens()?;
+        assert!(
+            matches!(
+                lexer.take_warnings()[..],
+                [Warning::CharUnrecognized { ch: '}', .. }]
+            ),
+            "Only '}}' should be unrecognized."
+        );
+
+        config.set_unrecognized_policy(UnrecognizedPolicy::Deny);
+        assert!(
+            Lexer::new(input.into_iter(), &config)
+                .read_all_tokens()
+                .is_err(),
+            "'}}' should be an error."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn lex_unrecognized_stats() -> Result<()> {
+        let input = as_char_results!("a}b}}+ ");
+        let config = Config::default();
+        let mut lexer = Lexer::new(input.into_iter(), &config);
+        lexer.read_all_tokens()?;
+        assert!(
+            lexer.take_unrecognized().is_none(),
+            "The unrecognized chars shouldn't be collected unless asked for."
+        );
+
+        let input = as_char_results!("a}b}}+ ");
+        let mut lexer = Lexer::new(input.into_iter(), &config).collect_unrecognized();
+        lexer.read_all_tokens()?;
+        let unrecognized = lexer
+            .take_unrecognized()
+            .expect("The unrecognized chars should be collected.");
+        assert!(unrecognized.total() == 5, "5 chars should be skipped.");
+        assert!(
+            matches!(
+                unrecognized.most_common(2)[..],
+                [
+                    (
+                        '}',
+                        3,
+                        Span {
+                            start: Position { colno: 2, .. },
+                            ..
+                        }
+                    ),
+                    ('a', 1, _)
+                ]
+            ),
+            "'}}' should be the most common."
+        );
+        assert!(
+            unrecognized.ratio() == 5.0 / 6.0,
+            "5 out of 6 non-whitespace chars should be unrecognized."
+        );
 
         Ok(())
     }
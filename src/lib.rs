@@ -0,0 +1,75 @@
+//! Preprocessor for brainfuck-like languages.
+//!
+//! The [`Lexer`][crate::lex::Lexer] reads the tokens from the input according to
+//! a [`Config`][crate::config::Config], which the [`pre`] functions emit as operators.
+//! The [`testing`] module helps checking configs and macros from other crates.
+
+/// Running batches of preprocessing jobs.
+pub mod batch;
+/// Cancelling the preprocessor from another thread.
+pub mod cancel;
+/// Saving and reading the checkpoints of resumable runs.
+pub mod checkpoint;
+/// Parsing args and acting on them accordingly.
+pub mod cli;
+/// Compressing outputs and decompressing inputs.
+#[cfg(feature = "compress")]
+pub mod compress;
+/// Packaging & verifying
+/// the preprocessor's configuration.
+pub mod config;
+/// Reporting the errors and warnings in various formats.
+pub mod diagnostic;
+/// Comparing the token trees of two sources.
+pub mod diff;
+/// Comparing the built-in interpreter with external ones.
+pub mod difftest;
+/// Checking whether two sources generate the same output.
+pub mod equiv;
+/// Splicing the outputs of external generators into the sources.
+pub mod generate;
+/// Encoding the operators made of multiple chars.
+pub mod grapheme;
+/// Injecting input and output failures for testing the error handling.
+pub mod inject;
+/// Module mainly containing
+/// the [`Lexer`][crate::lex::Lexer] iterator
+/// over the tokens recognized by the preprocessor.
+pub mod lex;
+/// Measuring the size and complexity of preprocessed inputs.
+pub mod metrics;
+/// Transforming the planned operators before they're emitted.
+pub mod pass;
+/// Planning the repetitions of the emitted operators.
+pub mod plan;
+/// Opening the outputs in web playgrounds.
+pub mod playground;
+/// Module containing the main preprocessor
+/// functions.
+pub mod pre;
+/// Recording and replaying the lexed tokens.
+pub mod record;
+/// Selecting the tokens read within a region of the input.
+pub mod region;
+/// Preprocessing interactively, line by line.
+pub mod repl;
+/// Running the preprocessed programs.
+pub mod run;
+/// Serving the preprocessor over HTTP.
+#[cfg(feature = "serve")]
+pub mod serve;
+/// Owning the loaded sources and resolving
+/// [`Spans`][crate::span::Span] within them.
+pub mod source;
+/// Locations of the [`Tokens`][crate::lex::Token]
+/// and diagnostics within the sources.
+pub mod span;
+/// Suggesting macros for the operators repeated in the sources.
+pub mod suggest;
+/// Interpolating variables into the sources before lexing.
+pub mod template;
+/// Helpers for testing configs and macros
+/// without going through the command line.
+pub mod testing;
+/// Interactively creating configs.
+pub mod wizard;
@@ -1,34 +1,54 @@
-/// Parsing args and acting on them accordingly.
-mod cli;
-/// Packaging & verifying 
-/// the preprocessor's configuration.
-mod config;
-/// Module mainly containing 
-/// the [`Lexer`][crate::lex::Lexer] iterator
-/// over the tokens recognized by the preprocessor.
-mod lex;
-/// Module containing the main preprocessor 
-/// functions.
-mod pre;
-
 use std::process::ExitCode;
 
-use anyhow::Result;
+use anyhow::{Context, Result};
 use colored::Colorize;
 
+use bfup::cancel::{CancellationToken, Cancelled};
+use bfup::cli;
+use bfup::diagnostic::Reported;
+
+/// Exit code returned when interrupted with Ctrl-C.
+const INTERRUPTED_EXIT_CODE: u8 = 130;
+
 // TODO: accept multiple files? (chain?)
 
 fn main() -> ExitCode {
-    check_and_print_result(cli::process_args())
+    let cancellation = CancellationToken::new();
+
+    check_and_print_result(
+        install_interrupt_handler(cancellation.clone())
+            .and_then(|_| cli::process_args(&cancellation)),
+    )
+}
+
+/// Cancel `cancellation` on Ctrl-C, exit immediately if it was already cancelled
+/// *(e.g. when blocked on reading stdin)*, removing the partial outputs.
+fn install_interrupt_handler(cancellation: CancellationToken) -> Result<()> {
+    ctrlc::set_handler(move || {
+        if cancellation.is_cancelled() {
+            cancellation.remove_partial_paths();
+            std::process::exit(INTERRUPTED_EXIT_CODE.into());
+        }
+        cancellation.cancel();
+    })
+    .with_context(|| "failed to set the interrupt handler")
 }
 
 fn check_and_print_result(result: Result<()>) -> ExitCode {
     if let Err(err) = result {
-        eprintln!("{} {}\n", "error:".red().bold(), err);
-        if let Some(cause) = err.chain().nth(1) {
-            eprintln!("{}", cause);
+        // already reported in a machine-readable format
+        if !err.is::<Reported>() {
+            eprintln!("{} {}\n", "error:".red().bold(), err);
+            if let Some(cause) = err.chain().nth(1) {
+                eprintln!("{}", cause);
+            }
+        }
+
+        if err.chain().any(|cause| cause.is::<Cancelled>()) {
+            ExitCode::from(INTERRUPTED_EXIT_CODE)
+        } else {
+            ExitCode::from(1)
         }
-        ExitCode::from(1)
     } else {
         ExitCode::from(0)
     }
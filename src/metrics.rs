@@ -0,0 +1,332 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::lex::{MacroDefinition, Provenance, Token};
+use crate::span::Span;
+
+/// Format of the statistics printed with `--stats`.
+#[derive(Clone, Copy, Default, PartialEq, Eq, fmt::Debug, clap::ValueEnum)]
+pub enum StatsFormat {
+    /// Colored lines
+    #[default]
+    Human,
+    /// A json object, with the usage of every macro
+    Json,
+}
+
+/// Size metrics of a macro definition.
+pub struct MacroMetrics {
+    pub symbol: char,
+    pub span: Span,
+    /// Number of operators emitted by a single expansion.
+    pub size: usize,
+    /// Max nesting depth of the groups within the macro.
+    pub depth: usize,
+}
+
+/// Size metrics of a group.
+pub struct GroupMetrics {
+    pub span: Span,
+    /// Number of operators emitted by a single expansion.
+    pub size: usize,
+    /// Nesting depth of the group's first occurence *(1 for outermost groups)*.
+    pub depth: usize,
+    /// Number of times the group is expanded in the output.
+    pub expansions: usize,
+}
+
+/// Size and complexity metrics of a preprocessed input.
+pub struct Metrics {
+    /// Metrics of every macro definition, in order of definition.
+    pub macros: Vec<MacroMetrics>,
+    /// Metrics of every group in the output, in order of occurence.
+    pub groups: Vec<GroupMetrics>,
+    /// Number of groups at every nesting depth.
+    pub depths: BTreeMap<usize, usize>,
+    /// Number of emitted operators, the estimated cost of the program.
+    pub operators: usize,
+}
+
+impl Metrics {
+    /// Collect the `Metrics` of the `tokens` read by a [`Lexer`][crate::lex::Lexer],
+    /// along with its `macro_definitions`.
+    pub fn new(tokens: &[Token], macro_definitions: &[MacroDefinition]) -> Self {
+        let macros = macro_definitions
+            .iter()
+            .map(|definition| MacroMetrics {
+                symbol: definition.symbol,
+                span: definition.span,
+                size: definition.expanded_len(),
+                depth: definition.tokens.iter().map(max_depth).max().unwrap_or(0),
+            })
+            .collect();
+
+        let mut groups: Vec<GroupMetrics> = Vec::new();
+        let mut group_indices: HashMap<Span, usize> = HashMap::new();
+        let operators = collect_groups(tokens, 1, 1, &mut groups, &mut group_indices);
+
+        let mut depths: BTreeMap<usize, usize> = BTreeMap::new();
+        for group in &groups {
+            *depths.entry(group.depth).or_insert(0) += 1;
+        }
+
+        Metrics {
+            macros,
+            groups,
+            depths,
+            operators,
+        }
+    }
+
+    /// Get at most `n` of the largest groups.
+    pub fn largest_groups(&self, n: usize) -> Vec<&GroupMetrics> {
+        let mut groups: Vec<&GroupMetrics> = self.groups.iter().collect();
+        groups.sort_by_key(|group| Reverse(group.size));
+        groups.truncate(n);
+
+        groups
+    }
+}
+
+/// Number of expansions of a macro definition in the output
+/// and the operators they emit.
+#[derive(Clone, PartialEq, Eq, fmt::Debug, Serialize)]
+pub struct MacroUsage {
+    pub symbol: char,
+    pub definition: Span,
+    /// Number of times the macro is expanded, including the expansions
+    /// repeated by numbers and the ones nested in other macros.
+    pub expansions: usize,
+    /// Number of operators emitted by the expansions, including
+    /// the ones emitted by the macros nested in them.
+    pub operators: usize,
+}
+
+/// Usage of the macros expanded in a preprocessed input.
+pub struct Usage {
+    /// Usage of every expanded macro definition, ordered by their position.
+    pub macros: Vec<MacroUsage>,
+    /// Number of emitted operators.
+    pub operators: usize,
+}
+
+impl Usage {
+    /// Collect the `Usage` of the macros expanded in the `tokens` read by a
+    /// [`Lexer`][crate::lex::Lexer] *(unused macros are left out)*.
+    pub fn new(tokens: &[Token]) -> Self {
+        let mut usage: HashMap<Span, MacroUsage> = HashMap::new();
+        let operators = collect_usage(tokens, 1, &mut usage);
+
+        let mut macros: Vec<MacroUsage> = usage.into_values().collect();
+        macros.sort_unstable_by_key(|usage| (usage.definition.source, usage.definition.start));
+
+        Usage { macros, operators }
+    }
+
+    /// Get the share of the output emitted by the expansions of `usage`, from 0 to 1.
+    pub fn share(&self, usage: &MacroUsage) -> f64 {
+        match self.operators {
+            0 => 0.0,
+            operators => usage.operators as f64 / operators as f64,
+        }
+    }
+}
+
+/// Record the usage of the macros expanded in `tokens` emitted `times` times,
+/// returning the number of operators emitted by `tokens`.
+///
+/// The tokens spliced from a single use of a macro share its provenance, so
+/// only a token whose provenance differs from the previous one's starts an expansion.
+fn collect_usage(tokens: &[Token], times: usize, usage: &mut HashMap<Span, MacroUsage>) -> usize {
+    let mut operators: usize = 0;
+    let mut multiplier: usize = 1;
+    let mut previous_uses: Vec<Span> = Vec::new();
+    for token in tokens {
+        let mut chain: Vec<&Provenance> = Vec::new();
+        let mut bare = token;
+        while let Token::Expanded(expanded, provenance) = bare {
+            chain.push(provenance);
+            bare = expanded;
+        }
+
+        let emitted_times = times.saturating_mul(multiplier);
+        let emitted = match bare {
+            Token::Number(number, ..) => {
+                multiplier = *number;
+                0
+            }
+            Token::Operator(..) => {
+                multiplier = 1;
+                emitted_times
+            }
+            Token::Str(..) | Token::Raw(..) | Token::Cell(..) => {
+                multiplier = 1;
+                emitted_times.saturating_mul(bare.expanded_len())
+            }
+            Token::Group(group, _) => {
+                multiplier = 1;
+                collect_usage(group, emitted_times, usage)
+            }
+            Token::Expanded(..) => unreachable!("The provenance should be unwrapped."),
+        };
+        operators = operators.saturating_add(emitted);
+
+        let continued = chain
+            .iter()
+            .zip(&previous_uses)
+            .take_while(|(provenance, previous_use)| provenance.use_site == **previous_use)
+            .count();
+        for (level, provenance) in chain.iter().enumerate() {
+            let macro_usage = usage
+                .entry(provenance.definition)
+                .or_insert_with(|| MacroUsage {
+                    symbol: provenance.macro_symbol,
+                    definition: provenance.definition,
+                    expansions: 0,
+                    operators: 0,
+                });
+            if level >= continued {
+                macro_usage.expansions = macro_usage.expansions.saturating_add(emitted_times);
+            }
+            macro_usage.operators = macro_usage.operators.saturating_add(emitted);
+        }
+        previous_uses = chain.iter().map(|provenance| provenance.use_site).collect();
+    }
+
+    operators
+}
+
+/// Get the max nesting depth of the groups in `token`.
+fn max_depth(token: &Token) -> usize {
+    match token.without_provenance() {
+        Token::Group(group, _) => 1 + group.iter().map(max_depth).max().unwrap_or(0),
+        _ => 0,
+    }
+}
+
+/// Record the groups in `tokens` expanded `times` times at `depth`
+/// into `groups`, returning the number of operators emitted by `tokens`.
+fn collect_groups(
+    tokens: &[Token],
+    depth: usize,
+    times: usize,
+    groups: &mut Vec<GroupMetrics>,
+    group_indices: &mut HashMap<Span, usize>,
+) -> usize {
+    let mut operators: usize = 0;
+    let mut multiplier: usize = 1;
+    for token in tokens {
+        match token.without_provenance() {
+            Token::Number(number, ..) => multiplier = *number,
+            Token::Operator(..) => {
+                operators = operators.saturating_add(multiplier);
+                multiplier = 1;
+            }
+            Token::Str(..) | Token::Raw(..) | Token::Cell(..) => {
+                operators =
+                    operators.saturating_add(token.expanded_len().saturating_mul(multiplier));
+                multiplier = 1;
+            }
+            Token::Group(group, span) => {
+                let expansions = times.saturating_mul(multiplier);
+                if let Some(index) = group_indices.get(span) {
+                    let metrics = &mut groups[*index];
+                    metrics.expansions = metrics.expansions.saturating_add(expansions);
+                } else {
+                    group_indices.insert(*span, groups.len());
+                    groups.push(GroupMetrics {
+                        span: *span,
+                        size: token.expanded_len(),
+                        depth,
+                        expansions,
+                    });
+                }
+
+                let size = collect_groups(group, depth + 1, expansions, groups, group_indices);
+                operators = operators.saturating_add(size.saturating_mul(multiplier));
+                multiplier = 1;
+            }
+            Token::Expanded(..) => unreachable!("The provenance should be stripped."),
+        }
+    }
+
+    operators
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::lex::Lexer;
+    use bfup_derive::as_char_results;
+
+    #[test]
+    fn metrics_sizes() -> Result<()> {
+        let input = as_char_results!("$x(#2(+-)>) #3x (<)");
+        let config = Config::default();
+        let mut lexer = Lexer::new(input.into_iter(), &config).keep_macro_definitions();
+        let tokens = lexer.read_all_tokens()?;
+        let metrics = Metrics::new(&tokens, &lexer.take_macro_definitions());
+
+        assert!(metrics.operators == 16, "16 operators should be emitted.");
+        assert!(
+            matches!(
+                metrics.macros[..],
+                [MacroMetrics {
+                    symbol: 'x',
+                    size: 5,
+                    depth: 2,
+                    ..
+                }]
+            ),
+            "'x' should expand to 5 operators."
+        );
+
+        let groups: Vec<(usize, usize, usize)> = metrics
+            .groups
+            .iter()
+            .map(|group| (group.size, group.depth, group.expansions))
+            .collect();
+        assert!(
+            groups == [(5, 1, 3), (2, 2, 6), (1, 1, 1)],
+            "{groups:?} should be [(5, 1, 3), (2, 2, 6), (1, 1, 1)]."
+        );
+        assert!(
+            metrics.depths == BTreeMap::from([(1, 2), (2, 1)]),
+            "2 groups should be outermost."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn metrics_macro_usage() -> Result<()> {
+        let input = as_char_results!("$a(+>) $b(a<) $x = #2-a\n#3b x a");
+        let mut config = Config::default();
+        config.set_line_macros(true);
+        let tokens = Lexer::new(input.into_iter(), &config).read_all_tokens()?;
+        let usage = Usage::new(&tokens);
+
+        let macros: Vec<(char, usize, usize)> = usage
+            .macros
+            .iter()
+            .map(|usage| (usage.symbol, usage.expansions, usage.operators))
+            .collect();
+        assert!(
+            macros == [('a', 5, 10), ('b', 3, 9), ('x', 1, 4)],
+            "{macros:?} should count the nested and repeated expansions."
+        );
+        assert!(usage.operators == 15, "15 operators should be emitted.");
+        assert!(
+            usage.share(&usage.macros[1]) == 0.6,
+            "'b' should emit 60% of the output."
+        );
+
+        Ok(())
+    }
+}
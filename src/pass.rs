@@ -0,0 +1,744 @@
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::config;
+use crate::plan::Step;
+
+/// Error type of running the [`Passes`][Pass].
+#[derive(thiserror::Error, fmt::Debug)]
+pub enum Error {
+    #[error("pass '{name}' failed: {message}")]
+    Failed { name: String, message: String },
+    #[cfg(feature = "unstable-pass-plugins")]
+    #[error("failed to load pass plugin '{path}': {message}")]
+    Load { path: String, message: String },
+}
+
+/// Transformation of the planned [`Steps`][Step], run by the [`PassManager`]
+/// after the operators are planned and before they're emitted.
+pub trait Pass {
+    fn name(&self) -> &str;
+
+    /// Transform `steps`, possibly changing the emitted operators.
+    fn run(&mut self, steps: Vec<Step>) -> Result<Vec<Step>, Error>;
+
+    /// Get how many emitted operators the pass removed so far,
+    /// 0 if it doesn't count them.
+    fn removed(&self) -> usize {
+        0
+    }
+}
+
+/// Built-in pass planning a repetition consisting of just another repetition
+/// *(`#a(#b(...))`)* as a single repetition `a * b` times.
+pub struct FlattenRepetitions;
+
+impl FlattenRepetitions {
+    /// Flatten the repetitions of `steps`, the innermost first.
+    pub fn flatten(steps: Vec<Step>) -> Vec<Step> {
+        steps
+            .into_iter()
+            .map(|step| match step {
+                Step::Repeat(times, steps) => {
+                    match <[Step; 1]>::try_from(FlattenRepetitions::flatten(steps)) {
+                        Ok([Step::Repeat(inner_times, steps)]) => {
+                            Step::Repeat(times.saturating_mul(inner_times), steps)
+                        }
+                        Ok([step]) => Step::Repeat(times, vec![step]),
+                        Err(steps) => Step::Repeat(times, steps),
+                    }
+                }
+                step => step,
+            })
+            .collect()
+    }
+}
+
+impl Pass for FlattenRepetitions {
+    fn name(&self) -> &str {
+        "flatten-repetitions"
+    }
+
+    fn run(&mut self, steps: Vec<Step>) -> Result<Vec<Step>, Error> {
+        Ok(FlattenRepetitions::flatten(steps))
+    }
+}
+
+/// Built-in pass dropping the steps emitting nothing, inlining the repetitions
+/// run once and merging consecutive operators.
+pub struct SimplifySteps;
+
+impl SimplifySteps {
+    /// Simplify `steps`, the innermost first.
+    pub fn simplify(steps: Vec<Step>) -> Vec<Step> {
+        let mut simplified = Vec::with_capacity(steps.len());
+        for step in steps {
+            match step {
+                Step::Operators(operators) if operators.is_empty() => (),
+                Step::Repeat(0, _) => (),
+                Step::Repeat(times, steps) => match SimplifySteps::simplify(steps) {
+                    steps if steps.is_empty() => (),
+                    steps if times == 1 => {
+                        for step in steps {
+                            push_step(&mut simplified, step);
+                        }
+                    }
+                    steps => simplified.push(Step::Repeat(times, steps)),
+                },
+                step => push_step(&mut simplified, step),
+            }
+        }
+
+        simplified
+    }
+}
+
+impl Pass for SimplifySteps {
+    fn name(&self) -> &str {
+        "simplify-steps"
+    }
+
+    fn run(&mut self, steps: Vec<Step>) -> Result<Vec<Step>, Error> {
+        Ok(SimplifySteps::simplify(steps))
+    }
+}
+
+/// Built-in pass cancelling the brainfuck operators undoing each other
+/// *(`+-`, `<>`)* and removing the empty loops *(`[]`)*, assumed to never be entered.
+///
+/// Only the operators planned next to each other are cancelled,
+/// so the ones on both sides of a repetition are kept.
+///
+/// The operators are assumed to be brainfuck's, [`Config::set_pipeline`][crate::config::Config::set_pipeline]
+/// refuses to run it with other ones.
+#[derive(Default)]
+pub struct Peephole {
+    removed: usize,
+}
+
+impl Peephole {
+    /// Cancel the no-ops of `steps`, until the simplified steps have none left,
+    /// adding the number of removed operators to `removed`.
+    pub fn optimize(steps: Vec<Step>, removed: &mut usize) -> Vec<Step> {
+        let mut steps = SimplifySteps::simplify(steps);
+        loop {
+            let before = *removed;
+            steps = SimplifySteps::simplify(Peephole::cancel(steps, 1, removed));
+            if *removed == before {
+                return steps;
+            }
+        }
+    }
+
+    /// Cancel the no-ops within every step of `steps`, the innermost first,
+    /// adding the operators removed from the `times` repeated steps to `removed`.
+    fn cancel(steps: Vec<Step>, times: usize, removed: &mut usize) -> Vec<Step> {
+        steps
+            .into_iter()
+            .map(|step| match step {
+                Step::Operators(operators) => {
+                    let mut kept = String::with_capacity(operators.len());
+                    for operator in operators.chars() {
+                        match (kept.chars().last(), operator) {
+                            (Some('+'), '-')
+                            | (Some('-'), '+')
+                            | (Some('<'), '>')
+                            | (Some('>'), '<')
+                            | (Some('['), ']') => {
+                                kept.pop();
+                                *removed = removed.saturating_add(times.saturating_mul(2));
+                            }
+                            _ => kept.push(operator),
+                        }
+                    }
+                    Step::Operators(kept)
+                }
+                Step::Repeat(repeat, steps) => Step::Repeat(
+                    repeat,
+                    Peephole::cancel(steps, times.saturating_mul(repeat), removed),
+                ),
+            })
+            .collect()
+    }
+}
+
+impl Pass for Peephole {
+    fn name(&self) -> &str {
+        "peephole"
+    }
+
+    fn run(&mut self, steps: Vec<Step>) -> Result<Vec<Step>, Error> {
+        Ok(Peephole::optimize(steps, &mut self.removed))
+    }
+
+    fn removed(&self) -> usize {
+        self.removed
+    }
+}
+
+/// Built-in pass dropping the brainfuck loops that can never be entered,
+/// the ones right after the end of another loop *(`[-][>+<-]`)*, which leaves the cell 0.
+///
+/// Only a loop planned within the same operators as the end before it is dropped.
+///
+/// Like [`Peephole`], it's only run with brainfuck's operators.
+#[derive(Default)]
+pub struct DeadLoops {
+    removed: usize,
+}
+
+impl DeadLoops {
+    /// Drop the unreachable loops of `steps`, the innermost first,
+    /// adding the number of removed operators to `removed`.
+    pub fn eliminate(steps: Vec<Step>, removed: &mut usize) -> Vec<Step> {
+        SimplifySteps::simplify(DeadLoops::eliminate_repeated(steps, 1, removed))
+    }
+
+    /// Drop the unreachable loops of the `times` repeated `steps`,
+    /// adding the number of removed operators to `removed`.
+    fn eliminate_repeated(steps: Vec<Step>, times: usize, removed: &mut usize) -> Vec<Step> {
+        steps
+            .into_iter()
+            .map(|step| match step {
+                Step::Operators(operators) => {
+                    Step::Operators(DeadLoops::eliminate_in(&operators, times, removed))
+                }
+                Step::Repeat(repeat, steps) => Step::Repeat(
+                    repeat,
+                    DeadLoops::eliminate_repeated(steps, times.saturating_mul(repeat), removed),
+                ),
+            })
+            .collect()
+    }
+
+    /// Drop the unreachable loops of the `times` repeated `operators`,
+    /// keeping the unmatched ones.
+    fn eliminate_in(operators: &str, times: usize, removed: &mut usize) -> String {
+        let operators: Vec<char> = operators.chars().collect();
+        let mut kept = String::with_capacity(operators.len());
+        let mut index = 0;
+        while let Some(&operator) = operators.get(index) {
+            index += 1;
+            if operator != '[' || !kept.ends_with(']') {
+                kept.push(operator);
+                continue;
+            }
+
+            let mut depth = 1;
+            let end = operators[index..].iter().position(|operator| {
+                match operator {
+                    '[' => depth += 1,
+                    ']' => depth -= 1,
+                    _ => (),
+                }
+                depth == 0
+            });
+            match end {
+                Some(end) => {
+                    index += end + 1;
+                    // the loop's operators with both brackets
+                    *removed = removed.saturating_add(times.saturating_mul(end + 2));
+                }
+                None => kept.push(operator),
+            }
+        }
+
+        kept
+    }
+}
+
+impl Pass for DeadLoops {
+    fn name(&self) -> &str {
+        "dead-loops"
+    }
+
+    fn run(&mut self, steps: Vec<Step>) -> Result<Vec<Step>, Error> {
+        Ok(DeadLoops::eliminate(steps, &mut self.removed))
+    }
+
+    fn removed(&self) -> usize {
+        self.removed
+    }
+}
+
+/// Push `step` to `steps`, merging consecutive operators.
+fn push_step(steps: &mut Vec<Step>, step: Step) {
+    match (steps.last_mut(), step) {
+        (Some(Step::Operators(operators)), Step::Operators(next)) => operators.push_str(&next),
+        (_, step) => steps.push(step),
+    }
+}
+
+/// Stage of the preprocessing pipeline, listed in the order they're run.
+#[derive(
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    fmt::Debug,
+    Serialize,
+    Deserialize,
+    clap::ValueEnum,
+)]
+pub enum Stage {
+    /// Reading the tokens of the input.
+    Lex,
+    /// Expanding the macros, done by the [`Lexer`][crate::lex::Lexer] while lexing.
+    MacroExpand,
+    /// Multiplying the nested repetitions *(see [`FlattenRepetitions`])*.
+    ConstFold,
+    /// Simplifying the planned steps *(see [`SimplifySteps`])*,
+    /// and cancelling the no-ops if opted into *(see [`Pipeline::with_peephole`])*.
+    Optimize,
+    /// Aligning the emitted operators into lines *(see [`AlignBy`][crate::pre::AlignBy])*.
+    Layout,
+    /// Writing the operators.
+    Emit,
+}
+
+impl Stage {
+    /// Every stage, in the order they're run.
+    pub const ALL: [Stage; 6] = [
+        Stage::Lex,
+        Stage::MacroExpand,
+        Stage::ConstFold,
+        Stage::Optimize,
+        Stage::Layout,
+        Stage::Emit,
+    ];
+
+    /// Check whether the stage can't be left out of a [`Pipeline`].
+    pub fn is_required(&self) -> bool {
+        matches!(self, Stage::Lex | Stage::MacroExpand | Stage::Emit)
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Stage::Lex => "lex",
+            Stage::MacroExpand => "macro-expand",
+            Stage::ConstFold => "const-fold",
+            Stage::Optimize => "optimize",
+            Stage::Layout => "layout",
+            Stage::Emit => "emit",
+        };
+
+        write!(f, "{name}")
+    }
+}
+
+/// [`Stages`][Stage] run while preprocessing, every one by default.
+///
+/// The optional stages can be left out, e.g. `lex,macro-expand,emit`
+/// emits the operators as they're planned, without aligning them.
+///
+/// The [`Peephole`] and [`DeadLoops`] passes are only run if opted into with `with_peephole()`,
+/// as part of [`Stage::Optimize`].
+#[derive(Clone, PartialEq, Eq, fmt::Debug)]
+pub struct Pipeline {
+    stages: Vec<Stage>,
+    peephole: bool,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Pipeline {
+            stages: Stage::ALL.to_vec(),
+            peephole: false,
+        }
+    }
+}
+
+impl Pipeline {
+    /// Initialize a pipeline running `stages`, returns error if
+    /// they're out of order or a [required][Stage::is_required] one is missing.
+    pub fn new<S: IntoIterator<Item = Stage>>(stages: S) -> Result<Self, config::Error> {
+        let stages: Vec<Stage> = stages.into_iter().collect();
+        if let Some(pair) = stages.windows(2).find(|pair| pair[0] >= pair[1]) {
+            return Err(config::Error::StageOrder(
+                pair[1].to_string(),
+                pair[0].to_string(),
+            ));
+        }
+        if let Some(stage) = Stage::ALL
+            .iter()
+            .find(|stage| stage.is_required() && !stages.contains(stage))
+        {
+            return Err(config::Error::MissingStage(stage.to_string()));
+        }
+
+        Ok(Pipeline {
+            stages,
+            peephole: false,
+        })
+    }
+
+    /// Set whether the [`Peephole`] and [`DeadLoops`] passes are run
+    /// after [`SimplifySteps`], if [`Stage::Optimize`] is.
+    pub fn with_peephole(mut self, peephole: bool) -> Self {
+        self.peephole = peephole;
+        self
+    }
+
+    /// Check whether the [`Peephole`] and [`DeadLoops`] passes are run.
+    pub fn runs_peephole(&self) -> bool {
+        self.peephole && self.runs(Stage::Optimize)
+    }
+
+    pub fn stages(&self) -> &[Stage] {
+        &self.stages
+    }
+
+    /// Check whether `stage` is run.
+    pub fn runs(&self, stage: Stage) -> bool {
+        self.stages.contains(&stage)
+    }
+
+    /// Get a [`PassManager`] with the built-in passes of the stages run on the planned steps.
+    pub fn passes(&self) -> PassManager {
+        let mut passes = PassManager::empty();
+        if self.runs(Stage::ConstFold) {
+            passes.add(Box::new(FlattenRepetitions));
+        }
+        if self.runs(Stage::Optimize) {
+            passes.add(Box::new(SimplifySteps));
+        }
+        if self.runs_peephole() {
+            passes.add(Box::<Peephole>::default());
+            passes.add(Box::<DeadLoops>::default());
+        }
+
+        passes
+    }
+}
+
+/// Pipeline of the [`Passes`][Pass] run on the planned steps, in the order they were added.
+pub struct PassManager {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl Default for PassManager {
+    /// Get a `PassManager` with every built-in pass.
+    fn default() -> Self {
+        Pipeline::default().passes()
+    }
+}
+
+impl PassManager {
+    /// Get a `PassManager` without any passes.
+    pub fn empty() -> Self {
+        PassManager { passes: Vec::new() }
+    }
+
+    /// Add `pass` to the end of the pipeline.
+    pub fn add(&mut self, pass: Box<dyn Pass>) {
+        self.passes.push(pass);
+    }
+
+    /// Get the names of the passes, in the order they're run.
+    pub fn names(&self) -> Vec<&str> {
+        self.passes.iter().map(|pass| pass.name()).collect()
+    }
+
+    /// Get how many emitted operators the passes removed so far *(see [`Pass::removed`])*.
+    pub fn removed(&self) -> usize {
+        self.passes
+            .iter()
+            .fold(0, |removed, pass| removed.saturating_add(pass.removed()))
+    }
+
+    /// Run every pass on `steps`, stopping at the first failing one.
+    pub fn run(&mut self, steps: Vec<Step>) -> Result<Vec<Step>, Error> {
+        self.passes
+            .iter_mut()
+            .try_fold(steps, |steps, pass| pass.run(steps))
+    }
+}
+
+#[cfg(feature = "unstable-pass-plugins")]
+pub use plugin::{DylibPass, PASS_ABI_VERSION};
+
+/// Passes loaded from dylibs.
+#[cfg(feature = "unstable-pass-plugins")]
+mod plugin {
+    use std::ffi::{c_char, CStr};
+    use std::path::Path;
+    use std::ptr;
+
+    use libloading::Library;
+
+    use super::{Error, Pass};
+    use crate::plan::Step;
+
+    /// Version of the ABI a plugin has to implement, returned by its `bfup_pass_abi_version`.
+    pub const PASS_ABI_VERSION: u32 = 1;
+
+    type AbiVersionFn = unsafe extern "C" fn() -> u32;
+    type NameFn = unsafe extern "C" fn() -> *const c_char;
+    type RunFn = unsafe extern "C" fn(*const u8, usize, *mut *mut u8, *mut usize) -> i32;
+    type FreeFn = unsafe extern "C" fn(*mut u8, usize);
+
+    /// [`Pass`] loaded from a dylib *(e.g. a Rust `cdylib`)*, exporting
+    /// the following unmangled `extern "C"` functions:
+    /// * `bfup_pass_abi_version() -> u32` - the [`PASS_ABI_VERSION`] it implements
+    /// * `bfup_pass_name() -> *const c_char` - its name, a static nul-terminated string
+    /// * `bfup_pass_run(steps: *const u8, steps_len: usize, output: *mut *mut u8,
+    ///   output_len: *mut usize) -> i32` - transform the steps, returning 0 on success
+    /// * `bfup_pass_free(output: *mut u8, output_len: usize)` - free an output of `bfup_pass_run`
+    ///
+    /// The steps are passed to `bfup_pass_run` as a json list of [`Steps`][Step]
+    /// *(`{"Operators": "+>"}` or `{"Repeat": [3, [...]]}`)*. It writes the transformed
+    /// steps in the same format to a buffer it allocates, or an utf-8 error message
+    /// when it fails. The buffer is freed with `bfup_pass_free` once read.
+    ///
+    /// The ABI is unstable and may change between versions.
+    pub struct DylibPass {
+        name: String,
+        run: RunFn,
+        free: FreeFn,
+        // kept loaded while the functions can be called
+        _library: Library,
+    }
+
+    impl DylibPass {
+        /// Load the pass from the dylib at `path`.
+        ///
+        /// # Safety
+        ///
+        /// Loading the dylib runs its initialization code, and its functions
+        /// are called as declared by the ABI, which can't be verified.
+        pub unsafe fn load(path: &Path) -> Result<DylibPass, Error> {
+            let load_error = |message: String| Error::Load {
+                path: path.display().to_string(),
+                message,
+            };
+
+            let library = Library::new(path).map_err(|error| load_error(error.to_string()))?;
+            let abi_version: AbiVersionFn = *library
+                .get(b"bfup_pass_abi_version\0")
+                .map_err(|error| load_error(error.to_string()))?;
+            if abi_version() != PASS_ABI_VERSION {
+                return Err(load_error(format!(
+                    "implements ABI version {}, expected version {PASS_ABI_VERSION}",
+                    abi_version()
+                )));
+            }
+            let name: NameFn = *library
+                .get(b"bfup_pass_name\0")
+                .map_err(|error| load_error(error.to_string()))?;
+            let run: RunFn = *library
+                .get(b"bfup_pass_run\0")
+                .map_err(|error| load_error(error.to_string()))?;
+            let free: FreeFn = *library
+                .get(b"bfup_pass_free\0")
+                .map_err(|error| load_error(error.to_string()))?;
+
+            let name = name();
+            if name.is_null() {
+                return Err(load_error(String::from("the name is null")));
+            }
+
+            Ok(DylibPass {
+                name: CStr::from_ptr(name).to_string_lossy().into_owned(),
+                run,
+                free,
+                _library: library,
+            })
+        }
+    }
+
+    impl Pass for DylibPass {
+        fn name(&self) -> &str {
+            &self.name
+        }
+
+        fn run(&mut self, steps: Vec<Step>) -> Result<Vec<Step>, Error> {
+            let failed = |message: String| Error::Failed {
+                name: self.name.clone(),
+                message,
+            };
+
+            let input = serde_json::to_vec(&steps).map_err(|error| failed(error.to_string()))?;
+            let mut output: *mut u8 = ptr::null_mut();
+            let mut output_len: usize = 0;
+            // SAFETY: the functions were loaded according to the ABI in `load`
+            let (status, output) = unsafe {
+                let status = (self.run)(input.as_ptr(), input.len(), &mut output, &mut output_len);
+                if output.is_null() {
+                    return Err(failed(format!("returned {status} without an output")));
+                }
+                let copied = std::slice::from_raw_parts(output, output_len).to_vec();
+                (self.free)(output, output_len);
+                (status, copied)
+            };
+
+            if status != 0 {
+                return Err(failed(String::from_utf8_lossy(&output).into_owned()));
+            }
+            serde_json::from_slice(&output)
+                .map_err(|error| failed(format!("returned malformed steps: {error}")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pass doubling every run of operators.
+    struct Double;
+
+    impl Pass for Double {
+        fn name(&self) -> &str {
+            "double"
+        }
+
+        fn run(&mut self, steps: Vec<Step>) -> Result<Vec<Step>, Error> {
+            Ok(steps
+                .into_iter()
+                .map(|step| Step::Repeat(2, vec![step]))
+                .collect())
+        }
+    }
+
+    #[test]
+    fn pass_pipeline() -> Result<(), Error> {
+        let mut passes = PassManager::default();
+        passes.add(Box::new(Double));
+        passes.add(Box::new(FlattenRepetitions));
+        assert!(
+            passes.names()
+                == [
+                    "flatten-repetitions",
+                    "simplify-steps",
+                    "double",
+                    "flatten-repetitions"
+                ],
+            "The passes should run in the order they were added."
+        );
+
+        let steps = passes.run(vec![
+            Step::Repeat(
+                3,
+                vec![Step::Repeat(4, vec![Step::Operators(String::from("+"))])],
+            ),
+            Step::Operators(String::from("-")),
+        ])?;
+        assert!(
+            steps
+                == [
+                    Step::Repeat(24, vec![Step::Operators(String::from("+"))]),
+                    Step::Repeat(2, vec![Step::Operators(String::from("-"))]),
+                ],
+            "{steps:?} should be flattened, doubled and flattened again."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn pass_dead_loops() {
+        let mut removed = 0;
+        let steps = DeadLoops::eliminate(
+            vec![
+                Step::Operators(String::from("+[-][>+<-][[-]>]>[-]")),
+                Step::Repeat(2, vec![Step::Operators(String::from("[.][,]"))]),
+                Step::Operators(String::from("[<]")),
+            ],
+            &mut removed,
+        );
+        assert!(
+            steps
+                == [
+                    Step::Operators(String::from("+[-]>[-]")),
+                    Step::Repeat(2, vec![Step::Operators(String::from("[.]"))]),
+                    Step::Operators(String::from("[<]")),
+                ],
+            "{steps:?} should only have the loops right after another one dropped."
+        );
+        assert!(
+            removed == 18,
+            "{removed} should count the operators of every dropped loop and repetition."
+        );
+        assert!(
+            DeadLoops::eliminate(vec![Step::Operators(String::from("[-][>"))], &mut removed)
+                == [Step::Operators(String::from("[-][>"))],
+            "An unmatched loop should be kept."
+        );
+    }
+
+    #[test]
+    fn pass_stages() -> Result<(), config::Error> {
+        let pipeline =
+            Pipeline::new([Stage::Lex, Stage::MacroExpand, Stage::Optimize, Stage::Emit])?;
+        assert!(
+            pipeline.passes().names() == ["simplify-steps"],
+            "Only the passes of the run stages should be added."
+        );
+
+        let steps = pipeline
+            .passes()
+            .run(vec![
+                Step::Repeat(1, vec![Step::Operators(String::from("+"))]),
+                Step::Repeat(0, vec![Step::Operators(String::from("<"))]),
+                Step::Operators(String::from(">")),
+                Step::Repeat(
+                    2,
+                    vec![Step::Repeat(3, vec![Step::Operators(String::new())])],
+                ),
+            ])
+            .expect("The built-in passes should not fail.");
+        assert!(
+            steps == [Step::Operators(String::from("+>"))],
+            "{steps:?} should be simplified."
+        );
+
+        let mut passes = Pipeline::default().with_peephole(true).passes();
+        let steps = passes.run(vec![
+            Step::Operators(String::from("+>[-+]<")),
+            Step::Repeat(3, vec![Step::Operators(String::from("<>"))]),
+            Step::Operators(String::from("-.+")),
+            Step::Repeat(2, vec![Step::Operators(String::from("+>"))]),
+            Step::Operators(String::from("-")),
+        ]);
+        let steps = steps.expect("The built-in passes should not fail.");
+        assert!(
+            steps
+                == [
+                    Step::Operators(String::from(".+")),
+                    Step::Repeat(2, vec![Step::Operators(String::from("+>"))]),
+                    Step::Operators(String::from("-")),
+                ],
+            "{steps:?} should have the no-ops cancelled."
+        );
+        assert!(
+            passes.removed() == 14,
+            "{} should count the cancelled operators of every repetition.",
+            passes.removed()
+        );
+
+        let pipeline = Pipeline::new([Stage::Lex, Stage::MacroExpand, Stage::Emit])?;
+        assert!(
+            pipeline.with_peephole(true).passes().names().is_empty(),
+            "The peephole passes should only run with the optimize stage."
+        );
+
+        let result = Pipeline::new([Stage::Lex, Stage::Emit, Stage::MacroExpand]);
+        assert!(
+            matches!(result, Err(config::Error::StageOrder(..))),
+            "Stages out of order should be errors."
+        );
+        let result = Pipeline::new([Stage::Lex, Stage::MacroExpand]);
+        assert!(
+            matches!(result, Err(config::Error::MissingStage(..))),
+            "Leaving out a required stage should be an error."
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,294 @@
+use std::fmt;
+use std::io::{self, Write};
+
+use serde::{Deserialize, Serialize};
+
+use crate::lex::Token;
+use crate::pass::Pipeline;
+use crate::pre::printing_code;
+
+/// Step of the plan of the operators emitted by the [`Tokens`][Token].
+#[derive(Clone, PartialEq, Eq, fmt::Debug, Serialize, Deserialize)]
+pub enum Step {
+    /// Operators written as they are.
+    Operators(String),
+    /// Steps written `times` times in a row.
+    Repeat(usize, Vec<Step>),
+}
+
+impl Step {
+    /// Get the number of operators emitted by the step.
+    pub fn operators(&self) -> usize {
+        match self {
+            Step::Operators(operators) => operators.chars().count(),
+            Step::Repeat(times, steps) => times.saturating_mul(
+                steps
+                    .iter()
+                    .fold(0, |len, step| len.saturating_add(step.operators())),
+            ),
+        }
+    }
+}
+
+/// Position of the pointer on the tape, modeled while planning
+/// the moves to the named cells *(see [`Token::Cell`])*.
+///
+/// The pointer starts at the cell 0 and is only moved by the planned `>` and `<`,
+/// so the loops are assumed to leave it where they found it. Cells can only be
+/// named with brainfuck's operators
+/// *(see [`Config::set_named_cells`][crate::config::Config::set_named_cells])*.
+#[derive(Clone, Copy, Default, PartialEq, Eq, fmt::Debug)]
+pub struct Pointer(isize);
+
+impl Pointer {
+    /// Move the pointer by the `>` and `<` of `operators`, written `times` times.
+    fn advance(&mut self, operators: &str, times: usize) {
+        let moved = operators
+            .chars()
+            .fold(0isize, |moved, operator| match operator {
+                '>' => moved + 1,
+                '<' => moved - 1,
+                _ => moved,
+            });
+        let times = isize::try_from(times).unwrap_or(isize::MAX);
+        self.0 = self.0.saturating_add(moved.saturating_mul(times));
+    }
+
+    /// Move the pointer to `cell`, returning the operators moving it there.
+    fn move_to(&mut self, cell: usize) -> String {
+        let cell = isize::try_from(cell).unwrap_or(isize::MAX);
+        let distance = cell.abs_diff(self.0);
+        let operator = if cell > self.0 { ">" } else { "<" };
+        self.0 = cell;
+
+        operator.repeat(distance)
+    }
+
+    /// Repeat `times` times the moves made since the pointer was at `start`.
+    fn repeat_from(&mut self, start: Pointer, times: usize) {
+        let times = isize::try_from(times).unwrap_or(isize::MAX);
+        self.0 = start
+            .0
+            .saturating_add(self.0.saturating_sub(start.0).saturating_mul(times));
+    }
+}
+
+/// Plan the operators emitted by `tokens`, so that every group is walked once,
+/// with every built-in pass run on the steps *(see [`PassManager`][crate::pass::PassManager])*.
+pub fn plan(tokens: &[Token]) -> Vec<Step> {
+    plan_with(tokens, &Pipeline::default())
+}
+
+/// Same as [`plan`], but only runs the passes of the stages in `pipeline`.
+pub fn plan_with(tokens: &[Token], pipeline: &Pipeline) -> Vec<Step> {
+    plan_at(tokens, pipeline, &mut Pointer::default())
+}
+
+/// Same as [`plan_with`], also returning how many operators the passes removed
+/// *(see [`PassManager::removed`][crate::pass::PassManager::removed])*.
+pub fn plan_counted(tokens: &[Token], pipeline: &Pipeline) -> (Vec<Step>, usize) {
+    let mut passes = pipeline.passes();
+    let steps = passes
+        .run(lower(tokens))
+        .expect("The built-in passes should not fail.");
+
+    (steps, passes.removed())
+}
+
+/// Same as [`plan_with`], but the moves to the named cells are planned
+/// from the `pointer`, which is moved by the planned operators
+/// *(so tokens planned one by one can share it)*.
+pub fn plan_at(tokens: &[Token], pipeline: &Pipeline, pointer: &mut Pointer) -> Vec<Step> {
+    pipeline
+        .passes()
+        .run(lower_at(tokens, pointer))
+        .expect("The built-in passes should not fail.")
+}
+
+/// Get the steps emitting the operators of `tokens`, before any pass is run.
+///
+/// Runs of single operators are merged into [`Step::Operators`], while every
+/// multiplied token becomes a [`Step::Repeat`].
+pub fn lower(tokens: &[Token]) -> Vec<Step> {
+    lower_at(tokens, &mut Pointer::default())
+}
+
+/// Same as [`lower`], but the moves to the named cells are planned from the `pointer`.
+///
+/// A multiplied group moving to a named cell is planned as its first repetition,
+/// followed by the rest, as only the first one starts from where the pointer was.
+fn lower_at(tokens: &[Token], pointer: &mut Pointer) -> Vec<Step> {
+    let mut steps = Vec::new();
+    let mut multiplier: usize = 1;
+    for token in tokens {
+        match token.without_provenance() {
+            Token::Number(number, ..) => {
+                multiplier = *number;
+                continue;
+            }
+            Token::Operator(operator, _) if multiplier == 1 => {
+                pointer.advance(&operator.to_string(), 1);
+                match steps.last_mut() {
+                    Some(Step::Operators(operators)) => operators.push(*operator),
+                    _ => steps.push(Step::Operators(operator.to_string())),
+                }
+            }
+            Token::Operator(operator, _) => {
+                pointer.advance(&operator.to_string(), multiplier);
+                steps.push(Step::Repeat(
+                    multiplier,
+                    vec![Step::Operators(operator.to_string())],
+                ))
+            }
+            Token::Group(group, _) => {
+                let start = *pointer;
+                let planned = lower_at(group, pointer);
+                if multiplier == 1 {
+                    for step in planned {
+                        push_step(&mut steps, step);
+                    }
+                } else if multiplier == 0 {
+                    *pointer = start;
+                } else if !moves_to_cell(group) {
+                    pointer.repeat_from(start, multiplier);
+                    if !planned.is_empty() {
+                        steps.push(Step::Repeat(multiplier, planned));
+                    }
+                } else {
+                    let repeated = lower_at(group, pointer);
+                    for step in planned {
+                        push_step(&mut steps, step);
+                    }
+                    if multiplier > 1 && !repeated.is_empty() {
+                        steps.push(Step::Repeat(multiplier - 1, repeated));
+                    }
+                }
+            }
+            Token::Str(text, _) if multiplier == 1 => {
+                push_step(&mut steps, Step::Operators(printing_code(text)))
+            }
+            Token::Str(text, _) => steps.push(Step::Repeat(
+                multiplier,
+                vec![Step::Operators(printing_code(text))],
+            )),
+            Token::Raw(text, _) if multiplier == 1 => {
+                pointer.advance(text, 1);
+                push_step(&mut steps, Step::Operators(text.to_string()))
+            }
+            Token::Raw(text, _) => {
+                pointer.advance(text, multiplier);
+                steps.push(Step::Repeat(
+                    multiplier,
+                    vec![Step::Operators(text.to_string())],
+                ))
+            }
+            // moving to the same cell again doesn't move the pointer
+            Token::Cell(cell, _) if multiplier > 0 => {
+                push_step(&mut steps, Step::Operators(pointer.move_to(cell.index)))
+            }
+            Token::Cell(..) => (),
+            Token::Expanded(..) => unreachable!("The provenance should be stripped."),
+        }
+        multiplier = 1;
+    }
+
+    steps
+}
+
+/// Check whether any of `tokens` moves to a named cell.
+pub fn moves_to_cell(tokens: &[Token]) -> bool {
+    tokens.iter().any(|token| match token.without_provenance() {
+        Token::Cell(..) => true,
+        Token::Group(group, _) => moves_to_cell(group),
+        _ => false,
+    })
+}
+
+/// Push `step` to `steps`, merging consecutive operators.
+fn push_step(steps: &mut Vec<Step>, step: Step) {
+    match (steps.last_mut(), step) {
+        (Some(Step::Operators(operators)), Step::Operators(next)) => operators.push_str(&next),
+        (_, step) => steps.push(step),
+    }
+}
+
+/// Write `steps` to `output` one per line, the repeated ones indented by `depth`.
+pub fn write_plan<W: Write>(output: &mut W, steps: &[Step], depth: usize) -> io::Result<()> {
+    for step in steps {
+        match step {
+            Step::Operators(operators) => {
+                writeln!(output, "{:indent$}{operators}", "", indent = depth * 2)?
+            }
+            Step::Repeat(times, steps) => {
+                writeln!(
+                    output,
+                    "{:indent$}{times} times ({} operators):",
+                    "",
+                    step.operators(),
+                    indent = depth * 2
+                )?;
+                write_plan(output, steps, depth + 1)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::config::Config;
+    use crate::testing;
+
+    #[test]
+    fn plan_nested_repetitions() -> Result<()> {
+        let tokens = testing::tokens("$x(#3(#4(+>))) +x-(<<)#2(.#2,)", &Config::default())?;
+        let steps = plan(&tokens);
+
+        assert!(
+            steps
+                == [
+                    Step::Operators(String::from("+")),
+                    Step::Repeat(12, vec![Step::Operators(String::from("+>"))]),
+                    Step::Operators(String::from("-<<")),
+                    Step::Repeat(
+                        2,
+                        vec![
+                            Step::Operators(String::from(".")),
+                            Step::Repeat(2, vec![Step::Operators(String::from(","))]),
+                        ]
+                    ),
+                ],
+            "{steps:?} should flatten the nested repetition."
+        );
+        assert!(
+            steps.iter().map(Step::operators).sum::<usize>() == 34,
+            "The plan should emit every operator."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn plan_named_cells() -> Result<()> {
+        let mut config = Config::default();
+        config.set_named_cells(true)?;
+        let tokens = testing::tokens("$&a#2 #3(&a+>) #2(>)", &config)?;
+        let steps = plan(&tokens);
+
+        assert!(
+            steps
+                == [
+                    Step::Operators(String::from(">>+>")),
+                    Step::Repeat(2, vec![Step::Operators(String::from("<+>"))]),
+                    Step::Repeat(2, vec![Step::Operators(String::from(">"))]),
+                ],
+            "{steps:?} should plan the first repetition from the start."
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,74 @@
+use std::io;
+use std::process::{Command, Stdio};
+
+/// Placeholder replaced with the encoded program in the URL template.
+pub const PROGRAM_PLACEHOLDER: &str = "{}";
+
+/// Percent-encode `text` to be used within a URL,
+/// leaving only the unreserved characters as they are.
+pub fn url_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            byte => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+
+    encoded
+}
+
+/// Get the URL of the `program` within `template`, replacing every
+/// [`PROGRAM_PLACEHOLDER`] *(or appended if there is none)* with the encoded program.
+pub fn playground_url(template: &str, program: &str) -> String {
+    let program = url_encode(program);
+    if template.contains(PROGRAM_PLACEHOLDER) {
+        template.replace(PROGRAM_PLACEHOLDER, &program)
+    } else {
+        format!("{template}{program}")
+    }
+}
+
+/// Open `url` in the default browser, without waiting for it to exit.
+pub fn open_in_browser(url: &str) -> io::Result<()> {
+    let mut command = if cfg!(target_os = "windows") {
+        let mut command = Command::new("cmd");
+        // the empty argument is the title of the started window
+        command.args(["/C", "start", ""]);
+        command
+    } else if cfg!(target_os = "macos") {
+        Command::new("open")
+    } else {
+        Command::new("xdg-open")
+    };
+
+    command
+        .arg(url)
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn playground_urls() {
+        let url = playground_url("https://copy.sh/brainfuck/?c={}", "+[>.]\n");
+        assert!(
+            url == "https://copy.sh/brainfuck/?c=%2B%5B%3E.%5D%0A",
+            "\"{url}\" should contain the encoded program."
+        );
+
+        let url = playground_url("https://example.com/#", ",.");
+        assert!(
+            url == "https://example.com/#%2C.",
+            "\"{url}\" should have the program appended."
+        );
+    }
+}
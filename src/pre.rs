@@ -1,53 +1,566 @@
 use std::error::Error as ErrorTrait;
-use std::io::Write;
+use std::fmt;
+use std::io::{self, Write};
 use std::marker::{Send, Sync};
+use std::num::NonZeroUsize;
 
 use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use unicode_segmentation::UnicodeSegmentation;
 
+use crate::cancel::CancellationToken;
+use crate::checkpoint::Checkpoint;
 use crate::config::Config;
-use crate::lex::{Lexer, Token};
+use crate::grapheme::Graphemes;
+use crate::lex::{
+    self, Coverage, ErrorGroup, Lexer, MacroDefinition, Token, UnrecognizedChars, Warning,
+};
+use crate::pass::{Pipeline, Stage};
+use crate::plan::{moves_to_cell, plan_at, plan_with, Pointer, Step};
+use crate::span::Span;
 
-/// Shorthand for a loop that runs $times times.
-macro_rules! repeat {
-    ($body:expr, $times:expr) => {
-        for _ in 0..$times {
-            $body;
+/// Largest chunk of output passed to the closure of [`preprocess_with`].
+pub const OUTPUT_CHUNK_SIZE: usize = 8192;
+/// Minimum number of digits of the line numbers written by [`Gutter`].
+pub const GUTTER_WIDTH: usize = 4;
+
+/// Information collected while preprocessing.
+#[derive(Default, fmt::Debug)]
+pub struct Report {
+    pub warnings: Vec<Warning>,
+    /// Only collected when the [`Lexer`] is asked to *(see [`Lexer::collect_unrecognized`])*.
+    pub unrecognized: Option<UnrecognizedChars>,
+    /// Only collected when the [`Lexer`] is asked to *(see [`Lexer::collect_coverage`])*.
+    pub coverage: Option<Coverage>,
+    /// Number of operators removed by the optimizing passes,
+    /// only counted when they run *(see [`Pipeline::with_peephole`])*.
+    pub optimized: Option<usize>,
+}
+
+impl<I, E> From<&mut Lexer<'_, I, E>> for Report
+where
+    I: Iterator<Item = Result<char, E>>,
+    E: ErrorTrait + Send + Sync + 'static,
+{
+    fn from(lexer: &mut Lexer<'_, I, E>) -> Self {
+        Report {
+            warnings: lexer.take_warnings(),
+            unrecognized: lexer.take_unrecognized(),
+            coverage: lexer.take_coverage(),
+            optimized: None,
         }
-    };
+    }
 }
 
-/// Define a write_token_iter function with optional, additional arguments
-/// and an statement to run after an operator has been written.
-macro_rules! define_write_token_iter {
-    {($output_ident:ident : $output_type:ty $(, $arg_ident:ident : $arg_type:ty)* ) $after: stmt} => {
-        fn write_token_iter<'a, T, W>(token_iter: T, $output_ident: $output_type, $($arg_ident: $arg_type),*) -> Result<()>
-        where
-            W: Write,
-            T: Iterator<Item = &'a Token>
-        {
-            let mut multiplier: usize = 1;
-            for token in token_iter {
-                match token {
-                    Token::Group(group) => {
-                        repeat!(write_token_iter(group.iter(), $output_ident, $($arg_ident),*)?, multiplier);
-                        multiplier = 1;
-                    },
-                    Token::Operator(operator) => {
-                        repeat!({
-                            write!($output_ident, "{operator}")?;
-                            $after
-                        }, multiplier);
-                        multiplier = 1;
-                    },
-                    Token::Number(number) => multiplier = *number,
+/// Metric used to measure the width of the lines
+/// when aligning the output.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash, fmt::Debug, clap::ValueEnum)]
+pub enum AlignBy {
+    /// Count the utf-8 encoded bytes of the operators.
+    Bytes,
+    /// Count the chars of the operators, a grapheme as a single char.
+    #[default]
+    Chars,
+    /// Count the operators.
+    Operators,
+}
+
+impl AlignBy {
+    /// Get the width of `operator`.
+    ///
+    /// An operator is a single char or [grapheme][crate::grapheme::Graphemes],
+    /// so `Chars` and `Operators` only differ for the operators made of words.
+    pub fn width(&self, operator: &str) -> usize {
+        match self {
+            AlignBy::Bytes => operator.len(),
+            AlignBy::Chars if operator.is_ascii() => operator.len(),
+            AlignBy::Chars => operator.graphemes(true).count(),
+            AlignBy::Operators => 1,
+        }
+    }
+
+    /// Get the widest operator of `config`, decoded, with its width.
+    ///
+    /// Operators wider than the line width can't fit in a line,
+    /// they're written on a line of their own, overflowing it.
+    pub fn widest_operator(&self, config: &Config) -> Option<(String, usize)> {
+        config
+            .get_operators()
+            .map(|ch| {
+                let operator = match config.get_graphemes().decode(ch) {
+                    Some(grapheme) => grapheme.to_owned(),
+                    None => ch.to_string(),
+                };
+                let width = self.width(&operator);
+                (operator, width)
+            })
+            .max_by(|(a, a_width), (b, b_width)| a_width.cmp(b_width).then(b.cmp(a)))
+    }
+}
+
+/// What is written instead of the preprocessed output.
+#[derive(Clone, Copy, Default, PartialEq, Eq, fmt::Debug, clap::ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Emit {
+    /// The preprocessed output
+    #[default]
+    Output,
+    /// The number of tokens of every kind as json, counted
+    /// in a single pass without lexing the whole input
+    TokensCount,
+    /// The lines and columns of the output emitted by every macro
+    /// expansion and group as json, for overlaying them onto the output
+    Annotations,
+}
+
+/// Options for resuming an interrupted run and saving [`Checkpoints`][Checkpoint].
+pub struct Resumable<'a> {
+    /// Checkpoint of the run to resume, its operators are skipped.
+    pub resume_from: Checkpoint,
+    /// Number of operators between saved checkpoints.
+    pub every: NonZeroUsize,
+    /// Called with every checkpoint, after the output has been flushed.
+    pub save: &'a mut dyn FnMut(&Checkpoint) -> Result<()>,
+}
+
+/// Line and column of an operator in the output, both 1-based.
+#[derive(Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, fmt::Debug, Serialize)]
+pub struct OutputPosition {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// Range of the output emitted by a macro expansion or a group,
+/// from its first to its last operator, found by [`annotate`].
+#[derive(Clone, PartialEq, Eq, fmt::Debug, Serialize)]
+pub struct Annotation {
+    /// Symbol of the expanded macro, `None` for a group.
+    pub macro_symbol: Option<char>,
+    /// Span of the macro's use or the group in the input.
+    pub span: Span,
+    pub start: OutputPosition,
+    pub end: OutputPosition,
+}
+
+impl Annotation {
+    /// Check whether the operator at `position` was emitted within the annotated range.
+    pub fn contains(&self, position: OutputPosition) -> bool {
+        self.start <= position && position <= self.end
+    }
+}
+
+/// Annotations recorded by the [`Emitter`], with the position of the next operator.
+#[derive(Default)]
+struct Annotations {
+    recorded: Vec<Annotation>,
+    /// Indices of the annotations opened before their first operator.
+    waiting: Vec<usize>,
+    /// Position of the last operator written.
+    last: OutputPosition,
+    /// 0-based line of the next operator and the chars written on it.
+    line: usize,
+    column: usize,
+}
+
+impl Annotations {
+    /// Open an annotation of `token` if it's a macro expansion or a group,
+    /// returning its index.
+    fn open(&mut self, token: &Token) -> Option<usize> {
+        let (macro_symbol, span) = match token {
+            Token::Expanded(_, provenance) => (Some(provenance.macro_symbol), provenance.use_site),
+            Token::Group(_, span) => (None, *span),
+            _ => return None,
+        };
+        self.waiting.push(self.recorded.len());
+        self.recorded.push(Annotation {
+            macro_symbol,
+            span,
+            start: OutputPosition::default(),
+            end: OutputPosition::default(),
+        });
+
+        Some(self.recorded.len() - 1)
+    }
+
+    /// Close the annotation at `index`, ending it at the last operator
+    /// if any were written since it was opened.
+    fn close(&mut self, index: usize) {
+        match self.waiting.iter().position(|waiting| *waiting == index) {
+            Some(position) => {
+                self.waiting.remove(position);
+            }
+            None => self.recorded[index].end = self.last,
+        }
+    }
+
+    /// Record the position of the written `operator`,
+    /// starting the annotations waiting for it.
+    fn record(&mut self, operator: &str) {
+        self.last = OutputPosition {
+            line: self.line + 1,
+            column: self.column + 1,
+        };
+        for index in self.waiting.drain(..) {
+            self.recorded[index].start = self.last;
+        }
+        self.column += operator.chars().count();
+    }
+}
+
+/// Writer of the operators emitted by the tokens.
+struct Emitter<'a, 'r, W: Write> {
+    output: &'a mut W,
+    cancellation: &'a CancellationToken,
+    /// Line width and its metric, if aligned.
+    alignment: Option<(usize, AlignBy)>,
+    /// Graphemes the operators are decoded into.
+    graphemes: &'a Graphemes,
+    state: Checkpoint,
+    /// Number of operators left to skip.
+    skip: usize,
+    resumable: Option<Resumable<'r>>,
+    /// Annotations of the macro expansions and groups, if recorded.
+    annotations: Option<Annotations>,
+}
+
+impl<'a, 'r, W: Write> Emitter<'a, 'r, W> {
+    /// Create an `Emitter` writing to `output`, resuming from the checkpoint
+    /// of `resumable`, if passed.
+    ///
+    /// The output isn't aligned if the config's pipeline leaves out [`Stage::Layout`].
+    ///
+    /// Returns error if the line width is 0.
+    fn new(
+        output: &'a mut W,
+        config: &'a Config,
+        alignment: Option<(usize, AlignBy)>,
+        resumable: Option<Resumable<'r>>,
+        cancellation: &'a CancellationToken,
+    ) -> Result<Self> {
+        if let Some((0, _)) = alignment {
+            anyhow::bail!("the line width must be at least 1.");
+        }
+        let state = resumable
+            .as_ref()
+            .map(|resumable| resumable.resume_from)
+            .unwrap_or_default();
+        Ok(Emitter {
+            output,
+            cancellation,
+            alignment: alignment.filter(|_| config.get_pipeline().runs(Stage::Layout)),
+            graphemes: config.get_graphemes(),
+            state,
+            skip: state.operators,
+            resumable,
+            annotations: None,
+        })
+    }
+
+    /// Emit the operators of the planned `steps`.
+    fn emit_steps(&mut self, steps: &[Step]) -> Result<()> {
+        for step in steps {
+            let size = step.operators();
+            if self.skip >= size {
+                self.skip -= size;
+                continue;
+            }
+
+            match step {
+                Step::Operators(operators) => {
+                    let skipped = operators.chars().take(self.skip).count();
+                    self.skip = 0;
+                    for operator in operators.chars().skip(skipped) {
+                        self.emit_operator(operator)?;
+                    }
+                }
+                Step::Repeat(times, steps) => {
+                    let mut times = *times;
+                    let repetition = size / times;
+                    if let Some(skipped) = self.skip.checked_div(repetition) {
+                        self.skip -= skipped * repetition;
+                        times -= skipped;
+                    }
+
+                    match &steps[..] {
+                        [Step::Operators(operators)] if self.skip == 0 => {
+                            for _ in 0..times {
+                                self.emit_str(operators)?;
+                            }
+                        }
+                        steps => {
+                            for _ in 0..times {
+                                self.emit_steps(steps)?;
+                            }
+                        }
+                    }
                 }
             }
+        }
+
+        Ok(())
+    }
 
-            Ok(())
+    /// Emit the operators of `tokens`, like [`emit_unsplit`].
+    fn emit_unsplit(
+        &mut self,
+        tokens: &[Token],
+        pipeline: &Pipeline,
+        pointer: &mut Pointer,
+    ) -> Result<()> {
+        // the current token, with the numbers before it
+        let mut item: Vec<Token> = Vec::new();
+        let mut multiplier: usize = 1;
+        for token in tokens {
+            item.push(token.clone());
+            match token.without_provenance() {
+                Token::Number(number, ..) => {
+                    multiplier = *number;
+                    continue;
+                }
+                Token::Group(group, _) if group.iter().any(is_group) => {
+                    for _ in 0..multiplier {
+                        self.emit_unsplit(group, pipeline, pointer)?;
+                    }
+                }
+                Token::Group(..) => {
+                    let steps = plan_at(&item, pipeline, pointer);
+                    self.keep_together(self.width(&steps))?;
+                    self.emit_steps(&steps)?;
+                }
+                _ => self.emit_steps(&plan_at(&item, pipeline, pointer))?,
+            }
+            item.clear();
+            multiplier = 1;
+        }
+
+        Ok(())
+    }
+
+    /// Emit the operators of `tokens`, annotating them like [`annotate`].
+    fn emit_annotated(
+        &mut self,
+        tokens: &[Token],
+        pipeline: &Pipeline,
+        pointer: &mut Pointer,
+    ) -> Result<()> {
+        // the current token, with the numbers before it
+        let mut item: Vec<Token> = Vec::new();
+        let mut multiplier: usize = 1;
+        for token in tokens {
+            item.push(token.clone());
+            if let Token::Number(number, ..) = token.without_provenance() {
+                multiplier = *number;
+                continue;
+            }
+
+            let opened = self
+                .annotations
+                .as_mut()
+                .and_then(|annotations| annotations.open(token));
+            match token {
+                // the group of a macro's body is annotated as the macro
+                Token::Expanded(expanded, _) => {
+                    let inner = match &**expanded {
+                        Token::Group(group, _) => group,
+                        token => std::slice::from_ref(token),
+                    };
+                    for _ in 0..multiplier {
+                        self.emit_annotated(inner, pipeline, pointer)?;
+                    }
+                }
+                Token::Group(group, _) => {
+                    for _ in 0..multiplier {
+                        self.emit_annotated(group, pipeline, pointer)?;
+                    }
+                }
+                _ => self.emit_steps(&plan_at(&item, pipeline, pointer))?,
+            }
+            if let (Some(annotations), Some(index)) = (&mut self.annotations, opened) {
+                annotations.close(index);
+            }
+            item.clear();
+            multiplier = 1;
+        }
+
+        Ok(())
+    }
+
+    /// Get the width of the operators of `steps`, in the metric of the alignment.
+    fn width(&self, steps: &[Step]) -> usize {
+        let Some((_, align_by)) = self.alignment else {
+            return 0;
+        };
+        steps.iter().fold(0, |width, step| {
+            width.saturating_add(match step {
+                Step::Operators(operators) => operators
+                    .chars()
+                    .map(|operator| match self.graphemes.decode(operator) {
+                        Some(grapheme) => align_by.width(grapheme),
+                        None => align_by.width(operator.encode_utf8(&mut [0; 4])),
+                    })
+                    .sum(),
+                Step::Repeat(times, steps) => times.saturating_mul(self.width(steps)),
+            })
+        })
+    }
+
+    /// Pad the line with spaces and start a new one, if aligned and the next
+    /// `width` wide operators fit in a line, but not in the rest of this one.
+    fn keep_together(&mut self, width: usize) -> Result<()> {
+        let Some((line_width, _)) = self.alignment else {
+            return Ok(());
+        };
+        if self.state.line_len > 0
+            && width <= line_width
+            && self.state.line_len + width > line_width
+        {
+            let padding = line_width - self.state.line_len;
+            write!(self.output, "{}", " ".repeat(padding))?;
+            self.state.bytes += padding as u64;
+            self.newline()?;
+        }
+
+        Ok(())
+    }
+
+    /// Write every operator in `operators`, at once if the output isn't aligned,
+    /// no checkpoints are saved and no operators are graphemes.
+    fn emit_str(&mut self, operators: &str) -> Result<()> {
+        if self.alignment.is_some()
+            || self.resumable.is_some()
+            || self.annotations.is_some()
+            || !self.graphemes.is_empty()
+        {
+            return operators
+                .chars()
+                .try_for_each(|operator| self.emit_operator(operator));
+        }
+
+        self.cancellation.check()?;
+        self.output.write_all(operators.as_bytes())?;
+        self.state.bytes += operators.len() as u64;
+        self.state.operators += operators.chars().count();
+
+        Ok(())
+    }
+
+    /// Write `operator`, breaking the line if aligned
+    /// and saving a checkpoint if it's due.
+    fn emit_operator(&mut self, operator: char) -> Result<()> {
+        self.cancellation.check()?;
+        let mut encoded = [0; 4];
+        let operator = match self.graphemes.decode(operator) {
+            Some(grapheme) => grapheme,
+            None => operator.encode_utf8(&mut encoded),
+        };
+
+        if let Some((line_width, align_by)) = self.alignment {
+            if self.state.line_len > 0
+                && self.state.line_len + align_by.width(operator) > line_width
+            {
+                self.newline()?;
+            }
+            write!(self.output, "{operator}")?;
+            if let Some(annotations) = &mut self.annotations {
+                annotations.record(operator);
+            }
+            self.state.line_len += align_by.width(operator);
+            if self.state.line_len >= line_width {
+                self.newline()?;
+            }
+        } else {
+            write!(self.output, "{operator}")?;
+            if let Some(annotations) = &mut self.annotations {
+                annotations.record(operator);
+            }
+        }
+        self.state.bytes += operator.len() as u64;
+        self.state.operators += 1;
+
+        if let Some(resumable) = &mut self.resumable {
+            if self.state.operators.is_multiple_of(resumable.every.get()) {
+                self.output.flush()?;
+                (resumable.save)(&self.state)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Write a newline, starting a new line.
+    fn newline(&mut self) -> Result<()> {
+        writeln!(self.output)?;
+        self.state.bytes += 1;
+        self.state.line_len = 0;
+        if let Some(annotations) = &mut self.annotations {
+            annotations.line += 1;
+            annotations.column = 0;
+        }
+
+        Ok(())
+    }
+}
+
+/// Record the operators emitted by `tokens` repeated `times` times in `coverage`.
+pub fn record_coverage(coverage: &mut Coverage, tokens: &[Token], times: usize) {
+    let mut multiplier: usize = 1;
+    for token in tokens {
+        match token.without_provenance() {
+            Token::Group(group, _) => {
+                record_coverage(coverage, group, times.saturating_mul(multiplier));
+                multiplier = 1;
+            }
+            Token::Operator(_, span) => {
+                coverage.emit(span, times.saturating_mul(multiplier));
+                multiplier = 1;
+            }
+            Token::Number(number, ..) => multiplier = *number,
+            Token::Str(..) | Token::Raw(..) | Token::Cell(..) => multiplier = 1,
+            Token::Expanded(..) => unreachable!("The provenance should be stripped."),
+        }
+    }
+}
+
+/// Get the code printing the UTF-8 bytes of `text` with the current cell,
+/// expected to be zero and left zero.
+///
+/// Every byte is reached from the previous one with `+` or `-` *(wrapping)*,
+/// or from a cell cleared with `[-]`, whichever is shorter.
+pub fn printing_code(text: &str) -> String {
+    let adjust = |code: &mut String, from: u8, to: u8| {
+        let up = to.wrapping_sub(from);
+        if up <= 128 {
+            code.extend(std::iter::repeat_n('+', up.into()));
+        } else {
+            code.extend(std::iter::repeat_n('-', up.wrapping_neg().into()));
         }
     };
+    let distance = |from: u8, to: u8| {
+        let up = to.wrapping_sub(from);
+        usize::from(up.min(up.wrapping_neg()))
+    };
+
+    let mut code = String::new();
+    let mut cell: u8 = 0;
+    for byte in text.bytes() {
+        if CLEAR.len() + distance(0, byte) < distance(cell, byte) {
+            code.push_str(CLEAR);
+            cell = 0;
+        }
+        adjust(&mut code, cell, byte);
+        code.push('.');
+        cell = byte;
+    }
+    if cell != 0 {
+        code.push_str(CLEAR);
+    }
+
+    code
 }
 
+/// Code setting the current cell to zero.
+const CLEAR: &str = "[-]";
+
 /// Run the preprocessor with the passed `config` on `input`, writing the result
 /// to `output`.
 ///
@@ -58,63 +571,511 @@ macro_rules! define_write_token_iter {
 /// 1. Macros are expanded
 /// 2. The escape prefix skips the next `char`.
 /// 3. A number prefix followed by a number **n**
-/// multiply the next token **n** times.
+///    multiply the next token **n** times.
 /// 4. A macro prefix followed by any `char`, followed by a token,
-/// defines the `char` as a macro evaluating to said token.
+///    defines the `char` as a macro evaluating to said token.
 /// 5. Groups enclosed in group delimiters are treated as
-/// a single token.
+///    a single token.
 /// 6. Operators are copied to output.
 /// 7. Every other `char` is skipped.
 ///
 /// See [`Lexer`] for details about how tokens are recognized.
-pub fn preprocess<I, W, E>(input: I, output: &mut W, config: &Config) -> Result<()>
+///
+/// Returns the [`Report`] of the warnings and statistics collected while lexing.
+///
+/// The input is preprocessed as it's read, one top-level token at a time,
+/// so only the group being read is kept in memory, not the whole input.
+/// The lexing errors are all reported at the end, but nothing is written
+/// after the first one.
+///
+/// Fails with [`Cancelled`][crate::cancel::Cancelled] once `cancellation`
+/// is cancelled, leaving the output written so far in `output`.
+pub fn preprocess<I, W, E>(
+    input: I,
+    output: &mut W,
+    config: &Config,
+    cancellation: &CancellationToken,
+) -> Result<Report>
 where
     I: Iterator<Item = Result<char, E>>,
     W: Write,
-    E: ErrorTrait + Sync + Send + 'static,
+    E: ErrorTrait + Send + Sync + 'static,
+{
+    preprocess_resumable(input, output, config, None, None, cancellation)
+}
+
+/// Same as [`preprocess`], but passes the output to `on_chunk` in chunks
+/// of at most [`OUTPUT_CHUNK_SIZE`] bytes instead of writing it.
+///
+/// Preprocessing stops with the error returned by `on_chunk`,
+/// so it can enforce its own limits on the output.
+pub fn preprocess_with<I, E, F>(
+    input: I,
+    config: &Config,
+    cancellation: &CancellationToken,
+    on_chunk: F,
+) -> Result<Report>
+where
+    I: Iterator<Item = Result<char, E>>,
+    E: ErrorTrait + Send + Sync + 'static,
+    F: FnMut(&[u8]) -> io::Result<()>,
 {
-    define_write_token_iter!((output: &mut W) {});
+    let mut output = Chunked {
+        buffer: Vec::with_capacity(OUTPUT_CHUNK_SIZE),
+        on_chunk,
+    };
+    let report = preprocess(input, &mut output, config, cancellation)?;
+    output.flush()?;
 
-    let tokens = Lexer::new(input, config).read_all_tokens()?;
-    write_token_iter(tokens.iter(), output)?;
+    Ok(report)
+}
 
-    Ok(())
+/// Writer passing the written bytes to `on_chunk` once
+/// [`OUTPUT_CHUNK_SIZE`] of them are buffered, or when flushed.
+struct Chunked<F: FnMut(&[u8]) -> io::Result<()>> {
+    buffer: Vec<u8>,
+    on_chunk: F,
+}
+
+impl<F: FnMut(&[u8]) -> io::Result<()>> Write for Chunked<F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut rest = buf;
+        while !rest.is_empty() {
+            let taken = rest.len().min(OUTPUT_CHUNK_SIZE - self.buffer.len());
+            self.buffer.extend_from_slice(&rest[..taken]);
+            rest = &rest[taken..];
+            if self.buffer.len() == OUTPUT_CHUNK_SIZE {
+                self.flush()?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.buffer.is_empty() {
+            (self.on_chunk)(&self.buffer)?;
+            self.buffer.clear();
+        }
+        Ok(())
+    }
+}
+
+/// Writer prefixing every line of the aligned output with its number,
+/// from 1, right-aligned in a gutter of [`GUTTER_WIDTH`] digits.
+///
+/// The gutter is made of digits and spaces, which brainfuck treats as
+/// comments, so the numbered output still runs the same.
+/// Empty lines aren't numbered.
+pub struct Gutter<W: Write> {
+    writer: W,
+    numbered: bool,
+    /// Number of the last numbered line.
+    row: usize,
+    line_start: bool,
+}
+
+impl<W: Write> Gutter<W> {
+    /// Create a `Gutter` over `writer`, passing the bytes through unless `numbered`.
+    pub fn new(writer: W, numbered: bool) -> Self {
+        Gutter {
+            writer,
+            numbered,
+            row: 0,
+            line_start: true,
+        }
+    }
+}
+
+impl<W: Write> Write for Gutter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.numbered {
+            return self.writer.write(buf);
+        }
+
+        for line in buf.split_inclusive(|byte| *byte == b'\n') {
+            if self.line_start && line != b"\n" {
+                self.row += 1;
+                write!(self.writer, "{:>GUTTER_WIDTH$} ", self.row)?;
+            }
+            self.writer.write_all(line)?;
+            self.line_start = line.ends_with(b"\n");
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
 }
 
 /// Same as [`preprocess`], but aligns the output
-/// in a rectangle of width `line_width`
+/// in a rectangle of width `line_width`, measured with `align_by`.
+///
+/// Operators that don't fit in the rest of a line are moved to the next one,
+/// an operator wider than `line_width` overflows a line of its own.
+/// The lines are never padded, so a `line_width` larger than the output
+/// leaves it on a single line.
+///
+/// Returns error if `line_width` is 0.
 pub fn preprocess_and_align<I, W, E>(
     input: I,
     output: &mut W,
     config: &Config,
     line_width: usize,
-) -> Result<()>
+    align_by: AlignBy,
+    cancellation: &CancellationToken,
+) -> Result<Report>
 where
     I: Iterator<Item = Result<char, E>>,
     W: Write,
-    E: ErrorTrait + Sync + Send + 'static,
+    E: ErrorTrait + Send + Sync + 'static,
 {
-    define_write_token_iter!((output: &mut W, line_len: &mut usize, line_max_len: usize) {
-        *line_len += 1;
-        if *line_len == line_max_len {
-            writeln!(output)?;
-            *line_len = 0;
+    preprocess_resumable(
+        input,
+        output,
+        config,
+        Some((line_width, align_by)),
+        None,
+        cancellation,
+    )
+}
+
+/// Same as [`preprocess`], but aligns the output if `alignment`
+/// *(line width and its metric)* is passed, and can resume
+/// an interrupted run and save checkpoints, according to `resumable`.
+///
+/// The output is deterministic, so when resuming, the operators up to
+/// the checkpoint are skipped and only the rest is written to `output`.
+pub fn preprocess_resumable<I, W, E>(
+    input: I,
+    output: &mut W,
+    config: &Config,
+    alignment: Option<(usize, AlignBy)>,
+    resumable: Option<Resumable>,
+    cancellation: &CancellationToken,
+) -> Result<Report>
+where
+    I: Iterator<Item = Result<char, E>>,
+    W: Write,
+    E: ErrorTrait + Send + Sync + 'static,
+{
+    let mut lexer = Lexer::new(input, config).with_cancellation(cancellation);
+    stream_tokens(
+        &mut lexer,
+        output,
+        config,
+        alignment,
+        resumable,
+        cancellation,
+    )?;
+
+    Ok(Report::from(&mut lexer))
+}
+
+/// Write the operators emitted by the tokens read by `lexer` to `output`
+/// as they're read, like in [`preprocess_resumable`].
+///
+/// Every top-level token is [planned][plan_with] and written with the numbers
+/// before it, once it's read. The built-in passes only act within a token,
+/// and the [`Pointer`] is shared by the tokens, so the output is the same
+/// as of planning all the tokens at once, except that the opted-into optimizing passes
+/// *(see [`Pipeline::with_peephole`])* can't remove the operators of different tokens.
+///
+/// Returns the number of operators of the whole output *(including
+/// the ones skipped when resuming)*.
+pub fn stream_tokens<I, W, E>(
+    lexer: &mut Lexer<'_, I, E>,
+    output: &mut W,
+    config: &Config,
+    alignment: Option<(usize, AlignBy)>,
+    resumable: Option<Resumable>,
+    cancellation: &CancellationToken,
+) -> Result<usize>
+where
+    I: Iterator<Item = Result<char, E>>,
+    W: Write,
+    E: ErrorTrait + Send + Sync + 'static,
+{
+    let mut emitter = Emitter::new(output, config, alignment, resumable, cancellation)?;
+    // the current top-level token, with the numbers before it
+    let mut item: Vec<Token> = Vec::new();
+    let mut errors: Vec<lex::Error> = Vec::new();
+    let mut pointer = Pointer::default();
+    while let Some(token) = lexer.next() {
+        match token {
+            Err(error @ (lex::Error::Input(_) | lex::Error::Cancelled(_))) => {
+                return Err(error.into())
+            }
+            Err(error) => errors.push(error),
+            Ok(token) if errors.is_empty() => {
+                let is_number = matches!(token.without_provenance(), Token::Number(..));
+                item.push(token);
+                if !is_number {
+                    if let Some(coverage) = lexer.coverage_mut() {
+                        record_coverage(coverage, &item, 1);
+                    }
+                    emitter.emit_steps(&plan_at(&item, config.get_pipeline(), &mut pointer))?;
+                    item.clear();
+                }
+            }
+            Ok(_) => (),
         }
-    });
+    }
+
+    if !errors.is_empty() {
+        return Err(lex::Error::Group(ErrorGroup::new(errors)).into());
+    }
 
-    let tokens = Lexer::new(input, config).read_all_tokens()?;
-    write_token_iter(tokens.iter(), output, &mut 0, line_width)?;
+    Ok(emitter.state.operators)
+}
+
+/// Read every token of `input` with the `macros` predefined, returning them
+/// with the [`Report`] of the lexing *(with the [`Coverage`] if `coverage` is set
+/// and the [`UnrecognizedChars`] if `unrecognized` is set)*.
+///
+/// Unlike [`preprocess_resumable`], this keeps every token in memory, for when
+/// they're needed as a whole *(e.g. to be recorded or planned with plugins)*.
+pub fn lex_tokens<I, E>(
+    input: I,
+    config: &Config,
+    macros: &[MacroDefinition],
+    coverage: bool,
+    unrecognized: bool,
+    cancellation: &CancellationToken,
+) -> Result<(Vec<Token>, Report)>
+where
+    I: Iterator<Item = Result<char, E>>,
+    E: ErrorTrait + Send + Sync + 'static,
+{
+    let mut lexer = Lexer::new(input, config)
+        .with_cancellation(cancellation)
+        .with_macros(macros.iter().cloned());
+    if coverage {
+        lexer = lexer.collect_coverage();
+    }
+    if unrecognized {
+        lexer = lexer.collect_unrecognized();
+    }
+    let tokens = lexer.read_all_tokens()?;
+    let report = Report::from(&mut lexer);
+
+    Ok((tokens, report))
+}
+
+/// Write the operators emitted by the lexed `tokens` to `output`, counting them
+/// in `coverage` if it's passed *(the tokens of [`lex_tokens`])*.
+///
+/// The operators are [planned][plan_with] with the passes of
+/// the config's [`Pipeline`][crate::pass::Pipeline] and written with [`emit_plan`].
+pub fn emit_tokens<W: Write>(
+    tokens: &[Token],
+    output: &mut W,
+    config: &Config,
+    alignment: Option<(usize, AlignBy)>,
+    resumable: Option<Resumable>,
+    coverage: Option<&mut Coverage>,
+    cancellation: &CancellationToken,
+) -> Result<()> {
+    if let Some(coverage) = coverage {
+        record_coverage(coverage, tokens, 1);
+    }
+    emit_plan(
+        &plan_with(tokens, config.get_pipeline()),
+        output,
+        config,
+        alignment,
+        resumable,
+        cancellation,
+    )?;
 
     Ok(())
 }
 
+/// Write the operators of the planned `steps` to `output`, aligned and resumable
+/// like in [`preprocess_resumable`] *(the steps can be transformed by
+/// [`Passes`][crate::pass::Pass] before)*.
+///
+/// The output isn't aligned if the config's pipeline leaves out [`Stage::Layout`].
+///
+/// Returns the number of operators of the whole output *(including
+/// the ones skipped when resuming)*.
+pub fn emit_plan<W: Write>(
+    steps: &[Step],
+    output: &mut W,
+    config: &Config,
+    alignment: Option<(usize, AlignBy)>,
+    resumable: Option<Resumable>,
+    cancellation: &CancellationToken,
+) -> Result<usize> {
+    let mut emitter = Emitter::new(output, config, alignment, resumable, cancellation)?;
+    emitter.emit_steps(steps)?;
+
+    Ok(emitter.state.operators)
+}
+
+/// Write the operators emitted by the lexed `tokens` to `output`, aligned like
+/// in [`preprocess_and_align`], without splitting the operators of an innermost group
+/// *(one without groups in it)* between lines.
+///
+/// A group that doesn't fit in the rest of a line starts the next one, padding
+/// the line with spaces, unless it doesn't fit in a line of its own either.
+/// Every innermost group is [planned][plan_at] on its own, so the built-in
+/// passes emit the same operators as when planning the tokens at once.
+///
+/// Returns the number of operators of the output.
+pub fn emit_unsplit<W: Write>(
+    tokens: &[Token],
+    output: &mut W,
+    config: &Config,
+    alignment: (usize, AlignBy),
+    cancellation: &CancellationToken,
+) -> Result<usize> {
+    let mut emitter = Emitter::new(output, config, Some(alignment), None, cancellation)?;
+    emitter.emit_unsplit(tokens, config.get_pipeline(), &mut Pointer::default())?;
+
+    Ok(emitter.state.operators)
+}
+
+/// Get the [`Annotations`][Annotation] of the output of the lexed `tokens`, aligned
+/// like in [`preprocess_and_align`] if `alignment` is passed, without writing it.
+///
+/// Every macro expansion and group emitting any operators is annotated once,
+/// with the operators of all its repetitions. The annotations are ordered
+/// by their start, the enclosing ones first.
+pub fn annotate(
+    tokens: &[Token],
+    config: &Config,
+    alignment: Option<(usize, AlignBy)>,
+    cancellation: &CancellationToken,
+) -> Result<Vec<Annotation>> {
+    let mut output = io::sink();
+    let mut emitter = Emitter::new(&mut output, config, alignment, None, cancellation)?;
+    emitter.annotations = Some(Annotations::default());
+    emitter.emit_annotated(tokens, config.get_pipeline(), &mut Pointer::default())?;
+
+    Ok(emitter
+        .annotations
+        .map(|annotations| annotations.recorded)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|annotation| annotation.start.line > 0)
+        .collect())
+}
+
+/// Check whether `token` is a group.
+fn is_group(token: &Token) -> bool {
+    matches!(token.without_provenance(), Token::Group(..))
+}
+
+/// Mismatch between the sizes of the output found by [`verify_size`],
+/// with the [`Span`] of the top-level token responsible, if it's found.
+#[derive(thiserror::Error, fmt::Debug)]
+#[error(
+    "{}internal error: the tokens emit {estimated} operators, {planned} were planned and {emitted} emitted.",
+    .span.map(|span| format!("[{span}]: ")).unwrap_or_default()
+)]
+pub struct SizeMismatch {
+    pub span: Option<Span>,
+    pub estimated: usize,
+    pub planned: usize,
+    pub emitted: usize,
+}
+
+/// Check that the number of operators emitted by `tokens`, the number of operators
+/// of their planned `steps` and the `emitted` number of operators are the same.
+///
+/// On a mismatch, every top-level token is planned alone with the passes
+/// of `pipeline`, to find the first one planned into a different number of
+/// operators. A mismatch caused by passes acting across the tokens has no span.
+///
+/// The moves to the named cells depend on the position of the pointer,
+/// and the optimizing passes *(see [`Pipeline::with_peephole`])* remove operators,
+/// so with either, only the planned and emitted numbers are compared.
+pub fn verify_size(
+    tokens: &[Token],
+    steps: &[Step],
+    emitted: usize,
+    pipeline: &Pipeline,
+) -> Result<(), SizeMismatch> {
+    let planned_len = |steps: &[Step]| {
+        steps
+            .iter()
+            .fold(0usize, |len, step| len.saturating_add(step.operators()))
+    };
+    // every token with the number before it and the operators it emits
+    let items: Vec<(&[Token], usize)> = tokens
+        .split_inclusive(|token| !matches!(token.without_provenance(), Token::Number(..)))
+        .map(|item| {
+            let multiplier = match item.iter().rev().nth(1).map(Token::without_provenance) {
+                Some(Token::Number(number, ..)) => *number,
+                _ => 1,
+            };
+            let operators = item.last().map_or(0, Token::expanded_len);
+            (item, operators.saturating_mul(multiplier))
+        })
+        .collect();
+
+    let planned = planned_len(steps);
+    let estimated = match moves_to_cell(tokens) || pipeline.runs_peephole() {
+        true => planned,
+        false => items
+            .iter()
+            .fold(0usize, |len, (_, operators)| len.saturating_add(*operators)),
+    };
+    if estimated == planned && planned == emitted {
+        return Ok(());
+    }
+
+    let span = items
+        .iter()
+        .find(|(item, operators)| planned_len(&plan_with(item, pipeline)) != *operators)
+        .and_then(|(item, _)| item.last())
+        .map(|token| *token.span());
+
+    Err(SizeMismatch {
+        span,
+        estimated,
+        planned,
+        emitted,
+    })
+}
+
+/// Output that isn't an exact rectangle, found by [`check_rectangle`],
+/// with the number of operators missing from its last line.
+#[derive(thiserror::Error, fmt::Debug)]
+#[error(
+    "the {emitted} emitted operators aren't a rectangle of width {line_width}, \
+    the last line is {shortfall} operators short."
+)]
+pub struct RaggedRectangle {
+    pub emitted: usize,
+    pub line_width: usize,
+    pub shortfall: usize,
+}
+
+/// Check that the `emitted` number of operators fills every line of
+/// `line_width` operators, so the output is an exact rectangle.
+pub fn check_rectangle(emitted: usize, line_width: usize) -> Result<(), RaggedRectangle> {
+    match emitted % line_width {
+        0 => Ok(()),
+        rest => Err(RaggedRectangle {
+            emitted,
+            line_width,
+            shortfall: line_width - rest,
+        }),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
 
+    use std::convert::Infallible;
+
     use anyhow::Result;
 
     use super::*;
+    use crate::cancel::Cancelled;
     use crate::config::Config;
     use bfup_derive::{as_char_results, as_char_results_and_input};
 
@@ -124,7 +1085,12 @@ mod tests {
             let input_chars;
             (input_chars, $input_ident) = as_char_results_and_input!($input);
 
-            preprocess(input_chars.into_iter(), &mut out, &Config::default())?;
+            preprocess(
+                input_chars.into_iter(),
+                &mut out,
+                &Config::default(),
+                &CancellationToken::new(),
+            )?;
 
             $output = String::from_utf8(out.into_inner())?;
         };
@@ -138,6 +1104,8 @@ mod tests {
                 &mut out,
                 &Config::default(),
                 $line_width,
+                AlignBy::default(),
+                &CancellationToken::new(),
             )?;
 
             $output = String::from_utf8(out.into_inner())?;
@@ -205,7 +1173,7 @@ mod tests {
         );
 
         assert!(
-            output == "",
+            output.is_empty(),
             "\"{input}\" preprocessed to \"{output}\" should be \"\"."
         );
 
@@ -217,11 +1185,16 @@ mod tests {
         let mut output = Cursor::new(String::new().into_bytes());
         let input_chars: [Result<char, std::convert::Infallible>; 0] = as_char_results!("");
 
-        preprocess(input_chars.into_iter(), &mut output, &Config::default())?;
+        preprocess(
+            input_chars.into_iter(),
+            &mut output,
+            &Config::default(),
+            &CancellationToken::new(),
+        )?;
 
         let output = String::from_utf8(output.into_inner())?;
 
-        assert!(output == "", "output should be empty.");
+        assert!(output.is_empty(), "output should be empty.");
 
         Ok(())
     }
@@ -242,4 +1215,386 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn preprocess_coverage() -> Result<()> {
+        let mut output = Cursor::new(String::new().into_bytes());
+        let input_chars = as_char_results!("$m+ #3- #0> mm");
+        let config = Config::default();
+
+        let report = preprocess(
+            input_chars.into_iter(),
+            &mut output,
+            &config,
+            &CancellationToken::new(),
+        )?;
+        assert!(
+            report.coverage.is_none(),
+            "The coverage should only be collected when asked for."
+        );
+
+        let mut lexer = Lexer::new(input_chars.into_iter(), &config).collect_coverage();
+        stream_tokens(
+            &mut lexer,
+            &mut output,
+            &config,
+            None,
+            None,
+            &CancellationToken::new(),
+        )?;
+        let coverage = lexer
+            .take_coverage()
+            .expect("The coverage should be collected.");
+
+        let emitted: Vec<(char, usize)> = coverage
+            .tokens()
+            .into_iter()
+            .map(|(_, operator, emitted)| (operator, emitted))
+            .collect();
+
+        assert!(
+            emitted == [('+', 2), ('-', 3), ('>', 0)],
+            "{emitted:?} should be [('+', 2), ('-', 3), ('>', 0)]."
+        );
+        assert!(
+            coverage.covered() == 2,
+            "2 out of {} tokens should be covered.",
+            coverage.total()
+        );
+
+        let mut config = Config::default();
+        config.set_operator_weights([('-', 4)])?;
+        let cost = coverage.weighted_cost(&config);
+        assert!(cost == 14, "Weighted cost {cost} should be 2 + 3 * 4.");
+
+        Ok(())
+    }
+
+    #[test]
+    fn preprocess_line_width_validation() -> Result<()> {
+        let mut output = Cursor::new(String::new().into_bytes());
+        let result = preprocess_and_align(
+            "+".chars().map(Ok::<char, std::convert::Infallible>),
+            &mut output,
+            &Config::default(),
+            0,
+            AlignBy::default(),
+            &CancellationToken::new(),
+        );
+        assert!(result.is_err(), "A line width of 0 should be an error.");
+
+        let config = Config::new("ąb".chars(), '(', ')', '#', '$', '\\')?;
+        let widest = AlignBy::Bytes.widest_operator(&config);
+        assert!(
+            widest == Some((String::from("ą"), 2)),
+            "{widest:?} should be 'ą' of 2 bytes."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn preprocess_align_by_bytes() -> Result<()> {
+        let mut output = Cursor::new(String::new().into_bytes());
+        let config = Config::new("ąb".chars(), '(', ')', '#', '$', '\\')?;
+
+        preprocess_and_align(
+            "ąbąą".chars().map(Ok::<char, std::convert::Infallible>),
+            &mut output,
+            &config,
+            4,
+            AlignBy::Bytes,
+            &CancellationToken::new(),
+        )?;
+
+        let output = String::from_utf8(output.into_inner())?;
+        assert!(
+            output == "ąb\nąą\n",
+            "\"{output}\" should be \"ąb\\nąą\\n\"."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn preprocess_resumed() -> Result<()> {
+        let input_chars = as_char_results!("#3(#5+>#4(-<))");
+        let alignment = Some((7, AlignBy::default()));
+
+        let mut full_output: Vec<u8> = Vec::new();
+        let mut checkpoints: Vec<Checkpoint> = Vec::new();
+        preprocess_resumable(
+            input_chars.into_iter(),
+            &mut full_output,
+            &Config::default(),
+            alignment,
+            Some(Resumable {
+                resume_from: Checkpoint::default(),
+                every: NonZeroUsize::new(4).expect("4 isn't 0."),
+                save: &mut |checkpoint| {
+                    checkpoints.push(*checkpoint);
+                    Ok(())
+                },
+            }),
+            &CancellationToken::new(),
+        )?;
+
+        assert!(
+            checkpoints.len() == 10,
+            "Every 4th operator should be saved."
+        );
+
+        for checkpoint in checkpoints {
+            let mut output: Vec<u8> = Vec::new();
+            preprocess_resumable(
+                input_chars.into_iter(),
+                &mut output,
+                &Config::default(),
+                alignment,
+                Some(Resumable {
+                    resume_from: checkpoint,
+                    every: NonZeroUsize::new(4).expect("4 isn't 0."),
+                    save: &mut |_| Ok(()),
+                }),
+                &CancellationToken::new(),
+            )?;
+
+            assert!(
+                output == full_output[checkpoint.bytes as usize..],
+                "Resuming from {checkpoint:?} should write the rest of the output."
+            );
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn preprocess_chunks() -> Result<()> {
+        let config = Config::default();
+        let cancellation = CancellationToken::new();
+        let mut chunks: Vec<Vec<u8>> = Vec::new();
+        preprocess_with(
+            as_char_results!("#10000+.").into_iter(),
+            &config,
+            &cancellation,
+            |chunk| {
+                chunks.push(chunk.to_vec());
+                Ok(())
+            },
+        )?;
+        let lens: Vec<usize> = chunks.iter().map(Vec::len).collect();
+        assert!(
+            lens == [OUTPUT_CHUNK_SIZE, 10001 - OUTPUT_CHUNK_SIZE] && chunks[1].ends_with(b"+."),
+            "{lens:?} should be the chunks of the whole output."
+        );
+
+        let result = preprocess_with(
+            as_char_results!("#10000+.").into_iter(),
+            &config,
+            &cancellation,
+            |_| Err(io::Error::other("output over its limit")),
+        );
+        assert!(
+            result.is_err_and(|error| format!("{error:#}").contains("over its limit")),
+            "The error of the closure should stop preprocessing."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn preprocess_streaming() -> Result<()> {
+        let config = Config::default();
+        let cancellation = CancellationToken::new();
+        // the input never ends, so it's only stopped by the output
+        let endless = "#2(+>)".chars().cycle().map(Ok::<char, Infallible>);
+        let mut written = 0;
+        let result = preprocess_with(endless, &config, &cancellation, |chunk| {
+            written += chunk.len();
+            Err(io::Error::other("enough output"))
+        });
+        assert!(
+            result.is_err() && written == OUTPUT_CHUNK_SIZE,
+            "The output should be written while the input is read."
+        );
+
+        let mut output: Vec<u8> = Vec::new();
+        let result = preprocess(
+            as_char_results!("+>) - ) <").into_iter(),
+            &mut output,
+            &config,
+            &cancellation,
+        );
+        assert!(
+            result.is_err_and(|error| matches!(
+                error.downcast_ref(),
+                Some(lex::Error::Group(group)) if group.errors().len() == 2
+            )) && output == b"+>",
+            "Every error should be reported, with just the output before the first one."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn preprocess_strings() -> Result<()> {
+        let mut config = Config::default();
+        config.set_strings(true)?;
+        let mut code: Vec<u8> = Vec::new();
+        preprocess(
+            as_char_results!("#2\"Hi\\n\">+<\"zz ñ~\"").into_iter(),
+            &mut code,
+            &config,
+            &CancellationToken::new(),
+        )?;
+
+        let code = String::from_utf8(code)?;
+        let mut output: Vec<u8> = Vec::new();
+        crate::run::Program::compile(code.chars())?.run(
+            &mut io::empty(),
+            &mut output,
+            &CancellationToken::new(),
+        )?;
+        assert!(
+            output == "Hi\nHi\nzz ñ~".as_bytes(),
+            "{output:?} should be printed by \"{code}\"."
+        );
+        assert!(
+            code.ends_with("[-]") && printing_code("zz").matches('+').count() == 122,
+            "\"{code}\" should clear the cell and repeat a char without adjusting."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn preprocess_gutter() -> Result<()> {
+        let mut output = Gutter::new(Vec::new(), true);
+        preprocess_and_align(
+            as_char_results!("#10+").into_iter(),
+            &mut output,
+            &Config::default(),
+            4,
+            AlignBy::default(),
+            &CancellationToken::new(),
+        )?;
+        writeln!(output)?;
+        let output = String::from_utf8(output.writer)?;
+        assert!(
+            output == "   1 ++++\n   2 ++++\n   3 ++\n",
+            "\"{output}\" should number every line."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn preprocess_no_split_groups() -> Result<()> {
+        let config = Config::default();
+        let tokens = crate::testing::tokens("+++(--)(>>>)#2(+(<<)-)(-----)", &config)?;
+        let mut output = Vec::new();
+        let emitted = emit_unsplit(
+            &tokens,
+            &mut output,
+            &config,
+            (4, AlignBy::default()),
+            &CancellationToken::new(),
+        )?;
+        let output = String::from_utf8(output)?;
+        assert!(
+            output == "+++ \n--  \n>>>+\n<<-+\n<<--\n----\n" && emitted == 21,
+            "\"{output}\" should keep the innermost groups fitting in a line together."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn preprocess_annotate() -> Result<()> {
+        let config = Config::default();
+        let tokens = crate::testing::tokens("$a(+>) +#3a#0(-)(--)", &config)?;
+        let annotations: Vec<(Option<char>, usize, usize, usize, usize)> = annotate(
+            &tokens,
+            &config,
+            Some((4, AlignBy::default())),
+            &CancellationToken::new(),
+        )?
+        .iter()
+        .map(|annotation| {
+            (
+                annotation.macro_symbol,
+                annotation.start.line,
+                annotation.start.column,
+                annotation.end.line,
+                annotation.end.column,
+            )
+        })
+        .collect();
+        assert!(
+            annotations == [(Some('a'), 1, 2, 2, 3), (None, 2, 4, 3, 1)],
+            "{annotations:?} should be the ranges of the expansion and the group emitting operators."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn preprocess_check_rectangle() {
+        assert!(
+            check_rectangle(12, 4).is_ok() && check_rectangle(0, 4).is_ok(),
+            "Full lines should be a rectangle."
+        );
+        let shortfall = check_rectangle(10, 4).map_err(|error| error.shortfall);
+        assert!(
+            shortfall == Err(2),
+            "{shortfall:?} should be the 2 operators missing from the last line."
+        );
+    }
+
+    #[test]
+    fn preprocess_verify_size() -> Result<()> {
+        let config = Config::default();
+        let tokens = crate::testing::tokens("$a(+>)#3a#0- (<.)", &config)?;
+        let steps = plan_with(&tokens, config.get_pipeline());
+        let emitted = emit_plan(
+            &steps,
+            &mut io::sink(),
+            &config,
+            Some((4, AlignBy::default())),
+            None,
+            &CancellationToken::new(),
+        )?;
+        verify_size(&tokens, &steps, emitted, config.get_pipeline())?;
+
+        let error = verify_size(&tokens, &steps[1..], emitted, config.get_pipeline())
+            .expect_err("Leaving out a step should be a mismatch.");
+        assert!(
+            error.span.is_none() && error.planned < error.estimated,
+            "{error} shouldn't blame any token, as they are planned right."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn preprocess_cancelled() -> Result<()> {
+        let mut output = Cursor::new(String::new().into_bytes());
+        let input_chars = as_char_results!("#1000(+)");
+        let cancellation = CancellationToken::new();
+        cancellation.cancel();
+
+        let result = preprocess(
+            input_chars.into_iter(),
+            &mut output,
+            &Config::default(),
+            &cancellation,
+        );
+
+        assert!(
+            result.is_err_and(|error| error.chain().any(|cause| cause.is::<Cancelled>())),
+            "preprocessing should be cancelled."
+        );
+
+        Ok(())
+    }
 }
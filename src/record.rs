@@ -0,0 +1,100 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::lex::Token;
+
+/// Bytes every recording starts with, followed by the [`FORMAT_VERSION`].
+pub const MAGIC: &[u8; 8] = b"BFUPTOKS";
+/// Version of the recording format, changed whenever the [`Tokens`][Token] change.
+pub const FORMAT_VERSION: u8 = 6;
+
+/// Error type of writing and reading the recordings.
+#[derive(thiserror::Error, fmt::Debug)]
+pub enum Error {
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("not a recording of tokens")]
+    NotARecording,
+    #[error("recording of version {0}, expected version {FORMAT_VERSION}")]
+    Version(u8),
+    #[error("malformed recording: {0}")]
+    Malformed(#[from] bincode::Error),
+}
+
+/// Write a recording of the lexed `tokens` to `writer` in the bincode format.
+///
+/// The operators are recorded as they were lexed, so a recording
+/// should only be replayed with the config it was recorded with.
+pub fn write_recording<W: Write>(writer: &mut W, tokens: &[Token]) -> Result<(), Error> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[FORMAT_VERSION])?;
+    bincode::serialize_into(writer, tokens)?;
+
+    Ok(())
+}
+
+/// Read the tokens recorded by [`write_recording`] from `reader`.
+pub fn read_recording<R: Read>(reader: &mut R) -> Result<Vec<Token>, Error> {
+    let mut header = [0; MAGIC.len() + 1];
+    match reader.read_exact(&mut header) {
+        Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => {
+            return Err(Error::NotARecording)
+        }
+        result => result?,
+    }
+    if header[..MAGIC.len()] != MAGIC[..] {
+        return Err(Error::NotARecording);
+    }
+    if header[MAGIC.len()] != FORMAT_VERSION {
+        return Err(Error::Version(header[MAGIC.len()]));
+    }
+
+    Ok(bincode::deserialize_from(reader)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use anyhow::Result;
+
+    use super::*;
+    use crate::cancel::CancellationToken;
+    use crate::config::Config;
+    use crate::pre::emit_tokens;
+    use crate::testing;
+
+    #[test]
+    fn record_replay() -> Result<()> {
+        let config = Config::default();
+        let tokens = testing::tokens("$x(#3(+>)) x-#2x", &config)?;
+
+        let mut recording = Vec::new();
+        write_recording(&mut recording, &tokens)?;
+        let replayed = read_recording(&mut Cursor::new(&recording))?;
+
+        let mut output = Vec::new();
+        emit_tokens(
+            &replayed,
+            &mut output,
+            &config,
+            None,
+            None,
+            None,
+            &CancellationToken::new(),
+        )?;
+        let output = String::from_utf8(output)?;
+        assert!(
+            output == "+>+>+>-+>+>+>+>+>+>",
+            "\"{output}\" should be emitted from the replayed tokens."
+        );
+
+        let result = read_recording(&mut Cursor::new(b"(+>)"));
+        assert!(
+            matches!(result, Err(Error::NotARecording)),
+            "Sources should not be read as recordings."
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+use std::fmt;
+use std::ops::Range;
+use std::str::FromStr;
+
+use crate::lex::Token;
+use crate::span::{SourceId, Span};
+
+/// Error type for parsing a [`Region`].
+#[derive(thiserror::Error, fmt::Debug)]
+pub enum Error {
+    #[error(
+        "'{0}' should be START:END, with the bytes suffixed with 'b' (e.g. 12:20 or 300b:420b)"
+    )]
+    Format(String),
+    #[error("'{0}' should not end before it starts")]
+    Reversed(String),
+    #[error("'{0}' should start at line 1 or later")]
+    LineZero(String),
+}
+
+/// Region of a source, either its lines from `start` to `end` *(1-based,
+/// inclusive)* or its bytes from `start` up to `end` *(0-based, exclusive)*.
+#[derive(Clone, Copy, PartialEq, Eq, fmt::Debug)]
+pub enum Region {
+    Lines { start: usize, end: usize },
+    Bytes { start: usize, end: usize },
+}
+
+impl FromStr for Region {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let format = || Error::Format(s.to_string());
+        let (start, end) = s.split_once(':').ok_or_else(format)?;
+
+        let region = match (start.strip_suffix('b'), end.strip_suffix('b')) {
+            (Some(start), Some(end)) => Region::Bytes {
+                start: start.parse().map_err(|_| format())?,
+                end: end.parse().map_err(|_| format())?,
+            },
+            (None, None) => Region::Lines {
+                start: start.parse().map_err(|_| format())?,
+                end: end.parse().map_err(|_| format())?,
+            },
+            _ => return Err(format()),
+        };
+
+        match region {
+            Region::Lines { start: 0, .. } => Err(Error::LineZero(s.to_string())),
+            Region::Lines { start, end } | Region::Bytes { start, end } if end < start => {
+                Err(Error::Reversed(s.to_string()))
+            }
+            _ => Ok(region),
+        }
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Region::Lines { start, end } => write!(f, "{start}:{end}"),
+            Region::Bytes { start, end } => write!(f, "{start}b:{end}b"),
+        }
+    }
+}
+
+impl Region {
+    /// Get the offsets of the chars of `text` within the region,
+    /// as counted by the [`Lexer`][crate::lex::Lexer].
+    ///
+    /// A byte offset within a char is rounded up to the next char.
+    pub fn chars(&self, text: &str) -> Range<usize> {
+        let char_offset = |byte: usize| {
+            text.char_indices()
+                .take_while(|(char_byte, _)| *char_byte < byte)
+                .count()
+        };
+        // the start of the line after the `lines`-th newline
+        let line_offset = |lines: usize| {
+            text.chars()
+                .enumerate()
+                .filter(|(_, ch)| *ch == '\n')
+                .nth(lines.wrapping_sub(1))
+                .map_or(text.chars().count(), |(offset, _)| offset + 1)
+        };
+
+        match *self {
+            Region::Lines { start, end } => {
+                let start = if start == 1 {
+                    0
+                } else {
+                    line_offset(start - 1)
+                };
+                start..line_offset(end)
+            }
+            Region::Bytes { start, end } => char_offset(start)..char_offset(end),
+        }
+    }
+}
+
+/// Select the `tokens` read within the `chars` of the input *(see [`Region::chars`])*.
+///
+/// Macros expanded within the region are kept, wherever they were defined.
+/// Groups partially within the region are replaced with their tokens within it,
+/// while the numbers are kept with the tokens they precede. Tokens read from
+/// other sources *(e.g. [included][SourceId::INCLUDED] files)* are left out.
+pub fn select(tokens: &[Token], chars: &Range<usize>) -> Vec<Token> {
+    let mut selected = Vec::new();
+    let mut number: Option<&Token> = None;
+    for token in tokens {
+        let span = match token {
+            Token::Number(..) => {
+                number = Some(token);
+                continue;
+            }
+            Token::Operator(_, span)
+            | Token::Group(_, span)
+            | Token::Str(_, span)
+            | Token::Raw(_, span)
+            | Token::Cell(.., span) => span,
+            Token::Expanded(_, provenance) => &provenance.use_site,
+        };
+
+        if within(span, chars) {
+            selected.extend(number.take().cloned());
+            selected.push(token.clone());
+        } else if let Token::Group(group, span) = token {
+            if overlaps(span, chars) {
+                selected.extend(select(group, chars));
+            }
+        }
+        number = None;
+    }
+
+    selected
+}
+
+/// Check whether the whole `span` of the input lies within `chars`.
+fn within(span: &Span, chars: &Range<usize>) -> bool {
+    span.source != SourceId::INCLUDED
+        && chars.contains(&span.start.offset)
+        && chars.contains(&span.end.offset)
+}
+
+/// Check whether any part of the `span` of the input lies within `chars`.
+fn overlaps(span: &Span, chars: &Range<usize>) -> bool {
+    span.source != SourceId::INCLUDED
+        && span.start.offset < chars.end
+        && chars.start <= span.end.offset
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::testing;
+
+    #[test]
+    fn region_select() -> anyhow::Result<()> {
+        let text = "$a(+>)\n#2a -\n(<<\n.#3,)\n>";
+        let tokens = testing::tokens(text, &Config::default())?;
+        let emitted = |region: &str| -> anyhow::Result<String> {
+            let region: Region = region.parse()?;
+            let selected = select(&tokens, &region.chars(text));
+            let mut output = Vec::new();
+            crate::pre::emit_tokens(
+                &selected,
+                &mut output,
+                &Config::default(),
+                None,
+                None,
+                None,
+                &Default::default(),
+            )?;
+            Ok(String::from_utf8(output)?)
+        };
+
+        let output = emitted("2:2")?;
+        assert!(
+            output == "+>+>-",
+            "\"{output}\" should expand the macro defined before the region."
+        );
+        let output = emitted("4:5")?;
+        assert!(
+            output == ".,,,>",
+            "\"{output}\" should keep the part of the group within the region."
+        );
+        let output = emitted("7b:11b")?;
+        assert!(
+            output == "+>+>",
+            "\"{output}\" should select the bytes, with the number before the macro."
+        );
+        assert!(
+            matches!("3:2".parse::<Region>(), Err(Error::Reversed(_)))
+                && matches!("2b:5".parse::<Region>(), Err(Error::Format(_))),
+            "Reversed and mixed regions should be errors."
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,458 @@
+use std::convert::Infallible;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufWriter, Write};
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+
+use crate::cancel::CancellationToken;
+use crate::config::Config;
+use crate::lex::Lexer;
+use crate::pre::{preprocess, preprocess_and_align, AlignBy};
+use crate::run::Program;
+
+/// Prefix of the meta-commands, every other line is preprocessed.
+///
+/// Saved sessions contain the settings as meta-commands,
+/// which the [`Lexer`] skips over as unrecognized chars.
+pub const COMMAND_PREFIX: char = ':';
+pub const PROMPT: &str = "> ";
+
+/// Lines entered into the [`Repl`], appended to a file as they're entered.
+#[derive(Default)]
+pub struct History {
+    lines: Vec<String>,
+    file: Option<File>,
+}
+
+impl History {
+    /// Read the history saved in `path` and append the new lines to it,
+    /// the file is created if it doesn't exist.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let lines = match fs::read_to_string(path) {
+            Ok(history) => history.lines().map(String::from).collect(),
+            Err(error) if error.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(error) => return Err(error),
+        };
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(History {
+            lines,
+            file: Some(file),
+        })
+    }
+
+    /// Add `line` to the history.
+    pub fn push(&mut self, line: &str) -> io::Result<()> {
+        if let Some(file) = &mut self.file {
+            writeln!(file, "{line}")?;
+        }
+        self.lines.push(line.to_string());
+
+        Ok(())
+    }
+
+    pub fn lines(&self) -> &[String] {
+        &self.lines
+    }
+}
+
+/// Interactive preprocessor, reading lines from `input` and writing
+/// the emitted operators to `output`.
+///
+/// The macros defined in a line stay defined in the following ones.
+/// Lines starting with [`COMMAND_PREFIX`] are meta-commands:
+/// * `:save FILE` - write the definitions and settings to FILE as source
+/// * `:load FILE` - evaluate every line of FILE
+/// * `:history` - print the lines entered so far
+/// * `:width [N]` - align the output to N chars, unaligned without N
+/// * `:macros` - print the definitions entered so far
+/// * `:config` - print the config in the ron format
+/// * `:tokens LINE` - print the tokens of LINE, one per line
+/// * `:run [LINE]` - run the program emitted by LINE, or the last line
+pub struct Repl<'a, R: BufRead, W: Write> {
+    input: &'a mut R,
+    output: &'a mut W,
+    config: &'a Config,
+    cancellation: &'a CancellationToken,
+    history: History,
+    /// Source of every macro definition entered so far, with its symbol.
+    definitions: Vec<(char, String)>,
+    width: Option<usize>,
+    /// Operators emitted by the last preprocessed line.
+    emitted: Vec<u8>,
+}
+
+impl<'a, R: BufRead, W: Write> Repl<'a, R, W> {
+    /// Create a new `Repl` reading lines from `input` and writing
+    /// the prompts, outputs and errors to `output`.
+    pub fn new(
+        input: &'a mut R,
+        output: &'a mut W,
+        config: &'a Config,
+        cancellation: &'a CancellationToken,
+    ) -> Self {
+        Repl {
+            input,
+            output,
+            config,
+            cancellation,
+            history: History::default(),
+            definitions: Vec::new(),
+            width: None,
+            emitted: Vec::new(),
+        }
+    }
+
+    /// Record the entered lines in `history`.
+    pub fn with_history(mut self, history: History) -> Self {
+        self.history = history;
+        self
+    }
+
+    /// Evaluate lines until the input ends, reporting
+    /// the failures of single lines without stopping.
+    pub fn run(mut self) -> Result<()> {
+        loop {
+            write!(self.output, "{PROMPT}")?;
+            self.output.flush()?;
+
+            let mut line = String::new();
+            if self.input.read_line(&mut line)? == 0 {
+                writeln!(self.output)?;
+                return Ok(());
+            }
+            let line = line.trim_end_matches(['\n', '\r']);
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            self.history
+                .push(line)
+                .with_context(|| "failed to write the history")?;
+            if let Err(error) = self.eval(line) {
+                writeln!(self.output, "error: {error:#}")?;
+            }
+        }
+    }
+
+    /// Evaluate a meta-command or preprocess `line`.
+    fn eval(&mut self, line: &str) -> Result<()> {
+        let Some(command) = line.strip_prefix(COMMAND_PREFIX) else {
+            return self.preprocess_line(line);
+        };
+
+        let (name, arg) = match command.trim().split_once(char::is_whitespace) {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (command.trim(), ""),
+        };
+        match name {
+            "save" => self.save(Path::new(arg)),
+            "load" => self.load(Path::new(arg)),
+            "history" => {
+                for line in self.history.lines() {
+                    writeln!(self.output, "{line}")?;
+                }
+                Ok(())
+            }
+            "width" if arg.is_empty() => {
+                self.width = None;
+                Ok(())
+            }
+            "width" => {
+                let width = arg
+                    .parse()
+                    .with_context(|| format!("invalid width '{arg}'"))?;
+                if width == 0 {
+                    bail!("width must be greater than 0");
+                }
+                self.width = Some(width);
+                Ok(())
+            }
+            "macros" => {
+                for (_, definition) in &self.definitions {
+                    writeln!(self.output, "{definition}")?;
+                }
+                Ok(())
+            }
+            "config" => {
+                let config = self
+                    .config
+                    .to_ron_string()
+                    .with_context(|| "failed to serialize config")?;
+                writeln!(self.output, "{config}")?;
+                Ok(())
+            }
+            "tokens" => self.print_tokens(arg),
+            "run" => self.run_program(arg),
+            name => bail!("unknown command '{COMMAND_PREFIX}{name}'"),
+        }
+    }
+
+    /// Preprocess `line` and write the emitted operators.
+    fn preprocess_line(&mut self, line: &str) -> Result<()> {
+        let emitted = self.emit(line)?;
+        if !emitted.is_empty() {
+            self.output.write_all(&emitted)?;
+            if !emitted.ends_with(b"\n") {
+                writeln!(self.output)?;
+            }
+        }
+        self.emitted = emitted;
+
+        Ok(())
+    }
+
+    /// Get the chars of `line` preceded by the definitions entered so far,
+    /// with the graphemes encoded.
+    fn with_definitions(&self, line: &str) -> Vec<Result<char, Infallible>> {
+        let source: String = self
+            .definitions
+            .iter()
+            .flat_map(|(_, definition)| definition.chars().chain(['\n']))
+            .chain(line.chars())
+            .collect();
+
+        self.config
+            .get_graphemes()
+            .encode_str(&source)
+            .chars()
+            .map(Ok)
+            .collect()
+    }
+
+    /// Preprocess `line` after the definitions entered so far,
+    /// remembering the macros it defines.
+    fn emit(&mut self, line: &str) -> Result<Vec<u8>> {
+        let chars = self.with_definitions(line);
+
+        let mut lexer = Lexer::new(chars.clone().into_iter(), self.config)
+            .with_cancellation(self.cancellation)
+            .keep_macro_definitions();
+        lexer.read_all_tokens()?;
+        for definition in lexer.take_macro_definitions() {
+            self.definitions
+                .retain(|(symbol, _)| *symbol != definition.symbol);
+            self.definitions
+                .push((definition.symbol, definition.to_source(self.config)));
+        }
+
+        let mut emitted = Vec::new();
+        match self.width {
+            Some(width) => preprocess_and_align(
+                chars.into_iter(),
+                &mut emitted,
+                self.config,
+                width,
+                AlignBy::default(),
+                self.cancellation,
+            )?,
+            None => preprocess(
+                chars.into_iter(),
+                &mut emitted,
+                self.config,
+                self.cancellation,
+            )?,
+        };
+
+        Ok(emitted)
+    }
+
+    /// Write the tokens of `line` as source, one per line.
+    fn print_tokens(&mut self, line: &str) -> Result<()> {
+        let tokens = Lexer::new(self.with_definitions(line).into_iter(), self.config)
+            .with_cancellation(self.cancellation)
+            .read_all_tokens()?;
+        for token in &tokens {
+            writeln!(self.output, "{}", token.to_source(self.config))?;
+        }
+
+        Ok(())
+    }
+
+    /// Run the program emitted by `line`, or by the last preprocessed line
+    /// if `line` is empty, with an empty input.
+    fn run_program(&mut self, line: &str) -> Result<()> {
+        let code = if line.is_empty() {
+            self.emitted.clone()
+        } else {
+            self.emit(line)?
+        };
+        let code = String::from_utf8(code).with_context(|| "preprocessed program is not utf-8")?;
+        let program = Program::compile(code.chars()).with_context(|| "invalid program")?;
+
+        program
+            .run(&mut io::empty(), &mut self.output, self.cancellation)
+            .with_context(|| "failure while running")?;
+        writeln!(self.output)?;
+
+        Ok(())
+    }
+
+    /// Write the settings and definitions to `path`, one per line.
+    fn save(&mut self, path: &Path) -> Result<()> {
+        if path.as_os_str().is_empty() {
+            bail!("expected a file to save the session to");
+        }
+
+        let mut writer = BufWriter::new(
+            File::create(path).with_context(|| format!("failed to create '{}'", path.display()))?,
+        );
+        if let Some(width) = self.width {
+            writeln!(writer, "{COMMAND_PREFIX}width {width}")?;
+        }
+        for (_, definition) in &self.definitions {
+            writeln!(writer, "{definition}")?;
+        }
+        writer.flush()?;
+
+        writeln!(
+            self.output,
+            "saved {} definitions to '{}'",
+            self.definitions.len(),
+            path.display()
+        )?;
+
+        Ok(())
+    }
+
+    /// Evaluate every non-empty line of `path`.
+    fn load(&mut self, path: &Path) -> Result<()> {
+        let session = fs::read_to_string(path)
+            .with_context(|| format!("failed to read '{}'", path.display()))?;
+        for line in session.lines().filter(|line| !line.trim().is_empty()) {
+            self.eval(line)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use anyhow::Result;
+
+    use super::*;
+
+    /// Evaluate the `lines` in a new [`Repl`], returning its output.
+    fn repl_output(lines: &str) -> Result<String> {
+        let config = Config::default();
+        let cancellation = CancellationToken::new();
+        let mut input = Cursor::new(lines);
+        let mut output = Vec::new();
+        Repl::new(&mut input, &mut output, &config, &cancellation).run()?;
+
+        Ok(String::from_utf8(output)?)
+    }
+
+    #[test]
+    fn repl_macros() -> Result<()> {
+        let output = repl_output("$p(#65+.)\n$q(-)\n$p(+)\n:macros\n")?;
+        assert!(
+            output.ends_with("> $q(-)\n$p(+)\n> \n"),
+            "\"{output}\" should list the definitions, redefined ones last."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn repl_config() -> Result<()> {
+        let output = repl_output(":config\n")?;
+        let ron = Config::default().to_ron_string()?;
+        assert!(
+            output == format!("> {ron}\n> \n"),
+            "\"{output}\" should be the config in the ron format."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn repl_tokens() -> Result<()> {
+        let output = repl_output("$p(#65+.)\n:tokens #2p-\n:tokens (\n")?;
+        assert!(
+            output.contains("> #2\n(#65+.)\n-\n> error: "),
+            "\"{output}\" should list the tokens with the macros expanded, then the error."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn repl_run() -> Result<()> {
+        let output = repl_output(":run\n$p(#65+.)\np\n:run\n:run p+.\n")?;
+        assert!(
+            output.starts_with("> \n>") && output.contains("> A\n> AB\n"),
+            "\"{output}\" should run nothing, then the last line, then the passed line."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn repl_width() -> Result<()> {
+        let output = repl_output(":width 4\n#6+\n:width\n#6+\n:width 0\n:width x\n")?;
+        assert!(
+            output.contains("> ++++\n++\n")
+                && output.contains("> ++++++\n")
+                && output.contains("error: width must be greater than 0")
+                && output.contains("error: invalid width 'x'"),
+            "\"{output}\" should be aligned, then unaligned, then reject the widths."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn repl_save_load() -> Result<()> {
+        let dir = std::env::temp_dir().join(format!("bfup-repl-{}", std::process::id()));
+        fs::create_dir_all(&dir)?;
+        let session = dir.join("session.bfup");
+        let config = Config::default();
+        let cancellation = CancellationToken::new();
+
+        let mut input = Cursor::new(format!(
+            "$x(+>)\n#2x\n:width 4\n:save {}\n:nope\n",
+            session.display()
+        ));
+        let mut output = Vec::new();
+        Repl::new(&mut input, &mut output, &config, &cancellation)
+            .with_history(History::open(&dir.join("history"))?)
+            .run()?;
+        let output = String::from_utf8(output)?;
+        assert!(
+            output.contains("> +>+>\n") && output.contains("error: unknown command ':nope'"),
+            "\"{output}\" should contain the output and the error."
+        );
+
+        let saved = fs::read_to_string(&session)?;
+        assert!(
+            saved == ":width 4\n$x(+>)\n",
+            "\"{saved}\" should contain the settings and definitions."
+        );
+
+        let mut input = Cursor::new(format!(":load {}\n#3x\n", session.display()));
+        let mut output = Vec::new();
+        Repl::new(&mut input, &mut output, &config, &cancellation)
+            .with_history(History::open(&dir.join("history"))?)
+            .run()?;
+        let output = String::from_utf8(output)?;
+        assert!(
+            output.contains("+>+>\n+>\n"),
+            "\"{output}\" should be aligned with the loaded macro."
+        );
+
+        let history = History::open(&dir.join("history"))?;
+        assert!(
+            history.lines().len() == 7,
+            "{:?} should contain the lines of both sessions.",
+            history.lines()
+        );
+
+        fs::remove_dir_all(&dir)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,367 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use crate::cancel::{CancellationToken, Cancelled};
+
+/// Number of cells the tape starts with, it grows to the right when needed.
+const TAPE_INIT_LEN: usize = 30000;
+/// Number of executed instructions between cancellation checks.
+const CANCELLATION_CHECK_INTERVAL: usize = 1 << 16;
+
+/// Error type returned when compiling or running a [`Program`].
+#[derive(thiserror::Error, fmt::Debug)]
+pub enum Error {
+    #[error("'[' at operator {0} is never closed.")]
+    LoopUnclosed(usize),
+    #[error("']' at operator {0} must have a preceding '['.")]
+    LoopUnopened(usize),
+    #[error("pointer moved left of the first cell.")]
+    PointerUnderflow,
+    #[error("argument {0} is empty, empty arguments can't be passed on the tape.")]
+    ArgumentEmpty(usize),
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    #[error("{0}")]
+    Cancelled(#[from] Cancelled),
+}
+
+/// A single instruction of a compiled [`Program`].
+#[derive(Clone, Copy, PartialEq, Eq, fmt::Debug)]
+pub enum Instruction {
+    /// Add to the current cell *(wrapping)*, folded from a run of `+` and `-`.
+    Add(u8),
+    /// Move the pointer, folded from a run of `<` and `>`.
+    Move(isize),
+    /// Set the current cell to zero, compiled from `[-]` and `[+]`.
+    Clear,
+    /// Jump past the matching [`Instruction::LoopEnd`] if the current cell is zero.
+    LoopStart(usize),
+    /// Jump past the matching [`Instruction::LoopStart`] if the current cell isn't zero.
+    LoopEnd(usize),
+    /// Write the current cell to output.
+    Output,
+    /// Read a byte from input into the current cell,
+    /// the cell is left unchanged at the end of input.
+    Input,
+}
+
+/// A brainfuck program compiled into [`Instructions`][Instruction].
+///
+/// Runs of `+`/`-` and `<`/`>` are folded into single instructions,
+/// clearing loops are replaced with [`Instruction::Clear`] and every
+/// loop knows the position of its counterpart, so no searching
+/// is done when running.
+///
+/// Every `char` other than the 8 brainfuck operators is ignored.
+///
+/// The tape starts zeroed, unless arguments are passed with `with_arguments()`.
+pub struct Program {
+    instructions: Vec<Instruction>,
+    /// Index of the first operator every instruction was compiled from.
+    origins: Vec<usize>,
+    /// Cells the tape starts with, followed by zeros.
+    initial_cells: Vec<u8>,
+}
+
+/// Number of executions of every instruction of a [`Program`],
+/// collected by [`Program::run_profiled`].
+pub struct Profile {
+    executions: Vec<u64>,
+}
+
+/// An often executed instruction within a [`Profile`].
+#[derive(fmt::Debug)]
+pub struct Hotspot {
+    pub instruction: Instruction,
+    /// Index of the first operator the instruction was compiled from.
+    pub origin: usize,
+    pub executions: u64,
+}
+
+impl Profile {
+    /// Get at most `n` of the most executed instructions of `program`.
+    pub fn hotspots(&self, program: &Program, n: usize) -> Vec<Hotspot> {
+        let mut hotspots: Vec<Hotspot> = self
+            .executions
+            .iter()
+            .enumerate()
+            .filter(|(_, executions)| **executions > 0)
+            .map(|(index, executions)| Hotspot {
+                instruction: program.instructions[index],
+                origin: program.origins[index],
+                executions: *executions,
+            })
+            .collect();
+        hotspots.sort_by(|left, right| {
+            right
+                .executions
+                .cmp(&left.executions)
+                .then(left.origin.cmp(&right.origin))
+        });
+        hotspots.truncate(n);
+
+        hotspots
+    }
+}
+
+impl Program {
+    /// Compile `source` into a `Program`,
+    /// returns error if the loops aren't balanced.
+    pub fn compile<S: IntoIterator<Item = char>>(source: S) -> Result<Self, Error> {
+        let mut instructions: Vec<Instruction> = Vec::new();
+        let mut origins: Vec<usize> = Vec::new();
+        let mut loop_starts: Vec<(usize, usize)> = Vec::new();
+
+        for (operator_index, operator) in source.into_iter().enumerate() {
+            match (operator, instructions.last_mut()) {
+                ('+', Some(Instruction::Add(value))) => *value = value.wrapping_add(1),
+                ('-', Some(Instruction::Add(value))) => *value = value.wrapping_sub(1),
+                ('>', Some(Instruction::Move(offset))) => *offset += 1,
+                ('<', Some(Instruction::Move(offset))) => *offset -= 1,
+                ('+', _) => instructions.push(Instruction::Add(1)),
+                ('-', _) => instructions.push(Instruction::Add(u8::MAX)),
+                ('>', _) => instructions.push(Instruction::Move(1)),
+                ('<', _) => instructions.push(Instruction::Move(-1)),
+                ('.', _) => instructions.push(Instruction::Output),
+                (',', _) => instructions.push(Instruction::Input),
+                ('[', _) => {
+                    loop_starts.push((instructions.len(), operator_index));
+                    // the jump target is set once the loop is closed
+                    instructions.push(Instruction::LoopStart(0));
+                }
+                (']', _) => {
+                    let Some((start, _)) = loop_starts.pop() else {
+                        return Err(Error::LoopUnopened(operator_index));
+                    };
+
+                    if let [Instruction::LoopStart(_), Instruction::Add(1 | u8::MAX)] =
+                        instructions[start..]
+                    {
+                        instructions.truncate(start + 1);
+                        instructions[start] = Instruction::Clear;
+                    } else {
+                        let end = instructions.len();
+                        instructions[start] = Instruction::LoopStart(end + 1);
+                        instructions.push(Instruction::LoopEnd(start + 1));
+                    }
+                }
+                _ => continue,
+            }
+
+            // runs folded into nothing are removed
+            if let Some(Instruction::Add(0) | Instruction::Move(0)) = instructions.last() {
+                instructions.pop();
+            }
+            origins.resize(instructions.len(), operator_index);
+        }
+
+        if let Some((_, operator_index)) = loop_starts.pop() {
+            return Err(Error::LoopUnclosed(operator_index));
+        }
+
+        Ok(Program {
+            instructions,
+            origins,
+            initial_cells: Vec::new(),
+        })
+    }
+
+    /// Pass `arguments` to the `Program` on its tape.
+    ///
+    /// The bytes of every argument are written from the first cell,
+    /// each followed by a zero cell, so the list ends with two zero cells
+    /// *(none for no arguments)*. The pointer starts at the first cell,
+    /// so `[>]>` moves it past an argument.
+    ///
+    /// Returns [`Error::ArgumentEmpty`] for an empty argument,
+    /// as it would end the list early.
+    pub fn with_arguments<A: AsRef<[u8]>>(mut self, arguments: &[A]) -> Result<Self, Error> {
+        self.initial_cells.clear();
+        for (index, argument) in arguments.iter().enumerate() {
+            if argument.as_ref().is_empty() {
+                return Err(Error::ArgumentEmpty(index));
+            }
+            self.initial_cells.extend_from_slice(argument.as_ref());
+            self.initial_cells.push(0);
+        }
+
+        Ok(self)
+    }
+
+    /// Run the `Program`, reading from `input` and writing to `output`.
+    ///
+    /// Stops with [`Error::Cancelled`] once `cancellation` is cancelled.
+    pub fn run<R: Read, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        cancellation: &CancellationToken,
+    ) -> Result<(), Error> {
+        self.execute(input, output, cancellation, None)
+    }
+
+    /// Same as [`run`][Program::run], but counts the executions of every instruction.
+    pub fn run_profiled<R: Read, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        cancellation: &CancellationToken,
+    ) -> Result<Profile, Error> {
+        let mut executions: Vec<u64> = vec![0; self.instructions.len()];
+        self.execute(input, output, cancellation, Some(&mut executions))?;
+
+        Ok(Profile { executions })
+    }
+
+    fn execute<R: Read, W: Write>(
+        &self,
+        input: &mut R,
+        output: &mut W,
+        cancellation: &CancellationToken,
+        mut executions: Option<&mut Vec<u64>>,
+    ) -> Result<(), Error> {
+        let mut tape: Vec<u8> = vec![0; self.initial_cells.len() + TAPE_INIT_LEN];
+        tape[..self.initial_cells.len()].copy_from_slice(&self.initial_cells);
+        let mut pointer: usize = 0;
+        let mut counter: usize = 0;
+        let mut executed: usize = 0;
+
+        while let Some(instruction) = self.instructions.get(counter) {
+            if let Some(executions) = executions.as_deref_mut() {
+                executions[counter] += 1;
+            }
+            counter += 1;
+            match *instruction {
+                Instruction::Add(value) => tape[pointer] = tape[pointer].wrapping_add(value),
+                Instruction::Move(offset) => {
+                    pointer = pointer
+                        .checked_add_signed(offset)
+                        .ok_or(Error::PointerUnderflow)?;
+                    if pointer >= tape.len() {
+                        tape.resize(pointer + 1 + TAPE_INIT_LEN, 0);
+                    }
+                }
+                Instruction::Clear => tape[pointer] = 0,
+                Instruction::LoopStart(end) if tape[pointer] == 0 => counter = end,
+                Instruction::LoopEnd(start) if tape[pointer] != 0 => counter = start,
+                Instruction::LoopStart(_) | Instruction::LoopEnd(_) => (),
+                Instruction::Output => output.write_all(&tape[pointer..=pointer])?,
+                Instruction::Input => {
+                    let mut byte = [0; 1];
+                    if input.read(&mut byte)? == 1 {
+                        tape[pointer] = byte[0];
+                    }
+                }
+            }
+
+            executed += 1;
+            if executed.is_multiple_of(CANCELLATION_CHECK_INTERVAL) {
+                cancellation.check()?;
+            }
+        }
+
+        output.flush()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+
+    #[test]
+    fn compile_folded() -> Result<()> {
+        let program = Program::compile("+++--><<<[-]x[>+<-]".chars())?;
+
+        assert!(
+            program.instructions
+                == [
+                    Instruction::Add(1),
+                    Instruction::Move(-2),
+                    Instruction::Clear,
+                    Instruction::LoopStart(9),
+                    Instruction::Move(1),
+                    Instruction::Add(1),
+                    Instruction::Move(-1),
+                    Instruction::Add(u8::MAX),
+                    Instruction::LoopEnd(4),
+                ],
+            "{:?} isn't folded correctly.",
+            program.instructions
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn compile_unbalanced() {
+        assert!(
+            matches!(Program::compile("[[]".chars()), Err(Error::LoopUnclosed(0))),
+            "'[' should be unclosed."
+        );
+        assert!(
+            matches!(Program::compile("[]]".chars()), Err(Error::LoopUnopened(2))),
+            "']' should be unopened."
+        );
+    }
+
+    #[test]
+    fn run_program() -> Result<()> {
+        // prints "Hi" followed by the input
+        let program = Program::compile("++++++++[>+++++++++>+++++++++++++<<-]>.>+.,.".chars())?;
+        let mut output: Vec<u8> = Vec::new();
+
+        program.run(&mut "!".as_bytes(), &mut output, &CancellationToken::new())?;
+
+        assert!(output == b"Hi!", "{output:?} should be \"Hi!\".");
+
+        Ok(())
+    }
+
+    #[test]
+    fn run_arguments() -> Result<()> {
+        let program = Program::compile("[[.>]>]".chars())?.with_arguments(&["ab", "cd"])?;
+        let mut output: Vec<u8> = Vec::new();
+        program.run(&mut io::empty(), &mut output, &CancellationToken::new())?;
+
+        assert!(
+            output == b"abcd",
+            "{output:?} should contain every argument."
+        );
+        assert!(
+            matches!(
+                Program::compile("".chars())?.with_arguments(&["a", ""]),
+                Err(Error::ArgumentEmpty(1))
+            ),
+            "Empty arguments should be an error."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn run_profiled() -> Result<()> {
+        let program = Program::compile("+++[>++<-]".chars())?;
+
+        let profile =
+            program.run_profiled(&mut io::empty(), &mut io::sink(), &CancellationToken::new())?;
+        let hotspots = profile.hotspots(&program, 1);
+
+        assert!(
+            matches!(
+                hotspots[..],
+                [Hotspot {
+                    origin: 4,
+                    executions: 3,
+                    ..
+                }]
+            ),
+            "{hotspots:?} should be the loop body."
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,470 @@
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+use crate::cancel::CancellationToken;
+use crate::config::{Config, ConfigCache};
+use crate::diagnostic::{Diagnostic, Severity};
+use crate::lex::MacroDefinition;
+use crate::pre::{emit_tokens, lex_tokens, AlignBy};
+
+/// Path of the preprocessing endpoint.
+pub const PREPROCESS_PATH: &str = "/preprocess";
+/// Largest accepted request body.
+pub const MAX_BODY_LEN: usize = 1 << 20;
+/// Interval of checking for new connections and the cancellation.
+const ACCEPT_INTERVAL: Duration = Duration::from_millis(50);
+/// Time a client has to send the whole request.
+const READ_TIMEOUT: Duration = Duration::from_secs(5);
+/// Default largest output of a request.
+pub const DEFAULT_MAX_OUTPUT_LEN: usize = 16 << 20;
+/// Default time a request can take to be preprocessed.
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Body of a request to the [`PREPROCESS_PATH`] endpoint.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PreprocessRequest {
+    pub source: String,
+    /// Config in the ron format *(the server's default if `None`)*.
+    #[serde(default)]
+    pub config: Option<String>,
+    /// Width the output is aligned to *(unaligned if `None`)*.
+    #[serde(default)]
+    pub width: Option<usize>,
+}
+
+/// Minimal HTTP server preprocessing the sources posted to [`PREPROCESS_PATH`]
+/// as json *(see [`PreprocessRequest`])*.
+///
+/// Responds with `{"output": ..., "diagnostics": [...]}` when preprocessed
+/// *(the diagnostics being the warnings)*, or with `{"diagnostics": [...]}`
+/// and status 422 when the source or config is invalid.
+/// The config of a request can't enable includes or generators,
+/// which would read the server's files or run its commands.
+///
+/// The requests are handled one at a time, every connection is closed
+/// after its response. A request is answered with an error once its
+/// output is longer than `with_max_output_len()` bytes, or once it takes
+/// longer than `with_timeout()` *(by default [`DEFAULT_MAX_OUTPUT_LEN`]
+/// and [`DEFAULT_TIMEOUT`])*.
+///
+/// Macros read once at startup *(e.g. from libraries)* can be predefined
+/// for every request with `with_macros()`, their bodies are shared
+/// so the requests don't lex them again.
+pub struct Server {
+    listener: TcpListener,
+    default_config: Arc<Config>,
+    cache: ConfigCache,
+    macros: Vec<MacroDefinition>,
+    max_output_len: usize,
+    timeout: Duration,
+}
+
+impl Server {
+    /// Listen on `addr`, preprocessing the requests without a config with `default_config`.
+    pub fn bind(addr: SocketAddr, default_config: Arc<Config>) -> io::Result<Self> {
+        let listener = TcpListener::bind(addr)?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Server {
+            listener,
+            default_config,
+            cache: ConfigCache::default(),
+            macros: Vec::new(),
+            max_output_len: DEFAULT_MAX_OUTPUT_LEN,
+            timeout: DEFAULT_TIMEOUT,
+        })
+    }
+
+    /// Predefine the `macros` in every request, whatever its config.
+    pub fn with_macros(mut self, macros: Vec<MacroDefinition>) -> Self {
+        self.macros = macros;
+        self
+    }
+
+    /// Answer the requests with an output longer than `max_output_len` bytes with an error.
+    pub fn with_max_output_len(mut self, max_output_len: usize) -> Self {
+        self.max_output_len = max_output_len;
+        self
+    }
+
+    /// Answer the requests taking longer than `timeout` with an error.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Handle the requests until `cancellation` is cancelled,
+    /// calling `on_response` with the config cache after every response.
+    pub fn run(
+        &mut self,
+        cancellation: &CancellationToken,
+        on_response: &mut dyn FnMut(&ConfigCache),
+    ) -> Result<()> {
+        while !cancellation.is_cancelled() {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    // a failed connection shouldn't stop the server
+                    let _ = self.handle_connection(stream, cancellation);
+                    on_response(&self.cache);
+                }
+                Err(error) if error.kind() == io::ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_INTERVAL);
+                }
+                Err(error) => return Err(error).with_context(|| "failed to accept a connection"),
+            }
+        }
+
+        Ok(())
+    }
+
+    fn handle_connection(
+        &mut self,
+        stream: TcpStream,
+        cancellation: &CancellationToken,
+    ) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let mut reader = BufReader::new(&stream);
+
+        let (status, body) = match read_request(&mut reader) {
+            Ok((method, path, _)) if path != PREPROCESS_PATH => (
+                404,
+                json!({ "error": format!("no such endpoint '{method} {path}'") }),
+            ),
+            Ok((method, _, _)) if method != "POST" => (
+                405,
+                json!({ "error": format!("expected POST, got {method}") }),
+            ),
+            Ok((_, _, body)) => self.preprocess_json(&body, cancellation),
+            Err(error) => (400, json!({ "error": format!("{error:#}") })),
+        };
+
+        write_response(&mut &stream, status, &body)
+    }
+
+    /// Preprocess the json [`PreprocessRequest`] in `body`,
+    /// returning the status and body of the response.
+    pub fn preprocess_json(
+        &mut self,
+        body: &[u8],
+        cancellation: &CancellationToken,
+    ) -> (u16, Value) {
+        let request: PreprocessRequest = match serde_json::from_slice(body) {
+            Ok(request) => request,
+            Err(error) => return (400, json!({ "error": format!("invalid request: {error}") })),
+        };
+
+        let config = match &request.config {
+            Some(config) => match self.cache.get_or_read(config.as_bytes(), None) {
+                Ok(config) => config,
+                Err(error) => {
+                    let diagnostic = Diagnostic::from_error(
+                        &anyhow::Error::new(error).context("failed to parse config"),
+                    );
+                    return (422, diagnostics_json(&diagnostic));
+                }
+            },
+            None => Arc::clone(&self.default_config),
+        };
+        // the server's files and commands are off limits to its clients
+        if request.config.is_some() && (config.has_includes() || config.has_generators()) {
+            let diagnostic = Diagnostic::new(
+                Severity::Error,
+                String::from("the config of a request cannot enable includes or generators"),
+            );
+            return (422, diagnostics_json(&[diagnostic]));
+        }
+
+        let source = config.get_graphemes().encode_str(&request.source);
+        let chars = source.chars().map(Ok::<char, io::Error>);
+        let mut output = CappedOutput {
+            output: Vec::new(),
+            max_len: self.max_output_len,
+        };
+        let alignment = request
+            .width
+            .filter(|width| *width > 0)
+            .map(|width| (width, AlignBy::default()));
+        let request_cancellation = cancellation.with_deadline(Instant::now() + self.timeout);
+        let result = lex_tokens(
+            chars,
+            &config,
+            &self.macros,
+            false,
+            false,
+            &request_cancellation,
+        )
+        .and_then(|(tokens, report)| {
+            emit_tokens(
+                &tokens,
+                &mut output,
+                &config,
+                alignment,
+                None,
+                None,
+                &request_cancellation,
+            )?;
+            Ok(report)
+        });
+
+        match result {
+            Ok(report) => {
+                let warnings: Vec<Diagnostic> =
+                    report.warnings.iter().map(Diagnostic::from).collect();
+                let mut body = diagnostics_json(&warnings);
+                body["output"] = String::from_utf8_lossy(&output.output).into();
+                (200, body)
+            }
+            Err(_) if request_cancellation.is_past_deadline() && !cancellation.is_cancelled() => {
+                let diagnostic = Diagnostic::new(
+                    Severity::Error,
+                    format!(
+                        "the request took longer than {}s",
+                        self.timeout.as_secs_f64()
+                    ),
+                );
+                (422, diagnostics_json(&[diagnostic]))
+            }
+            Err(error) => (422, diagnostics_json(&Diagnostic::from_error(&error))),
+        }
+    }
+}
+
+/// Output of a request, failing to write more than `max_len` bytes.
+struct CappedOutput {
+    output: Vec<u8>,
+    max_len: usize,
+}
+
+impl Write for CappedOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.output.len() + buf.len() > self.max_len {
+            return Err(io::Error::other(format!(
+                "the output is longer than {} bytes",
+                self.max_len
+            )));
+        }
+        self.output.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Get `{"diagnostics": [...]}` with every one of `diagnostics`.
+fn diagnostics_json(diagnostics: &[Diagnostic]) -> Value {
+    json!({
+        "diagnostics": diagnostics.iter().map(Diagnostic::to_json).collect::<Vec<Value>>(),
+    })
+}
+
+/// Read the method, path and body of an HTTP request.
+fn read_request<R: BufRead>(reader: &mut R) -> Result<(String, String, Vec<u8>)> {
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut parts = request_line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        bail!("malformed request line");
+    };
+
+    let mut content_len: usize = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 {
+            bail!("request headers not terminated");
+        }
+        let header = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+
+        if let Some((name, value)) = header.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("content-length") {
+                content_len = value
+                    .trim()
+                    .parse()
+                    .with_context(|| "invalid Content-Length")?;
+            }
+        }
+    }
+    if content_len > MAX_BODY_LEN {
+        bail!("request body larger than {MAX_BODY_LEN} bytes");
+    }
+
+    let mut body = vec![0; content_len];
+    reader.read_exact(&mut body)?;
+
+    Ok((method.to_string(), path.to_string(), body))
+}
+
+/// Write an HTTP response with the json `body`.
+fn write_response<W: Write>(writer: &mut W, status: u16, body: &Value) -> io::Result<()> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        _ => "Unprocessable Entity",
+    };
+    let body = body.to_string();
+
+    write!(
+        writer,
+        "HTTP/1.1 {status} {reason}\r\n\
+         Content-Type: application/json\r\n\
+         Content-Length: {}\r\n\
+         Connection: close\r\n\r\n{body}",
+        body.len()
+    )?;
+    writer.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use anyhow::Result;
+
+    use super::*;
+    use crate::testing;
+
+    #[test]
+    fn serve_requests() -> Result<()> {
+        let mut server = Server::bind(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            Arc::new(Config::default()),
+        )?;
+        let cancellation = CancellationToken::new();
+
+        let (status, body) = server.preprocess_json(
+            br##"{"source": "#3(+>)", "config": "(operators: \"+>\")", "width": 4}"##,
+            &cancellation,
+        );
+        assert!(
+            status == 200 && body["output"] == "+>+>\n+>",
+            "{body} should contain the aligned output."
+        );
+
+        let (status, body) = server.preprocess_json(br#"{"source": "(+"}"#, &cancellation);
+        assert!(
+            status == 422 && body["diagnostics"][0]["severity"] == "error",
+            "{body} should contain the unclosed group error."
+        );
+
+        let request = "POST /preprocess HTTP/1.1\r\nContent-Length: 4\r\n\r\nbody";
+        let (method, path, body) = read_request(&mut Cursor::new(request))?;
+        assert!(
+            method == "POST" && path == PREPROCESS_PATH && body == b"body",
+            "The request should be parsed."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn serve_preloaded_lib() -> Result<()> {
+        let config = Config::default();
+        let mut lib = testing::lexer("$z([-]) $c(z>)", &config).keep_macro_definitions();
+        lib.read_all_tokens()?;
+        let mut server = Server::bind(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            Arc::new(config.clone()),
+        )?
+        .with_macros(lib.take_macro_definitions());
+        let cancellation = CancellationToken::new();
+
+        let (status, body) = server.preprocess_json(br##"{"source": "+z#2c"}"##, &cancellation);
+        assert!(
+            status == 200 && body["output"] == "+[-][-]>[-]>",
+            "{body} should expand the macros of the lib."
+        );
+        let (status, body) = server.preprocess_json(
+            br#"{"source": "c", "config": "(max_macros: 4)"}"#,
+            &cancellation,
+        );
+        assert!(
+            status == 200 && body["output"] == "[-]>",
+            "{body} should expand the macros of the lib with the config of the request."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn serve_untrusted_config() -> Result<()> {
+        let mut server = Server::bind(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            Arc::new(Config::default()),
+        )?;
+        let cancellation = CancellationToken::new();
+
+        let (status, body) = server.preprocess_json(
+            br#"{"source": "$%\"/etc/hostname\"", "config": "(includes: true)"}"#,
+            &cancellation,
+        );
+        assert!(
+            status == 422 && body.get("output").is_none(),
+            "{body} should reject the config enabling includes."
+        );
+        let (status, _) = server.preprocess_json(
+            br#"{"source": "+", "config": "(generators: {'g': \"cat /etc/hostname\"})"}"#,
+            &cancellation,
+        );
+        assert!(
+            status == 422,
+            "The config enabling generators should be rejected."
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn serve_limits() -> Result<()> {
+        let server = Server::bind(
+            SocketAddr::from(([127, 0, 0, 1], 0)),
+            Arc::new(Config::default()),
+        )?;
+        let cancellation = CancellationToken::new();
+
+        let mut server = server.with_max_output_len(8);
+        let (status, body) = server.preprocess_json(br##"{"source": "#8+"}"##, &cancellation);
+        assert!(
+            status == 200 && body["output"] == "++++++++",
+            "{body} should fit in the output."
+        );
+        let (status, body) = server.preprocess_json(
+            br##"{"source": "#18446744073709551615(+)"}"##,
+            &cancellation,
+        );
+        assert!(
+            status == 422
+                && body["diagnostics"][0]["message"]
+                    .as_str()
+                    .is_some_and(|message| message.contains("longer than 8 bytes")),
+            "{body} should report the output limit."
+        );
+
+        let mut server = server.with_timeout(Duration::ZERO);
+        let (status, body) = server.preprocess_json(br#"{"source": "+"}"#, &cancellation);
+        assert!(
+            status == 422 && body["diagnostics"][0]["message"] == "the request took longer than 0s",
+            "{body} should report the timeout."
+        );
+
+        Ok(())
+    }
+}
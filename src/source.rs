@@ -0,0 +1,162 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::span::{Position, SourceId, Span};
+
+/// A single source owned by the [`SourceManager`].
+struct Source {
+    name: String,
+    text: String,
+    /// Char and byte offsets of every newline in `text`.
+    newlines: Vec<(usize, usize)>,
+}
+
+impl Source {
+    fn new(name: String, text: String) -> Self {
+        let newlines = text
+            .char_indices()
+            .enumerate()
+            .filter(|(_, (_, ch))| *ch == '\n')
+            .map(|(offset, (byte, _))| (offset, byte))
+            .collect();
+
+        Source {
+            name,
+            text,
+            newlines,
+        }
+    }
+}
+
+/// Struct owning every loaded source *(main file, includes, libraries)*,
+/// identified by their [`SourceId`].
+///
+/// Use `position()` to resolve a char offset into a [`Position`].
+///
+/// Use `line()` to get a line's text for diagnostics.
+///
+/// Line and column numbers are resolved the same way as in the
+/// [`Lexer`][crate::lex::Lexer], so a newline `char` is placed at
+/// column 0 of the following line.
+#[derive(Default)]
+pub struct SourceManager {
+    sources: Vec<Source>,
+}
+
+impl SourceManager {
+    /// Create an empty `SourceManager`.
+    pub fn new() -> Self {
+        SourceManager::default()
+    }
+
+    /// Add a source named `name`, returning its [`SourceId`].
+    pub fn add<N: Into<String>, T: Into<String>>(&mut self, name: N, text: T) -> SourceId {
+        self.sources.push(Source::new(name.into(), text.into()));
+
+        SourceId(self.sources.len() - 1)
+    }
+
+    /// Read the file at `path` and add it as a source named after the path.
+    pub fn load(&mut self, path: &Path) -> io::Result<SourceId> {
+        let text = fs::read_to_string(path)?;
+
+        Ok(self.add(path.display().to_string(), text))
+    }
+
+    /// Get the name of the source.
+    pub fn name(&self, source: SourceId) -> &str {
+        &self.get(source).name
+    }
+
+    /// Get the whole text of the source.
+    pub fn text(&self, source: SourceId) -> &str {
+        &self.get(source).text
+    }
+
+    /// Resolve the char `offset` within the source into a [`Position`].
+    pub fn position(&self, source: SourceId, offset: usize) -> Position {
+        let newlines = &self.get(source).newlines;
+        let lines_before = newlines.partition_point(|(newline, _)| *newline <= offset);
+
+        let colno = match lines_before {
+            0 => offset + 1,
+            _ => offset - newlines[lines_before - 1].0,
+        };
+
+        Position::new(offset, lines_before + 1, colno)
+    }
+
+    /// Get the text of the line `lineno` *(without the newline)*,
+    /// or `None` if the source is shorter.
+    pub fn line(&self, source: SourceId, lineno: usize) -> Option<&str> {
+        let Source { text, newlines, .. } = self.get(source);
+
+        if lineno == 0 || lineno > newlines.len() + 1 {
+            return None;
+        }
+
+        let start = match lineno {
+            1 => 0,
+            _ => newlines[lineno - 2].1 + 1,
+        };
+        let end = newlines
+            .get(lineno - 1)
+            .map_or(text.len(), |(_, byte)| *byte);
+
+        Some(&text[start..end])
+    }
+
+    /// Format the location of `span` as `name:lineno:colno`.
+    pub fn location(&self, span: &Span) -> String {
+        format!("{}:{}", self.name(span.source), span.start)
+    }
+
+    fn get(&self, source: SourceId) -> &Source {
+        self.sources
+            .get(source.0)
+            .expect("SourceId should come from this SourceManager.")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn source_position() {
+        let mut sources = SourceManager::new();
+        let id = sources.add("main", "+-\nżółw\n.");
+
+        let positions = [(0, 1, 1), (1, 1, 2), (2, 2, 0), (4, 2, 2), (8, 3, 1)];
+        for (offset, lineno, colno) in positions {
+            let position = sources.position(id, offset);
+            assert!(
+                position == Position::new(offset, lineno, colno),
+                "offset {offset} should be at {lineno}:{colno}, not {position}."
+            );
+        }
+    }
+
+    #[test]
+    fn source_line() {
+        let mut sources = SourceManager::new();
+        sources.add("lib", "$a+");
+        let id = sources.add("main", "+-\nżółw\n.");
+
+        assert!(sources.name(id) == "main", "source names don't match.");
+        assert!(
+            sources.line(id, 1) == Some("+-"),
+            "first lines don't match."
+        );
+        assert!(
+            sources.line(id, 2) == Some("żółw"),
+            "second lines don't match."
+        );
+        assert!(sources.line(id, 3) == Some("."), "third lines don't match.");
+        assert!(
+            sources.line(id, 4).is_none(),
+            "there should be no fourth line."
+        );
+    }
+}
@@ -0,0 +1,174 @@
+use std::cmp::{max, min};
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+/// Identifier of the source a [`Span`] points into.
+#[derive(
+    Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord, Hash, fmt::Debug, Serialize, Deserialize,
+)]
+pub struct SourceId(pub usize);
+
+impl SourceId {
+    /// Source of the macros defined on the command line.
+    pub const COMMAND_LINE: SourceId = SourceId(usize::MAX);
+    /// Source of the tokens read from included files.
+    pub const INCLUDED: SourceId = SourceId(usize::MAX - 1);
+}
+
+/// Position of a `char` within a source.
+///
+/// `offset` is the 0-based index of the `char`, while `lineno`
+/// and `colno` are the 1-based line and column numbers.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, fmt::Debug, Serialize, Deserialize)]
+pub struct Position {
+    pub offset: usize,
+    pub lineno: usize,
+    pub colno: usize,
+}
+
+impl Position {
+    pub fn new(offset: usize, lineno: usize, colno: usize) -> Self {
+        Position {
+            offset,
+            lineno,
+            colno,
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.lineno, self.colno)
+    }
+}
+
+/// A range of `chars` within a source, both `start` and `end` inclusive.
+///
+/// When displayed, a span of a single `char` is printed as `lineno:colno`,
+/// while longer spans are printed as `lineno:colno-lineno:colno`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, fmt::Debug, Serialize, Deserialize)]
+pub struct Span {
+    pub source: SourceId,
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Span {
+    /// Create a new `Span` from `start` to `end`.
+    pub fn new(source: SourceId, start: Position, end: Position) -> Self {
+        debug_assert!(start <= end, "Span shouldn't end before it starts.");
+
+        Span { source, start, end }
+    }
+
+    /// Create a `Span` of the single `char` at `position`.
+    pub fn at(source: SourceId, position: Position) -> Self {
+        Span::new(source, position, position)
+    }
+
+    /// Create the smallest `Span` containing both `self` and `other`.
+    pub fn merge(&self, other: &Span) -> Self {
+        assert!(
+            self.source == other.source,
+            "Spans from different sources cannot be merged."
+        );
+
+        Span::new(
+            self.source,
+            min(self.start, other.start),
+            max(self.end, other.end),
+        )
+    }
+
+    /// Check whether the `char` at `position` is within the `Span`.
+    pub fn contains(&self, position: &Position) -> bool {
+        self.start <= *position && *position <= self.end
+    }
+
+    /// Check whether the `char` at line `lineno` and column `colno` is within the `Span`.
+    pub fn contains_location(&self, lineno: usize, colno: usize) -> bool {
+        (self.start.lineno, self.start.colno) <= (lineno, colno)
+            && (lineno, colno) <= (self.end.lineno, self.end.colno)
+    }
+
+    /// Check whether `other` is wholly within the `Span`.
+    pub fn contains_span(&self, other: &Span) -> bool {
+        self.source == other.source && self.contains(&other.start) && self.contains(&other.end)
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.start == self.end {
+            write!(f, "{}", self.start)
+        } else {
+            write!(f, "{}-{}", self.start, self.end)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SOURCE: SourceId = SourceId(0);
+
+    #[test]
+    fn span_merge() {
+        let left = Span::new(SOURCE, Position::new(0, 1, 1), Position::new(2, 1, 3));
+        let right = Span::new(SOURCE, Position::new(5, 2, 1), Position::new(7, 2, 3));
+
+        let merged = left.merge(&right);
+
+        assert!(
+            merged.start == left.start && merged.end == right.end,
+            "{merged} should span from {} to {}.",
+            left.start,
+            right.end
+        );
+        assert!(merged == right.merge(&left), "merge should be commutative.");
+    }
+
+    #[test]
+    fn span_contains() {
+        let span = Span::new(SOURCE, Position::new(2, 1, 3), Position::new(4, 1, 5));
+
+        assert!(
+            span.contains(&Position::new(2, 1, 3)),
+            "start should be within the span."
+        );
+        assert!(
+            span.contains(&Position::new(4, 1, 5)),
+            "end should be within the span."
+        );
+        assert!(
+            !span.contains(&Position::new(5, 1, 6)),
+            "5 shouldn't be within the span."
+        );
+        assert!(
+            span.contains_location(1, 4) && !span.contains_location(2, 1),
+            "1:4 should be within the span, unlike 2:1."
+        );
+        assert!(
+            span.contains_span(&Span::at(SOURCE, Position::new(3, 1, 4))),
+            "3 should be within the span."
+        );
+        assert!(
+            !span.contains_span(&Span::at(SourceId(1), Position::new(3, 1, 4))),
+            "spans from different sources shouldn't contain each other."
+        );
+    }
+
+    #[test]
+    fn span_display() {
+        let point = Span::at(SOURCE, Position::new(4, 2, 1));
+        let range = Span::new(SOURCE, Position::new(0, 1, 1), Position::new(4, 2, 1));
+
+        assert!(point.to_string() == "2:1", "\"{point}\" should be \"2:1\".");
+        assert!(
+            range.to_string() == "1:1-2:1",
+            "\"{range}\" should be \"1:1-2:1\"."
+        );
+    }
+}
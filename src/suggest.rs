@@ -0,0 +1,136 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+use crate::config::Config;
+
+/// Number of chars a macro definition takes besides its operators
+/// *(the macro prefix, the symbol and the group delimiters)*.
+pub const DEFINITION_OVERHEAD: usize = 4;
+
+/// Macro suggested for a run of operators repeated throughout a source.
+#[derive(Clone, PartialEq, Eq, fmt::Debug)]
+pub struct Suggestion {
+    /// Operators the macro would expand to.
+    pub operators: String,
+    /// Number of non-overlapping occurrences of the operators.
+    pub occurrences: usize,
+    /// Estimated number of chars saved by defining the macro
+    /// and using it in place of every occurrence.
+    pub savings: usize,
+}
+
+/// Find the runs of `operators` with their length in `lengths` that save
+/// the most chars when replaced with a macro, returning at most `top` of them.
+///
+/// The suggestions are picked one at a time, the occurrences of every picked
+/// one are taken out of the operators before the next one is picked, so
+/// that the suggestions don't overlap. The occurrences of a run are counted
+/// left to right without overlapping. Runs of a single operator are left out,
+/// as a number prefix is shorter.
+pub fn suggest(operators: &[char], lengths: RangeInclusive<usize>, top: usize) -> Vec<Suggestion> {
+    let mut remaining: Vec<Option<char>> = operators.iter().copied().map(Some).collect();
+    let mut suggestions: Vec<Suggestion> = Vec::new();
+    while suggestions.len() < top {
+        let Some((suggestion, starts)) = best_run(&remaining, lengths.clone()) else {
+            break;
+        };
+        let len = suggestion.operators.chars().count();
+        for start in starts {
+            remaining[start..start + len].fill(None);
+        }
+        suggestions.push(suggestion);
+    }
+
+    suggestions
+}
+
+/// Find the run of the `remaining` operators saving the most chars, along with
+/// the starts of its occurrences *(the runs can't contain taken out operators)*.
+fn best_run(
+    remaining: &[Option<char>],
+    lengths: RangeInclusive<usize>,
+) -> Option<(Suggestion, Vec<usize>)> {
+    let mut best: Option<(Suggestion, Vec<usize>)> = None;
+    // a single operator can't be replaced with anything shorter
+    for len in (*lengths.start()).max(2)..=*lengths.end() {
+        if len > remaining.len() {
+            break;
+        }
+
+        let mut starts: HashMap<&[Option<char>], Vec<usize>> = HashMap::new();
+        for (start, run) in remaining.windows(len).enumerate() {
+            if run.iter().any(Option::is_none) || run.iter().all(|operator| *operator == run[0]) {
+                continue;
+            }
+            let run_starts = starts.entry(run).or_default();
+            if run_starts.last().is_none_or(|last| start >= last + len) {
+                run_starts.push(start);
+            }
+        }
+
+        for (run, run_starts) in starts {
+            let occurrences = run_starts.len();
+            let Some(savings) = (occurrences * (len - 1))
+                .checked_sub(len + DEFINITION_OVERHEAD)
+                .filter(|savings| *savings > 0)
+            else {
+                continue;
+            };
+            let suggestion = Suggestion {
+                operators: run.iter().flatten().collect(),
+                occurrences,
+                savings,
+            };
+            let better = best.as_ref().is_none_or(|(best, _)| {
+                (Reverse(suggestion.savings), &suggestion.operators)
+                    < (Reverse(best.savings), &best.operators)
+            });
+            if better {
+                best = Some((suggestion, run_starts));
+            }
+        }
+    }
+
+    best
+}
+
+/// Get the letters usable as the symbols of the suggested macros,
+/// the ones not assigned to any field of `config` and not in `used`.
+pub fn free_symbols<'a>(config: &'a Config, used: &'a str) -> impl Iterator<Item = char> + 'a {
+    ('a'..='z')
+        .chain('A'..='Z')
+        .filter(move |ch| config.get_field(ch).is_none() && !used.contains(*ch))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suggest_repeated_runs() {
+        let operators: Vec<char> = "[->+<]>.[->+<]>,[->+<]<<[->+<]".chars().collect();
+        let suggestions = suggest(&operators, 2..=8, 3);
+
+        assert!(
+            suggestions.first()
+                == Some(&Suggestion {
+                    operators: String::from("[->+<]"),
+                    occurrences: 4,
+                    savings: 10,
+                }),
+            "{suggestions:?} should start with the repeated loop."
+        );
+        assert!(
+            suggestions.len() == 1,
+            "{suggestions:?} should leave out the runs overlapping the loop."
+        );
+
+        let symbols: String = free_symbols(&Config::default(), "abc").take(3).collect();
+        assert!(
+            symbols == "def",
+            "\"{symbols}\" should skip the used symbols."
+        );
+    }
+}
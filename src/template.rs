@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::error::Error as ErrorTrait;
+use std::fmt;
+use std::iter::Peekable;
+
+use crate::config::Config;
+
+/// Marks the start of a variable reference *(`@{key}`)*, when followed
+/// by [`VARIABLE_OPEN`], unless the config sets another one.
+pub const DEFAULT_VARIABLE_PREFIX: char = '@';
+pub const VARIABLE_OPEN: char = '{';
+pub const VARIABLE_CLOSE: char = '}';
+/// Builtin variables also referenced as directives, without the braces *(`@version`)*.
+pub const DIRECTIVES: [&str; 2] = ["output_width", "version"];
+
+/// Error type for reading the input with the variables interpolated,
+/// the interpolation errors contain the line of the failed reference.
+#[derive(thiserror::Error, fmt::Debug)]
+pub enum Error<E: ErrorTrait + 'static> {
+    #[error("{0}")]
+    Input(#[source] E),
+    #[error("line {lineno}: variable '{key}' is not set")]
+    Unset { key: String, lineno: usize },
+    #[error(
+        "line {lineno}: '{prefix}{VARIABLE_OPEN}' is not closed by '{VARIABLE_CLOSE}' within the line"
+    )]
+    Unclosed { prefix: char, lineno: usize },
+}
+
+/// Variables interpolated into the input, the builtin ones overridden
+/// by the ones set explicitly.
+#[derive(Clone, Default, fmt::Debug)]
+pub struct Variables {
+    values: HashMap<String, String>,
+    /// Whether any variable was set explicitly, which enables the interpolation.
+    explicit: bool,
+}
+
+impl Variables {
+    /// Get the `explicit` variables with the builtin ones:
+    /// * `version` - the preprocessor's version as a single number,
+    ///   `major * 10000 + minor * 100 + patch` *(10203 for 1.2.3)*
+    /// * `version_major`, `version_minor`, `version_patch` - digits of the preprocessor's version
+    /// * `output_width` - width of the aligned output, unset when not aligned
+    ///
+    /// The version isn't written with its dots, as they would be read as operators.
+    pub fn new<V: IntoIterator<Item = (String, String)>>(
+        output_width: Option<usize>,
+        explicit: V,
+    ) -> Self {
+        let mut explicit = explicit.into_iter().peekable();
+        let is_explicit = explicit.peek().is_some();
+        let mut values = builtin_variables(output_width);
+        values.extend(explicit);
+
+        Variables {
+            values,
+            explicit: is_explicit,
+        }
+    }
+
+    /// Get the `char` starting the references replaced in an input read with `config`,
+    /// the one it sets, or [`DEFAULT_VARIABLE_PREFIX`] if any variable was set explicitly.
+    ///
+    /// Returns `None` if the references are read as they are, so the `char`s
+    /// that aren't assigned to any field stay comments *(`email me @{home}`)*.
+    pub fn prefix(&self, config: &Config) -> Option<char> {
+        config
+            .get_variable_prefix()
+            .or(self.explicit.then_some(DEFAULT_VARIABLE_PREFIX))
+    }
+}
+
+/// Get the variables always available to the sources, see [`Variables::new`].
+fn builtin_variables(output_width: Option<usize>) -> HashMap<String, String> {
+    let part = |part: &str| part.parse::<usize>().unwrap_or_default();
+    let version = part(env!("CARGO_PKG_VERSION_MAJOR")) * 10_000
+        + part(env!("CARGO_PKG_VERSION_MINOR")) * 100
+        + part(env!("CARGO_PKG_VERSION_PATCH"));
+    let mut variables = HashMap::from([
+        (String::from("version"), version.to_string()),
+        (
+            String::from("version_major"),
+            String::from(env!("CARGO_PKG_VERSION_MAJOR")),
+        ),
+        (
+            String::from("version_minor"),
+            String::from(env!("CARGO_PKG_VERSION_MINOR")),
+        ),
+        (
+            String::from("version_patch"),
+            String::from(env!("CARGO_PKG_VERSION_PATCH")),
+        ),
+    ]);
+    if let Some(output_width) = output_width {
+        variables.insert(String::from("output_width"), output_width.to_string());
+    }
+
+    variables
+}
+
+/// Iterator over the chars of an input with every `@{key}`
+/// replaced with the value of `key` in the variables,
+/// the chars are passed through if there's no prefix.
+///
+/// The [`DIRECTIVES`] are also replaced without the braces *(`@output_width`)*,
+/// while the prefix followed by any other name is passed through.
+pub struct Interpolating<I: Iterator> {
+    chars: Peekable<I>,
+    variables: Variables,
+    prefix: Option<char>,
+    /// Chars of the value being interpolated, reversed.
+    value: Vec<char>,
+    lineno: usize,
+}
+
+impl<I, E> Interpolating<I>
+where
+    E: ErrorTrait + 'static,
+    I: Iterator<Item = Result<char, E>>,
+{
+    /// Replace the references starting with `prefix` *(see [`Variables::prefix`])*.
+    pub fn new(chars: I, variables: Variables, prefix: Option<char>) -> Self {
+        Interpolating {
+            chars: chars.peekable(),
+            variables,
+            prefix,
+            value: Vec::new(),
+            lineno: 1,
+        }
+    }
+
+    /// Read the name following the prefix, replacing it with the value
+    /// of the directive it names, or passing it through with the `prefix`.
+    fn read_directive(&mut self, prefix: char) -> Result<(), Error<E>> {
+        let mut name = String::new();
+        while let Some(Ok(ch)) = self
+            .chars
+            .next_if(|ch| matches!(ch, Ok(ch) if ch.is_ascii_alphanumeric() || *ch == '_'))
+        {
+            name.push(ch);
+        }
+
+        if !DIRECTIVES.contains(&name.as_str()) {
+            self.value = name.chars().rev().collect();
+            self.value.push(prefix);
+            return Ok(());
+        }
+        match self.variables.values.get(&name) {
+            Some(value) => {
+                self.value = value.chars().rev().collect();
+                Ok(())
+            }
+            None => Err(Error::Unset {
+                key: name,
+                lineno: self.lineno,
+            }),
+        }
+    }
+
+    /// Read the key of a reference up to the closing char,
+    /// with the opening char already consumed.
+    fn read_key(&mut self, prefix: char) -> Result<String, Error<E>> {
+        let mut key = String::new();
+        loop {
+            match self.chars.next() {
+                Some(Ok(VARIABLE_CLOSE)) => return Ok(key.trim().to_string()),
+                Some(Ok('\n')) | None => {
+                    return Err(Error::Unclosed {
+                        prefix,
+                        lineno: self.lineno,
+                    })
+                }
+                Some(Ok(ch)) => key.push(ch),
+                Some(Err(error)) => return Err(Error::Input(error)),
+            }
+        }
+    }
+}
+
+impl<I, E> Iterator for Interpolating<I>
+where
+    E: ErrorTrait + 'static,
+    I: Iterator<Item = Result<char, E>>,
+{
+    type Item = Result<char, Error<E>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if let Some(ch) = self.value.pop() {
+            return Some(Ok(ch));
+        }
+
+        match self.chars.next()? {
+            Ok(ch)
+                if Some(ch) == self.prefix
+                    && matches!(self.chars.peek(), Some(Ok(VARIABLE_OPEN))) =>
+            {
+                self.chars.next();
+                let key = match self.read_key(ch) {
+                    Ok(key) => key,
+                    Err(error) => return Some(Err(error)),
+                };
+                let Some(value) = self.variables.values.get(&key) else {
+                    return Some(Err(Error::Unset {
+                        key,
+                        lineno: self.lineno,
+                    }));
+                };
+
+                self.value = value.chars().rev().collect();
+                self.next()
+            }
+            Ok(ch) if Some(ch) == self.prefix => {
+                if let Err(error) = self.read_directive(ch) {
+                    return Some(Err(error));
+                }
+                // the prefix passed through isn't read as a prefix again
+                match self.value.pop() {
+                    Some(ch) => Some(Ok(ch)),
+                    None => self.next(),
+                }
+            }
+            Ok(ch) => {
+                if ch == '\n' {
+                    self.lineno += 1;
+                }
+                Some(Ok(ch))
+            }
+            Err(error) => Some(Err(Error::Input(error))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use bfup_derive::as_char_results;
+
+    use super::*;
+
+    #[test]
+    fn template_interpolating() -> Result<()> {
+        let variables = Variables::new(Some(16), [(String::from("op"), String::from("+"))]);
+        let prefix = variables.prefix(&Config::default());
+
+        let input = as_char_results!("#@{output_width}(@{ op })\n@");
+        let interpolated = Interpolating::new(input.into_iter(), variables.clone(), prefix)
+            .collect::<Result<String, _>>()?;
+        assert!(
+            interpolated == "#16(+)\n@",
+            "\"{interpolated}\" should have the variables replaced."
+        );
+
+        let input = as_char_results!("#@output_width>#@version @home@");
+        let interpolated = Interpolating::new(input.into_iter(), variables.clone(), prefix)
+            .collect::<Result<String, _>>()?;
+        let version = env!("CARGO_PKG_VERSION_MAJOR").parse::<usize>()? * 10_000
+            + env!("CARGO_PKG_VERSION_MINOR").parse::<usize>()? * 100
+            + env!("CARGO_PKG_VERSION_PATCH").parse::<usize>()?;
+        assert!(
+            interpolated == format!("#16>#{version} @home@"),
+            "\"{interpolated}\" should have only the directives replaced."
+        );
+
+        let input = as_char_results!("+@output_width");
+        let result = Interpolating::new(
+            input.into_iter(),
+            Variables::new(None, [(String::from("op"), String::from("+"))]),
+            prefix,
+        )
+        .collect::<Result<String, _>>();
+        assert!(
+            matches!(&result, Err(Error::Unset { key, lineno: 1 }) if key == "output_width"),
+            "The output width should be unset without alignment, got {result:?}."
+        );
+
+        let input = as_char_results!("+\n@{height}");
+        let result =
+            Interpolating::new(input.into_iter(), variables, prefix).collect::<Result<String, _>>();
+        assert!(
+            matches!(&result, Err(Error::Unset { key, lineno: 2 }) if key == "height"),
+            "Unset variables should be errors, got {result:?}."
+        );
+
+        let input = as_char_results!("comment: email me @{home} +++");
+        let variables = Variables::new(None, []);
+        let prefix = variables.prefix(&Config::default());
+        let interpolated = Interpolating::new(input.into_iter(), variables.clone(), prefix)
+            .collect::<Result<String, _>>()?;
+        assert!(
+            prefix.is_none() && interpolated == "comment: email me @{home} +++",
+            "\"{interpolated}\" shouldn't be interpolated without variables set."
+        );
+
+        let mut config = Config::default();
+        config.set_variable_prefix(Some('%'));
+        let input = as_char_results!("#%{version_major}+ @{home}");
+        let interpolated = Interpolating::new(
+            input.into_iter(),
+            variables.clone(),
+            variables.prefix(&config),
+        )
+        .collect::<Result<String, _>>()?;
+        assert!(
+            interpolated == format!("#{}+ @{{home}}", env!("CARGO_PKG_VERSION_MAJOR")),
+            "\"{interpolated}\" should only have the references with the config's prefix replaced."
+        );
+
+        Ok(())
+    }
+}
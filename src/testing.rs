@@ -0,0 +1,101 @@
+use std::convert::Infallible;
+use std::io::Cursor;
+use std::vec;
+
+use anyhow::Result;
+
+use crate::cancel::CancellationToken;
+use crate::config::Config;
+use crate::lex::{self, Lexer, Token, Warning};
+use crate::pre::preprocess;
+
+/// [`Lexer`] over the chars of a string.
+pub type StrLexer<'a> = Lexer<'a, vec::IntoIter<Result<char, Infallible>>, Infallible>;
+
+/// Create a [`Lexer`] reading `input` according to `config`.
+pub fn lexer<'a>(input: &str, config: &'a Config) -> StrLexer<'a> {
+    let chars: Vec<Result<char, Infallible>> = input.chars().map(Ok).collect();
+
+    Lexer::new(chars.into_iter(), config)
+}
+
+/// Read every [`Token`] of `input` according to `config`.
+pub fn tokens(input: &str, config: &Config) -> Result<Vec<Token>, lex::Error> {
+    lexer(input, config).read_all_tokens()
+}
+
+/// Read every token of `input` according to `config`,
+/// returning the [`Warnings`][Warning] collected along the way.
+pub fn warnings(input: &str, config: &Config) -> Result<Vec<Warning>, lex::Error> {
+    let mut lexer = lexer(input, config);
+    lexer.read_all_tokens()?;
+
+    Ok(lexer.take_warnings())
+}
+
+/// Preprocess `input` according to `config`, returning the emitted operators.
+pub fn preprocessed(input: &str, config: &Config) -> Result<String> {
+    let chars: Vec<Result<char, Infallible>> = input.chars().map(Ok).collect();
+    let mut output = Cursor::new(Vec::new());
+    preprocess(
+        chars.into_iter(),
+        &mut output,
+        config,
+        &CancellationToken::new(),
+    )?;
+
+    Ok(String::from_utf8(output.into_inner())?)
+}
+
+/// Assert that lexing `input` according to `config` fails
+/// with an error whose message contains `message`.
+#[track_caller]
+pub fn assert_error(input: &str, config: &Config, message: &str) {
+    match tokens(input, config) {
+        Ok(tokens) => panic!("{input:?} should fail with {message:?}, got {tokens:?}."),
+        Err(error) => assert!(
+            error.to_string().contains(message),
+            "{input:?} should fail with {message:?}, got \"{error}\"."
+        ),
+    }
+}
+
+/// Assert that lexing `input` according to `config` succeeds
+/// with a warning whose message contains `message`.
+#[track_caller]
+pub fn assert_warning(input: &str, config: &Config, message: &str) {
+    match warnings(input, config) {
+        Ok(warnings) => assert!(
+            warnings
+                .iter()
+                .any(|warning| warning.to_string().contains(message)),
+            "{input:?} should warn with {message:?}, got {warnings:?}."
+        ),
+        Err(error) => panic!("{input:?} should warn with {message:?}, got \"{error}\"."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+    use crate::config::EofPolicy;
+
+    #[test]
+    fn testing_helpers() -> Result<()> {
+        let mut config = Config::default();
+
+        let output = preprocessed("$x(#3+>) xx", &config)?;
+        assert!(
+            output == "+++>+++>",
+            "\"{output}\" should expand the macro."
+        );
+
+        assert_error("(+", &config, "expected ')'");
+        config.set_eof_policy(EofPolicy::Lenient);
+        assert_warning("(+", &config, "unclosed group");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+
+use anyhow::{bail, Result};
+
+use crate::config::{
+    Config, ConfigField, DEFAULT_ESCAPE_PREFIX, DEFAULT_GROUP_END_DELIMITER,
+    DEFAULT_GROUP_START_DELIMITER, DEFAULT_MACRO_PREFIX, DEFAULT_NUMBER_PREFIX, DEFAULT_OPERATORS,
+};
+
+/// Interactive prompter for the fields of a [`Config`],
+/// validating their uniqueness as they're entered.
+pub struct Wizard<'a, R: BufRead, W: Write> {
+    input: &'a mut R,
+    prompts: &'a mut W,
+    /// Field of every char entered so far.
+    fields: HashMap<char, ConfigField>,
+}
+
+impl<'a, R: BufRead, W: Write> Wizard<'a, R, W> {
+    /// Create a new `Wizard` reading answers from `input` and writing prompts to `prompts`.
+    pub fn new(input: &'a mut R, prompts: &'a mut W) -> Self {
+        Wizard {
+            input,
+            prompts,
+            fields: HashMap::new(),
+        }
+    }
+
+    /// Prompt for every field and build the [`Config`].
+    pub fn run(mut self) -> Result<Config> {
+        let operators = self.prompt_operators()?;
+        let group_start_delimiter = self.prompt_char(
+            ConfigField::GroupStartDelimiter,
+            DEFAULT_GROUP_START_DELIMITER,
+        )?;
+        let group_end_delimiter =
+            self.prompt_char(ConfigField::GroupEndDelimiter, DEFAULT_GROUP_END_DELIMITER)?;
+        let number_prefix = self.prompt_char(ConfigField::NumberPrefix, DEFAULT_NUMBER_PREFIX)?;
+        let macro_prefix = self.prompt_char(ConfigField::MacroPrefix, DEFAULT_MACRO_PREFIX)?;
+        let escape_prefix = self.prompt_char(ConfigField::EscapePrefix, DEFAULT_ESCAPE_PREFIX)?;
+
+        Ok(Config::new(
+            operators.chars(),
+            group_start_delimiter,
+            group_end_delimiter,
+            number_prefix,
+            macro_prefix,
+            escape_prefix,
+        )?)
+    }
+
+    /// Prompt for the operators until they're non-empty.
+    fn prompt_operators(&mut self) -> Result<String> {
+        loop {
+            let operators = self.prompt("operators", DEFAULT_OPERATORS)?;
+            if operators.is_empty() {
+                writeln!(self.prompts, "there must be at least one operator.")?;
+                continue;
+            }
+
+            for ch in operators.chars() {
+                self.fields.insert(ch, ConfigField::Operator);
+            }
+            return Ok(operators);
+        }
+    }
+
+    /// Prompt for the char of `field` until it's a single char not assigned to another field.
+    fn prompt_char(&mut self, field: ConfigField, default: char) -> Result<char> {
+        loop {
+            let answer = self.prompt(&field.to_string(), &default.to_string())?;
+
+            let mut chars = answer.chars();
+            let ch = match (chars.next(), chars.next()) {
+                (Some(ch), None) => ch,
+                _ => {
+                    writeln!(self.prompts, "{field} must be a single character.")?;
+                    continue;
+                }
+            };
+
+            if let Some(other_field) = self.fields.get(&ch) {
+                writeln!(self.prompts, "'{ch}' is already the {other_field}.")?;
+                continue;
+            }
+
+            self.fields.insert(ch, field);
+            return Ok(ch);
+        }
+    }
+
+    /// Prompt for `name`, returning `default` on an empty answer.
+    fn prompt(&mut self, name: &str, default: &str) -> Result<String> {
+        write!(self.prompts, "{name} [{default}]: ")?;
+        self.prompts.flush()?;
+
+        let mut answer = String::new();
+        if self.input.read_line(&mut answer)? == 0 {
+            bail!("input ended before the {name} was entered");
+        }
+
+        let answer = answer.trim_end_matches(['\n', '\r']);
+        Ok(if answer.is_empty() {
+            String::from(default)
+        } else {
+            String::from(answer)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+
+    use super::*;
+
+    #[test]
+    fn wizard_reprompts() -> Result<()> {
+        let mut input = "abc\n\n{}\na\n}\n\n@\n\n".as_bytes();
+        let mut prompts: Vec<u8> = Vec::new();
+
+        let config = Wizard::new(&mut input, &mut prompts).run()?;
+        let prompts = String::from_utf8(prompts)?;
+
+        assert!(
+            config == Config::new("abc".chars(), '(', '}', '#', '@', '\\')?,
+            "The config should contain the answers."
+        );
+        assert!(
+            prompts.contains("must be a single character")
+                && prompts.contains("'a' is already the operator"),
+            "Invalid answers should be reprompted."
+        );
+
+        Ok(())
+    }
+}
@@ -0,0 +1,529 @@
+//! End-to-end tests of the `bfup` binary, run with `--test-mode`
+//! so the output doesn't depend on the terminal or the version.
+
+use std::fs;
+use std::path::PathBuf;
+
+use assert_cmd::Command;
+
+/// Get the `bfup` command in test mode, run in a directory of its own,
+/// so no config is discovered.
+fn bfup(test_name: &str) -> Command {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join(test_name);
+    fs::create_dir_all(&dir).expect("The test directory should be created.");
+
+    let mut command = Command::cargo_bin("bfup").expect("The binary should be built.");
+    command.current_dir(dir).arg("--test-mode");
+    command
+}
+
+/// Get the stdout of the command as a `String`.
+fn stdout(command: &mut Command) -> String {
+    let output = command.assert().success().get_output().stdout.clone();
+    String::from_utf8(output).expect("The output should be utf-8.")
+}
+
+#[test]
+fn cli_preprocess_stdin() {
+    let output = stdout(bfup("cli_preprocess_stdin").write_stdin("$a(+>)#3a -"));
+    assert!(
+        output == "+>+>+>-\n",
+        "\"{output}\" should be the preprocessed input with a newline."
+    );
+}
+
+#[test]
+fn cli_streamed_output() {
+    let input = "$a(+>)#3(a#2-) $b(<a) b";
+    let streamed = stdout(bfup("cli_streamed_output").write_stdin(input));
+    // verifying the size needs the tokens of the whole input
+    let lexed = stdout(
+        bfup("cli_streamed_output")
+            .arg("--verify-size")
+            .write_stdin(input),
+    );
+    assert!(
+        streamed == "+>--+>--+>--<+>\n" && streamed == lexed,
+        "\"{streamed}\" should be the same as \"{lexed}\" of the whole input."
+    );
+}
+
+#[test]
+fn cli_alignment() {
+    let output = stdout(
+        bfup("cli_alignment")
+            .args(["--line-width", "4"])
+            .write_stdin("#10+"),
+    );
+    assert!(
+        output == "++++\n++++\n++\n",
+        "\"{output}\" should be aligned to 4 operators."
+    );
+}
+
+#[test]
+fn cli_line_width_validation() {
+    bfup("cli_line_width_validation")
+        .args(["--line-width", "0"])
+        .write_stdin("+")
+        .assert()
+        .failure()
+        .code(2);
+
+    let assert = bfup("cli_line_width_validation")
+        .args(["--word-operator", "Ook.", "--line-width", "2"])
+        .write_stdin("Ook.")
+        .assert()
+        .success();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(
+        stderr.contains("operator 'Ook.' is 4 wide, over the line width of 2"),
+        "\"{stderr}\" should warn about the operator overflowing the lines."
+    );
+}
+
+#[test]
+fn cli_exact_rectangle() {
+    let output = stdout(
+        bfup("cli_exact_rectangle")
+            .args(["--line-width", "4", "--exact-rectangle"])
+            .write_stdin("#8+"),
+    );
+    assert!(
+        output == "++++\n++++\n\n",
+        "\"{output}\" should be a rectangle of 2 lines."
+    );
+
+    let assert = bfup("cli_exact_rectangle")
+        .args(["--line-width", "4", "--exact-rectangle"])
+        .write_stdin("#10+")
+        .assert()
+        .failure();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(
+        stderr.contains("the last line is 2 operators short"),
+        "\"{stderr}\" should report the shortfall of the last line."
+    );
+}
+
+#[test]
+fn cli_gutter() {
+    let output = stdout(
+        bfup("cli_gutter")
+            .args(["--line-width", "4", "--gutter"])
+            .write_stdin("#6+"),
+    );
+    assert!(
+        output == "   1 ++++\n   2 ++\n",
+        "\"{output}\" should have the lines numbered."
+    );
+
+    bfup("cli_gutter")
+        .args(["--gutter", "--no-align"])
+        .write_stdin("+")
+        .assert()
+        .failure()
+        .code(2);
+}
+
+#[test]
+fn cli_tee() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("cli_tee");
+    let input = "$a(+>)#2a -";
+    let output = stdout(
+        bfup("cli_tee")
+            .args(["--tee", "input.bfup"])
+            .write_stdin(input),
+    );
+    let copy = fs::read_to_string(dir.join("input.bfup")).expect("The copy should be written.");
+    assert!(
+        output == "+>+>-\n" && copy == input,
+        "\"{output}\" should be preprocessed from \"{copy}\", the copied input."
+    );
+}
+
+#[test]
+fn cli_outputs() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("cli_outputs");
+    let output = stdout(bfup("cli_outputs").args(["-o", "-"]).write_stdin("#2+"));
+    assert!(
+        output == "++\n",
+        "\"{output}\" should be written to stdout."
+    );
+
+    let output = stdout(
+        bfup("cli_outputs")
+            .args(["-o", "a.bf", "-o", "b.bf"])
+            .write_stdin("#2+"),
+    );
+    let a = fs::read_to_string(dir.join("a.bf")).expect("The first output should be written.");
+    let b = fs::read_to_string(dir.join("b.bf")).expect("The second output should be written.");
+    assert!(
+        output.is_empty() && a == "++\n" && a == b,
+        "\"{a}\" and \"{b}\" should be the same output, not written to stdout."
+    );
+
+    fs::remove_file(dir.join("a.bf")).expect("The first output should be removed.");
+    fs::remove_file(dir.join("b.bf")).expect("The second output should be removed.");
+    bfup("cli_outputs")
+        .args(["-o", "a.bf", "-o", "b.bf", "--inject-write-error", "1"])
+        .write_stdin("#2+")
+        .assert()
+        .failure();
+    let left: Vec<_> = ["a.bf", "b.bf", "a.bf.part", "b.bf.part"]
+        .into_iter()
+        .filter(|name| dir.join(name).exists())
+        .collect();
+    assert!(
+        left.is_empty(),
+        "{left:?} shouldn't be left behind by the failed run."
+    );
+}
+
+#[cfg(feature = "compress")]
+#[test]
+fn cli_compress() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("cli_compress");
+
+    for (format, extension, magic) in [
+        ("gzip", "gz", &[0x1f, 0x8b][..]),
+        ("zstd", "zst", &[0x28, 0xb5, 0x2f, 0xfd][..]),
+    ] {
+        let output = stdout(
+            bfup("cli_compress")
+                .args(["-o", "out.bf", "--compress", format])
+                .write_stdin("#2+"),
+        );
+        assert!(output.is_empty(), "\"{output}\" should only be compressed.");
+
+        let path = format!("out.bf.{extension}");
+        let compressed = fs::read(dir.join(&path)).expect("The output should be written.");
+        assert!(
+            compressed.starts_with(magic),
+            "The output should be compressed with {format}."
+        );
+        let output = stdout(bfup("cli_compress").arg(&path));
+        assert!(
+            output == "++\n",
+            "\"{output}\" should be decompressed from {format}."
+        );
+    }
+}
+
+#[test]
+fn cli_config_file() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("cli_config_file");
+    fs::create_dir_all(&dir).expect("The test directory should be created.");
+    fs::write(
+        dir.join("config.ron"),
+        "Config(operators: \"ab\", number_prefix: '*')",
+    )
+    .expect("The config should be written.");
+
+    let output = stdout(
+        bfup("cli_config_file")
+            .args(["--config-file", "config.ron"])
+            .write_stdin("*3a b+"),
+    );
+    assert!(
+        output == "aaab\n",
+        "\"{output}\" should be preprocessed with the config from the file."
+    );
+
+    bfup("cli_config_file")
+        .args(["--config-file", "missing.ron"])
+        .write_stdin("+")
+        .assert()
+        .failure()
+        .code(1);
+}
+
+#[test]
+fn cli_error_rendering() {
+    let assert = bfup("cli_error_rendering")
+        .write_stdin("+\n (-")
+        .assert()
+        .failure()
+        .code(1);
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(
+        stderr == "error: failure while preprocessing\n\n[2:4]: expected ')'.\n",
+        "\"{stderr}\" should be the uncolored error with its cause."
+    );
+}
+
+#[test]
+fn cli_unrecognized_threshold() {
+    let input = "comment: increment the cell +";
+    let assert = bfup("cli_unrecognized_threshold")
+        .write_stdin(input)
+        .assert()
+        .success();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(
+        stderr.is_empty(),
+        "\"{stderr}\" shouldn't warn about the comments by default."
+    );
+
+    let assert = bfup("cli_unrecognized_threshold")
+        .arg("--unrecognized-threshold")
+        .write_stdin(input)
+        .assert()
+        .success();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(
+        stderr.contains("96% of the input's characters are unrecognized"),
+        "\"{stderr}\" should warn about the unrecognized characters when asked to."
+    );
+}
+
+#[test]
+fn cli_list_macros() {
+    let output = stdout(
+        bfup("cli_list_macros")
+            .args(["--list-macros", "-D", "d=++"])
+            .write_stdin("$a(+>)\n$b(#2a-)\n$a(<)\nabd"),
+    );
+    assert!(
+        output
+            == "'a' at 1:1-1:6: (+>) (2 operators)\n\
+                'b' at 2:1-2:8: (#2(+>)-) (5 operators)\n\
+                'a' at 3:1-3:5: (<) (1 operators)\n",
+        "\"{output}\" should list the expanded definitions of the input, \
+        redefinitions included, without the ones passed with -D."
+    );
+}
+
+#[test]
+fn cli_operator_weights() {
+    let assert = bfup("cli_operator_weights")
+        .args(["--stats", "--operator-weight=-=4"])
+        .write_stdin("++---")
+        .assert()
+        .success();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(
+        stderr.contains("emitted operators cost 14"),
+        "\"{stderr}\" should report the cost of 2 '+' and 3 '-' weighing 4."
+    );
+
+    let assert = bfup("cli_operator_weights")
+        .arg("--stats")
+        .write_stdin("++---")
+        .assert()
+        .success();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(
+        stderr.contains("emitted operators cost 5"),
+        "\"{stderr}\" should report the unweighted cost."
+    );
+
+    bfup("cli_operator_weights")
+        .args(["--operator-weight", "x=4"])
+        .write_stdin("+")
+        .assert()
+        .failure();
+}
+
+#[test]
+fn cli_optimize() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("cli_optimize");
+    fs::create_dir_all(&dir).expect("The test directory should be created.");
+    fs::write(dir.join("config.ron"), "Config()").expect("The config should be written.");
+
+    let assert = bfup("cli_optimize")
+        .args(["--optimize", "--config-file", "config.ron", "--stats"])
+        .write_stdin("+-+>[-][+]<>")
+        .assert()
+        .success();
+    let output = String::from_utf8_lossy(&assert.get_output().stdout);
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(
+        output == "+>[-]\n",
+        "\"{output}\" should be optimized with the config file."
+    );
+    assert!(
+        stderr.contains("optimizing removed 7 operator(s)"),
+        "\"{stderr}\" should report the removed operators with the stats."
+    );
+
+    let output = stdout(
+        bfup("cli_optimize")
+            .args(["--optimize", "--passes", "lex,macro-expand,emit"])
+            .write_stdin("+-+"),
+    );
+    assert!(
+        output == "+-+\n",
+        "\"{output}\" should only be optimized with the optimize stage."
+    );
+
+    let assert = bfup("cli_optimize")
+        .args(["--optimize", "--operators", "+-"])
+        .write_stdin("+-+")
+        .assert()
+        .failure();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(
+        stderr.contains("can only be optimized with brainfuck's operators"),
+        "\"{stderr}\" should refuse to optimize other operators."
+    );
+}
+
+#[test]
+fn cli_range() {
+    let output = stdout(
+        bfup("cli_range")
+            .args(["--range", "2:3"])
+            .write_stdin("+(>\n<)\n-\ncomment\n)"),
+    );
+    assert!(
+        output == "<-\n",
+        "\"{output}\" should only be lexed up to the end of the region."
+    );
+}
+
+#[test]
+fn cli_profile() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("cli_profile");
+    fs::create_dir_all(&dir).expect("The test directory should be created.");
+    fs::write(dir.join("loop.bfup"), "$a(>+<-) #3+ (a) [a]").expect("The input should be written.");
+
+    let assert = bfup("cli_profile")
+        .args(["--run", "--profile", "1", "loop.bfup"])
+        .assert()
+        .success();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(
+        stderr
+            == "profile: most executed instructions:\n  \
+                Move(1) at operator 8 in macro 'a' used at [1:19], executed 2 time(s)\n\
+                profile: most executed macros and groups:\n  \
+                macro 'a' used at [1:19], executed 8 instruction(s)\n",
+        "\"{stderr}\" should trace the hotspots back to the macro in the loop."
+    );
+}
+
+#[test]
+fn cli_batch() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("cli_batch");
+    fs::create_dir_all(&dir).expect("The test directory should be created.");
+    fs::write(dir.join("plus.bfup"), "#@{n}+d").expect("The input should be written.");
+    fs::write(dir.join("open.bfup"), "(+>+").expect("The input should be written.");
+    fs::write(dir.join("bad.bfup"), "#0x+").expect("The input should be written.");
+    fs::write(
+        dir.join("jobs.json"),
+        r#"[
+            {"input": "plus.bfup", "output": "plus.bf"},
+            {"input": "plus.bfup", "output": "counts.json", "emit": "tokens-count"},
+            {"input": "open.bfup", "output": "open.bf", "width": 2},
+            {"input": "bad.bfup", "output": "bad.bf"}
+        ]"#,
+    )
+    .expect("The jobs should be written.");
+
+    let assert = bfup("cli_batch")
+        .args(["--batch", "jobs.json", "--set", "n=3", "-D", "d=->"])
+        .args(["--eof-policy", "lenient"])
+        .assert()
+        .failure();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(
+        stderr.contains("unclosed group was closed") && stderr.contains("1 of 4 jobs failed"),
+        "\"{stderr}\" should report the warnings of the jobs and the failed one."
+    );
+
+    let output = fs::read_to_string(dir.join("plus.bf")).expect("The output should be written.");
+    assert!(
+        output == "+++->\n",
+        "\"{output}\" should use the variables and the defined macros."
+    );
+    let counts =
+        fs::read_to_string(dir.join("counts.json")).expect("The counts should be written.");
+    assert!(
+        counts.contains("\"numbers\":1"),
+        "\"{counts}\" should contain the token counts."
+    );
+    let output = fs::read_to_string(dir.join("open.bf")).expect("The output should be written.");
+    assert!(output == "+>\n+\n", "\"{output}\" should be aligned.");
+    assert!(
+        !dir.join("bad.bf").exists() && !dir.join("bad.bf.part").exists(),
+        "The failed job shouldn't leave an output behind."
+    );
+}
+
+#[test]
+fn cli_resume_stdin() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("cli_resume_stdin");
+    let resumable = ["--checkpoint-every", "4", "-o", "plus.bf"];
+
+    bfup("cli_resume_stdin")
+        .args(resumable)
+        .args(["--inject-write-error", "20"])
+        .write_stdin("#40+")
+        .assert()
+        .failure();
+    assert!(
+        dir.join("plus.bf.part.stdin").exists(),
+        "The copy of stdin should be kept to resume."
+    );
+
+    bfup("cli_resume_stdin")
+        .args(resumable)
+        .args(["--resume-from", "20"])
+        .write_stdin("#40+")
+        .assert()
+        .success();
+    let output = fs::read_to_string(dir.join("plus.bf")).expect("The output should be written.");
+    assert!(
+        output == format!("{}\n{}\n", "+".repeat(32), "+".repeat(8)),
+        "\"{output}\" should be resumed with the same stdin."
+    );
+    assert!(
+        !dir.join("plus.bf.part.stdin").exists(),
+        "The copy of stdin should be removed with the checkpoints."
+    );
+}
+
+#[test]
+fn cli_resume_other_run() {
+    let dir = PathBuf::from(env!("CARGO_TARGET_TMPDIR")).join("cli_resume_other_run");
+    fs::create_dir_all(&dir).expect("The test directory should be created.");
+    fs::write(dir.join("plus.bfup"), "#40+").expect("The input should be written.");
+    let resumable = ["--checkpoint-every", "4", "-o", "plus.bf", "plus.bfup"];
+
+    bfup("cli_resume_other_run")
+        .args(resumable)
+        .args(["--inject-write-error", "20"])
+        .assert()
+        .failure();
+    let assert = bfup("cli_resume_other_run")
+        .args(resumable)
+        .args(["--resume-from", "20", "--line-width", "10"])
+        .assert()
+        .failure();
+    let stderr = String::from_utf8_lossy(&assert.get_output().stderr);
+    assert!(
+        stderr.contains("the checkpoints were saved by a run with another input"),
+        "\"{stderr}\" should refuse to resume with another alignment."
+    );
+
+    bfup("cli_resume_other_run")
+        .args(resumable)
+        .args(["--resume-from", "20"])
+        .assert()
+        .success();
+    let output = fs::read_to_string(dir.join("plus.bf")).expect("The output should be written.");
+    assert!(
+        output == format!("{}\n{}\n", "+".repeat(32), "+".repeat(8)),
+        "\"{output}\" should be resumed by the same run."
+    );
+}
+
+#[test]
+fn cli_version() {
+    let output = stdout(bfup("cli_version").arg("--version"));
+    assert!(
+        output == "bfup 0.0.0-test\n",
+        "\"{output}\" should be the fixed version."
+    );
+}